@@ -0,0 +1,29 @@
+fn main() -> std::io::Result<()> {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "protobuf")]
+    {
+        // Builds without a system protoc, unless PROTOC names one.
+        if std::env::var_os("PROTOC").is_none() {
+            let protoc = protoc_bin_vendored::protoc_bin_path().map_err(std::io::Error::other)?;
+            std::env::set_var("PROTOC", protoc);
+        }
+        prost_build::compile_protos(&["src/mem_ipc.proto"], &["src/"])?;
+        println!("cargo:rerun-if-changed=src/mem_ipc.proto");
+    }
+    #[cfg(feature = "ffi")]
+    {
+        // The ffi tests check that include/mem_ipc.h matches.
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").expect("Set by cargo");
+        let out_dir = std::env::var("OUT_DIR").expect("Set by cargo");
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).map_err(std::io::Error::other)?;
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(format!("{}/src/ffi.rs", crate_dir))
+            .generate()
+            .map_err(std::io::Error::other)?
+            .write_to_file(format!("{}/mem_ipc.h", out_dir));
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+    }
+    Ok(())
+}
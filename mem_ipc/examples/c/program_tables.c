@@ -0,0 +1,99 @@
+/*
+ * Adds an entry to the ternary table "acl" and one to the direct table
+ * "nexthop" of a server started with `-n /tables`, through the C API of
+ * mem_ipc.
+ *
+ *   cargo build --features ffi
+ *   cc -I include examples/c/program_tables.c target/debug/libmem_ipc.a \
+ *      -lpthread -ldl -lm -lrt -o program_tables
+ *   ./program_tables /tables /program_tables.reply
+ */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "mem_ipc.h"
+
+#define CHECK(call)                                                            \
+    do {                                                                       \
+        MemIpcStatus check_status = (call);                                    \
+        if (check_status != MEM_IPC_STATUS_OK) {                               \
+            fprintf(stderr, "%s failed (%d): %s\n", #call, check_status,       \
+                    mem_ipc_last_error());                                     \
+            return 1;                                                          \
+        }                                                                      \
+    } while (0)
+
+/* Sends a request and checks the server's reply. */
+static int apply(MemIpcQueue *server, MemIpcQueue *replies, uint8_t *request, size_t request_len) {
+    uint8_t *reply;
+    size_t reply_len;
+
+    CHECK(mem_ipc_send(server, request, request_len));
+    mem_ipc_bytes_free(request, request_len);
+    CHECK(mem_ipc_receive(replies, 5000, &reply, &reply_len));
+    MemIpcStatus status = mem_ipc_reply_check(reply, reply_len);
+    mem_ipc_bytes_free(reply, reply_len);
+    CHECK(status);
+    return 0;
+}
+
+static int add_acl(MemIpcQueue *server, MemIpcQueue *replies, const char *reply_to) {
+    const uint8_t key[] = {10, 0}, mask[] = {0xff, 0};
+    const char *result = "permit";
+    MemIpcCItem *item, *decoded;
+    uint8_t *data;
+    size_t len, key_len;
+
+    CHECK(mem_ipc_citem_new("acl", MEM_IPC_ACTION_ADD, 1, key, mask, sizeof key,
+                            (const uint8_t *)result, strlen(result), &item));
+
+    /* Items survive encoding. */
+    CHECK(mem_ipc_citem_encode(item, &data, &len));
+    CHECK(mem_ipc_citem_decode(data, len, &decoded));
+    mem_ipc_bytes_free(data, len);
+    const uint8_t *decoded_key = mem_ipc_citem_key(decoded, &key_len);
+    int same = strcmp(mem_ipc_citem_table_id(decoded), "acl") == 0
+        && mem_ipc_citem_action(decoded) == MEM_IPC_ACTION_ADD
+        && mem_ipc_citem_priority(decoded) == 1
+        && key_len == sizeof key && memcmp(decoded_key, key, key_len) == 0;
+    mem_ipc_citem_free(decoded);
+    if (!same) {
+        fprintf(stderr, "Decoded acl item differs\n");
+        return 1;
+    }
+
+    CHECK(mem_ipc_citem_request(item, reply_to, &data, &len));
+    mem_ipc_citem_free(item);
+    return apply(server, replies, data, len);
+}
+
+static int add_nexthop(MemIpcQueue *server, MemIpcQueue *replies, const char *reply_to) {
+    const char *value = "eth0";
+    MemIpcSItem *item;
+    uint8_t *data;
+    size_t len;
+
+    CHECK(mem_ipc_sitem_new("nexthop", MEM_IPC_ACTION_ADD, 3, (const uint8_t *)value, strlen(value), &item));
+    CHECK(mem_ipc_sitem_request(item, reply_to, &data, &len));
+    mem_ipc_sitem_free(item);
+    return apply(server, replies, data, len);
+}
+
+int main(int argc, char **argv) {
+    MemIpcQueue *server, *replies;
+
+    if (argc != 3) {
+        fprintf(stderr, "Usage: %s <server queue> <reply queue>\n", argv[0]);
+        return 2;
+    }
+    CHECK(mem_ipc_open_writer(argv[1], 1024, 10, &server));
+    CHECK(mem_ipc_open_reader(argv[2], 1024, 10, &replies));
+
+    int failed = add_acl(server, replies, argv[2]) || add_nexthop(server, replies, argv[2]);
+
+    mem_ipc_queue_unlink(replies);
+    mem_ipc_queue_free(replies);
+    mem_ipc_queue_free(server);
+    return failed;
+}
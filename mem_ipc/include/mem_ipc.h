@@ -0,0 +1,336 @@
+/* Generated from src/ffi.rs by cbindgen, do not edit. */
+
+#ifndef MEM_IPC_H
+#define MEM_IPC_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+/*
+ Values of `action` in `mem_ipc_citem_new` and `mem_ipc_sitem_new`, see `Actions`.
+ */
+#define MEM_IPC_ACTION_NOOP 0
+
+#define MEM_IPC_ACTION_ADD 1
+
+#define MEM_IPC_ACTION_DELETE 2
+
+#define MEM_IPC_ACTION_QUERY 3
+
+#define MEM_IPC_ACTION_READ_COUNTERS 4
+
+#define MEM_IPC_ACTION_RESET_COUNTERS 5
+
+/*
+ Result of every fallible function.
+ */
+typedef enum MemIpcStatus {
+  MEM_IPC_STATUS_OK = 0,
+  /*
+   A null pointer, a string that is not UTF-8 or an invalid item.
+   */
+  MEM_IPC_STATUS_INVALID_ARGUMENT = 1,
+  /*
+   Opening, sending on or receiving from a queue failed.
+   */
+  MEM_IPC_STATUS_QUEUE_ERROR = 2,
+  /*
+   Nothing arrived before the timeout.
+   */
+  MEM_IPC_STATUS_TIMEOUT = 3,
+  /*
+   A received buffer is not a valid item or reply.
+   */
+  MEM_IPC_STATUS_DECODE_ERROR = 4,
+  /*
+   The server answered with an error.
+   */
+  MEM_IPC_STATUS_SERVER_ERROR = 5,
+} MemIpcStatus;
+
+/*
+ A `CItem`, with its table id as a C string.
+ */
+typedef struct MemIpcCItem MemIpcCItem;
+
+/*
+ A message queue opened for writing or reading.
+ */
+typedef struct MemIpcQueue MemIpcQueue;
+
+/*
+ An `SItem`, with its table id as a C string.
+ */
+typedef struct MemIpcSItem MemIpcSItem;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Describes the last failure on this thread. Valid until the next failing
+ call on the same thread.
+ */
+const char *mem_ipc_last_error(void);
+
+/*
+ Opens the queue `name` for writing, creating it with room for `max_items`
+ items of `max_item_size` bytes if it does not exist; 0 picks the default.
+
+ # Safety
+ `name` must be a NUL-terminated string and `out` writable.
+ */
+enum MemIpcStatus mem_ipc_open_writer(const char *name,
+                                      size_t max_item_size,
+                                      size_t max_items,
+                                      struct MemIpcQueue **out);
+
+/*
+ Like `mem_ipc_open_writer`, for reading.
+
+ # Safety
+ `name` must be a NUL-terminated string and `out` writable.
+ */
+enum MemIpcStatus mem_ipc_open_reader(const char *name,
+                                      size_t max_item_size,
+                                      size_t max_items,
+                                      struct MemIpcQueue **out);
+
+/*
+ Sends `len` bytes at `data` as one message.
+
+ # Safety
+ `queue` must come from `mem_ipc_open_writer` and `data` hold `len` bytes.
+ */
+enum MemIpcStatus mem_ipc_send(const struct MemIpcQueue *queue, const uint8_t *data, size_t len);
+
+/*
+ Receives one message, waiting at most `timeout_ms` or forever if it is
+ negative. The message is released with `mem_ipc_bytes_free`.
+
+ # Safety
+ `queue` must come from `mem_ipc_open_reader`, `data` and `len` be writable.
+ */
+enum MemIpcStatus mem_ipc_receive(const struct MemIpcQueue *queue,
+                                  int64_t timeout_ms,
+                                  uint8_t **data,
+                                  size_t *len);
+
+/*
+ Removes the queue's name, e.g. for a reply queue nobody else uses.
+
+ # Safety
+ `queue` must come from `mem_ipc_open_writer` or `mem_ipc_open_reader`.
+ */
+enum MemIpcStatus mem_ipc_queue_unlink(const struct MemIpcQueue *queue);
+
+/*
+ Closes a queue. Null is ignored.
+
+ # Safety
+ `queue` must come from `mem_ipc_open_writer` or `mem_ipc_open_reader`, and
+ not be used afterwards.
+ */
+void mem_ipc_queue_free(struct MemIpcQueue *queue);
+
+/*
+ Releases a buffer returned by this library. Null is ignored.
+
+ # Safety
+ `data` and `len` must be exactly what the library returned.
+ */
+void mem_ipc_bytes_free(uint8_t *data, size_t len);
+
+/*
+ Builds a `CItem` for `action`, one of `MEM_IPC_ACTION_*`, on a ternary table. `key`
+ and `mask` both hold `key_len` bytes.
+
+ # Safety
+ `table_id` must be a NUL-terminated string, the buffers hold the given
+ number of bytes (or be null if it is 0) and `out` be writable.
+ */
+enum MemIpcStatus mem_ipc_citem_new(const char *table_id,
+                                    uint8_t action,
+                                    uint16_t priority,
+                                    const uint8_t *key,
+                                    const uint8_t *mask,
+                                    size_t key_len,
+                                    const uint8_t *result,
+                                    size_t result_len,
+                                    struct MemIpcCItem **out);
+
+/*
+ Decodes a `CItem` written by `mem_ipc_citem_encode`.
+
+ # Safety
+ `data` must hold `len` bytes and `out` be writable.
+ */
+enum MemIpcStatus mem_ipc_citem_decode(const uint8_t *data, size_t len, struct MemIpcCItem **out);
+
+/*
+ Encodes the item in the `CItem::pack` layout, to be released with
+ `mem_ipc_bytes_free`.
+
+ # Safety
+ `item` must come from this library, `data` and `len` be writable.
+ */
+enum MemIpcStatus mem_ipc_citem_encode(const struct MemIpcCItem *item, uint8_t **data, size_t *len);
+
+/*
+ Encodes a request applying the item, with the reply going to the queue
+ `reply_to` (none if empty). Released with `mem_ipc_bytes_free`.
+
+ # Safety
+ `item` must come from this library, `reply_to` be a NUL-terminated string,
+ `data` and `len` be writable.
+ */
+enum MemIpcStatus mem_ipc_citem_request(const struct MemIpcCItem *item,
+                                        const char *reply_to,
+                                        uint8_t **data,
+                                        size_t *len);
+
+/*
+ The item's table id, valid as long as the item.
+
+ # Safety
+ `item` must come from this library.
+ */
+const char *mem_ipc_citem_table_id(const struct MemIpcCItem *item);
+
+/*
+ # Safety
+ `item` must come from this library.
+ */
+uint8_t mem_ipc_citem_action(const struct MemIpcCItem *item);
+
+/*
+ # Safety
+ `item` must come from this library.
+ */
+uint16_t mem_ipc_citem_priority(const struct MemIpcCItem *item);
+
+/*
+ The item's key, `*len` bytes valid as long as the item.
+
+ # Safety
+ `item` must come from this library and `len` be writable or null.
+ */
+const uint8_t *mem_ipc_citem_key(const struct MemIpcCItem *item, size_t *len);
+
+/*
+ Like `mem_ipc_citem_key`, for the mask.
+
+ # Safety
+ `item` must come from this library and `len` be writable or null.
+ */
+const uint8_t *mem_ipc_citem_mask(const struct MemIpcCItem *item, size_t *len);
+
+/*
+ Like `mem_ipc_citem_key`, for the result.
+
+ # Safety
+ `item` must come from this library and `len` be writable or null.
+ */
+const uint8_t *mem_ipc_citem_result(const struct MemIpcCItem *item, size_t *len);
+
+/*
+ Releases an item. Null is ignored.
+
+ # Safety
+ `item` must come from this library and not be used afterwards.
+ */
+void mem_ipc_citem_free(struct MemIpcCItem *item);
+
+/*
+ Builds an `SItem` for `action`, one of `MEM_IPC_ACTION_*`, on a direct table.
+
+ # Safety
+ `table_id` must be a NUL-terminated string, `value` hold `value_len` bytes
+ (or be null if it is 0) and `out` be writable.
+ */
+enum MemIpcStatus mem_ipc_sitem_new(const char *table_id,
+                                    uint8_t action,
+                                    uint16_t index,
+                                    const uint8_t *value,
+                                    size_t value_len,
+                                    struct MemIpcSItem **out);
+
+/*
+ Decodes an `SItem` written by `mem_ipc_sitem_encode`.
+
+ # Safety
+ `data` must hold `len` bytes and `out` be writable.
+ */
+enum MemIpcStatus mem_ipc_sitem_decode(const uint8_t *data, size_t len, struct MemIpcSItem **out);
+
+/*
+ Encodes the item in the `SItem::pack` layout, to be released with
+ `mem_ipc_bytes_free`.
+
+ # Safety
+ `item` must come from this library, `data` and `len` be writable.
+ */
+enum MemIpcStatus mem_ipc_sitem_encode(const struct MemIpcSItem *item, uint8_t **data, size_t *len);
+
+/*
+ Like `mem_ipc_citem_request`, for an `SItem`.
+
+ # Safety
+ `item` must come from this library, `reply_to` be a NUL-terminated string,
+ `data` and `len` be writable.
+ */
+enum MemIpcStatus mem_ipc_sitem_request(const struct MemIpcSItem *item,
+                                        const char *reply_to,
+                                        uint8_t **data,
+                                        size_t *len);
+
+/*
+ The item's table id, valid as long as the item.
+
+ # Safety
+ `item` must come from this library.
+ */
+const char *mem_ipc_sitem_table_id(const struct MemIpcSItem *item);
+
+/*
+ # Safety
+ `item` must come from this library.
+ */
+uint8_t mem_ipc_sitem_action(const struct MemIpcSItem *item);
+
+/*
+ # Safety
+ `item` must come from this library.
+ */
+uint16_t mem_ipc_sitem_index(const struct MemIpcSItem *item);
+
+/*
+ The item's value, `*len` bytes valid as long as the item.
+
+ # Safety
+ `item` must come from this library and `len` be writable or null.
+ */
+const uint8_t *mem_ipc_sitem_value(const struct MemIpcSItem *item, size_t *len);
+
+/*
+ Releases an item. Null is ignored.
+
+ # Safety
+ `item` must come from this library and not be used afterwards.
+ */
+void mem_ipc_sitem_free(struct MemIpcSItem *item);
+
+/*
+ Checks the server's reply to a request: `ServerError` if it is an error
+ or a conflict, with the server's message in `mem_ipc_last_error`.
+
+ # Safety
+ `data` must hold `len` bytes.
+ */
+enum MemIpcStatus mem_ipc_reply_check(const uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MEM_IPC_H */
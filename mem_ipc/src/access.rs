@@ -0,0 +1,264 @@
+//! Who may do what to which table.
+//!
+//! A [`Policy`] grants access levels to clients, identified by the peer
+//! credentials of their socket connection or by a token sent with every
+//! request. Without a policy every client may do everything; with one,
+//! anything not granted is refused.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::protocol::RequestBody;
+use crate::socket::PeerCredentials;
+use crate::Actions;
+
+/// Access levels, each including the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    /// Lookups, dumps, counters and subscriptions.
+    ReadOnly,
+    /// Adding and deleting entries, defaults and resetting counters.
+    Write,
+    /// Snapshots, reconciling and reloading the configuration.
+    Admin,
+}
+
+impl Access {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Access::ReadOnly => "read-only",
+            Access::Write => "write",
+            Access::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-only" => Ok(Access::ReadOnly),
+            "write" => Ok(Access::Write),
+            "admin" => Ok(Access::Admin),
+            _ => Err(format!("Unknown access level '{}', expected 'read-only', 'write' or 'admin'", s)),
+        }
+    }
+}
+
+/// What a requirement is about.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope<'a> {
+    Table(&'a str),
+    All,
+    /// At least one table, for requests about none in particular.
+    Any,
+}
+
+impl fmt::Display for Scope<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Table(table) => write!(f, "{}", table),
+            Scope::All => write!(f, "*"),
+            Scope::Any => write!(f, "any"),
+        }
+    }
+}
+
+/// Clients a rule applies to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Principal {
+    Uid(u32),
+    /// Only the primary group counts, it is all the kernel reports.
+    Gid(u32),
+    /// Clients that sent the token with this name.
+    Token(String),
+    Anyone,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub principal: Principal,
+    /// Tables the rule covers; empty means every table.
+    pub tables: Vec<String>,
+    pub access: Access,
+}
+
+impl Rule {
+    fn applies_to(&self, caller: &Caller) -> bool {
+        match &self.principal {
+            Principal::Uid(uid) => caller.peer.is_some_and(|p| p.uid == *uid),
+            Principal::Gid(gid) => caller.peer.is_some_and(|p| p.gid == *gid),
+            Principal::Token(name) => caller.token.as_ref() == Some(name),
+            Principal::Anyone => true,
+        }
+    }
+
+    /// Whether the rule covers `table`, or every table if `None`.
+    fn covers(&self, table: Option<&str>) -> bool {
+        self.tables.is_empty() || table.is_some_and(|t| self.tables.iter().any(|name| name == t))
+    }
+}
+
+/// Who sent a request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Caller {
+    /// Known for clients connected over a socket.
+    pub peer: Option<PeerCredentials>,
+    /// Name of the token the client authenticated with.
+    pub token: Option<String>,
+}
+
+impl Caller {
+    /// Whether both requests come from the same client as far as the policy
+    /// can tell: the same user and token, whatever the process.
+    pub fn same_client(&self, other: &Caller) -> bool {
+        self.peer.map(|p| p.uid) == other.peer.map(|p| p.uid) && self.token == other.token
+    }
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.peer, &self.token) {
+            (None, None) => write!(f, "anonymous"),
+            (Some(peer), None) => write!(f, "pid={} uid={} gid={}", peer.pid, peer.uid, peer.gid),
+            (None, Some(token)) => write!(f, "token={}", token),
+            (Some(peer), Some(token)) => write!(f, "pid={} uid={} gid={} token={}", peer.pid, peer.uid, peer.gid, token),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Policy {
+    /// Token secrets by name.
+    pub tokens: BTreeMap<String, String>,
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Name of the token whose secret is `secret`.
+    pub fn authenticate(&self, secret: &str) -> Option<&str> {
+        // Compares against every token in full, so the time taken does not
+        // tell how much of a guess was right.
+        self.tokens.iter().fold(None, |found, (name, token)| {
+            if constant_time_eq(token.as_bytes(), secret.as_bytes()) { Some(name.as_str()) } else { found }
+        })
+    }
+
+    /// Highest access `caller` has to `table`, or to every table if `None`.
+    pub fn access(&self, caller: &Caller, table: Option<&str>) -> Option<Access> {
+        self.rules.iter()
+            .filter(|rule| rule.applies_to(caller) && rule.covers(table))
+            .map(|rule| rule.access)
+            .max()
+    }
+
+    pub fn check(&self, caller: &Caller, scope: Scope, needed: Access) -> Result<(), String> {
+        let access = match scope {
+            Scope::Table(table) => self.access(caller, Some(table)),
+            Scope::All => self.access(caller, None),
+            Scope::Any => self.rules.iter().filter(|rule| rule.applies_to(caller)).map(|rule| rule.access).max(),
+        };
+        match access {
+            Some(access) if access >= needed => Ok(()),
+            _ => Err(format!("Permission denied: {} access to {}", needed.as_str(), match scope {
+                Scope::Table(table) => format!("table {}", table),
+                Scope::All => "all tables".to_string(),
+                Scope::Any => "any table".to_string(),
+            })),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Access `body` needs.
+pub fn requirements(body: &RequestBody) -> Vec<(Scope<'_>, Access)> {
+    let item_access = |action: Actions| match action {
+        Actions::Add | Actions::Delete | Actions::ResetCounters => Access::Write,
+        Actions::Noop | Actions::Query | Actions::ReadCounters => Access::ReadOnly,
+    };
+    match body {
+        RequestBody::Ternary(item, _) => vec![(Scope::Table(&item.table_id), item_access(item.action))],
+        RequestBody::Direct(item, _) => vec![(Scope::Table(&item.table_id), item_access(item.action))],
+        RequestBody::Lookup { table_id, .. } | RequestBody::Dump { table_id } | RequestBody::GetDefault { table_id } => {
+            vec![(Scope::Table(table_id), Access::ReadOnly)]
+        },
+        RequestBody::ResetCounters { table_id } | RequestBody::SetDefault { table_id, .. } => {
+            vec![(Scope::Table(table_id), Access::Write)]
+        },
+        // The server only lists the tables the caller may read.
+        RequestBody::ListTables => Vec::new(),
+        RequestBody::Subscribe { tables, .. } if tables.is_empty() => vec![(Scope::All, Access::ReadOnly)],
+        RequestBody::Subscribe { tables, .. } => tables.iter().map(|t| (Scope::Table(t), Access::ReadOnly)).collect(),
+        // The server also checks that the subscriptions are the caller's.
+        RequestBody::Unsubscribe { .. } => vec![(Scope::Any, Access::ReadOnly)],
+        RequestBody::SaveSnapshot | RequestBody::Reconcile { .. } | RequestBody::Reload { .. } => vec![(Scope::All, Access::Admin)],
+        // Sessions own the entries their client writes.
+        RequestBody::OpenSession { .. } => vec![(Scope::Any, Access::Write)],
+        // The server only lets the client that opened a session use it.
+        RequestBody::Heartbeat { .. } | RequestBody::CloseSession { .. } => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod access_tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy {
+            tokens: BTreeMap::from([("monitoring".to_string(), "s3cret".to_string())]),
+            rules: vec![
+                Rule { principal: Principal::Uid(0), tables: vec![], access: Access::Admin },
+                Rule { principal: Principal::Gid(100), tables: vec!["acl".to_string()], access: Access::Write },
+                Rule { principal: Principal::Token("monitoring".to_string()), tables: vec![], access: Access::ReadOnly },
+            ],
+        }
+    }
+
+    fn peer(uid: u32, gid: u32) -> Caller {
+        Caller { peer: Some(PeerCredentials { pid: 1, uid, gid }), token: None }
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let policy = policy();
+        assert_eq!(policy.authenticate("s3cret"), Some("monitoring"));
+        assert_eq!(policy.authenticate("s3cre"), None);
+        assert_eq!(policy.authenticate(""), None);
+    }
+
+    #[test]
+    fn test_access() {
+        let policy = policy();
+        assert_eq!(policy.access(&peer(0, 0), None), Some(Access::Admin));
+        assert_eq!(policy.access(&peer(1000, 100), Some("acl")), Some(Access::Write));
+        assert_eq!(policy.access(&peer(1000, 100), Some("nexthop")), None);
+        assert_eq!(policy.access(&peer(1000, 100), None), None);
+        assert_eq!(policy.access(&Caller::default(), Some("acl")), None);
+
+        let monitoring = Caller { peer: None, token: Some("monitoring".to_string()) };
+        assert!(policy.check(&monitoring, Scope::Table("acl"), Access::ReadOnly).is_ok());
+        assert_eq!(policy.check(&monitoring, Scope::Table("acl"), Access::Write),
+                   Err("Permission denied: write access to table acl".to_string()));
+        assert_eq!(policy.check(&monitoring, Scope::All, Access::Admin),
+                   Err("Permission denied: admin access to all tables".to_string()));
+        assert!(policy.check(&peer(1000, 100), Scope::Any, Access::Write).is_ok());
+        assert!(policy.check(&monitoring, Scope::Any, Access::ReadOnly).is_ok());
+        assert_eq!(policy.check(&Caller::default(), Scope::Any, Access::ReadOnly),
+                   Err("Permission denied: read-only access to any table".to_string()));
+    }
+
+    #[test]
+    fn test_caller_display() {
+        assert_eq!(Caller::default().to_string(), "anonymous");
+        assert_eq!(peer(1000, 100).to_string(), "pid=1 uid=1000 gid=100");
+        let both = Caller { token: Some("monitoring".to_string()), ..peer(0, 0) };
+        assert_eq!(both.to_string(), "pid=1 uid=0 gid=0 token=monitoring");
+        assert!(both.same_client(&Caller { peer: Some(PeerCredentials { pid: 2, uid: 0, gid: 5 }), ..both.clone() }));
+        assert!(!both.same_client(&peer(0, 0)));
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::TableBackend;
+use crate::tables::{from_hex, to_hex, Counters, DirectEntry, Entry, TableKind, TableSpec, TernaryEntry, Timeouts};
+
+/// Keeps every table in a text file `<dir>/<table>.entries`, one entry per line.
+///
+/// Ternary entries are written as `<priority> <key> <mask> <result>` and direct
+/// entries as `<index> <value>`, with byte strings in hex (`-` when empty).
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+impl FileBackend {
+    pub fn open(dir: &Path) -> Result<FileBackend, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        Ok(FileBackend { dir: dir.to_path_buf() })
+    }
+
+    fn path(&self, table: &TableSpec) -> Result<PathBuf, String> {
+        if table.name.is_empty() || table.name.starts_with('.') || table.name.contains('/') {
+            return Err(format!("Table name '{}' cannot be used as a file name", table.name));
+        }
+        Ok(self.dir.join(format!("{}.entries", table.name)))
+    }
+
+    fn load(&self, table: &TableSpec) -> Result<Vec<Entry>, String> {
+        let path = self.path(table)?;
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| parse_line(table.kind, line).map_err(|e| format!("{}: {}", path.display(), e)))
+            .collect()
+    }
+
+    fn store(&self, table: &TableSpec, entries: &[Entry]) -> Result<(), String> {
+        let path = self.path(table)?;
+        let text: String = entries.iter().map(|e| format_line(e) + "\n").collect();
+        let tmp = path.with_extension("entries.tmp");
+        fs::write(&tmp, text).map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+        fs::rename(&tmp, &path).map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+    }
+
+    fn update<F>(&self, table: &TableSpec, entry: &Entry, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Vec<Entry>, Option<usize>) -> Result<(), String>,
+    {
+        let mut entries = self.load(table)?;
+        let pos = entries.iter().position(|e| e.same_key(entry));
+        f(&mut entries, pos)?;
+        self.store(table, &entries)
+    }
+}
+
+fn hex_field(bytes: &[u8]) -> String {
+    if bytes.is_empty() { "-".to_string() } else { to_hex(bytes) }
+}
+
+fn parse_hex_field(field: &str) -> Result<Vec<u8>, String> {
+    if field == "-" { Ok(Vec::new()) } else { from_hex(field) }
+}
+
+fn format_line(entry: &Entry) -> String {
+    match entry {
+        Entry::Ternary(e) => format!("{} {} {} {}", e.priority, hex_field(&e.key), hex_field(&e.mask), hex_field(&e.result)),
+        Entry::Direct(e) => format!("{} {}", e.index, hex_field(&e.value)),
+    }
+}
+
+fn parse_line(kind: TableKind, line: &str) -> Result<Entry, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let number = |field: &str| field.parse::<u16>().map_err(|_| format!("Invalid number '{}'", field));
+    match (kind, fields.as_slice()) {
+        (TableKind::Ternary, [priority, key, mask, result]) => Ok(Entry::Ternary(TernaryEntry {
+            priority: number(priority)?,
+            key: parse_hex_field(key)?,
+            mask: parse_hex_field(mask)?,
+            result: parse_hex_field(result)?,
+            counters: Counters::default(),
+            timeouts: Timeouts::default(),
+            installed: 0,
+            owner: Default::default(),
+            version: 0,
+        })),
+        (TableKind::Direct, [index, value]) => Ok(Entry::Direct(DirectEntry {
+            index: number(index)?,
+            value: parse_hex_field(value)?,
+            counters: Counters::default(),
+            timeouts: Timeouts::default(),
+            installed: 0,
+            owner: Default::default(),
+            version: 0,
+        })),
+        _ => Err(format!("Invalid entry line '{}'", line)),
+    }
+}
+
+impl TableBackend for FileBackend {
+    fn program(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String> {
+        self.update(table, entry, |entries, pos| match pos {
+            Some(_) => Err(format!("Entry already installed in {}: {}", table.name, entry)),
+            None => {
+                entries.push(entry.clone());
+                Ok(())
+            }
+        })
+    }
+
+    fn modify(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String> {
+        self.update(table, entry, |entries, pos| match pos {
+            Some(pos) => {
+                entries[pos] = entry.clone();
+                Ok(())
+            }
+            None => Err(format!("Entry not installed in {}: {}", table.name, entry)),
+        })
+    }
+
+    fn remove(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String> {
+        self.update(table, entry, |entries, pos| match pos {
+            Some(pos) => {
+                entries.remove(pos);
+                Ok(())
+            }
+            None => Err(format!("Entry not installed in {}: {}", table.name, entry)),
+        })
+    }
+
+    fn read_back(&mut self, table: &TableSpec) -> Result<Vec<Entry>, String> {
+        self.load(table)
+    }
+}
+
+#[cfg(test)]
+mod file_tests {
+    use super::*;
+
+    fn ternary(priority: u16, key: &[u8], result: &[u8]) -> Entry {
+        Entry::Ternary(TernaryEntry::new(priority, key, &vec![0xff; key.len()], result))
+    }
+
+    #[test]
+    fn test_program_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("mem_ipc_file_backend_test.{}", std::process::id()));
+        let spec: TableSpec = "acl:ternary:2:16".parse().unwrap();
+        let mut backend = FileBackend::open(&dir).unwrap();
+
+        backend.program(&spec, &ternary(1, &[10, 0], b"permit")).unwrap();
+        backend.program(&spec, &ternary(2, &[10, 1], b"")).unwrap();
+        assert!(backend.program(&spec, &ternary(1, &[10, 0], b"deny")).is_err());
+        backend.modify(&spec, &ternary(1, &[10, 0], b"deny")).unwrap();
+        backend.remove(&spec, &ternary(2, &[10, 1], b"")).unwrap();
+
+        // A second backend on the same directory sees what the first wrote.
+        let mut reopened = FileBackend::open(&dir).unwrap();
+        assert_eq!(reopened.read_back(&spec).unwrap(), vec![ternary(1, &[10, 0], b"deny")]);
+        assert_eq!(fs::read_to_string(dir.join("acl.entries")).unwrap(), "1 0a00 ffff 64656e79\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bad_table_name() {
+        let dir = std::env::temp_dir().join(format!("mem_ipc_file_backend_name_test.{}", std::process::id()));
+        let mut backend = FileBackend::open(&dir).unwrap();
+        let spec = TableSpec { name: "../acl".to_string(), kind: TableKind::Direct, key_width: 2, capacity: 4 };
+        assert!(backend.read_back(&spec).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,29 @@
+//! Targets the shadow tables are pushed to.
+//!
+//! The server keeps the authoritative copy of every table and calls a
+//! [`TableBackend`] after each change it commits. Implementations program a
+//! device (or a stand-in for one) and can read its entries back.
+
+mod file;
+mod sim;
+mod tcam;
+
+pub use file::FileBackend;
+pub use sim::SimBackend;
+pub use tcam::{lookup_slots, SlotOp, TcamBackend, TcamStats};
+
+use crate::tables::{Entry, TableSpec};
+
+pub trait TableBackend: Send {
+    /// Installs a new entry.
+    fn program(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String>;
+
+    /// Replaces the result of an installed entry with the one in `entry`.
+    fn modify(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String>;
+
+    /// Removes an installed entry.
+    fn remove(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String>;
+
+    /// Returns the entries currently installed in `table`.
+    fn read_back(&mut self, table: &TableSpec) -> Result<Vec<Entry>, String>;
+}
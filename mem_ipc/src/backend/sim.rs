@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use super::TableBackend;
+use crate::tables::{Entry, TableSpec};
+
+#[derive(Default)]
+struct SimState {
+    tables: BTreeMap<String, Vec<Entry>>,
+    failures: usize,
+    operations: usize,
+}
+
+/// In-process stand-in for a device.
+///
+/// Clones share the same simulated device, so a test can hand one clone to the
+/// server and inspect or disturb the device through another.
+#[derive(Clone, Default)]
+pub struct SimBackend {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimBackend {
+    pub fn new() -> Self {
+        SimBackend::default()
+    }
+
+    /// Entries installed in the table named `table_id`.
+    pub fn entries(&self, table_id: &str) -> Vec<Entry> {
+        self.state.lock().unwrap().tables.get(table_id).cloned().unwrap_or_default()
+    }
+
+    /// Number of program, modify and remove calls that succeeded.
+    pub fn operations(&self) -> usize {
+        self.state.lock().unwrap().operations
+    }
+
+    /// Makes the next `count` program, modify, remove or read back calls fail.
+    pub fn fail_next(&self, count: usize) {
+        self.state.lock().unwrap().failures = count;
+    }
+
+    /// Drops every installed entry, like a device reset.
+    pub fn reset(&self) {
+        self.state.lock().unwrap().tables.clear();
+    }
+
+    fn apply<F>(&self, table: &TableSpec, entry: &Entry, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Vec<Entry>, Option<usize>) -> Result<(), String>,
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.failures > 0 {
+            state.failures -= 1;
+            return Err("Simulated device failure".to_string());
+        }
+        let entries = state.tables.entry(table.name.clone()).or_default();
+        let pos = entries.iter().position(|e| e.same_key(entry));
+        f(entries, pos)?;
+        state.operations += 1;
+        Ok(())
+    }
+}
+
+impl TableBackend for SimBackend {
+    fn program(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String> {
+        self.apply(table, entry, |entries, pos| match pos {
+            Some(_) => Err(format!("Entry already installed in {}: {}", table.name, entry)),
+            None => {
+                entries.push(entry.clone());
+                Ok(())
+            }
+        })
+    }
+
+    fn modify(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String> {
+        self.apply(table, entry, |entries, pos| match pos {
+            Some(pos) => {
+                entries[pos] = entry.clone();
+                Ok(())
+            }
+            None => Err(format!("Entry not installed in {}: {}", table.name, entry)),
+        })
+    }
+
+    fn remove(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String> {
+        self.apply(table, entry, |entries, pos| match pos {
+            Some(pos) => {
+                entries.remove(pos);
+                Ok(())
+            }
+            None => Err(format!("Entry not installed in {}: {}", table.name, entry)),
+        })
+    }
+
+    fn read_back(&mut self, table: &TableSpec) -> Result<Vec<Entry>, String> {
+        let mut state = self.state.lock().unwrap();
+        if state.failures > 0 {
+            state.failures -= 1;
+            return Err("Simulated device failure".to_string());
+        }
+        Ok(state.tables.get(&table.name).cloned().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod sim_tests {
+    use super::*;
+    use crate::tables::DirectEntry;
+
+    fn entry(index: u16, value: &[u8]) -> Entry {
+        Entry::Direct(DirectEntry::new(index, value))
+    }
+
+    #[test]
+    fn test_program_modify_remove() {
+        let spec: TableSpec = "nexthop:direct:16".parse().unwrap();
+        let sim = SimBackend::new();
+        let mut backend = sim.clone();
+
+        backend.program(&spec, &entry(1, b"a")).unwrap();
+        assert!(backend.program(&spec, &entry(1, b"b")).is_err());
+        backend.modify(&spec, &entry(1, b"b")).unwrap();
+        assert!(backend.modify(&spec, &entry(2, b"b")).is_err());
+        assert_eq!(sim.entries("nexthop"), vec![entry(1, b"b")]);
+
+        backend.remove(&spec, &entry(1, b"b")).unwrap();
+        assert!(backend.remove(&spec, &entry(1, b"b")).is_err());
+        assert!(backend.read_back(&spec).unwrap().is_empty());
+        assert_eq!(sim.operations(), 3);
+    }
+
+    #[test]
+    fn test_failures_and_reset() {
+        let spec: TableSpec = "nexthop:direct:16".parse().unwrap();
+        let sim = SimBackend::new();
+        let mut backend = sim.clone();
+
+        sim.fail_next(1);
+        assert!(backend.program(&spec, &entry(1, b"a")).is_err());
+        backend.program(&spec, &entry(1, b"a")).unwrap();
+
+        sim.reset();
+        assert!(sim.entries("nexthop").is_empty());
+    }
+}
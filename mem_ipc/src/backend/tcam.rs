@@ -0,0 +1,429 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use super::TableBackend;
+use crate::tables::{DirectEntry, Entry, TableSpec, TernaryEntry};
+
+/// A single write to the simulated device.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SlotOp {
+    Write { slot: usize, entry: TernaryEntry },
+    Invalidate { slot: usize },
+}
+
+impl SlotOp {
+    /// Applies the operation to a copy of a table's slots.
+    pub fn apply(&self, slots: &mut [Option<TernaryEntry>]) {
+        match self {
+            SlotOp::Write { slot, entry } => slots[*slot] = Some(entry.clone()),
+            SlotOp::Invalidate { slot } => slots[*slot] = None,
+        }
+    }
+}
+
+/// Cost of the inserts into one table.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TcamStats {
+    pub inserts: u64,
+    /// Entries relocated to make room for inserts.
+    pub moves: u64,
+    pub max_moves: u64,
+}
+
+impl TcamStats {
+    pub fn moves_per_insert(&self) -> f64 {
+        if self.inserts == 0 { 0.0 } else { self.moves as f64 / self.inserts as f64 }
+    }
+}
+
+/// Returns the result of the first valid slot matching `key`.
+pub fn lookup_slots<'a>(slots: &'a [Option<TernaryEntry>], key: &[u8]) -> Option<&'a [u8]> {
+    slots.iter().flatten().find(|e| e.matches(key)).map(|e| e.result.as_slice())
+}
+
+struct TcamTable {
+    slots: Vec<Option<TernaryEntry>>,
+    stats: TcamStats,
+    trace: Vec<SlotOp>,
+}
+
+impl TcamTable {
+    fn new(size: usize) -> Self {
+        TcamTable { slots: vec![None; size], stats: TcamStats::default(), trace: Vec::new() }
+    }
+
+    fn write(&mut self, slot: usize, entry: TernaryEntry) {
+        let op = SlotOp::Write { slot, entry };
+        op.apply(&mut self.slots);
+        self.trace.push(op);
+    }
+
+    fn invalidate(&mut self, slot: usize) {
+        let op = SlotOp::Invalidate { slot };
+        op.apply(&mut self.slots);
+        self.trace.push(op);
+    }
+
+    /// Copies the entry at `from` into the free slot `to`, then frees `from`.
+    /// Both copies are identical while the move is in progress, so lookups
+    /// never miss the entry.
+    fn relocate(&mut self, from: usize, to: usize) {
+        let entry = self.slots[from].clone().expect("Moving an empty slot");
+        self.write(to, entry);
+        self.invalidate(from);
+        self.stats.moves += 1;
+    }
+
+    fn find(&self, entry: &TernaryEntry) -> Option<usize> {
+        self.slots.iter().position(|s| {
+            s.as_ref().is_some_and(|e| e.priority == entry.priority && e.key == entry.key && e.mask == entry.mask)
+        })
+    }
+
+    /// Slots `lo..hi` are where an entry of `priority` may go: after every
+    /// entry of higher or equal priority, before every entry of lower priority.
+    fn gap(&self, priority: u16) -> (usize, usize) {
+        let lo = self.slots.iter().rposition(|s| s.as_ref().is_some_and(|e| e.priority >= priority)).map_or(0, |i| i + 1);
+        let hi = self.slots.iter().position(|s| s.as_ref().is_some_and(|e| e.priority < priority)).unwrap_or(self.slots.len());
+        (lo, hi.max(lo))
+    }
+
+    /// Starting points of the runs of equal priority among the occupied slots
+    /// in `range`.
+    fn group_starts(&self, range: std::ops::Range<usize>) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut previous = None;
+        for slot in range {
+            let priority = self.slots[slot].as_ref().map(|e| e.priority);
+            if priority != previous {
+                starts.push(slot);
+                previous = priority;
+            }
+        }
+        starts
+    }
+
+    /// Frees a slot inside the gap `lo..hi` by moving one entry per priority
+    /// group towards the nearest free slot, and returns it. The slots between
+    /// the gap and the free slot are all occupied.
+    fn make_room(&mut self, lo: usize, hi: usize) -> Result<usize, String> {
+        let below = (hi..self.slots.len()).find(|&s| self.slots[s].is_none());
+        let above = (0..lo).rev().find(|&s| self.slots[s].is_none());
+
+        let cost_below = below.map(|f| self.group_starts(hi..f).len());
+        let cost_above = above.map(|f| self.group_starts(f + 1..lo).len());
+        match (cost_below, cost_above) {
+            (Some(down), Some(up)) if up < down => Ok(self.shift_up(above.unwrap(), lo)),
+            (Some(_), _) => Ok(self.shift_down(hi, below.unwrap())),
+            (None, Some(_)) => Ok(self.shift_up(above.unwrap(), lo)),
+            (None, None) => Err("TCAM is full".to_string()),
+        }
+    }
+
+    /// Moves the first entry of each group in `hi..free` to the end of its
+    /// group, starting with the group nearest the free slot. Frees slot `hi`.
+    fn shift_down(&mut self, hi: usize, free: usize) -> usize {
+        let mut hole = free;
+        for start in self.group_starts(hi..free).into_iter().rev() {
+            self.relocate(start, hole);
+            hole = start;
+        }
+        hole
+    }
+
+    /// Moves the last entry of each group in `free + 1..lo` to the front of its
+    /// group, starting with the group nearest the free slot. Frees slot `lo - 1`.
+    fn shift_up(&mut self, free: usize, lo: usize) -> usize {
+        let mut hole = free;
+        let starts = self.group_starts(free + 1..lo);
+        for (i, _) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(lo) - 1;
+            self.relocate(end, hole);
+            hole = end;
+        }
+        hole
+    }
+
+    fn insert(&mut self, entry: TernaryEntry) -> Result<(), String> {
+        if self.find(&entry).is_some() {
+            return Err(format!("Entry already installed: {}", entry));
+        }
+        let moves_before = self.stats.moves;
+        let (lo, hi) = self.gap(entry.priority);
+        // Use the middle of the free run in the gap, leaving room on both
+        // sides for later inserts.
+        let free: Vec<usize> = (lo..hi).filter(|&s| self.slots[s].is_none()).collect();
+        let slot = if free.is_empty() { self.make_room(lo, hi)? } else { free[free.len() / 2] };
+        self.write(slot, entry);
+
+        let moves = self.stats.moves - moves_before;
+        self.stats.inserts += 1;
+        self.stats.max_moves = self.stats.max_moves.max(moves);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct TcamState {
+    size: usize,
+    tables: BTreeMap<String, TcamTable>,
+    /// Direct tables, one slot per index, as they would live in plain SRAM
+    /// next to the TCAM.
+    arrays: BTreeMap<String, Vec<Option<DirectEntry>>>,
+}
+
+/// Simulated TCAM. Every ternary table gets a region of a fixed number of
+/// slots; slot 0 has the highest priority and lookups return the first
+/// matching slot. Direct tables are kept in indexed arrays of their declared
+/// capacity.
+///
+/// Entries of equal priority form a group whose order inside the region is not
+/// kept, as on hardware, so only non-overlapping entries should share a
+/// priority. Clones share the same device.
+#[derive(Clone)]
+pub struct TcamBackend {
+    state: Arc<Mutex<TcamState>>,
+}
+
+impl TcamBackend {
+    pub fn new(slots_per_table: usize) -> Self {
+        TcamBackend {
+            state: Arc::new(Mutex::new(TcamState { size: slots_per_table, ..Default::default() })),
+        }
+    }
+
+    pub fn stats(&self, table_id: &str) -> TcamStats {
+        self.state.lock().unwrap().tables.get(table_id).map(|t| t.stats).unwrap_or_default()
+    }
+
+    /// Contents of every slot of a table's region.
+    pub fn slots(&self, table_id: &str) -> Vec<Option<TernaryEntry>> {
+        let state = self.state.lock().unwrap();
+        match state.tables.get(table_id) {
+            Some(table) => table.slots.clone(),
+            None => vec![None; state.size],
+        }
+    }
+
+    /// Returns and clears the slot writes made to a table so far.
+    pub fn take_trace(&self, table_id: &str) -> Vec<SlotOp> {
+        self.state.lock().unwrap().tables.get_mut(table_id).map(|t| std::mem::take(&mut t.trace)).unwrap_or_default()
+    }
+
+    pub fn lookup(&self, table_id: &str, key: &[u8]) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        lookup_slots(&state.tables.get(table_id)?.slots, key).map(|r| r.to_vec())
+    }
+
+    fn with_table<F>(&self, table: &TableSpec, entry: &TernaryEntry, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut TcamTable, &TernaryEntry) -> Result<(), String>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let size = state.size;
+        let tcam = state.tables.entry(table.name.clone()).or_insert_with(|| TcamTable::new(size));
+        f(tcam, entry)
+    }
+
+    /// Runs `f` on the array slot of a direct entry, which must be empty for
+    /// `installed == false` and hold an entry otherwise.
+    fn with_slot<F>(&self, table: &TableSpec, entry: &DirectEntry, installed: bool, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Option<DirectEntry>),
+    {
+        let mut state = self.state.lock().unwrap();
+        let array = state.arrays.entry(table.name.clone()).or_insert_with(|| vec![None; table.capacity]);
+        let slot = array.get_mut(entry.index as usize).ok_or_else(|| format!("Index out of range: {}", entry))?;
+        match (slot.is_some(), installed) {
+            (true, false) => Err(format!("Entry already installed: {}", entry)),
+            (false, true) => Err(format!("Entry not installed: {}", entry)),
+            _ => {
+                f(slot);
+                Ok(())
+            },
+        }
+    }
+}
+
+impl TableBackend for TcamBackend {
+    fn program(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String> {
+        match entry {
+            Entry::Ternary(entry) => self.with_table(table, entry, |tcam, entry| tcam.insert(entry.clone())),
+            Entry::Direct(entry) => self.with_slot(table, entry, false, |slot| *slot = Some(entry.clone())),
+        }
+    }
+
+    fn modify(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String> {
+        match entry {
+            Entry::Ternary(entry) => self.with_table(table, entry, |tcam, entry| {
+                let slot = tcam.find(entry).ok_or_else(|| format!("Entry not installed: {}", entry))?;
+                // Rewriting a slot in place is atomic for lookups.
+                tcam.write(slot, entry.clone());
+                Ok(())
+            }),
+            Entry::Direct(entry) => self.with_slot(table, entry, true, |slot| *slot = Some(entry.clone())),
+        }
+    }
+
+    fn remove(&mut self, table: &TableSpec, entry: &Entry) -> Result<(), String> {
+        match entry {
+            Entry::Ternary(entry) => self.with_table(table, entry, |tcam, entry| {
+                let slot = tcam.find(entry).ok_or_else(|| format!("Entry not installed: {}", entry))?;
+                tcam.invalidate(slot);
+                Ok(())
+            }),
+            Entry::Direct(entry) => self.with_slot(table, entry, true, |slot| *slot = None),
+        }
+    }
+
+    fn read_back(&mut self, table: &TableSpec) -> Result<Vec<Entry>, String> {
+        let state = self.state.lock().unwrap();
+        let ternary = state.tables.get(&table.name).into_iter()
+            .flat_map(|t| t.slots.iter().flatten().cloned().map(Entry::Ternary));
+        let direct = state.arrays.get(&table.name).into_iter()
+            .flat_map(|a| a.iter().flatten().cloned().map(Entry::Direct));
+        Ok(ternary.chain(direct).collect())
+    }
+}
+
+#[cfg(test)]
+mod tcam_tests {
+    use super::*;
+
+    fn entry(priority: u16, key: u8) -> TernaryEntry {
+        TernaryEntry::new(priority, &[key], &[0xff], &[key])
+    }
+
+    fn spec() -> TableSpec {
+        "acl:ternary:1:64".parse().unwrap()
+    }
+
+    /// Slots hold entries in non-increasing priority order.
+    fn assert_ordered(slots: &[Option<TernaryEntry>]) {
+        let priorities: Vec<u16> = slots.iter().flatten().map(|e| e.priority).collect();
+        assert!(priorities.windows(2).all(|w| w[0] >= w[1]), "Out of order: {:?}", priorities);
+    }
+
+    #[test]
+    fn test_priority_follows_slot_order() {
+        let tcam = TcamBackend::new(8);
+        let mut backend = tcam.clone();
+        for (priority, key) in [(5, 1), (9, 2), (1, 3), (7, 4)] {
+            backend.program(&spec(), &Entry::Ternary(entry(priority, key))).unwrap();
+            assert_ordered(&tcam.slots("acl"));
+        }
+        let order: Vec<u16> = backend.read_back(&spec()).unwrap().iter().map(|e| match e {
+            Entry::Ternary(e) => e.priority,
+            Entry::Direct(_) => unreachable!(),
+        }).collect();
+        assert_eq!(order, vec![9, 7, 5, 1]);
+        assert_eq!(tcam.lookup("acl", &[4]), Some(vec![4]));
+    }
+
+    #[test]
+    fn test_full() {
+        let mut backend = TcamBackend::new(3);
+        for key in 0..3 {
+            backend.program(&spec(), &Entry::Ternary(entry(key as u16, key))).unwrap();
+        }
+        assert!(backend.program(&spec(), &Entry::Ternary(entry(9, 9))).is_err());
+        backend.remove(&spec(), &Entry::Ternary(entry(0, 0))).unwrap();
+        backend.program(&spec(), &Entry::Ternary(entry(9, 9))).unwrap();
+        assert_ordered(&backend.slots("acl"));
+    }
+
+    #[test]
+    fn test_moves_are_one_per_priority_group() {
+        let tcam = TcamBackend::new(9);
+        let mut backend = tcam.clone();
+        // Fill slots 0..8 with three groups of equal priority, leaving the last slot free.
+        for key in 0..8u8 {
+            let priority = [30, 20, 10][(key / 3) as usize];
+            let slot = key as usize;
+            let mut table = tcam.state.lock().unwrap();
+            let table = table.tables.entry("acl".to_string()).or_insert_with(|| TcamTable::new(9));
+            table.write(slot, entry(priority, key));
+        }
+        tcam.take_trace("acl");
+
+        // Goes right after the priority 30 group: the 20 and 10 groups each move one entry.
+        backend.program(&spec(), &Entry::Ternary(entry(25, 99))).unwrap();
+        assert_ordered(&tcam.slots("acl"));
+        assert_eq!(tcam.stats("acl").moves, 2);
+        assert_eq!(tcam.slots("acl")[3].as_ref().unwrap().key, vec![99]);
+    }
+
+    #[test]
+    fn test_lookups_stay_correct_during_moves() {
+        let tcam = TcamBackend::new(16);
+        let mut backend = tcam.clone();
+        let keys: Vec<u8> = (0..15).collect();
+        // Ascending priorities force every insert in front of the existing entries.
+        for (i, key) in keys.iter().enumerate() {
+            let before = tcam.slots("acl");
+            let expected: Vec<Option<Vec<u8>>> = keys.iter().map(|k| lookup_slots(&before, &[*k]).map(|r| r.to_vec())).collect();
+            tcam.take_trace("acl");
+
+            backend.program(&spec(), &Entry::Ternary(entry(i as u16, *key))).unwrap();
+
+            // Replay every slot write; lookups of other keys never change.
+            let mut slots = before;
+            for op in tcam.take_trace("acl") {
+                op.apply(&mut slots);
+                for (k, expected) in keys.iter().zip(&expected) {
+                    if k != key {
+                        assert_eq!(lookup_slots(&slots, &[*k]).map(|r| r.to_vec()), *expected);
+                    }
+                }
+            }
+            assert_ordered(&slots);
+        }
+        let stats = tcam.stats("acl");
+        assert_eq!(stats.inserts, 15);
+        assert!(stats.max_moves < 15);
+    }
+
+    #[test]
+    fn test_fewer_moves_than_packing() {
+        let tcam = TcamBackend::new(32);
+        let mut backend = tcam.clone();
+        let priorities: Vec<u16> = (0..24).map(|i| (i * 7) % 24).collect();
+        for (key, priority) in priorities.iter().enumerate() {
+            backend.program(&spec(), &Entry::Ternary(entry(*priority, key as u8))).unwrap();
+        }
+        assert_ordered(&tcam.slots("acl"));
+
+        // Keeping entries packed from slot 0 moves every lower priority entry down.
+        let packed: usize = (0..priorities.len()).map(|i| priorities[..i].iter().filter(|p| **p < priorities[i]).count()).sum();
+        let stats = tcam.stats("acl");
+        assert!((stats.moves as usize) < packed, "{:?} against {} when packed", stats, packed);
+    }
+
+    #[test]
+    fn test_modify_and_remove() {
+        let tcam = TcamBackend::new(4);
+        let mut backend = tcam.clone();
+        backend.program(&spec(), &Entry::Ternary(entry(1, 1))).unwrap();
+        let mut changed = entry(1, 1);
+        changed.result = vec![42];
+        backend.modify(&spec(), &Entry::Ternary(changed)).unwrap();
+        assert_eq!(tcam.lookup("acl", &[1]), Some(vec![42]));
+        backend.remove(&spec(), &Entry::Ternary(entry(1, 1))).unwrap();
+        assert_eq!(tcam.lookup("acl", &[1]), None);
+        assert!(backend.remove(&spec(), &Entry::Ternary(entry(1, 1))).is_err());
+    }
+
+    #[test]
+    fn test_direct() {
+        let spec: TableSpec = "nexthop:direct:4".parse().unwrap();
+        let mut backend = TcamBackend::new(2);
+        backend.program(&spec, &Entry::Direct(DirectEntry::new(3, b"a"))).unwrap();
+        assert!(backend.program(&spec, &Entry::Direct(DirectEntry::new(3, b"b"))).is_err());
+        assert!(backend.program(&spec, &Entry::Direct(DirectEntry::new(4, b"b"))).is_err());
+        backend.modify(&spec, &Entry::Direct(DirectEntry::new(3, b"c"))).unwrap();
+        assert!(backend.modify(&spec, &Entry::Direct(DirectEntry::new(1, b"c"))).is_err());
+        assert_eq!(backend.read_back(&spec).unwrap(), vec![Entry::Direct(DirectEntry::new(3, b"c"))]);
+        backend.remove(&spec, &Entry::Direct(DirectEntry::new(3, b"c"))).unwrap();
+        assert!(backend.read_back(&spec).unwrap().is_empty());
+    }
+}
@@ -1,3 +1,58 @@
+use clap::{arg, command, value_parser};
+use mem_ipc::client::TableClient;
+use mem_ipc::json::read_items;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
 fn main() {
+    let matches = command!()
+            .about("applies a JSON-lines bulk file of items to a server")
+            .arg(
+                arg!(
+                    -n --name <SHM_NAME>
+                )
+                .help("name of the server's message queue")
+                .required(true)
+                .value_parser(value_parser!(String))
+            )
+            .arg(
+                arg!(
+                    -l --load <FILE>
+                )
+                .help("bulk file to apply, one item per line, - for stdin")
+                .required(true)
+                .value_parser(value_parser!(PathBuf))
+            )
+            .arg(
+                arg!(
+                    --token <TOKEN>
+                )
+                .help("access token to send to a server with an access policy")
+                .required(false)
+                .value_parser(value_parser!(String))
+            )
+            .get_matches();
+
+    let name = matches.get_one::<String>("name").expect("name is required");
+    let path = matches.get_one::<PathBuf>("load").expect("load is required");
+    match load(name, path, matches.get_one::<String>("token")) {
+        Ok(applied) => println!("applied {} items", applied),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-}
\ No newline at end of file
+fn load(name: &str, path: &Path, token: Option<&String>) -> Result<usize, String> {
+    let items = if path.as_os_str() == "-" {
+        read_items(std::io::stdin().lock())?
+    } else {
+        let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        read_items(BufReader::new(file))?
+    };
+    let mut client = TableClient::connect(name)?;
+    client.set_token(token.cloned());
+    client.load(items)
+}
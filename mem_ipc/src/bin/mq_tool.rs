@@ -0,0 +1,195 @@
+use clap::{arg, command, value_parser, ArgAction, ArgMatches, Command};
+use mem_ipc::config::Config;
+use mem_ipc::inspect::{list_queues, Limits, QueueInfo, LIMITS_DIR, MQUEUE_DIR};
+use mem_ipc::{unlink_mq, MAX_QITEMS, MAX_QITEM_SIZE};
+use std::path::{Path, PathBuf};
+
+fn main() {
+    let matches = command!()
+            .about("inspects and cleans up POSIX message queues")
+            .subcommand_required(true)
+            .arg(
+                arg!(
+                    --dir <DIR>
+                )
+                .help("where the mqueue file system is mounted; queues are still opened and removed by name, \
+                       so this must be the system's mount")
+                .default_value(MQUEUE_DIR)
+                .value_parser(value_parser!(PathBuf))
+            )
+            .subcommand(
+                Command::new("list")
+                    .about("lists queues with their depth and sizes, flagging abandoned ones")
+                    .arg(
+                        arg!(
+                            -p --prefix <PREFIX>
+                        )
+                        .help("only queues whose name starts with PREFIX")
+                        .required(false)
+                        .value_parser(value_parser!(String))
+                    )
+            )
+            .subcommand(
+                Command::new("unlink")
+                    .about("removes queues")
+                    .arg(
+                        arg!(
+                            -p --prefix <PREFIX>
+                        )
+                        .help("removes the queues whose name starts with PREFIX, which must not be empty")
+                        .required(true)
+                        .value_parser(value_parser!(String))
+                    )
+                    .arg(
+                        arg!(
+                            --stale "Only removes queues whose owner is gone or that nobody has open"
+                        )
+                        .action(ArgAction::SetTrue)
+                    )
+                    .arg(
+                        arg!(
+                            -n --"dry-run" "Prints what would be removed"
+                        )
+                        .action(ArgAction::SetTrue)
+                    )
+            )
+            .subcommand(
+                Command::new("limits")
+                    .about("checks the system limits against the queues a server will create")
+                    .arg(
+                        arg!(
+                            -c --config <FILE>
+                        )
+                        .help("takes the queue depth and message size from a server config file")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf))
+                    )
+                    .arg(
+                        arg!(
+                            --depth <ITEMS>
+                        )
+                        .help("queue depth to check")
+                        .required(false)
+                        .conflicts_with("config")
+                        .value_parser(value_parser!(usize))
+                    )
+                    .arg(
+                        arg!(
+                            --"message-size" <BYTES>
+                        )
+                        .help("queue item size to check")
+                        .required(false)
+                        .conflicts_with("config")
+                        .value_parser(value_parser!(usize))
+                    )
+                    .arg(
+                        arg!(
+                            --"limits-dir" <DIR>
+                        )
+                        .help("where the limits are read from")
+                        .default_value(LIMITS_DIR)
+                        .value_parser(value_parser!(PathBuf))
+                    )
+            )
+            .get_matches();
+
+    let dir = matches.get_one::<PathBuf>("dir").expect("has a default");
+    let result = match matches.subcommand() {
+        Some(("list", args)) => list(dir, args.get_one::<String>("prefix").map(String::as_str)),
+        Some(("unlink", args)) => unlink(dir, args),
+        Some(("limits", args)) => limits(args),
+        _ => unreachable!("a subcommand is required"),
+    };
+    match result {
+        Ok(true) => {},
+        Ok(false) => std::process::exit(2),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn queues_with_prefix(dir: &Path, prefix: Option<&str>) -> Result<Vec<QueueInfo>, String> {
+    let mut queues = list_queues(dir)?;
+    if let Some(prefix) = prefix {
+        let prefix = if prefix.starts_with('/') { prefix.to_string() } else { format!("/{}", prefix) };
+        queues.retain(|q| q.name.starts_with(&prefix));
+    }
+    Ok(queues)
+}
+
+fn list(dir: &Path, prefix: Option<&str>) -> Result<bool, String> {
+    let queues = queues_with_prefix(dir, prefix)?;
+    println!("{:<40} {:>7} {:>7} {:>9}  holders", "queue", "depth", "max", "msgsize");
+    for queue in queues {
+        let (depth, max, size) = match queue.attributes {
+            Some(a) => (a.pending.to_string(), a.max_items.to_string(), a.max_item_size.to_string()),
+            None => ("?".to_string(), "?".to_string(), "?".to_string()),
+        };
+        let holders: Vec<String> = queue.holders.iter().map(|p| p.to_string()).collect();
+        print!("{:<40} {:>7} {:>7} {:>9}  {}", queue.name, depth, max, size, holders.join(","));
+        match queue.stale_reason() {
+            Some(reason) => println!("  STALE: {}", reason),
+            None => println!(),
+        }
+    }
+    Ok(true)
+}
+
+fn unlink(dir: &Path, args: &ArgMatches) -> Result<bool, String> {
+    let prefix = args.get_one::<String>("prefix").map(String::as_str);
+    // An empty prefix would match every queue on the system.
+    if prefix.is_some_and(|p| p.trim_start_matches('/').is_empty()) {
+        return Err("Refusing to unlink every queue, give a non-empty prefix".to_string());
+    }
+    let stale_only = args.get_flag("stale");
+    let dry_run = args.get_flag("dry-run");
+
+    let mut ok = true;
+    for queue in queues_with_prefix(dir, prefix)? {
+        let reason = queue.stale_reason();
+        if stale_only && reason.is_none() {
+            continue;
+        }
+        let reason = reason.map(|r| format!(" ({})", r)).unwrap_or_default();
+        if dry_run {
+            println!("Would unlink {}{}", queue.name, reason);
+            continue;
+        }
+        match unlink_mq(&queue.name) {
+            Ok(()) => println!("Unlinked {}{}", queue.name, reason),
+            Err(e) => {
+                eprintln!("{} {}", e, queue.name);
+                ok = false;
+            }
+        }
+    }
+    Ok(ok)
+}
+
+fn limits(args: &ArgMatches) -> Result<bool, String> {
+    let limits = Limits::read(args.get_one::<PathBuf>("limits-dir").expect("has a default"))?;
+    let (depth, message_size) = match args.get_one::<PathBuf>("config") {
+        Some(path) => {
+            let config = Config::load(path)?;
+            (config.queue.depth, config.queue.message_size)
+        },
+        None => (
+            args.get_one::<usize>("depth").copied().unwrap_or(MAX_QITEMS),
+            args.get_one::<usize>("message-size").copied().unwrap_or(MAX_QITEM_SIZE),
+        ),
+    };
+
+    println!("msg_max {}, msgsize_max {}, queues_max {}, RLIMIT_MSGQUEUE {}",
+             limits.msg_max, limits.msgsize_max, limits.queues_max,
+             limits.rlimit_bytes.map_or("unlimited".to_string(), |b| b.to_string()));
+    let problems = limits.check(depth, message_size);
+    if problems.is_empty() {
+        println!("Queues of {} items of {} bytes fit", depth, message_size);
+    }
+    for problem in &problems {
+        println!("Queues of {} items of {} bytes cannot be created: {}", depth, message_size, problem);
+    }
+    Ok(problems.is_empty())
+}
@@ -1,3 +1,184 @@
+use clap::{arg, command, value_parser, ArgAction};
+use mem_ipc::protocol::{Reply, Request, RequestBody, TableInfo};
+use mem_ipc::snapshot;
+#[cfg(feature = "json")]
+use mem_ipc::json::Item;
+use mem_ipc::tables::{to_hex, Entry, Table};
+use mem_ipc::{unlink_mq, TableInterface};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long to wait for each reply of the server.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn main() {
+    let command = command!()
+            .about("prints the shadow tables held by a server, or stored in a snapshot")
+            .arg(
+                arg!(
+                    -n --name <SHM_NAME>
+                )
+                .help("name of the server's message queue")
+                .required_unless_present_any(["snapshot", "socket"])
+                .value_parser(value_parser!(String))
+            )
+            .arg(
+                arg!(
+                    -s --snapshot <FILE>
+                )
+                .help("read the tables from a snapshot file instead of a server")
+                .required(false)
+                .conflicts_with_all(["name", "socket"])
+                .value_parser(value_parser!(PathBuf))
+            )
+            .arg(
+                arg!(
+                    --socket <PATH>
+                )
+                .help("connect to a server listening on this Unix socket instead of a message queue")
+                .required(false)
+                .conflicts_with("name")
+                .value_parser(value_parser!(PathBuf))
+            )
+            .arg(
+                arg!(
+                    --token <TOKEN>
+                )
+                .help("access token to send to a server with an access policy")
+                .required(false)
+                .conflicts_with("snapshot")
+                .value_parser(value_parser!(String))
+            )
+            .arg(
+                arg!(
+                    -t --table <TABLE>
+                )
+                .help("only print this table")
+                .required(false)
+                .value_parser(value_parser!(String))
+            )
+            .arg(
+                arg!(
+                    -c --counters ... "Show per-entry hit counters"
+                )
+                .action(ArgAction::SetTrue)
+            );
+    #[cfg(feature = "json")]
+    let command = command.arg(
+                arg!(
+                    --json ... "Print the entries as a JSON-lines bulk file the client can load"
+                )
+                .action(ArgAction::SetTrue)
+                .conflicts_with("counters")
+            );
+    let matches = command.get_matches();
+
+    let only = matches.get_one::<String>("table");
+    let style = if matches.get_flag("counters") {
+        Style::Counters
+    } else if cfg!(feature = "json") && matches.get_flag("json") {
+        Style::Json
+    } else {
+        Style::Plain
+    };
+    let token = matches.get_one::<String>("token");
+
+    let result = match (matches.get_one::<PathBuf>("snapshot"), matches.get_one::<PathBuf>("socket")) {
+        (Some(path), _) => print_snapshot(path, only, style),
+        (None, Some(path)) => TableInterface::connect_socket(path).and_then(|connection| {
+            // Replies come back over the connection; `reply_to` only has to ask for one.
+            print_tables(&connection, &connection, "print_tables", token, only, style)
+        }),
+        (None, None) => {
+            let name = matches.get_one::<String>("name").expect("name is required");
+            let reply_to = format!("{}.print_tables.{}", name, std::process::id());
+            let queues = TableInterface::get_writer(name)
+                .and_then(|writer| Ok((writer, TableInterface::get_table_reader(&reply_to)?)));
+            let result = match queues {
+                Ok((writer, reader)) => print_tables(&writer, &reader, &reply_to, token, only, style),
+                Err(e) => Err(e.to_string()),
+            };
+            let _ = unlink_mq(&reply_to);
+            result
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// How `print_tables` prints the entries.
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Plain,
+    Counters,
+    /// One bulk item per line, and no headers.
+    Json,
+}
+
+fn print_header(table: &TableInfo, style: Style) {
+    if style == Style::Json {
+        return;
+    }
+    print!("{} ({}, key width {}, {}/{} entries, version {}",
+           table.name, table.kind.as_str(), table.key_width, table.len, table.capacity, table.version);
+    match &table.default_result {
+        Some(result) => println!(", default {})", to_hex(result)),
+        None => println!(")"),
+    }
+}
+
+fn print_entry(table_id: &str, entry: Entry, style: Style) {
+    match (style, &entry) {
+        (Style::Plain, _) => println!("  {}", entry),
+        (Style::Counters, Entry::Ternary(e)) => println!("  {} {}", e, e.counters),
+        (Style::Counters, Entry::Direct(e)) => println!("  {} {}", e, e.counters),
+        #[cfg(feature = "json")]
+        (Style::Json, _) => println!("{}", Item::from_entry(table_id, &entry).to_line()),
+        #[cfg(not(feature = "json"))]
+        (Style::Json, _) => unreachable!("{} is never printed as JSON without the json feature", table_id),
+    }
+}
+
+/// Prints the tables of a server, sending requests on `writer` and reading
+/// the replies, which go to `reply_to`, from `reader`.
+fn print_tables(writer: &TableInterface, reader: &TableInterface, reply_to: &str, token: Option<&String>,
+                only: Option<&String>, style: Style) -> Result<(), String> {
+    let request = |body: RequestBody| -> Result<Reply, String> {
+        writer.write(&Request { reply_to: reply_to.to_string(), sequence: 0, token: token.cloned(), body }.pack())?;
+        let reply = reader.read_timeout(REPLY_TIMEOUT)?
+            .ok_or_else(|| format!("No reply from the server within {:?}", REPLY_TIMEOUT))?;
+        match Reply::unpack(&reply)? {
+            Reply::Error(e) => Err(e),
+            reply => Ok(reply),
+        }
+    };
+
+    let tables = match request(RequestBody::ListTables)? {
+        Reply::Tables(tables) => tables,
+        reply => return Err(format!("Unexpected reply {:?}", reply)),
+    };
+    for table in tables.iter().filter(|t| only.is_none_or(|name| *name == t.name)) {
+        print_header(table, style);
+        match request(RequestBody::Dump { table_id: table.name.clone() })? {
+            Reply::Ternary(entries) => entries.into_iter().for_each(|e| print_entry(&table.name, Entry::Ternary(e), style)),
+            Reply::Direct(entries) => entries.into_iter().for_each(|e| print_entry(&table.name, Entry::Direct(e), style)),
+            reply => return Err(format!("Unexpected reply {:?}", reply)),
+        }
+    }
+    Ok(())
+}
 
+fn print_snapshot(path: &Path, only: Option<&String>, style: Style) -> Result<(), String> {
+    let tables = snapshot::load(path)?;
+    for table in tables.iter().filter(|t| only.is_none_or(|name| *name == t.spec().name)) {
+        print_header(&TableInfo::from(table), style);
+        let name = &table.spec().name;
+        match table {
+            Table::Ternary(t) => t.entries().iter().for_each(|e| print_entry(name, Entry::Ternary(e.clone()), style)),
+            Table::Direct(t) => t.entries().for_each(|e| print_entry(name, Entry::Direct(e.clone()), style)),
+        }
+    }
+    Ok(())
 }
@@ -1,17 +1,58 @@
-use clap::{arg, command, value_parser, ArgAction};
-// use mem_ipc::{init_or_open_shm, };
+use clap::{arg, command, value_parser, ArgAction, ArgMatches};
+use mem_ipc::backend::{FileBackend, SimBackend, TableBackend, TcamBackend};
+use mem_ipc::config::{Config, QueueConfig, Transport};
+use mem_ipc::protocol::Encoding;
+use mem_ipc::server::{Notification, Server, Subscription};
+use mem_ipc::signals::{self, Signal};
+use mem_ipc::snapshot;
+use mem_ipc::socket::{poll_readable, PeerCredentials, SocketListener};
+use mem_ipc::tables::{TableSpec, Tables};
+use mem_ipc::transport::CONNECTION_CLOSED;
+use mem_ipc::{mq_attributes, mq_exists, unlink_mq, TableInterface};
+use mem_ipc::tables::now_nanos;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often the server looks for expired entries and vanished subscribers.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Exit code after a shutdown that could not save the snapshot, flush the
+/// log or remove the queues. Startup errors exit with 1.
+const EXIT_SHUTDOWN_FAILED: i32 = 2;
 
 fn main() {
     let matches = command!()
             .about("manages shadow copies of tables")
+            .after_help("Exits with 0 after a clean shutdown on SIGINT or SIGTERM, 1 if it cannot start \
+                         and 2 if shutting down failed.")
             .arg(
                 arg!(
                     -n --name <SHM_NAME>
                 )
                 .help("name of message queue to use for client/server IPC")
-                .required(true)
+                .required_unless_present_any(["config", "socket"])
                 .value_parser(value_parser!(String))
             )
+            .arg(
+                arg!(
+                    -c --config <FILE>
+                )
+                .help("reads queues, tables, log and snapshot paths from a TOML file; other options override it")
+                .required(false)
+                .value_parser(value_parser!(PathBuf))
+            )
+            .arg(
+                arg!(
+                    --socket <PATH>
+                )
+                .help("listens for clients on a Unix socket instead of the message queue")
+                .required(false)
+                .conflicts_with("name")
+                .value_parser(value_parser!(PathBuf))
+            )
             .arg(
                 arg!(
                     -l --logfile <FILE>
@@ -19,8 +60,71 @@ fn main() {
                 // We don't have syntax yet for optional options, so manually calling `required`
                 .help("Enables a logfile for logging all transactions")
                 .required(false)
+                .value_parser(value_parser!(PathBuf))
+            )
+            .arg(
+                arg!(
+                    -s --snapshot <FILE>
+                )
+                .help("restores the tables from this file at startup if it exists, and saves them to it on request")
+                .required(false)
+                .value_parser(value_parser!(PathBuf))
+            )
+            .arg(
+                arg!(
+                    -t --table <SPEC>
+                )
+                .help("declares a table as name:ternary:<key_width>:<capacity> or name:direct:<capacity>")
+                .required(false)
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(TableSpec))
+            )
+            .arg(
+                arg!(
+                    -b --backend <BACKEND>
+                )
+                .help("pushes table changes to 'file:<dir>', a simulated device 'sim' or a simulated TCAM 'tcam:<slots per ternary table>'")
+                .required(false)
                 .value_parser(value_parser!(String))
             )
+            .arg(
+                arg!(
+                    --reconcile <SECONDS>
+                )
+                .help("compares the tables with the backend every SECONDS seconds")
+                .required(false)
+                .requires("backend")
+                .value_parser(value_parser!(u64).range(1..))
+            )
+            .arg(
+                arg!(
+                    --repair "Reprograms the backend when reconciling finds differences"
+                )
+                .requires("reconcile")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                arg!(
+                    --notify <QUEUE>
+                )
+                .help("message queue that receives a notification for every change to any table")
+                .required(false)
+                .value_parser(value_parser!(String))
+            )
+            .arg(
+                arg!(
+                    --"final-snapshot" "Saves the tables to the snapshot file on shutdown"
+                )
+                .requires("snapshot")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                arg!(
+                    --"keep-queues" "Leaves the request and notification queues in place on shutdown, and reuses them with \
+                                     any pending messages on startup"
+                )
+                .action(ArgAction::SetTrue)
+            )
             .arg(
                 arg!(
                     -d --debug ... "Turn debugging information on"
@@ -35,17 +139,467 @@ fn main() {
             )
             .get_matches();
 
-    // You can check the value provided by positional arguments, or option arguments
+    let mut config = match matches.get_one::<PathBuf>("config") {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Config::default(),
+    };
+    apply_options(&matches, &mut config);
+    if config.queue.transport == Transport::Queue && config.queue.name.is_none() {
+        eprintln!("No request queue name given");
+        std::process::exit(1);
+    }
+    let debug = matches.get_flag("debug") || matches.get_flag("verbose");
+    let verbose = matches.get_flag("verbose");
+
+    let snapshot_path = config.snapshot.as_ref();
+    let mut tables = match snapshot_path {
+        Some(path) if path.exists() => match snapshot::load(path) {
+            Ok(tables) => {
+                println!("Restored {} tables from {}", tables.iter().count(), path.display());
+                tables
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => Tables::default(),
+    };
+    for spec in &config.tables {
+        if let Err(e) = tables.declare_or_match(spec.clone()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let mut server = Server::new(tables);
+    server.set_policy(config.policy.clone());
+    if let Some(path) = matches.get_one::<PathBuf>("config") {
+        let options = matches.clone();
+        server.set_config(path.clone(), config.clone(), Box::new(move |config| apply_options(&options, config)));
+        if let Err(e) = signals::catch(Signal::Hangup) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = snapshot_path {
+        server.set_snapshot_path(path.clone());
+    }
+    if let Some(backend) = matches.get_one::<String>("backend") {
+        match open_backend(backend) {
+            Ok(backend) => server.set_backend(backend),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(logfile) = &config.log {
+        println!("Logfile to : {}", logfile.display());
+        match OpenOptions::new().create(true).append(true).open(logfile) {
+            Ok(file) => server.set_log(file),
+            Err(e) => {
+                eprintln!("Failed to open logfile {}: {}", logfile.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let queues = &config.queue;
+    let keep_queues = matches.get_flag("keep-queues");
+    let request_queue = match &queues.transport {
+        Transport::Queue => queues.name.as_ref(),
+        Transport::Socket(_) => None,
+    };
+    for queue in request_queue.iter().copied().chain(&queues.notify) {
+        if let Err(e) = prepare_queue(queue, queues, keep_queues) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = signals::catch(Signal::Interrupt).and_then(|_| signals::catch(Signal::Terminate)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let mut endpoint = match Endpoint::open(queues) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(name) = request_queue {
+        server.set_client_queue_prefix(format!("{}.", name));
+    }
+    let mut notify_writers: HashMap<String, TableInterface> = HashMap::new();
+    if let Some(queue) = &queues.notify {
+        match TableInterface::get_nonblocking_writer_sized(queue, queues.message_size, queues.depth) {
+            Ok(writer) => { notify_writers.insert(queue.clone(), writer); },
+            Err(e) => {
+                eprintln!("{} {}", e, queue);
+                std::process::exit(1);
+            }
+        }
+        let subscription = Subscription { queue: queue.clone(), tables: Vec::new(), filter: None, encoding: Encoding::Native, owner: None };
+        server.subscribe(subscription)
+            .expect("Subscribing to all tables cannot fail");
+    }
+    println!("Serving {} tables on {}", server.tables().iter().count(), endpoint);
+
+    let reconcile_interval = matches.get_one::<u64>("reconcile").map(|s| Duration::from_secs(*s));
+    let repair = matches.get_flag("repair");
+
+    let mut reply_writers: HashMap<String, TableInterface> = HashMap::new();
+    let mut last_tick = Instant::now();
+    let mut last_reconcile = Instant::now();
+    let mut backend_errors = 0;
+    loop {
+        if signals::take(Signal::Interrupt) || signals::take(Signal::Terminate) {
+            break;
+        }
+        if signals::take(Signal::Hangup) {
+            match server.reload_config(false) {
+                Ok(changes) if changes.is_empty() => println!("Reloaded configuration, nothing changed"),
+                Ok(changes) => changes.iter().for_each(|change| println!("Reloaded configuration: {}", change)),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        if last_tick.elapsed() >= TICK_INTERVAL {
+            last_tick = Instant::now();
+            server.tick(now_nanos());
+            prune_subscribers(&mut server, &mut notify_writers);
+            prune_reply_writers(&mut reply_writers);
+        }
+        if reconcile_interval.is_some_and(|interval| last_reconcile.elapsed() >= interval) {
+            last_reconcile = Instant::now();
+            match server.reconcile(repair) {
+                Ok(drifts) => drifts.iter().for_each(|drift| eprintln!("Backend drift: {}", drift)),
+                Err(e) => eprintln!("Reconcile failed: {}", e),
+            }
+        }
+        if server.backend_errors() > backend_errors {
+            eprintln!("Backend failed {} times, see the transaction log", server.backend_errors() - backend_errors);
+            backend_errors = server.backend_errors();
+        }
+        for notification in server.take_notifications() {
+            if debug {
+                println!("notify {}: {:?}", notification.queue, notification.event);
+            }
+            send_notification(&mut server, &mut notify_writers, &notification);
+        }
+
+        endpoint.serve(&mut server, &mut reply_writers, TICK_INTERVAL, debug, verbose);
+    }
+
+    println!("Shutting down");
+    let mut clean = true;
+    // Clients can no longer open the queues or connect; requests already
+    // sent are still served below.
+    if let Endpoint::Socket { listener, .. } = &endpoint {
+        if let Err(e) = listener.unlink() {
+            eprintln!("{}", e);
+            clean = false;
+        }
+    }
+    if !keep_queues {
+        for queue in request_queue.iter().copied().chain(&queues.notify) {
+            if let Err(e) = unlink_mq(queue) {
+                eprintln!("{} {}", e, queue);
+                clean = false;
+            }
+        }
+    }
+    let mut drained = 0;
+    loop {
+        match endpoint.serve(&mut server, &mut reply_writers, Duration::ZERO, debug, verbose) {
+            0 => break,
+            served => drained += served,
+        }
+    }
+    for notification in server.take_notifications() {
+        send_notification(&mut server, &mut notify_writers, &notification);
+    }
+    if drained > 0 {
+        println!("Served {} pending requests", drained);
+    }
+    if matches.get_flag("final-snapshot") {
+        match server.save_snapshot() {
+            Ok(()) => println!("Saved tables to {}", config.snapshot.as_ref().expect("required by --final-snapshot").display()),
+            Err(e) => {
+                eprintln!("{}", e);
+                clean = false;
+            }
+        }
+    }
+    if let Err(e) = server.shutdown() {
+        eprintln!("{}", e);
+        clean = false;
+    }
+    std::process::exit(if clean { 0 } else { EXIT_SHUTDOWN_FAILED });
+}
+
+/// Where requests come from.
+enum Endpoint {
+    Queue(TableInterface),
+    Socket { listener: SocketListener, clients: Vec<Client> },
+}
+
+struct Client {
+    connection: TableInterface,
+    fd: RawFd,
+    peer: PeerCredentials,
+}
+
+impl Endpoint {
+    fn open(queues: &QueueConfig) -> Result<Endpoint, String> {
+        match &queues.transport {
+            Transport::Queue => {
+                let name = queues.name.as_ref().expect("checked after reading the options");
+                TableInterface::get_table_reader_sized(name, queues.message_size, queues.depth)
+                    .map(Endpoint::Queue)
+                    .map_err(|e| format!("{}{}", e, name))
+            },
+            Transport::Socket(path) => Ok(Endpoint::Socket { listener: SocketListener::bind(path)?, clients: Vec::new() }),
+        }
+    }
+
+    /// Waits at most `timeout` for requests and serves them, at most one per
+    /// client. Returns the number of requests served.
+    fn serve(&mut self, server: &mut Server, reply_writers: &mut HashMap<String, TableInterface>,
+             timeout: Duration, debug: bool, verbose: bool) -> usize {
+        let (listener, clients) = match self {
+            Endpoint::Queue(reader) => return match reader.read_timeout(timeout) {
+                Ok(Some(message)) => {
+                    if let Some((reply_to, reply)) = handle_message(server, &message, None, debug, verbose) {
+                        send_reply(reply_writers, &reply_to, &reply);
+                    }
+                    1
+                },
+                Ok(None) => 0,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    0
+                },
+            },
+            Endpoint::Socket { listener, clients } => (listener, clients),
+        };
+
+        let mut fds = vec![listener.as_raw_fd()];
+        fds.extend(clients.iter().map(|c| c.fd));
+        let ready = match poll_readable(&fds, timeout) {
+            Ok(ready) => ready,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 0;
+            }
+        };
+        let mut served = 0;
+        let mut closed = Vec::new();
+        for index in ready.iter().filter(|i| **i > 0).map(|i| i - 1) {
+            let client = &clients[index];
+            match client.connection.read_timeout(Duration::ZERO) {
+                Ok(Some(message)) => {
+                    served += 1;
+                    let Some((reply_to, reply)) = handle_message(server, &message, Some(client.peer), debug, verbose) else {
+                        continue;
+                    };
+                    if reply_to.is_empty() {
+                        continue;
+                    }
+                    // The connection does not block, so a client that stops
+                    // reading is dropped instead of stalling the server.
+                    if let Err(e) = client.connection.write(&reply) {
+                        eprintln!("Failed to reply to pid {}: {}", client.peer.pid, e);
+                        closed.push(index);
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    if verbose || e != CONNECTION_CLOSED {
+                        println!("Client pid {} disconnected: {}", client.peer.pid, e);
+                    }
+                    closed.push(index);
+                },
+            }
+        }
+        for index in closed.into_iter().rev() {
+            clients.remove(index);
+        }
+        if ready.first() == Some(&0) {
+            loop {
+                match listener.accept_timeout(Duration::ZERO) {
+                    Ok(Some(connection)) => match connection.peer_credentials() {
+                        Ok(peer) => {
+                            if verbose {
+                                println!("Client pid {} uid {} gid {} connected", peer.pid, peer.uid, peer.gid);
+                            }
+                            let fd = connection.as_raw_fd();
+                            let connection = TableInterface::with_transport(&format!("pid {}", peer.pid), Box::new(connection));
+                            clients.push(Client { connection, fd, peer });
+                        },
+                        Err(e) => eprintln!("{}", e),
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        break;
+                    }
+                }
+            }
+        }
+        served
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Queue(reader) => write!(f, "{}", reader.name()),
+            Endpoint::Socket { listener, .. } => write!(f, "{}", listener.path().display()),
+        }
+    }
+}
+
+/// Handles one request. Returns the encoded reply and the queue it should go
+/// to, unless the request was malformed.
+fn handle_message(server: &mut Server, message: &[u8], peer: Option<PeerCredentials>,
+                  debug: bool, verbose: bool) -> Option<(String, Vec<u8>)> {
+    let (request, reply, encoding) = match server.handle_message(message, peer) {
+        Ok(handled) => handled,
+        Err(e) => {
+            eprintln!("Dropping malformed request: {}", e);
+            return None;
+        }
+    };
+    if verbose {
+        println!("{:?} request of {} bytes, reply to '{}'", encoding, message.len(), request.reply_to);
+    }
+    if debug {
+        println!("reply: {:?}", reply);
+    }
+    Some((request.reply_to, reply.encode(encoding)))
+}
+
+/// Makes sure a queue the server creates matches the configured settings. A
+/// queue left behind by an earlier server may hold requests nobody waits for
+/// any more, so it is replaced unless `keep` is set. A queue with a different
+/// item size or depth is always replaced, as it would keep its old settings.
+fn prepare_queue(queue: &str, queues: &QueueConfig, keep: bool) -> Result<(), String> {
+    if !mq_exists(queue) {
+        return Ok(());
+    }
+    let attributes = mq_attributes(queue)?;
+    let matching = attributes.max_item_size == queues.message_size && attributes.max_items == queues.depth;
+    if keep && matching {
+        if attributes.pending > 0 {
+            println!("Reusing queue {} with {} pending messages", queue, attributes.pending);
+        }
+        return Ok(());
+    }
+    println!("Replacing queue {} left behind with {} pending messages", queue, attributes.pending);
+    unlink_mq(queue)
+}
+
+/// Lets command line options override what the config file says. A table
+/// declared with `--table` replaces the config file's table of the same name.
+fn apply_options(matches: &ArgMatches, config: &mut Config) {
     if let Some(name) = matches.get_one::<String>("name") {
-        println!("Shared Memory: {name}");
+        config.queue.name = Some(name.clone());
+        config.queue.transport = Transport::Queue;
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("socket") {
+        config.queue.transport = Transport::Socket(path.clone());
     }
+    if let Some(queue) = matches.get_one::<String>("notify") {
+        config.queue.notify = Some(queue.clone());
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("logfile") {
+        config.log = Some(path.clone());
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("snapshot") {
+        config.snapshot = Some(path.clone());
+    }
+    for spec in matches.get_many::<TableSpec>("table").unwrap_or_default() {
+        config.tables.retain(|t| t.name != spec.name);
+        config.tables.push(spec.clone());
+    }
+}
 
-    if let Some(logfile) = matches.get_one::<String>("logfile") {
-        println!("Logfile to : {}", logfile);
+fn open_backend(spec: &str) -> Result<Box<dyn TableBackend>, String> {
+    match spec.split_once(':') {
+        Some(("file", dir)) => Ok(Box::new(FileBackend::open(Path::new(dir))?)),
+        Some(("tcam", slots)) => match slots.parse::<usize>() {
+            Ok(slots) if slots > 0 => Ok(Box::new(TcamBackend::new(slots))),
+            _ => Err(format!("Invalid TCAM size '{}'", slots)),
+        },
+        None if spec == "sim" => Ok(Box::new(SimBackend::new())),
+        _ => Err(format!("Unknown backend '{}'", spec)),
     }
+}
 
-    // You can see how many times a particular flag or argument occurred
-    // Note, only flags can have multiple occurrences
-    println!("debug: {:?}", matches.get_flag("debug"));
-    println!("verbose: {:?}", matches.get_flag("verbose"));
+/// Drops the subscriptions of queues that no longer exist.
+fn prune_subscribers(server: &mut Server, writers: &mut HashMap<String, TableInterface>) {
+    let mut gone: Vec<String> = server.subscriptions().iter()
+        .filter(|s| !mq_exists(&s.queue))
+        .map(|s| s.queue.clone())
+        .collect();
+    gone.sort();
+    gone.dedup();
+    for queue in gone {
+        println!("Subscriber queue {} disappeared, unsubscribing", queue);
+        server.unsubscribe(&queue);
+        writers.remove(&queue);
+    }
+}
+
+fn send_notification(server: &mut Server, writers: &mut HashMap<String, TableInterface>, notification: &Notification) {
+    let queue = &notification.queue;
+    if !writers.contains_key(queue) {
+        match TableInterface::open_existing_writer(queue) {
+            Ok(writer) => { writers.insert(queue.clone(), writer); },
+            Err(e) => {
+                eprintln!("{} {}, unsubscribing", e, queue);
+                server.unsubscribe(queue);
+                return;
+            }
+        }
+    }
+    // The writer does not block, so a subscriber that stops reading loses
+    // notifications instead of stalling the server.
+    if let Err(e) = writers[queue].write(&notification.event.encode(notification.encoding)) {
+        eprintln!("Dropping notification for {}: {}", queue, e);
+    }
+}
+
+/// Drops the writers of reply queues that no longer exist.
+fn prune_reply_writers(writers: &mut HashMap<String, TableInterface>) {
+    writers.retain(|queue, _| mq_exists(queue));
+}
+
+/// Replies on the client's queue, which the client creates. Like
+/// notifications, a reply to a queue that is full or gone is dropped rather
+/// than stalling the server.
+fn send_reply(writers: &mut HashMap<String, TableInterface>, reply_to: &str, reply: &[u8]) {
+    if reply_to.is_empty() {
+        return;
+    }
+    if !writers.contains_key(reply_to) {
+        match TableInterface::open_existing_writer(reply_to) {
+            Ok(writer) => { writers.insert(reply_to.to_string(), writer); },
+            Err(e) => {
+                eprintln!("{} {}", e, reply_to);
+                return;
+            }
+        }
+    }
+    if let Err(e) = writers[reply_to].write(reply) {
+        eprintln!("Dropping reply for {}: {}", reply_to, e);
+        writers.remove(reply_to);
+    }
 }
\ No newline at end of file
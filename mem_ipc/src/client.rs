@@ -0,0 +1,299 @@
+//! Typed client for the shadow table server.
+//!
+//! [`TableClient`] builds the requests, sends them over the server's request
+//! queue and waits for the reply on a queue of its own. Every request is
+//! numbered and only the reply carrying its number is taken. Requests that
+//! change something are resent until acknowledged, so each is applied exactly
+//! once; reads are sent once.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+#[cfg(feature = "json")]
+use crate::json::Item;
+use crate::protocol::{Encoding, Reply, Request, RequestBody, TableInfo, WriteOptions};
+use crate::tables::{DirectEntry, TernaryEntry};
+use crate::{Actions, CItem, SItem, TableInterface};
+
+/// How long a request may take, retries included, unless `set_timeout` says otherwise.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a reply before sending a request again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+static NEXT_CLIENT: AtomicU32 = AtomicU32::new(1);
+
+pub struct TableClient {
+    requests: TableInterface,
+    replies: TableInterface,
+    token: Option<String>,
+    timeout: Duration,
+    encoding: Encoding,
+}
+
+impl TableClient {
+    /// Connects to the server reading requests from the message queue `name`,
+    /// creating a reply queue named after it. Fails if there is no such queue,
+    /// i.e. no server is running.
+    pub fn connect(name: &str) -> Result<TableClient, String> {
+        let reply_to = format!("{}.reply.{}.{}", name, std::process::id(), NEXT_CLIENT.fetch_add(1, Ordering::Relaxed));
+        let requests = TableInterface::open_existing_writer(name).map_err(|e| format!("{} {}", e, name))?;
+        let replies = TableInterface::get_table_reader(&reply_to)?;
+        Ok(TableClient::with_queues(requests, replies))
+    }
+
+    /// A client sending requests on `requests` and reading the replies from
+    /// `replies`, which it unlinks when dropped. `requests` should not block
+    /// when full, see `TableInterface::open_existing_writer`.
+    pub fn with_queues(requests: TableInterface, replies: TableInterface) -> TableClient {
+        TableClient { requests, replies, token: None, timeout: DEFAULT_TIMEOUT, encoding: Encoding::Native }
+    }
+
+    /// Sends `token` with every request from now on.
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+
+    /// Limits how long a request may take, retries included.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sends the requests in `encoding`; the server replies in kind.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Sends `body` and returns the server's reply. A `Reply::Error` becomes an
+    /// error, other replies are returned as they are.
+    pub fn request(&self, body: RequestBody) -> Result<Reply, String> {
+        let mut request = Request { reply_to: self.replies.name().to_string(), sequence: 0, token: self.token.clone(), body };
+        let retry = if request.body.reads_only() { self.timeout } else { RETRY_INTERVAL.min(self.timeout) };
+        let reply = self.requests.write_acked(&mut request, self.encoding, &self.replies, retry, self.timeout)?;
+        match reply {
+            Reply::Error(e) => Err(e),
+            Reply::Conflict { current } => Err(format!("Conflict, the entry is at version {}", current)),
+            reply => Ok(reply),
+        }
+    }
+
+    pub fn add_ternary(&self, table: &str, key: &[u8], mask: &[u8], priority: u16, result: &[u8]) -> Result<(), String> {
+        self.add_ternary_with(table, key, mask, priority, result, WriteOptions::default())
+    }
+
+    /// Like `add_ternary`, with aging, session or expected version.
+    pub fn add_ternary_with(&self, table: &str, key: &[u8], mask: &[u8], priority: u16, result: &[u8],
+                            options: WriteOptions) -> Result<(), String> {
+        let item = ternary_item(table, Actions::Add, key, mask, priority, result)?;
+        expect_ok(self.request(RequestBody::Ternary(item, options))?)
+    }
+
+    /// Deletes a ternary entry.
+    pub fn delete(&self, table: &str, key: &[u8], mask: &[u8], priority: u16) -> Result<(), String> {
+        let item = ternary_item(table, Actions::Delete, key, mask, priority, &[])?;
+        expect_ok(self.request(RequestBody::Ternary(item, WriteOptions::default()))?)
+    }
+
+    /// Reads a ternary entry, `None` if there is none.
+    pub fn query(&self, table: &str, key: &[u8], mask: &[u8], priority: u16) -> Result<Option<TernaryEntry>, String> {
+        let item = ternary_item(table, Actions::Query, key, mask, priority, &[])?;
+        match missing_as_none(self.request(RequestBody::Ternary(item, WriteOptions::default())))? {
+            Some(Reply::Ternary(mut entries)) if entries.len() == 1 => Ok(entries.pop()),
+            Some(reply) => Err(unexpected(reply)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the entry at `index` of a direct table.
+    pub fn set_index(&self, table: &str, index: u16, value: &[u8]) -> Result<(), String> {
+        self.set_index_with(table, index, value, WriteOptions::default())
+    }
+
+    /// Like `set_index`, with aging, session or expected version.
+    pub fn set_index_with(&self, table: &str, index: u16, value: &[u8], options: WriteOptions) -> Result<(), String> {
+        let item = direct_item(table, Actions::Add, index, value)?;
+        expect_ok(self.request(RequestBody::Direct(item, options))?)
+    }
+
+    /// Reads the entry at `index` of a direct table, `None` if there is none.
+    pub fn get_index(&self, table: &str, index: u16) -> Result<Option<DirectEntry>, String> {
+        let item = direct_item(table, Actions::Query, index, &[])?;
+        match missing_as_none(self.request(RequestBody::Direct(item, WriteOptions::default())))? {
+            Some(Reply::Direct(mut entries)) if entries.len() == 1 => Ok(entries.pop()),
+            Some(reply) => Err(unexpected(reply)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_index(&self, table: &str, index: u16) -> Result<(), String> {
+        let item = direct_item(table, Actions::Delete, index, &[])?;
+        expect_ok(self.request(RequestBody::Direct(item, WriteOptions::default()))?)
+    }
+
+    /// Classifies `key` like the data path would, falling back to the table's
+    /// default result. `None` is a miss.
+    pub fn lookup(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match self.request(RequestBody::Lookup { table_id: table.to_string(), key: key.to_vec(), bytes: 0 })? {
+            Reply::Result(result) => Ok(Some(result)),
+            Reply::Miss => Ok(None),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub fn list_tables(&self) -> Result<Vec<TableInfo>, String> {
+        match self.request(RequestBody::ListTables)? {
+            Reply::Tables(tables) => Ok(tables),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Applies bulk `items` in order, stopping at the first one the server
+    /// refuses. Returns how many were applied.
+    #[cfg(feature = "json")]
+    pub fn load(&self, items: impl IntoIterator<Item = Item>) -> Result<usize, String> {
+        let mut applied = 0;
+        for item in items {
+            self.request(item.into_request()).map_err(|e| format!("Item {}: {}", applied + 1, e))?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+}
+
+impl Drop for TableClient {
+    /// The server opens reply queues without creating them and drops the
+    /// replies it cannot deliver without blocking, so a late reply to a
+    /// request that timed out is lost rather than stalling it.
+    fn drop(&mut self) {
+        let _ = self.replies.unlink();
+    }
+}
+
+fn ternary_item(table: &str, action: Actions, key: &[u8], mask: &[u8], priority: u16, result: &[u8]) -> Result<CItem, String> {
+    Ok(CItem::builder(table).action(action).priority(priority).key(key).mask(mask).result(result).build()?)
+}
+
+fn direct_item(table: &str, action: Actions, index: u16, value: &[u8]) -> Result<SItem, String> {
+    Ok(SItem::builder(table).action(action).index(index).value(value).build()?)
+}
+
+fn expect_ok(reply: Reply) -> Result<(), String> {
+    match reply {
+        Reply::Ok => Ok(()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Turns the server's "No such entry" error into `None`.
+fn missing_as_none(result: Result<Reply, String>) -> Result<Option<Reply>, String> {
+    match result {
+        Err(e) if e == "No such entry" => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+fn unexpected(reply: Reply) -> String {
+    format!("Unexpected reply {:?}", reply)
+}
+
+#[cfg(test)]
+mod client_tests {
+    use super::*;
+    use crate::server::Server;
+    use crate::tables::Tables;
+    use crate::testing::TestServer;
+
+    fn server() -> TestServer {
+        let mut tables = Tables::default();
+        tables.declare("acl:ternary:2:16".parse().unwrap()).unwrap();
+        tables.declare("nexthop:direct:16".parse().unwrap()).unwrap();
+        TestServer::start(Server::new(tables))
+    }
+
+    #[test]
+    fn test_ternary() {
+        let server = server();
+        let client = server.table_client();
+        client.add_ternary("acl", &[10, 0], &[0xff, 0], 1, b"permit").unwrap();
+        assert_eq!(client.lookup("acl", &[10, 7]), Ok(Some(b"permit".to_vec())));
+        assert_eq!(client.lookup("acl", &[11, 7]), Ok(None));
+
+        let entry = client.query("acl", &[10, 0], &[0xff, 0], 1).unwrap().expect("Entry added");
+        assert_eq!((entry.result.as_slice(), entry.version), (&b"permit"[..], 1));
+        let stale = WriteOptions { expected_version: Some(0), ..Default::default() };
+        assert_eq!(client.add_ternary_with("acl", &[10, 0], &[0xff, 0], 1, b"deny", stale),
+                   Err("Conflict, the entry is at version 1".to_string()));
+
+        client.delete("acl", &[10, 0], &[0xff, 0], 1).unwrap();
+        assert_eq!(client.query("acl", &[10, 0], &[0xff, 0], 1), Ok(None));
+        assert_eq!(client.delete("acl", &[10, 0], &[0xff, 0], 1), Err("No such entry".to_string()));
+        assert_eq!(client.add_ternary("acl", &[10], &[0xff], 1, b""), Err("Key length does not match table key width".to_string()));
+    }
+
+    #[test]
+    fn test_direct() {
+        let server = server();
+        let client = server.table_client();
+        client.set_index("nexthop", 3, b"eth0").unwrap();
+        assert_eq!(client.get_index("nexthop", 3).unwrap().map(|e| e.value), Some(b"eth0".to_vec()));
+        assert_eq!(client.get_index("nexthop", 4), Ok(None));
+        client.delete_index("nexthop", 3).unwrap();
+        assert_eq!(client.get_index("nexthop", 3), Ok(None));
+        assert!(client.get_index("missing", 3).is_err());
+        assert_eq!(client.list_tables().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_reads() {
+        let server = server();
+        let client = server.table_client();
+        client.set_index("nexthop", 3, b"eth0").unwrap();
+        assert_eq!(client.get_index("nexthop", 3).unwrap().map(|e| e.value), Some(b"eth0".to_vec()));
+        // A reply to the first read arriving late is not taken for the reply
+        // to the next one, nor is an unnumbered reply.
+        let sequence = client.requests.sequence.load(Ordering::Relaxed);
+        let late = Reply::Acked { sequence, reply: Box::new(Reply::Error("No such entry".to_string())) };
+        client.replies.write(&late.encode(Encoding::Native)).unwrap();
+        client.replies.write(&Reply::Ok.encode(Encoding::Native)).unwrap();
+        assert_eq!(client.get_index("nexthop", 3).unwrap().map(|e| e.value), Some(b"eth0".to_vec()));
+        assert_eq!(client.list_tables().unwrap().len(), 2);
+        assert_eq!(client.requests.sequence.load(Ordering::Relaxed), sequence + 2);
+    }
+
+    #[test]
+    fn test_connect_missing() {
+        let name = format!("/client_test_missing.{}", std::process::id());
+        assert!(TableClient::connect(&name).is_err());
+        assert!(!crate::mq_exists(&name));
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn test_protobuf() {
+        let server = server();
+        let mut client = server.table_client();
+        client.set_encoding(Encoding::Protobuf);
+        client.add_ternary("acl", &[10, 0], &[0xff, 0], 1, b"permit").unwrap();
+        assert_eq!(client.lookup("acl", &[10, 7]), Ok(Some(b"permit".to_vec())));
+        assert_eq!(client.query("acl", &[10, 0], &[0xff, 0], 1).unwrap().map(|e| e.version), Some(1));
+        assert_eq!(client.get_index("nexthop", 4), Ok(None));
+        assert_eq!(client.list_tables().unwrap().len(), 2);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_load() {
+        let server = server();
+        let client = server.table_client();
+        let bulk = concat!(
+            r#"{"kind":"ternary","table":"acl","action":"add","priority":1,"key":"0a00","mask":"ff00","result":"7065726d6974"}"#, "\n",
+            r#"{"kind":"direct","table":"nexthop","action":"add","index":3,"value":"65746830"}"#, "\n",
+            r#"{"kind":"direct","table":"missing","action":"add","index":3,"value":""}"#, "\n",
+            r#"{"kind":"direct","table":"nexthop","action":"add","index":4,"value":""}"#, "\n");
+        let items = crate::json::read_items(bulk.as_bytes()).unwrap();
+        assert_eq!(client.load(items), Err("Item 3: No such table".to_string()));
+        assert_eq!(client.lookup("acl", &[10, 1]), Ok(Some(b"permit".to_vec())));
+        assert_eq!(client.get_index("nexthop", 3).unwrap().map(|e| e.value), Some(b"eth0".to_vec()));
+        assert_eq!(client.get_index("nexthop", 4), Ok(None));
+    }
+}
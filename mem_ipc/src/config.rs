@@ -0,0 +1,337 @@
+//! Server configuration file.
+//!
+//! A TOML file declares the queues, the tables and where the log and snapshot
+//! go:
+//!
+//! ```toml
+//! log = "/var/log/tables.log"
+//! snapshot = "/var/lib/tables.snap"
+//!
+//! [queue]
+//! name = "/tables"
+//! notify = "/tables.events"
+//! depth = 10
+//! message_size = 8192
+//!
+//! [[table]]
+//! name = "acl"
+//! kind = "ternary"
+//! key_width = 4
+//! capacity = 1024
+//!
+//! [[table]]
+//! name = "nexthop"
+//! kind = "direct"
+//! capacity = 4096
+//! ```
+//!
+//! `depth` and `message_size` default to `MAX_QITEMS` and `MAX_QITEM_SIZE`.
+//!
+//! Requests arrive on the queue `name` unless `transport = "socket"` is set,
+//! in which case the server listens on the Unix socket at `socket` instead:
+//!
+//! ```toml
+//! [queue]
+//! transport = "socket"
+//! socket = "/run/tables.sock"
+//! ```
+//!
+//! `[[access]]` rules restrict what clients may do, see the
+//! [`access`](crate::access) module. A rule names a `uid` or `gid` (socket
+//! clients only) or a `token` from `[tokens]`, or none of them for anyone,
+//! and grants `read-only`, `write` or `admin` access to `tables`, or to every
+//! table if omitted. Once there is a rule, anything not granted is refused.
+//! Except for admins, clients may then only have replies and events sent to
+//! queues named `<name>.<anything>`, or, over the socket, to queues their
+//! user owns:
+//!
+//! ```toml
+//! [tokens]
+//! monitoring = "c2VjcmV0"
+//!
+//! [[access]]
+//! uid = 0
+//! level = "admin"
+//!
+//! [[access]]
+//! token = "monitoring"
+//! level = "read-only"
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::access::{Policy, Principal, Rule};
+use crate::fragment::FRAGMENT_HEADER_SIZE;
+use crate::tables::{TableKind, TableSpec};
+use crate::{MAX_QITEMS, MAX_QITEM_SIZE};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    log: Option<PathBuf>,
+    snapshot: Option<PathBuf>,
+    queue: Option<RawQueue>,
+    #[serde(default, rename = "table")]
+    tables: Vec<RawTable>,
+    tokens: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    access: Vec<RawRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawQueue {
+    name: Option<String>,
+    notify: Option<String>,
+    depth: Option<usize>,
+    message_size: Option<usize>,
+    transport: Option<String>,
+    socket: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    token: Option<String>,
+    tables: Option<Vec<String>>,
+    level: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTable {
+    name: String,
+    kind: String,
+    key_width: Option<usize>,
+    capacity: usize,
+}
+
+/// How clients reach the server.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Transport {
+    /// The request queue `QueueConfig::name`.
+    #[default]
+    Queue,
+    /// A Unix socket at this path, see the [`socket`](crate::socket) module.
+    Socket(PathBuf),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueConfig {
+    /// Queue the server reads requests from.
+    pub name: Option<String>,
+    /// Queue that receives a notification for every change.
+    pub notify: Option<String>,
+    pub depth: usize,
+    pub message_size: usize,
+    pub transport: Transport,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig { name: None, notify: None, depth: MAX_QITEMS, message_size: MAX_QITEM_SIZE, transport: Transport::Queue }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub queue: QueueConfig,
+    pub log: Option<PathBuf>,
+    pub snapshot: Option<PathBuf>,
+    pub tables: Vec<TableSpec>,
+    /// `None` if there are no access rules.
+    pub policy: Option<Policy>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// Parses and validates a configuration.
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let raw: RawConfig = toml::from_str(text).map_err(|e| e.message().to_string())?;
+        let mut config = Config { log: raw.log, snapshot: raw.snapshot, ..Config::default() };
+
+        if let Some(queue) = raw.queue {
+            for name in queue.name.iter().chain(&queue.notify) {
+                check_queue_name(name)?;
+            }
+            config.queue.name = queue.name;
+            config.queue.notify = queue.notify;
+            config.queue.depth = queue.depth.unwrap_or(MAX_QITEMS);
+            config.queue.message_size = queue.message_size.unwrap_or(MAX_QITEM_SIZE);
+            config.queue.transport = match (queue.transport.as_deref(), queue.socket) {
+                (None | Some("mqueue"), None) => Transport::Queue,
+                (None | Some("mqueue"), Some(_)) => return Err("A socket path needs transport = \"socket\"".to_string()),
+                (Some("socket"), Some(path)) => Transport::Socket(path),
+                (Some("socket"), None) => return Err("The socket transport needs a socket path".to_string()),
+                (Some(transport), _) => return Err(format!("Unknown transport '{}', expected 'mqueue' or 'socket'", transport)),
+            };
+        }
+        if config.queue.depth == 0 {
+            return Err("Queue depth must not be zero".to_string());
+        }
+        if config.queue.message_size <= FRAGMENT_HEADER_SIZE {
+            return Err(format!("Queue message size must exceed {} bytes", FRAGMENT_HEADER_SIZE));
+        }
+
+        for table in raw.tables {
+            let spec = table_spec(table)?;
+            if config.tables.iter().any(|t| t.name == spec.name) {
+                return Err(format!("Table '{}' declared twice", spec.name));
+            }
+            config.tables.push(spec);
+        }
+
+        let tokens = raw.tokens.unwrap_or_default();
+        if raw.access.is_empty() && !tokens.is_empty() {
+            return Err("Tokens are only used by [[access]] rules".to_string());
+        }
+        if !raw.access.is_empty() {
+            let rules = raw.access.into_iter().map(|rule| access_rule(rule, &tokens)).collect::<Result<_, _>>()?;
+            config.policy = Some(Policy { tokens, rules });
+        }
+        Ok(config)
+    }
+}
+
+fn check_queue_name(name: &str) -> Result<(), String> {
+    if !name.starts_with('/') || name.len() < 2 || name[1..].contains('/') {
+        return Err(format!("Queue name '{}' must be '/' followed by a name without '/'", name));
+    }
+    Ok(())
+}
+
+fn access_rule(rule: RawRule, tokens: &BTreeMap<String, String>) -> Result<Rule, String> {
+    let principal = match (rule.uid, rule.gid, rule.token) {
+        (Some(uid), None, None) => Principal::Uid(uid),
+        (None, Some(gid), None) => Principal::Gid(gid),
+        (None, None, Some(token)) if tokens.contains_key(&token) => Principal::Token(token),
+        (None, None, Some(token)) => return Err(format!("Access rule names unknown token '{}'", token)),
+        (None, None, None) => Principal::Anyone,
+        _ => return Err("An access rule names at most one of uid, gid and token".to_string()),
+    };
+    Ok(Rule { principal, tables: rule.tables.unwrap_or_default(), access: rule.level.parse()? })
+}
+
+fn table_spec(table: RawTable) -> Result<TableSpec, String> {
+    let kind: TableKind = table.kind.parse()?;
+    let key_width = match (kind, table.key_width) {
+        (TableKind::Ternary, Some(key_width)) => key_width,
+        (TableKind::Ternary, None) => return Err(format!("Ternary table '{}' needs a key_width", table.name)),
+        (TableKind::Direct, None | Some(2)) => 2,
+        (TableKind::Direct, Some(_)) => return Err(format!("Direct table '{}' is indexed by 2 byte keys", table.name)),
+    };
+    let spec = TableSpec { name: table.name, kind, key_width, capacity: table.capacity };
+    spec.validate()?;
+    Ok(spec)
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+    use crate::access::Access;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(r#"
+            log = "/tmp/tables.log"
+
+            [queue]
+            name = "/tables"
+            depth = 10
+            message_size = 8192
+
+            [[table]]
+            name = "acl"
+            kind = "ternary"
+            key_width = 4
+            capacity = 1024
+
+            [[table]]
+            name = "nexthop"
+            kind = "direct"
+            capacity = 16
+        "#).unwrap();
+        assert_eq!(config.queue.name.as_deref(), Some("/tables"));
+        assert_eq!(config.queue.notify, None);
+        assert_eq!((config.queue.depth, config.queue.message_size), (10, 8192));
+        assert_eq!(config.queue.transport, Transport::Queue);
+        assert_eq!(config.log, Some(PathBuf::from("/tmp/tables.log")));
+        assert_eq!(config.snapshot, None);
+        assert_eq!(config.tables, vec!["acl:ternary:4:1024".parse().unwrap(), "nexthop:direct:16".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_socket_transport() {
+        let config = Config::parse("[queue]\ntransport = \"socket\"\nsocket = \"/run/tables.sock\"").unwrap();
+        assert_eq!(config.queue.transport, Transport::Socket(PathBuf::from("/run/tables.sock")));
+        assert_eq!(config.queue.name, None);
+    }
+
+    #[test]
+    fn test_access_rules() {
+        let config = Config::parse(r#"
+            [tokens]
+            monitoring = "s3cret"
+
+            [[access]]
+            gid = 100
+            tables = ["acl"]
+            level = "write"
+
+            [[access]]
+            token = "monitoring"
+            level = "read-only"
+        "#).unwrap();
+        let policy = config.policy.unwrap();
+        assert_eq!(policy.authenticate("s3cret"), Some("monitoring"));
+        assert_eq!(policy.rules, vec![
+            Rule { principal: Principal::Gid(100), tables: vec!["acl".to_string()], access: Access::Write },
+            Rule { principal: Principal::Token("monitoring".to_string()), tables: vec![], access: Access::ReadOnly },
+        ]);
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.queue.depth, MAX_QITEMS);
+        assert_eq!(config.queue.message_size, MAX_QITEM_SIZE);
+        assert!(config.tables.is_empty());
+        assert_eq!(config.policy, None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = [
+            "[queue]\nname = \"tables\"",
+            "[queue]\ndepth = 0",
+            "[queue]\nmessage_size = 8",
+            "[queue]\nsize = 8",
+            "[queue]\ntransport = \"socket\"",
+            "[queue]\ntransport = \"tcp\"\nsocket = \"/run/tables.sock\"",
+            "[queue]\nsocket = \"/run/tables.sock\"",
+            "[[table]]\nname = \"acl\"\nkind = \"ternary\"\ncapacity = 4",
+            "[[table]]\nname = \"acl\"\nkind = \"hash\"\ncapacity = 4",
+            "[[table]]\nname = \"nh\"\nkind = \"direct\"\nkey_width = 4\ncapacity = 4",
+            "[[table]]\nname = \"nh\"\nkind = \"direct\"\ncapacity = 0",
+            "[[table]]\nname = \"nh\"\nkind = \"direct\"\ncapacity = 4\n[[table]]\nname = \"nh\"\nkind = \"direct\"\ncapacity = 8",
+            "log = 3",
+            "[tokens]\nmonitoring = \"s3cret\"",
+            "[[access]]\nlevel = \"root\"",
+            "[[access]]\ntoken = \"monitoring\"\nlevel = \"write\"",
+            "[[access]]\nuid = 0\ngid = 0\nlevel = \"write\"",
+        ];
+        for text in invalid {
+            assert!(Config::parse(text).is_err(), "Accepted {:?}", text);
+        }
+    }
+}
@@ -0,0 +1,589 @@
+//! C ABI, for clients written in C. `include/mem_ipc.h` is generated from this
+//! module by cbindgen; `examples/c/program_tables.c` shows how to use it.
+//!
+//! Queues and items are opaque handles, created by the `_open_`, `_new` and
+//! `_decode` functions and released with the matching `_free`. Functions
+//! return a [`MemIpcStatus`]; on failure `mem_ipc_last_error` describes what
+//! went wrong. Byte buffers returned to C are released with
+//! `mem_ipc_bytes_free`. Only built with the `ffi` feature.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::time::Duration;
+
+use crate::protocol::{Reply, Request, RequestBody};
+use crate::{CItem, SItem, TableInterface, MAX_QITEMS, MAX_QITEM_SIZE};
+
+/// Values of `action` in `mem_ipc_citem_new` and `mem_ipc_sitem_new`, see `Actions`.
+pub const MEM_IPC_ACTION_NOOP: u8 = 0;
+pub const MEM_IPC_ACTION_ADD: u8 = 1;
+pub const MEM_IPC_ACTION_DELETE: u8 = 2;
+pub const MEM_IPC_ACTION_QUERY: u8 = 3;
+pub const MEM_IPC_ACTION_READ_COUNTERS: u8 = 4;
+pub const MEM_IPC_ACTION_RESET_COUNTERS: u8 = 5;
+
+/// Result of every fallible function.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemIpcStatus {
+    Ok = 0,
+    /// A null pointer, a string that is not UTF-8 or an invalid item.
+    InvalidArgument = 1,
+    /// Opening, sending on or receiving from a queue failed.
+    QueueError = 2,
+    /// Nothing arrived before the timeout.
+    Timeout = 3,
+    /// A received buffer is not a valid item or reply.
+    DecodeError = 4,
+    /// The server answered with an error.
+    ServerError = 5,
+}
+
+/// A message queue opened for writing or reading.
+pub struct MemIpcQueue(TableInterface);
+
+/// A `CItem`, with its table id as a C string.
+pub struct MemIpcCItem {
+    item: CItem,
+    table_id: CString,
+}
+
+/// An `SItem`, with its table id as a C string.
+pub struct MemIpcSItem {
+    item: SItem,
+    table_id: CString,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn fail(status: MemIpcStatus, error: impl Into<String>) -> MemIpcStatus {
+    let error = CString::new(error.into().replace('\0', " ")).expect("NULs replaced");
+    LAST_ERROR.with(|last| *last.borrow_mut() = error);
+    status
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, MemIpcStatus> {
+    if s.is_null() {
+        return Err(fail(MemIpcStatus::InvalidArgument, "Null string"));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| fail(MemIpcStatus::InvalidArgument, "String is not valid UTF-8"))
+}
+
+/// `len` bytes at `data`, which may be null if `len` is 0.
+unsafe fn bytes_arg<'a>(data: *const u8, len: usize) -> Result<&'a [u8], MemIpcStatus> {
+    match (data.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => Err(fail(MemIpcStatus::InvalidArgument, "Null buffer")),
+        (false, _) => Ok(std::slice::from_raw_parts(data, len)),
+    }
+}
+
+/// Hands `bytes` to C, to be released with `mem_ipc_bytes_free`.
+unsafe fn give_bytes(bytes: Vec<u8>, data: *mut *mut u8, len: *mut usize) -> MemIpcStatus {
+    if data.is_null() || len.is_null() {
+        return fail(MemIpcStatus::InvalidArgument, "Null output pointer");
+    }
+    let bytes = Box::into_raw(bytes.into_boxed_slice());
+    *len = bytes.len();
+    *data = bytes as *mut u8;
+    MemIpcStatus::Ok
+}
+
+unsafe fn give<T>(value: T, out: *mut *mut T) -> MemIpcStatus {
+    if out.is_null() {
+        return fail(MemIpcStatus::InvalidArgument, "Null output pointer");
+    }
+    *out = Box::into_raw(Box::new(value));
+    MemIpcStatus::Ok
+}
+
+/// Sets `*len` to the length of `bytes` and returns them.
+unsafe fn lend_bytes(bytes: &[u8], len: *mut usize) -> *const u8 {
+    if !len.is_null() {
+        *len = bytes.len();
+    }
+    bytes.as_ptr()
+}
+
+macro_rules! try_status {
+    ($e:expr) => {
+        match $e {
+            Ok(value) => value,
+            Err(status) => return status,
+        }
+    };
+}
+
+/// Describes the last failure on this thread. Valid until the next failing
+/// call on the same thread.
+#[no_mangle]
+pub extern "C" fn mem_ipc_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Opens the queue `name` for writing, creating it with room for `max_items`
+/// items of `max_item_size` bytes if it does not exist; 0 picks the default.
+///
+/// # Safety
+/// `name` must be a NUL-terminated string and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_open_writer(name: *const c_char, max_item_size: usize, max_items: usize,
+                                             out: *mut *mut MemIpcQueue) -> MemIpcStatus {
+    let name = try_status!(str_arg(name));
+    let (size, items) = queue_size(max_item_size, max_items);
+    match TableInterface::get_writer_sized(name, size, items) {
+        Ok(queue) => give(MemIpcQueue(queue), out),
+        Err(e) => fail(MemIpcStatus::QueueError, format!("{} {}", e, name)),
+    }
+}
+
+/// Like `mem_ipc_open_writer`, for reading.
+///
+/// # Safety
+/// `name` must be a NUL-terminated string and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_open_reader(name: *const c_char, max_item_size: usize, max_items: usize,
+                                             out: *mut *mut MemIpcQueue) -> MemIpcStatus {
+    let name = try_status!(str_arg(name));
+    let (size, items) = queue_size(max_item_size, max_items);
+    match TableInterface::get_table_reader_sized(name, size, items) {
+        Ok(queue) => give(MemIpcQueue(queue), out),
+        Err(e) => fail(MemIpcStatus::QueueError, format!("{} {}", e, name)),
+    }
+}
+
+fn queue_size(max_item_size: usize, max_items: usize) -> (usize, usize) {
+    let or_default = |value, default| if value == 0 { default } else { value };
+    (or_default(max_item_size, MAX_QITEM_SIZE), or_default(max_items, MAX_QITEMS))
+}
+
+/// Sends `len` bytes at `data` as one message.
+///
+/// # Safety
+/// `queue` must come from `mem_ipc_open_writer` and `data` hold `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_send(queue: *const MemIpcQueue, data: *const u8, len: usize) -> MemIpcStatus {
+    let Some(queue) = queue.as_ref() else {
+        return fail(MemIpcStatus::InvalidArgument, "Null queue");
+    };
+    let message = try_status!(bytes_arg(data, len));
+    match queue.0.write(message) {
+        Ok(()) => MemIpcStatus::Ok,
+        Err(e) => fail(MemIpcStatus::QueueError, e),
+    }
+}
+
+/// Receives one message, waiting at most `timeout_ms` or forever if it is
+/// negative. The message is released with `mem_ipc_bytes_free`.
+///
+/// # Safety
+/// `queue` must come from `mem_ipc_open_reader`, `data` and `len` be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_receive(queue: *const MemIpcQueue, timeout_ms: i64, data: *mut *mut u8,
+                                         len: *mut usize) -> MemIpcStatus {
+    let Some(queue) = queue.as_ref() else {
+        return fail(MemIpcStatus::InvalidArgument, "Null queue");
+    };
+    let message = match u64::try_from(timeout_ms) {
+        Ok(ms) => queue.0.read_timeout(Duration::from_millis(ms)),
+        Err(_) => queue.0.read().map(Some),
+    };
+    match message {
+        Ok(Some(message)) => give_bytes(message, data, len),
+        Ok(None) => fail(MemIpcStatus::Timeout, "No message before the timeout"),
+        Err(e) => fail(MemIpcStatus::QueueError, e),
+    }
+}
+
+/// Removes the queue's name, e.g. for a reply queue nobody else uses.
+///
+/// # Safety
+/// `queue` must come from `mem_ipc_open_writer` or `mem_ipc_open_reader`.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_queue_unlink(queue: *const MemIpcQueue) -> MemIpcStatus {
+    let Some(queue) = queue.as_ref() else {
+        return fail(MemIpcStatus::InvalidArgument, "Null queue");
+    };
+    match queue.0.unlink() {
+        Ok(()) => MemIpcStatus::Ok,
+        Err(e) => fail(MemIpcStatus::QueueError, e),
+    }
+}
+
+/// Closes a queue. Null is ignored.
+///
+/// # Safety
+/// `queue` must come from `mem_ipc_open_writer` or `mem_ipc_open_reader`, and
+/// not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_queue_free(queue: *mut MemIpcQueue) {
+    if !queue.is_null() {
+        drop(Box::from_raw(queue));
+    }
+}
+
+/// Releases a buffer returned by this library. Null is ignored.
+///
+/// # Safety
+/// `data` and `len` must be exactly what the library returned.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_bytes_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+/// Builds a `CItem` for `action`, one of `MEM_IPC_ACTION_*`, on a ternary table. `key`
+/// and `mask` both hold `key_len` bytes.
+///
+/// # Safety
+/// `table_id` must be a NUL-terminated string, the buffers hold the given
+/// number of bytes (or be null if it is 0) and `out` be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_new(table_id: *const c_char, action: u8, priority: u16,
+                                           key: *const u8, mask: *const u8, key_len: usize,
+                                           result: *const u8, result_len: usize,
+                                           out: *mut *mut MemIpcCItem) -> MemIpcStatus {
+    let action = try_status!(action.try_into().map_err(|e: &str| fail(MemIpcStatus::InvalidArgument, e)));
+    let item = CItem::builder(try_status!(str_arg(table_id)))
+        .action(action)
+        .priority(priority)
+        .key(try_status!(bytes_arg(key, key_len)))
+        .mask(try_status!(bytes_arg(mask, key_len)))
+        .result(try_status!(bytes_arg(result, result_len)))
+        .build();
+    match item {
+        Ok(item) => give(citem(item), out),
+        Err(e) => fail(MemIpcStatus::InvalidArgument, e),
+    }
+}
+
+fn citem(item: CItem) -> MemIpcCItem {
+    // Table ids come from Rust strings, which may hold NULs.
+    let table_id = CString::new(item.table_id()).unwrap_or_default();
+    MemIpcCItem { item, table_id }
+}
+
+/// Decodes a `CItem` written by `mem_ipc_citem_encode`.
+///
+/// # Safety
+/// `data` must hold `len` bytes and `out` be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_decode(data: *const u8, len: usize, out: *mut *mut MemIpcCItem) -> MemIpcStatus {
+    match CItem::unpack(try_status!(bytes_arg(data, len))) {
+        Ok(item) => give(citem(item), out),
+        Err(e) => fail(MemIpcStatus::DecodeError, e),
+    }
+}
+
+/// Encodes the item in the `CItem::pack` layout, to be released with
+/// `mem_ipc_bytes_free`.
+///
+/// # Safety
+/// `item` must come from this library, `data` and `len` be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_encode(item: *const MemIpcCItem, data: *mut *mut u8, len: *mut usize) -> MemIpcStatus {
+    match item.as_ref() {
+        Some(item) => give_bytes(item.item.to_bytes(), data, len),
+        None => fail(MemIpcStatus::InvalidArgument, "Null item"),
+    }
+}
+
+/// Encodes a request applying the item, with the reply going to the queue
+/// `reply_to` (none if empty). Released with `mem_ipc_bytes_free`.
+///
+/// # Safety
+/// `item` must come from this library, `reply_to` be a NUL-terminated string,
+/// `data` and `len` be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_request(item: *const MemIpcCItem, reply_to: *const c_char,
+                                               data: *mut *mut u8, len: *mut usize) -> MemIpcStatus {
+    let Some(item) = item.as_ref() else {
+        return fail(MemIpcStatus::InvalidArgument, "Null item");
+    };
+    let body = RequestBody::Ternary(item.item.clone(), Default::default());
+    give_bytes(request(try_status!(str_arg(reply_to)), body), data, len)
+}
+
+fn request(reply_to: &str, body: RequestBody) -> Vec<u8> {
+    Request { reply_to: reply_to.to_string(), sequence: 0, token: None, body }.pack()
+}
+
+/// The item's table id, valid as long as the item.
+///
+/// # Safety
+/// `item` must come from this library.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_table_id(item: *const MemIpcCItem) -> *const c_char {
+    (*item).table_id.as_ptr()
+}
+
+/// # Safety
+/// `item` must come from this library.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_action(item: *const MemIpcCItem) -> u8 {
+    (*item).item.action() as u8
+}
+
+/// # Safety
+/// `item` must come from this library.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_priority(item: *const MemIpcCItem) -> u16 {
+    (*item).item.priority()
+}
+
+/// The item's key, `*len` bytes valid as long as the item.
+///
+/// # Safety
+/// `item` must come from this library and `len` be writable or null.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_key(item: *const MemIpcCItem, len: *mut usize) -> *const u8 {
+    lend_bytes((*item).item.key(), len)
+}
+
+/// Like `mem_ipc_citem_key`, for the mask.
+///
+/// # Safety
+/// `item` must come from this library and `len` be writable or null.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_mask(item: *const MemIpcCItem, len: *mut usize) -> *const u8 {
+    lend_bytes((*item).item.mask(), len)
+}
+
+/// Like `mem_ipc_citem_key`, for the result.
+///
+/// # Safety
+/// `item` must come from this library and `len` be writable or null.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_result(item: *const MemIpcCItem, len: *mut usize) -> *const u8 {
+    lend_bytes((*item).item.result(), len)
+}
+
+/// Releases an item. Null is ignored.
+///
+/// # Safety
+/// `item` must come from this library and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_citem_free(item: *mut MemIpcCItem) {
+    if !item.is_null() {
+        drop(Box::from_raw(item));
+    }
+}
+
+/// Builds an `SItem` for `action`, one of `MEM_IPC_ACTION_*`, on a direct table.
+///
+/// # Safety
+/// `table_id` must be a NUL-terminated string, `value` hold `value_len` bytes
+/// (or be null if it is 0) and `out` be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_sitem_new(table_id: *const c_char, action: u8, index: u16,
+                                           value: *const u8, value_len: usize,
+                                           out: *mut *mut MemIpcSItem) -> MemIpcStatus {
+    let action = try_status!(action.try_into().map_err(|e: &str| fail(MemIpcStatus::InvalidArgument, e)));
+    let item = SItem::builder(try_status!(str_arg(table_id)))
+        .action(action)
+        .index(index)
+        .value(try_status!(bytes_arg(value, value_len)))
+        .build();
+    match item {
+        Ok(item) => give(sitem(item), out),
+        Err(e) => fail(MemIpcStatus::InvalidArgument, e),
+    }
+}
+
+fn sitem(item: SItem) -> MemIpcSItem {
+    let table_id = CString::new(item.table_id()).unwrap_or_default();
+    MemIpcSItem { item, table_id }
+}
+
+/// Decodes an `SItem` written by `mem_ipc_sitem_encode`.
+///
+/// # Safety
+/// `data` must hold `len` bytes and `out` be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_sitem_decode(data: *const u8, len: usize, out: *mut *mut MemIpcSItem) -> MemIpcStatus {
+    match SItem::unpack(try_status!(bytes_arg(data, len))) {
+        Ok(item) => give(sitem(item), out),
+        Err(e) => fail(MemIpcStatus::DecodeError, e),
+    }
+}
+
+/// Encodes the item in the `SItem::pack` layout, to be released with
+/// `mem_ipc_bytes_free`.
+///
+/// # Safety
+/// `item` must come from this library, `data` and `len` be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_sitem_encode(item: *const MemIpcSItem, data: *mut *mut u8, len: *mut usize) -> MemIpcStatus {
+    match item.as_ref() {
+        Some(item) => give_bytes(item.item.to_bytes(), data, len),
+        None => fail(MemIpcStatus::InvalidArgument, "Null item"),
+    }
+}
+
+/// Like `mem_ipc_citem_request`, for an `SItem`.
+///
+/// # Safety
+/// `item` must come from this library, `reply_to` be a NUL-terminated string,
+/// `data` and `len` be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_sitem_request(item: *const MemIpcSItem, reply_to: *const c_char,
+                                               data: *mut *mut u8, len: *mut usize) -> MemIpcStatus {
+    let Some(item) = item.as_ref() else {
+        return fail(MemIpcStatus::InvalidArgument, "Null item");
+    };
+    let body = RequestBody::Direct(item.item.clone(), Default::default());
+    give_bytes(request(try_status!(str_arg(reply_to)), body), data, len)
+}
+
+/// The item's table id, valid as long as the item.
+///
+/// # Safety
+/// `item` must come from this library.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_sitem_table_id(item: *const MemIpcSItem) -> *const c_char {
+    (*item).table_id.as_ptr()
+}
+
+/// # Safety
+/// `item` must come from this library.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_sitem_action(item: *const MemIpcSItem) -> u8 {
+    (*item).item.action() as u8
+}
+
+/// # Safety
+/// `item` must come from this library.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_sitem_index(item: *const MemIpcSItem) -> u16 {
+    (*item).item.index()
+}
+
+/// The item's value, `*len` bytes valid as long as the item.
+///
+/// # Safety
+/// `item` must come from this library and `len` be writable or null.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_sitem_value(item: *const MemIpcSItem, len: *mut usize) -> *const u8 {
+    lend_bytes((*item).item.value(), len)
+}
+
+/// Releases an item. Null is ignored.
+///
+/// # Safety
+/// `item` must come from this library and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_sitem_free(item: *mut MemIpcSItem) {
+    if !item.is_null() {
+        drop(Box::from_raw(item));
+    }
+}
+
+/// Checks the server's reply to a request: `ServerError` if it is an error
+/// or a conflict, with the server's message in `mem_ipc_last_error`.
+///
+/// # Safety
+/// `data` must hold `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mem_ipc_reply_check(data: *const u8, len: usize) -> MemIpcStatus {
+    let reply = match Reply::decode(try_status!(bytes_arg(data, len))) {
+        Ok(Reply::Acked { reply, .. }) => *reply,
+        Ok(reply) => reply,
+        Err(e) => return fail(MemIpcStatus::DecodeError, e),
+    };
+    match reply {
+        Reply::Error(e) => fail(MemIpcStatus::ServerError, e),
+        Reply::Conflict { current } => fail(MemIpcStatus::ServerError, format!("Conflict, the entry is at version {}", current)),
+        _ => MemIpcStatus::Ok,
+    }
+}
+
+#[cfg(test)]
+mod ffi_tests {
+    use super::*;
+    use crate::Actions;
+    use std::ptr::{null, null_mut};
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(mem_ipc_last_error()) }.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_actions() {
+        assert_eq!(MEM_IPC_ACTION_NOOP, Actions::Noop as u8);
+        assert_eq!(MEM_IPC_ACTION_ADD, Actions::Add as u8);
+        assert_eq!(MEM_IPC_ACTION_DELETE, Actions::Delete as u8);
+        assert_eq!(MEM_IPC_ACTION_QUERY, Actions::Query as u8);
+        assert_eq!(MEM_IPC_ACTION_READ_COUNTERS, Actions::ReadCounters as u8);
+        assert_eq!(MEM_IPC_ACTION_RESET_COUNTERS, Actions::ResetCounters as u8);
+    }
+
+    #[test]
+    fn test_items() {
+        unsafe {
+            let mut item = null_mut();
+            let (key, mask) = ([10u8, 0], [0xffu8, 0]);
+            let status = mem_ipc_citem_new(c"acl".as_ptr(), MEM_IPC_ACTION_ADD, 7, key.as_ptr(), mask.as_ptr(), 2,
+                                           b"permit".as_ptr(), 6, &mut item);
+            assert_eq!(status, MemIpcStatus::Ok);
+            let (mut data, mut len) = (null_mut(), 0);
+            assert_eq!(mem_ipc_citem_encode(item, &mut data, &mut len), MemIpcStatus::Ok);
+            let mut decoded = null_mut();
+            assert_eq!(mem_ipc_citem_decode(data, len, &mut decoded), MemIpcStatus::Ok);
+            mem_ipc_bytes_free(data, len);
+            assert_eq!((*decoded).item, (*item).item);
+            assert_eq!(CStr::from_ptr(mem_ipc_citem_table_id(decoded)), c"acl");
+            assert_eq!(mem_ipc_citem_priority(decoded), 7);
+            let result = mem_ipc_citem_result(decoded, &mut len);
+            assert_eq!(std::slice::from_raw_parts(result, len), b"permit");
+            mem_ipc_citem_free(decoded);
+            mem_ipc_citem_free(item);
+
+            let mut item = null_mut();
+            assert_eq!(mem_ipc_sitem_new(c"nexthop".as_ptr(), MEM_IPC_ACTION_DELETE, 3, null(), 0, &mut item), MemIpcStatus::Ok);
+            assert_eq!(mem_ipc_sitem_request(item, c"".as_ptr(), &mut data, &mut len), MemIpcStatus::Ok);
+            let request = Request::unpack(std::slice::from_raw_parts(data, len)).unwrap();
+            mem_ipc_bytes_free(data, len);
+            assert!(matches!(request.body, RequestBody::Direct(ref direct, _) if *direct == (*item).item));
+            assert_eq!(mem_ipc_sitem_action(item), MEM_IPC_ACTION_DELETE);
+            assert_eq!(mem_ipc_sitem_index(item), 3);
+            mem_ipc_sitem_free(item);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        unsafe {
+            let mut item = null_mut();
+            assert_eq!(mem_ipc_sitem_new(null(), MEM_IPC_ACTION_ADD, 0, null(), 0, &mut item), MemIpcStatus::InvalidArgument);
+            assert_eq!(last_error(), "Null string");
+            assert_eq!(mem_ipc_sitem_new(c"nexthop".as_ptr(), 9, 0, null(), 0, &mut item), MemIpcStatus::InvalidArgument);
+            assert_eq!(mem_ipc_sitem_new(c"nexthop".as_ptr(), MEM_IPC_ACTION_ADD, 0, null(), 4, &mut item),
+                       MemIpcStatus::InvalidArgument);
+            assert_eq!(last_error(), "Null buffer");
+            let mut citem = null_mut();
+            assert_eq!(mem_ipc_citem_new(c"".as_ptr(), MEM_IPC_ACTION_ADD, 0, null(), null(), 0, null(), 0, &mut citem),
+                       MemIpcStatus::InvalidArgument);
+            assert!(item.is_null() && citem.is_null());
+
+            assert_eq!(mem_ipc_citem_decode(b"\x01".as_ptr(), 1, &mut null_mut()), MemIpcStatus::DecodeError);
+            assert_eq!(mem_ipc_send(null(), null(), 0), MemIpcStatus::InvalidArgument);
+            assert_eq!(last_error(), "Null queue");
+
+            let reply = Reply::Acked { sequence: 1, reply: Box::new(Reply::Error("No such table".to_string())) }.pack();
+            assert_eq!(mem_ipc_reply_check(reply.as_ptr(), reply.len()), MemIpcStatus::ServerError);
+            assert_eq!(last_error(), "No such table");
+            let reply = Reply::Ok.pack();
+            assert_eq!(mem_ipc_reply_check(reply.as_ptr(), reply.len()), MemIpcStatus::Ok);
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let header = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/include/mem_ipc.h")).unwrap();
+        assert!(header == include_str!(concat!(env!("OUT_DIR"), "/mem_ipc.h")),
+                "include/mem_ipc.h is stale, copy it from {}", env!("OUT_DIR"));
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Size of the header prepended to every queue message.
+///
+/// Layout (little endian): sender pid (u32), message id (u32),
+/// fragment index (u16), fragment count (u16), total message length (u32).
+pub const FRAGMENT_HEADER_SIZE: usize = 16;
+
+/// Default upper bound on the size of a reassembled message.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Maximum number of partially received messages kept at the same time.
+const MAX_PARTIAL_MESSAGES: usize = 64;
+
+static NEXT_MSG_ID: AtomicU32 = AtomicU32::new(1);
+
+#[derive(Debug, PartialEq)]
+struct FragmentHeader {
+    sender: u32,
+    msg_id: u32,
+    index: u16,
+    count: u16,
+    total_len: u32,
+}
+
+impl FragmentHeader {
+    fn write_to(&self, buffer: &mut Vec<u8>) {
+        buffer.extend(self.sender.to_le_bytes());
+        buffer.extend(self.msg_id.to_le_bytes());
+        buffer.extend(self.index.to_le_bytes());
+        buffer.extend(self.count.to_le_bytes());
+        buffer.extend(self.total_len.to_le_bytes());
+    }
+
+    fn read_from(buffer: &[u8]) -> Result<FragmentHeader, &'static str> {
+        if buffer.len() < FRAGMENT_HEADER_SIZE {
+            return Err("Fragment too short to contain header");
+        }
+        Ok(FragmentHeader {
+            sender: u32::from_le_bytes(buffer[0..4].try_into().unwrap()),
+            msg_id: u32::from_le_bytes(buffer[4..8].try_into().unwrap()),
+            index: u16::from_le_bytes(buffer[8..10].try_into().unwrap()),
+            count: u16::from_le_bytes(buffer[10..12].try_into().unwrap()),
+            total_len: u32::from_le_bytes(buffer[12..16].try_into().unwrap()),
+        })
+    }
+}
+
+/// Splits `message` into queue items of at most `max_item_size` bytes and hands
+/// each one to `send`, in order.
+///
+/// Every item carries a fragment header, so even messages that fit into a
+/// single item are framed the same way.
+pub fn split<F>(message: &[u8],
+                max_item_size: usize,
+                max_message_size: usize,
+                mut send: F) -> Result<(), &'static str>
+where
+    F: FnMut(&[u8]) -> Result<(), &'static str>,
+{
+    if max_item_size <= FRAGMENT_HEADER_SIZE {
+        return Err("Queue item size too small to hold a fragment");
+    }
+    if message.len() > max_message_size || message.len() > u32::MAX as usize {
+        return Err("Message exceeds maximum message size");
+    }
+
+    let chunk_size = max_item_size - FRAGMENT_HEADER_SIZE;
+    let count = message.len().div_ceil(chunk_size).max(1);
+    if count > u16::MAX as usize {
+        return Err("Message needs too many fragments");
+    }
+
+    let mut header = FragmentHeader {
+        sender: std::process::id(),
+        msg_id: NEXT_MSG_ID.fetch_add(1, Ordering::Relaxed),
+        index: 0,
+        count: count as u16,
+        total_len: message.len() as u32,
+    };
+    let mut item = Vec::with_capacity(max_item_size.min(FRAGMENT_HEADER_SIZE + message.len()));
+    for index in 0..count {
+        let start = index * chunk_size;
+        let end = (start + chunk_size).min(message.len());
+        header.index = index as u16;
+        item.clear();
+        header.write_to(&mut item);
+        item.extend_from_slice(&message[start..end]);
+        send(&item)?;
+    }
+    Ok(())
+}
+
+struct Partial {
+    started: u64,
+    next: u16,
+    count: u16,
+    total_len: usize,
+    data: Vec<u8>,
+}
+
+/// Rebuilds messages from the fragments produced by [`split`].
+///
+/// Fragments of one message must arrive in order. Fragments of different
+/// messages (from other senders, or other threads of the same sender) may be
+/// interleaved.
+pub struct Reassembler {
+    max_message_size: usize,
+    started: u64,
+    partial: HashMap<(u32, u32), Partial>,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(MAX_MESSAGE_SIZE)
+    }
+}
+
+impl Reassembler {
+    pub fn new(max_message_size: usize) -> Self {
+        Reassembler {
+            max_message_size,
+            started: 0,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Number of messages that have been started but not completed.
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Adds one received queue item.
+    ///
+    /// Returns the full message once its last fragment has arrived, `None`
+    /// while more fragments are expected, and an error when a fragment is
+    /// missing or the message is malformed. The incomplete message is dropped
+    /// on error.
+    pub fn push(&mut self, item: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
+        let header = FragmentHeader::read_from(item)?;
+        let chunk = &item[FRAGMENT_HEADER_SIZE..];
+        let total_len = header.total_len as usize;
+        let key = (header.sender, header.msg_id);
+
+        if header.count == 0 || header.index >= header.count {
+            self.partial.remove(&key);
+            return Err("Invalid fragment index");
+        }
+        if total_len > self.max_message_size {
+            self.partial.remove(&key);
+            return Err("Message exceeds maximum message size");
+        }
+
+        if header.count == 1 {
+            if chunk.len() != total_len {
+                return Err("Fragment length does not match message length");
+            }
+            return Ok(Some(chunk.to_vec()));
+        }
+
+        if header.index == 0 {
+            if self.partial.remove(&key).is_some() {
+                return Err("Message restarted before completion");
+            }
+            if self.partial.len() >= MAX_PARTIAL_MESSAGES {
+                self.evict_oldest();
+            }
+            self.started += 1;
+            // The buffer grows as fragments arrive rather than trusting the
+            // length in the header, which any sender can inflate.
+            self.partial.insert(key, Partial {
+                started: self.started,
+                next: 1,
+                count: header.count,
+                total_len,
+                data: chunk.to_vec(),
+            });
+            return Ok(None);
+        }
+
+        let partial = match self.partial.get_mut(&key) {
+            Some(partial) => partial,
+            None => return Err("Missing fragment: no start of message"),
+        };
+        if header.index != partial.next || header.count != partial.count || total_len != partial.total_len {
+            self.partial.remove(&key);
+            return Err("Missing fragment");
+        }
+        if partial.data.len() + chunk.len() > partial.total_len {
+            self.partial.remove(&key);
+            return Err("Fragment length does not match message length");
+        }
+        partial.data.extend_from_slice(chunk);
+        partial.next += 1;
+
+        if partial.next < partial.count {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&key).unwrap();
+        if partial.data.len() != partial.total_len {
+            return Err("Fragment length does not match message length");
+        }
+        Ok(Some(partial.data))
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(key) = self.partial.iter().min_by_key(|(_, p)| p.started).map(|(k, _)| *k) {
+            self.partial.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod fragment_tests {
+    use super::*;
+
+    fn fragments(message: &[u8], max_item_size: usize) -> Vec<Vec<u8>> {
+        let mut items = Vec::new();
+        split(message, max_item_size, MAX_MESSAGE_SIZE, |item| {
+            items.push(item.to_vec());
+            Ok(())
+        }).expect("Failed to split message");
+        items
+    }
+
+    #[test]
+    fn test_single_fragment() {
+        let items = fragments(b"small", 1024);
+        assert_eq!(items.len(), 1);
+
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&items[0]), Ok(Some(b"small".to_vec())));
+    }
+
+    #[test]
+    fn test_empty_message() {
+        let items = fragments(b"", 1024);
+        assert_eq!(items.len(), 1);
+
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&items[0]), Ok(Some(Vec::new())));
+    }
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let message: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let items = fragments(&message, 1024);
+        assert_eq!(items.len(), message.len().div_ceil(1024 - FRAGMENT_HEADER_SIZE));
+        assert!(items.iter().all(|item| item.len() <= 1024));
+
+        let mut reassembler = Reassembler::default();
+        let (last, rest) = items.split_last().unwrap();
+        for item in rest {
+            assert_eq!(reassembler.push(item), Ok(None));
+        }
+        assert_eq!(reassembler.push(last), Ok(Some(message)));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_interleaved_messages() {
+        let first = vec![1u8; 3000];
+        let second = vec![2u8; 3000];
+        let a = fragments(&first, 1024);
+        let b = fragments(&second, 1024);
+
+        let mut reassembler = Reassembler::default();
+        let mut done = Vec::new();
+        for (x, y) in a.iter().zip(b.iter()) {
+            done.extend(reassembler.push(x).unwrap());
+            done.extend(reassembler.push(y).unwrap());
+        }
+        assert_eq!(done, vec![first, second]);
+    }
+
+    #[test]
+    fn test_missing_fragment() {
+        let items = fragments(&[7u8; 5000], 1024);
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&items[0]), Ok(None));
+        assert_eq!(reassembler.push(&items[2]), Err("Missing fragment"));
+        assert_eq!(reassembler.pending(), 0);
+        assert!(reassembler.push(&items[3]).is_err());
+    }
+
+    #[test]
+    fn test_message_size_limit() {
+        let result = split(&[0u8; 4096], 1024, 2048, |_| Ok(()));
+        assert_eq!(result, Err("Message exceeds maximum message size"));
+
+        let items = fragments(&[0u8; 4096], 1024);
+        let mut reassembler = Reassembler::new(2048);
+        assert_eq!(reassembler.push(&items[0]), Err("Message exceeds maximum message size"));
+    }
+
+    #[test]
+    fn test_inflated_length() {
+        // A start fragment claiming the largest message reserves only what it carries.
+        let mut item = Vec::new();
+        FragmentHeader { sender: 1, msg_id: 1, index: 0, count: 2, total_len: MAX_MESSAGE_SIZE as u32 }.write_to(&mut item);
+        item.extend_from_slice(b"start");
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&item), Ok(None));
+        assert!(reassembler.partial[&(1, 1)].data.capacity() < 1024);
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.push(&[0u8; 4]).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Looking at the system's POSIX message queues from the outside.
+//!
+//! The kernel does not record which process created a queue. A queue counts
+//! as held by every process with a descriptor open on it, found by scanning
+//! `/proc/<pid>/fd`; queues following the `<name>.<pid>` convention (e.g. the
+//! reply queues of `print_tables`) also name their owner.
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::path::Path;
+
+use crate::{mq_attributes, QueueAttributes};
+
+pub const MQUEUE_DIR: &str = "/dev/mqueue";
+pub const LIMITS_DIR: &str = "/proc/sys/fs/mqueue";
+
+/// `f_type` of the mqueue file system.
+const MQUEUE_MAGIC: i64 = 0x19800202;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueInfo {
+    /// Queue name, with the leading `/`.
+    pub name: String,
+    pub attributes: Option<QueueAttributes>,
+    /// Processes with the queue open.
+    pub holders: Vec<u32>,
+}
+
+impl QueueInfo {
+    /// Pid in a `<name>.<pid>` queue name.
+    pub fn named_owner(&self) -> Option<u32> {
+        pid_from_name(&self.name)
+    }
+
+    /// Why the queue looks abandoned, if it does: its named owner exited, or
+    /// no process has it open.
+    pub fn stale_reason(&self) -> Option<String> {
+        match self.named_owner() {
+            Some(pid) if !process_alive(pid) => Some(format!("owner {} is gone", pid)),
+            _ if self.holders.is_empty() => Some("not open in any process".to_string()),
+            _ => None,
+        }
+    }
+}
+
+pub fn pid_from_name(name: &str) -> Option<u32> {
+    let (_, pid) = name.rsplit_once('.')?;
+    if pid.is_empty() || !pid.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    pid.parse().ok()
+}
+
+pub fn process_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+/// Names of the queues in `dir`, where the mqueue file system is mounted.
+///
+/// Queues are opened and unlinked by name, which always refers to the mount
+/// of the process's IPC namespace, so `dir` has to be that mount.
+pub fn queue_names(dir: &Path) -> Result<Vec<String>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        format!("Failed to list {}: {} (mount it with 'mount -t mqueue none {}')", dir.display(), e, dir.display())
+    })?;
+    if !is_mqueue(dir) {
+        return Err(format!("{} is not an mqueue file system", dir.display()));
+    }
+    let mut names: Vec<String> = entries.filter_map(|e| e.ok())
+        .map(|e| format!("/{}", e.file_name().to_string_lossy()))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+fn is_mqueue(path: &Path) -> bool {
+    let Ok(c_path) = CString::new(path.as_os_str().as_encoded_bytes()) else {
+        return false;
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    unsafe { libc::statfs(c_path.as_ptr(), &mut stat) == 0 && stat.f_type as i64 == MQUEUE_MAGIC }
+}
+
+/// Pids of the processes holding each queue open, by queue name. Processes
+/// this user cannot inspect are skipped.
+pub fn queue_holders() -> BTreeMap<String, Vec<u32>> {
+    let mut holders: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return holders;
+    };
+    for process in processes.filter_map(|p| p.ok()) {
+        let Some(pid) = process.file_name().to_str().and_then(|p| p.parse::<u32>().ok()) else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.filter_map(|fd| fd.ok()) {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            // Queue descriptors link to "/<name>" within the mqueue file system.
+            let target = target.to_string_lossy().to_string();
+            if target.starts_with('/') && !target[1..].contains('/') && is_mqueue(&fd.path()) {
+                let pids = holders.entry(target).or_default();
+                if !pids.contains(&pid) {
+                    pids.push(pid);
+                }
+            }
+        }
+    }
+    holders
+}
+
+pub fn list_queues(dir: &Path) -> Result<Vec<QueueInfo>, String> {
+    let holders = queue_holders();
+    Ok(queue_names(dir)?.into_iter().map(|name| QueueInfo {
+        attributes: mq_attributes(&name).ok(),
+        holders: holders.get(&name).cloned().unwrap_or_default(),
+        name,
+    }).collect())
+}
+
+/// System limits on message queues.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// Largest depth an unprivileged process may ask for.
+    pub msg_max: usize,
+    /// Largest item size an unprivileged process may ask for.
+    pub msgsize_max: usize,
+    /// Number of queues allowed in the whole system.
+    pub queues_max: usize,
+    /// `RLIMIT_MSGQUEUE`: bytes all queues of this user may take.
+    pub rlimit_bytes: Option<u64>,
+}
+
+impl Limits {
+    /// Reads the limits from `dir`, normally `/proc/sys/fs/mqueue`.
+    pub fn read(dir: &Path) -> Result<Limits, String> {
+        let value = |file: &str| {
+            let path = dir.join(file);
+            fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+                .trim().parse::<usize>().map_err(|_| format!("Invalid number in {}", path.display()))
+        };
+        let mut rlimit: libc::rlimit = unsafe { std::mem::zeroed() };
+        let rlimit_bytes = match unsafe { libc::getrlimit(libc::RLIMIT_MSGQUEUE, &mut rlimit) } {
+            0 if rlimit.rlim_cur != libc::RLIM_INFINITY => Some(rlimit.rlim_cur),
+            _ => None,
+        };
+        Ok(Limits { msg_max: value("msg_max")?, msgsize_max: value("msgsize_max")?, queues_max: value("queues_max")?, rlimit_bytes })
+    }
+
+    /// Problems creating a queue of `depth` items of `item_size` bytes would
+    /// run into.
+    pub fn check(&self, depth: usize, item_size: usize) -> Vec<String> {
+        let mut problems = Vec::new();
+        if depth > self.msg_max {
+            problems.push(format!("depth {} exceeds msg_max {}", depth, self.msg_max));
+        }
+        if item_size > self.msgsize_max {
+            problems.push(format!("message size {} exceeds msgsize_max {}", item_size, self.msgsize_max));
+        }
+        // The kernel charges every item with its size plus a pointer.
+        let bytes = (depth * (item_size + std::mem::size_of::<usize>())) as u64;
+        if let Some(limit) = self.rlimit_bytes.filter(|limit| bytes > *limit) {
+            problems.push(format!("queue needs {} bytes, more than RLIMIT_MSGQUEUE {}", bytes, limit));
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod inspect_tests {
+    use super::*;
+    use crate::{unlink_mq, TableInterface};
+
+    #[test]
+    fn test_pid_from_name() {
+        assert_eq!(pid_from_name("/tables.print_tables.1234"), Some(1234));
+        assert_eq!(pid_from_name("/tables"), None);
+        assert_eq!(pid_from_name("/tables.v2x"), None);
+        assert_eq!(pid_from_name("/tables."), None);
+    }
+
+    #[test]
+    fn test_stale_reason() {
+        let mut info = QueueInfo { name: "/q".to_string(), attributes: None, holders: vec![] };
+        assert_eq!(info.stale_reason().as_deref(), Some("not open in any process"));
+        info.holders.push(std::process::id());
+        assert_eq!(info.stale_reason(), None);
+        info.name = format!("/q.{}", u32::MAX);
+        assert_eq!(info.stale_reason(), Some(format!("owner {} is gone", u32::MAX)));
+    }
+
+    #[test]
+    fn test_queue_holders() {
+        let name = "/holders_test_queue";
+        let reader = TableInterface::get_table_reader_sized(name, 1024, 10).expect("Failed to get reader");
+        assert!(queue_holders().get(name).is_some_and(|pids| pids.contains(&std::process::id())));
+        drop(reader);
+        unlink_mq(name).expect("Failed to unlink message queue");
+    }
+
+    #[test]
+    fn test_queue_names() {
+        let dir = std::env::temp_dir();
+        assert_eq!(queue_names(&dir), Err(format!("{} is not an mqueue file system", dir.display())));
+    }
+
+    #[test]
+    fn test_limits() {
+        let dir = std::env::temp_dir().join(format!("mem_ipc_limits_test.{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("msg_max"), "10\n").unwrap();
+        fs::write(dir.join("msgsize_max"), "8192\n").unwrap();
+        assert!(Limits::read(&dir).is_err());
+        fs::write(dir.join("queues_max"), "256\n").unwrap();
+
+        let limits = Limits { rlimit_bytes: Some(819200), ..Limits::read(&dir).unwrap() };
+        assert_eq!((limits.msg_max, limits.msgsize_max, limits.queues_max), (10, 8192, 256));
+        assert!(limits.check(10, 8192).is_empty());
+        let problems = limits.check(crate::MAX_QITEMS, crate::MAX_QITEM_SIZE);
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0], "depth 1024 exceeds msg_max 10");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
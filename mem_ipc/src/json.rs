@@ -0,0 +1,202 @@
+//! JSON mapping of the wire items, and a JSON-lines bulk format.
+//!
+//! Keys, masks, results and values are hex strings, so the mapping is
+//! lossless. A bulk file holds one [`Item`] per line, tagged by `kind`:
+//!
+//! ```text
+//! {"kind":"ternary","table":"acl","action":"add","priority":1,"key":"0a00","mask":"ff00","result":"7065726d6974"}
+//! {"kind":"direct","table":"nexthop","action":"add","index":3,"value":"65746830"}
+//! ```
+//!
+//! Blank lines are skipped. Only built with the `json` feature.
+
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{RequestBody, WriteOptions};
+use crate::tables::{from_hex, to_hex, Entry};
+use crate::{Actions, CItem, SItem};
+
+/// The JSON form of a [`CItem`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TernaryItem {
+    table: String,
+    action: Actions,
+    priority: u16,
+    key: String,
+    mask: String,
+    #[serde(default)]
+    result: String,
+}
+
+impl From<CItem> for TernaryItem {
+    fn from(item: CItem) -> Self {
+        TernaryItem {
+            table: item.table_id,
+            action: item.action,
+            priority: item.p,
+            key: to_hex(&item.k),
+            mask: to_hex(&item.m),
+            result: to_hex(&item.r),
+        }
+    }
+}
+
+impl TryFrom<TernaryItem> for CItem {
+    type Error = String;
+
+    fn try_from(item: TernaryItem) -> Result<Self, Self::Error> {
+        Ok(CItem::builder(&item.table)
+            .action(item.action)
+            .priority(item.priority)
+            .key(&from_hex(&item.key)?)
+            .mask(&from_hex(&item.mask)?)
+            .result(&from_hex(&item.result)?)
+            .build()?)
+    }
+}
+
+/// The JSON form of an [`SItem`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DirectItem {
+    table: String,
+    action: Actions,
+    index: u16,
+    #[serde(default)]
+    value: String,
+}
+
+impl From<SItem> for DirectItem {
+    fn from(item: SItem) -> Self {
+        DirectItem { table: item.table_id, action: item.action, index: item.index, value: to_hex(&item.value) }
+    }
+}
+
+impl TryFrom<DirectItem> for SItem {
+    type Error = String;
+
+    fn try_from(item: DirectItem) -> Result<Self, Self::Error> {
+        Ok(SItem::builder(&item.table).action(item.action).index(item.index).value(&from_hex(&item.value)?).build()?)
+    }
+}
+
+/// One line of a bulk file.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Item {
+    Ternary(CItem),
+    Direct(SItem),
+}
+
+impl Item {
+    /// The item adding `entry` to `table_id`, which is how tables are exported.
+    pub fn from_entry(table_id: &str, entry: &Entry) -> Item {
+        match entry {
+            Entry::Ternary(e) => Item::Ternary(CItem {
+                table_id: table_id.to_string(),
+                action: Actions::Add,
+                p: e.priority,
+                k: e.key.clone(),
+                m: e.mask.clone(),
+                r: e.result.clone(),
+            }),
+            Entry::Direct(e) => Item::Direct(SItem {
+                table_id: table_id.to_string(),
+                action: Actions::Add,
+                index: e.index,
+                value: e.value.clone(),
+            }),
+        }
+    }
+
+    pub fn parse_line(line: &str) -> Result<Item, String> {
+        serde_json::from_str(line).map_err(|e| e.to_string())
+    }
+
+    /// The item as a single line of JSON, without the newline.
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).expect("Items always serialize")
+    }
+
+    /// The request applying this item, with default write options.
+    pub fn into_request(self) -> RequestBody {
+        match self {
+            Item::Ternary(item) => RequestBody::Ternary(item, WriteOptions::default()),
+            Item::Direct(item) => RequestBody::Direct(item, WriteOptions::default()),
+        }
+    }
+}
+
+/// Reads a bulk file, reporting the first bad line by number.
+pub fn read_items(reader: impl BufRead) -> Result<Vec<Item>, String> {
+    let mut items = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("Failed to read line {}: {}", number + 1, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        items.push(Item::parse_line(&line).map_err(|e| format!("Line {}: {}", number + 1, e))?);
+    }
+    Ok(items)
+}
+
+/// Writes `items` as a bulk file.
+pub fn write_items<'a>(mut writer: impl Write, items: impl IntoIterator<Item = &'a Item>) -> Result<(), String> {
+    for item in items {
+        writeln!(writer, "{}", item.to_line()).map_err(|e| format!("Failed to write item: {}", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod json_tests {
+    use super::*;
+    use crate::tables::{Tables, Timeouts};
+
+    #[test]
+    fn test_items() {
+        let ternary = CItem::builder("acl").action(Actions::Add).priority(1).key(&[10, 0]).mask(&[0xff, 0]).result(b"permit").build().unwrap();
+        let json = serde_json::to_string(&ternary).unwrap();
+        assert_eq!(json, r#"{"table":"acl","action":"add","priority":1,"key":"0a00","mask":"ff00","result":"7065726d6974"}"#);
+        assert_eq!(serde_json::from_str::<CItem>(&json).unwrap(), ternary);
+
+        let direct = SItem::builder("nexthop").action(Actions::ResetCounters).index(3).build().unwrap();
+        let json = serde_json::to_string(&direct).unwrap();
+        assert_eq!(json, r#"{"table":"nexthop","action":"reset-counters","index":3,"value":""}"#);
+        assert_eq!(serde_json::from_str::<SItem>(&json).unwrap(), direct);
+
+        for bad in [r#"{"table":"acl","action":"add","priority":1,"key":"0a00","mask":"ff"}"#,
+                    r#"{"table":"acl","action":"add","priority":1,"key":"0a0","mask":"ff0"}"#,
+                    r#"{"table":"","action":"add","priority":1,"key":"","mask":""}"#,
+                    r#"{"table":"acl","action":"insert","priority":1,"key":"","mask":""}"#,
+                    r#"{"table":"acl","action":"add","priority":1,"key":"","mask":"","extra":1}"#] {
+            assert!(serde_json::from_str::<CItem>(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_bulk() {
+        let mut tables = Tables::default();
+        tables.declare("acl:ternary:2:16".parse().unwrap()).unwrap();
+        tables.declare("nexthop:direct:16".parse().unwrap()).unwrap();
+        let acl = tables.get_mut("acl").unwrap().as_ternary_mut().unwrap();
+        acl.insert(2, &[10, 0], &[0xff, 0], b"deny", Timeouts::default(), 0).unwrap();
+        let ternary = Entry::Ternary(acl.entries()[0].clone());
+        let nexthop = tables.get_mut("nexthop").unwrap().as_direct_mut().unwrap();
+        nexthop.insert(3, b"eth0", Timeouts::default(), 0).unwrap();
+        let direct = Entry::Direct(nexthop.entries().next().unwrap().clone());
+        let items = vec![Item::from_entry("acl", &ternary), Item::from_entry("nexthop", &direct)];
+
+        let mut buffer = Vec::new();
+        write_items(&mut buffer, &items).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert_eq!(text.lines().nth(1), Some(r#"{"kind":"direct","table":"nexthop","action":"add","index":3,"value":"65746830"}"#));
+        assert_eq!(read_items(format!("\n{}\n", text).as_bytes()), Ok(items));
+
+        let bad = r#"{"kind":"direct","table":"nexthop","action":"add","index":3,"value":"zz"}"#;
+        assert_eq!(read_items(format!("{}{}", text, bad).as_bytes()), Err("Line 3: Invalid hex string 'zz'".to_string()));
+    }
+}
@@ -1,20 +1,73 @@
-use libc::{mqd_t, mq_attr, O_CREAT, O_RDONLY, O_WRONLY};
+use libc::{mqd_t, mq_attr, O_CREAT, O_NONBLOCK, O_RDONLY, O_WRONLY};
 use std::ffi::CString;
 use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub mod access;
+pub mod backend;
+pub mod client;
+pub mod config;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fragment;
+pub mod inspect;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+pub mod protocol;
+pub mod reconcile;
+pub mod server;
+pub mod signals;
+pub mod snapshot;
+pub mod socket;
+pub mod tables;
+pub mod testing;
+pub mod transport;
+
+use fragment::Reassembler;
+use protocol::{Cursor, Encoding, Reply, Request};
+use socket::SocketTransport;
+use transport::{MemoryTransport, Transport};
+
+/// Items prefix every variable length field with its length as a `usize`.
+const LENGTH_SIZE: usize = std::mem::size_of::<usize>();
+
+fn put_field(buffer: &mut Vec<u8>, field: &[u8]) {
+    buffer.extend_from_slice(&field.len().to_le_bytes());
+    buffer.extend_from_slice(field);
+}
 
-
+/// What a `CItem` or `SItem` asks the server to do with its entry.
 #[repr(u8)]
-enum Actions {
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "kebab-case"))]
+pub enum Actions {
+    #[default]
     Noop=0,
     Add=1,
     Delete=2,
     Query=3,
+    ReadCounters=4,
+    ResetCounters=5,
 }
 
-impl Default for Actions {
-    fn default() -> Self {
-        Actions::Noop
+impl TryFrom<u8> for Actions {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Actions::Noop),
+            1 => Ok(Actions::Add),
+            2 => Ok(Actions::Delete),
+            3 => Ok(Actions::Query),
+            4 => Ok(Actions::ReadCounters),
+            5 => Ok(Actions::ResetCounters),
+            _ => Err("Invalid action value"),
+        }
     }
 }
 
@@ -25,7 +78,11 @@ impl Actions {
     }
 }
 
-#[derive(Default)]
+/// A ternary table entry, or the key of one, as sent to the server. Build
+/// one with [`CItem::builder`].
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize),
+           serde(into = "json::TernaryItem", try_from = "json::TernaryItem"))]
 pub struct CItem {
     table_id: String,
     action: Actions,
@@ -40,6 +97,36 @@ impl CItem {
         CItem::default()
     }
 
+    /// Starts an item for the table `table_id`, with action `Noop` and empty
+    /// key, mask and result.
+    pub fn builder(table_id: &str) -> CItemBuilder {
+        CItemBuilder { item: CItem { table_id: table_id.to_string(), ..CItem::default() } }
+    }
+
+    pub fn table_id(&self) -> &str {
+        &self.table_id
+    }
+
+    pub fn action(&self) -> Actions {
+        self.action
+    }
+
+    pub fn priority(&self) -> u16 {
+        self.p
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.k
+    }
+
+    pub fn mask(&self) -> &[u8] {
+        &self.m
+    }
+
+    pub fn result(&self) -> &[u8] {
+        &self.r
+    }
+
     #[allow(dead_code)]
     fn new_with_defaults() -> Self {
         CItem {
@@ -52,88 +139,41 @@ impl CItem {
         }
     }
 
-    pub fn pack(&self, mut buffer: Vec<u8>) {
-        buffer.extend(self.table_id.len().to_le_bytes());
-        buffer.extend(self.table_id.clone().into_bytes());
-        buffer.push(match self.action {
-            Actions::Noop => 0,
-            Actions::Add => 1,
-            Actions::Delete => 2,
-            Actions::Query => 3,
-        });
-        buffer.extend(self.p.to_le_bytes());
-        buffer.extend(self.k.len().to_le_bytes());
-        buffer.extend(self.k.clone());
-        buffer.extend(self.m.len().to_le_bytes());
-        buffer.extend(self.m.clone());
-        buffer.extend(self.r.len().to_le_bytes());
-        buffer.extend(self.r.clone());
-    }
-
-    pub fn unpack(buffer: &Vec<u8>) -> Result<CItem, &'static str> {
-        const MIN_SIZE: usize = std::mem::size_of::<usize>() * 4 // lengths
-                                + std::mem::size_of::<u16>() // prio
-                                + std::mem::size_of::<u8>(); // action
-
-        if buffer.len() < MIN_SIZE { // Minimum length check for header data
-            return Err("Buffer too short to contain valid data");
-        }
-
-        let mut result: CItem = Default::default();
-        let mut offset = 0;
-
-        // Extract table_id
-        let table_id_length = usize::from_le_bytes(buffer[offset..offset + std::mem::size_of::<usize>()].try_into().expect("Slice with incorrect length"));
-        offset += std::mem::size_of::<usize>();
-        if buffer.len() < offset + table_id_length {
-            return Err("Buffer too short to contain full table_id");
-        }
-        result.table_id = String::from_utf8_lossy(&buffer[offset..offset + table_id_length]).to_string();
-        offset += table_id_length;
-
-        // Extract action
-        match buffer[offset] {
-            0 => { offset += 1; result.action = Actions::Noop; },
-            1 => { offset += 1; result.action = Actions::Add; },
-            2 => { offset += 1; result.action = Actions::Delete; },
-            3 => { offset += 1; result.action = Actions::Query; },
-            _ => return Err("Invalid action value"),
-        }
+    /// Appends the encoded item to `buffer`, so several items can share one
+    /// buffer. `unpack` reads it back.
+    pub fn pack(&self, buffer: &mut Vec<u8>) {
+        buffer.reserve(self.encoded_len());
+        put_field(buffer, self.table_id.as_bytes());
+        buffer.push(self.action as u8);
+        buffer.extend_from_slice(&self.p.to_le_bytes());
+        put_field(buffer, &self.k);
+        put_field(buffer, &self.m);
+        put_field(buffer, &self.r);
+    }
 
-        result.p= u16::from_le_bytes([buffer[offset], buffer[offset + 1]]);
-        offset += std::mem::size_of::<u16>();
+    /// Number of bytes `pack` appends.
+    pub fn encoded_len(&self) -> usize {
+        LENGTH_SIZE * 4 + 1 + 2 + self.table_id.len() + self.k.len() + self.m.len() + self.r.len()
+    }
 
-        // Extract k length and data
-        let k_length = usize::from_le_bytes(buffer[offset..offset + std::mem::size_of::<usize>()].try_into().expect("Slice with incorrect length"));
-        offset += std::mem::size_of::<usize>();
-        if buffer.len() < offset + k_length {
-            return Err("Buffer too short to contain full k");
-        }
-        result.k = Vec::from(&buffer[offset..offset + k_length]);
-        offset += k_length;
-
-        // Extract m length and data
-        let m_length = usize::from_le_bytes(buffer[offset..offset + std::mem::size_of::<usize>()].try_into().expect("Slice with incorrect length"));
-        offset += std::mem::size_of::<usize>();
-        if buffer.len() < offset + m_length {
-            return Err("Buffer too short to contain full m");
-        }
-        result.m = Vec::from(&buffer[offset..offset + m_length]);
-        offset += m_length;
-
-        // Extract r length and data
-        let r_length = usize::from_le_bytes(buffer[offset..offset + std::mem::size_of::<usize>()].try_into().expect("Slice with incorrect length"));
-        offset += std::mem::size_of::<usize>();
-        if buffer.len() < offset + r_length {
-            return Err("Buffer too short to contain full r");
-        }
-        result.r = Vec::from(&buffer[offset..offset + r_length]);
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.encoded_len());
+        self.pack(&mut buffer);
+        buffer
+    }
 
-        Ok(result)
+    /// Decodes an item written by `pack` at the start of `buffer`. Use
+    /// `CItemRef::parse` to look at it without copying.
+    pub fn unpack(buffer: &[u8]) -> Result<CItem, &'static str> {
+        CItemRef::parse(buffer).map(|item| item.to_item())
     }
 }
 
-#[derive(Default)]
+/// A direct table entry, or its index, as sent to the server. Build one with
+/// [`SItem::builder`].
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize),
+           serde(into = "json::DirectItem", try_from = "json::DirectItem"))]
 pub struct SItem {
     table_id: String,
     action: Actions,
@@ -146,6 +186,28 @@ impl SItem {
         SItem::default()
     }
 
+    /// Starts an item for the table `table_id`, with action `Noop`, index 0
+    /// and an empty value.
+    pub fn builder(table_id: &str) -> SItemBuilder {
+        SItemBuilder { item: SItem { table_id: table_id.to_string(), ..SItem::default() } }
+    }
+
+    pub fn table_id(&self) -> &str {
+        &self.table_id
+    }
+
+    pub fn action(&self) -> Actions {
+        self.action
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
     #[allow(dead_code)]
     fn new_with_defaults() -> Self {
         SItem {
@@ -156,116 +218,452 @@ impl SItem {
         }
     }
 
-    pub fn pack(&self, mut buffer: Vec<u8>) {
-        buffer.extend(self.table_id.len().to_le_bytes());
-        buffer.extend(self.table_id.clone().into_bytes());
-        buffer.push(match self.action {
-            Actions::Noop => 0,
-            Actions::Add => 1,
-            Actions::Delete => 2,
-            Actions::Query => 3,
-        });
-        buffer.extend(self.index.to_le_bytes());
-        buffer.extend(self.value.len().to_le_bytes());
-        buffer.extend(self.value.clone());
+    /// Appends the encoded item to `buffer`, see `CItem::pack`.
+    pub fn pack(&self, buffer: &mut Vec<u8>) {
+        buffer.reserve(self.encoded_len());
+        put_field(buffer, self.table_id.as_bytes());
+        buffer.push(self.action as u8);
+        buffer.extend_from_slice(&self.index.to_le_bytes());
+        put_field(buffer, &self.value);
+    }
+
+    /// Number of bytes `pack` appends.
+    pub fn encoded_len(&self) -> usize {
+        LENGTH_SIZE * 2 + 1 + 2 + self.table_id.len() + self.value.len()
     }
 
-    pub fn unpack(buffer: &Vec<u8>) -> Result<Self, &'static str> {
-        const MIN_SIZE: usize = std::mem::size_of::<usize>() * 2 // lengths
-                                + std::mem::size_of::<u16>() // index
-                                + std::mem::size_of::<u8>(); // action
-        if buffer.len() < MIN_SIZE { // Minimum length check for header data
-            return Err("Buffer too short to contain valid data");
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.encoded_len());
+        self.pack(&mut buffer);
+        buffer
+    }
+
+    /// Decodes an item written by `pack`, see `CItem::unpack`.
+    pub fn unpack(buffer: &[u8]) -> Result<Self, &'static str> {
+        SItemRef::parse(buffer).map(|item| item.to_item())
+    }
+}
+
+/// A [`CItem`] decoded in place: table id, key, mask and result borrow from
+/// the buffer it was read from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CItemRef<'a> {
+    table_id: &'a str,
+    action: Actions,
+    p: u16,
+    k: &'a [u8],
+    m: &'a [u8],
+    r: &'a [u8],
+}
+
+impl<'a> CItemRef<'a> {
+    /// Decodes an item written by `CItem::pack` at the start of `buffer`.
+    /// Fails on truncated items and table ids that are not UTF-8.
+    pub fn parse(buffer: &'a [u8]) -> Result<CItemRef<'a>, &'static str> {
+        let mut cursor = Cursor::new(buffer);
+        Ok(CItemRef {
+            table_id: cursor.get_str()?,
+            action: Actions::try_from(cursor.get_u8()?)?,
+            p: cursor.get_u16()?,
+            k: cursor.get_bytes()?,
+            m: cursor.get_bytes()?,
+            r: cursor.get_bytes()?,
+        })
+    }
+
+    pub fn table_id(&self) -> &'a str {
+        self.table_id
+    }
+
+    pub fn action(&self) -> Actions {
+        self.action
+    }
+
+    pub fn priority(&self) -> u16 {
+        self.p
+    }
+
+    pub fn key(&self) -> &'a [u8] {
+        self.k
+    }
+
+    pub fn mask(&self) -> &'a [u8] {
+        self.m
+    }
+
+    pub fn result(&self) -> &'a [u8] {
+        self.r
+    }
+
+    /// Number of bytes of the buffer the item took up.
+    pub fn encoded_len(&self) -> usize {
+        LENGTH_SIZE * 4 + 1 + 2 + self.table_id.len() + self.k.len() + self.m.len() + self.r.len()
+    }
+
+    pub fn to_item(&self) -> CItem {
+        CItem {
+            table_id: self.table_id.to_string(),
+            action: self.action,
+            p: self.p,
+            k: self.k.to_vec(),
+            m: self.m.to_vec(),
+            r: self.r.to_vec(),
         }
+    }
+}
 
-        let mut result: SItem = Default::default();
-        let mut offset = 0;
+/// An [`SItem`] decoded in place, see [`CItemRef`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SItemRef<'a> {
+    table_id: &'a str,
+    action: Actions,
+    index: u16,
+    value: &'a [u8],
+}
+
+impl<'a> SItemRef<'a> {
+    /// Decodes an item written by `SItem::pack` at the start of `buffer`.
+    pub fn parse(buffer: &'a [u8]) -> Result<SItemRef<'a>, &'static str> {
+        let mut cursor = Cursor::new(buffer);
+        Ok(SItemRef {
+            table_id: cursor.get_str()?,
+            action: Actions::try_from(cursor.get_u8()?)?,
+            index: cursor.get_u16()?,
+            value: cursor.get_bytes()?,
+        })
+    }
+
+    pub fn table_id(&self) -> &'a str {
+        self.table_id
+    }
+
+    pub fn action(&self) -> Actions {
+        self.action
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn value(&self) -> &'a [u8] {
+        self.value
+    }
 
-        // Extract table_id
-        let table_id_length = usize::from_le_bytes(buffer[offset..offset + std::mem::size_of::<usize>()].try_into().expect("Slice with incorrect length"));
-        offset += std::mem::size_of::<usize>();
-        if buffer.len() < offset + table_id_length {
-            return Err("Buffer too short to contain full table_id");
+    /// Number of bytes of the buffer the item took up.
+    pub fn encoded_len(&self) -> usize {
+        LENGTH_SIZE * 2 + 1 + 2 + self.table_id.len() + self.value.len()
+    }
+
+    pub fn to_item(&self) -> SItem {
+        SItem { table_id: self.table_id.to_string(), action: self.action, index: self.index, value: self.value.to_vec() }
+    }
+}
+
+/// Builds a [`CItem`], checking it on `build`.
+#[derive(Clone, Debug)]
+pub struct CItemBuilder {
+    item: CItem,
+}
+
+impl CItemBuilder {
+    pub fn action(mut self, action: Actions) -> Self {
+        self.item.action = action;
+        self
+    }
+
+    pub fn priority(mut self, priority: u16) -> Self {
+        self.item.p = priority;
+        self
+    }
+
+    pub fn key(mut self, key: &[u8]) -> Self {
+        self.item.k = key.to_vec();
+        self
+    }
+
+    pub fn mask(mut self, mask: &[u8]) -> Self {
+        self.item.m = mask.to_vec();
+        self
+    }
+
+    pub fn result(mut self, result: &[u8]) -> Self {
+        self.item.r = result.to_vec();
+        self
+    }
+
+    /// Fails if the table id is empty or key and mask differ in length.
+    pub fn build(self) -> Result<CItem, &'static str> {
+        if self.item.table_id.is_empty() {
+            return Err("Table id must not be empty");
         }
-        result.table_id = String::from_utf8_lossy(&buffer[offset..offset + table_id_length]).to_string();
-        offset += table_id_length;
-
-        // Extract action
-        match buffer[offset] {
-            0 => { offset += 1; result.action = Actions::Noop; },
-            1 => { offset += 1; result.action = Actions::Add; },
-            2 => { offset += 1; result.action = Actions::Delete; },
-            3 => { offset += 1; result.action = Actions::Query; },
-            _ => return Err("Invalid action value"),
+        if self.item.k.len() != self.item.m.len() {
+            return Err("Key and mask length differ");
         }
+        Ok(self.item)
+    }
+}
+
+/// Builds an [`SItem`], checking it on `build`.
+#[derive(Clone, Debug)]
+pub struct SItemBuilder {
+    item: SItem,
+}
 
-        // Extract index
-        result.index = u16::from_le_bytes([buffer[offset], buffer[offset + 1]]);
-        offset += std::mem::size_of::<u16>();
+impl SItemBuilder {
+    pub fn action(mut self, action: Actions) -> Self {
+        self.item.action = action;
+        self
+    }
 
-        // Extract value length and data
-        let value_length = usize::from_le_bytes(buffer[offset..offset + std::mem::size_of::<usize>()].try_into().expect("Slice with incorrect length"));
-        offset += std::mem::size_of::<usize>();
-        if buffer.len() < offset + value_length {
-            return Err("Buffer too short to contain full value");
-        }
-        result.value = Vec::from(&buffer[offset..offset + value_length]);
+    pub fn index(mut self, index: u16) -> Self {
+        self.item.index = index;
+        self
+    }
 
-        Ok(result)
+    pub fn value(mut self, value: &[u8]) -> Self {
+        self.item.value = value.to_vec();
+        self
+    }
+
+    /// Fails if the table id is empty.
+    pub fn build(self) -> Result<SItem, &'static str> {
+        if self.item.table_id.is_empty() {
+            return Err("Table id must not be empty");
+        }
+        Ok(self.item)
     }
 }
 
-const MAX_QITEM_SIZE:usize = 65536;
-const MAX_QITEMS:usize = 1024;
+pub const MAX_QITEM_SIZE:usize = 65536;
+pub const MAX_QITEMS:usize = 1024;
 
+/// One end of a message queue.
+///
+/// Messages larger than a single queue item are split into fragments on
+/// `write` and rebuilt by `read`, see the [`fragment`] module. The items
+/// travel over a [`Transport`]: a POSIX message queue, a Unix socket
+/// connection, or an in-process queue for tests.
 pub struct TableInterface {
-    handle: Arc<Mutex<i32>>,
+    transport: Box<dyn Transport>,
+    name: String,
+    max_item_size: usize,
+    max_message_size: usize,
+    reassembler: Mutex<Reassembler>,
+    /// Last sequence number `write_acked` gave a request.
+    sequence: AtomicU64,
 }
 
 impl TableInterface {
-    pub fn write(&self, buffer: &Vec<u8>) -> Result<(), &'static str> {
-        send_message(&self.handle.lock().unwrap(), buffer.as_slice()).expect("Failed to send message");
-        Ok(())
+    pub fn write(&self, buffer: &[u8]) -> Result<(), &'static str> {
+        fragment::split(buffer, self.max_item_size, self.max_message_size, |item| {
+            self.transport.send(item).map_err(|_| "Failed to send message")
+        })
+    }
+
+    /// Numbers `request` and sends it in `encoding` until the server
+    /// acknowledges it on `replies`, resending it every `retry`, and returns
+    /// the server's reply.
+    /// The server applies it once however often it arrives. Fails once
+    /// `timeout` has passed without an acknowledgement, in which case the
+    /// request may or may not have been applied. A send that fails, e.g.
+    /// on a full nonblocking queue, is retried like a lost request.
+    pub fn write_acked(&self, request: &mut Request, encoding: Encoding, replies: &TableInterface, retry: Duration,
+                       timeout: Duration) -> Result<Reply, String> {
+        request.sequence = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let message = request.encode(encoding);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let unsent = self.write(&message).err();
+            let resend = (Instant::now() + retry).min(deadline);
+            loop {
+                let now = Instant::now();
+                if now >= resend {
+                    break;
+                }
+                let Some(reply) = replies.read_timeout(resend - now)? else {
+                    break;
+                };
+                // Skips late acknowledgements of earlier requests.
+                if let Ok(Reply::Acked { sequence, reply }) = Reply::decode(&reply) {
+                    if sequence == request.sequence {
+                        return Ok(*reply);
+                    }
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(match unsent {
+                    Some(e) => format!("{} for request {}", e, request.sequence),
+                    None => format!("No acknowledgement for request {} within {:?}", request.sequence, timeout),
+                });
+            }
+        }
     }
 
     pub fn read(&self) -> Result<Vec<u8>, &'static str> {
-        let mut buffer = vec![0u8; MAX_QITEM_SIZE];
-        let received_bytes = receive_message(&self.handle.lock().unwrap(), &mut buffer)
-            .expect("Failed to receive message");
-        let result: Vec<u8> = buffer[0..received_bytes].to_vec();
-        Ok(result)
+        self.receive(None).map(|message| message.expect("Reading without a deadline returns a message"))
+    }
+
+    /// Like `read`, but gives up and returns `None` once `timeout` has passed
+    /// without a complete message. Fragments received so far are kept for the
+    /// next call.
+    pub fn read_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, &'static str> {
+        self.receive(Some(Instant::now() + timeout))
+    }
+
+    /// Limits the size of messages accepted by `write` and rebuilt by `read`.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+        self.reassembler = Mutex::new(Reassembler::new(max_message_size));
     }
 
     pub fn get_writer(name: &str) -> Result<TableInterface, &'static str> {
-        let msgq = init_or_open_mq(name, O_CREAT | O_WRONLY, MAX_QITEM_SIZE, MAX_QITEMS);
-        match msgq {
-            Ok(result) => {
-                let (handle, _mqd) = result;
-                let table = TableInterface{
-                    handle: handle,
-                };
-                Ok(table)
-            },
+        TableInterface::get_writer_sized(name, MAX_QITEM_SIZE, MAX_QITEMS)
+    }
+
+    pub fn get_table_reader(name: &str) -> Result<TableInterface, &'static str> {
+        TableInterface::get_table_reader_sized(name, MAX_QITEM_SIZE, MAX_QITEMS)
+    }
+
+    /// Opens a writer whose `write` fails instead of blocking when the queue is
+    /// full, for queues the reader may have stopped draining.
+    pub fn get_nonblocking_writer(name: &str) -> Result<TableInterface, &'static str> {
+        TableInterface::get_nonblocking_writer_sized(name, MAX_QITEM_SIZE, MAX_QITEMS)
+    }
+
+    /// Like `get_nonblocking_writer`, with explicit queue item size and queue depth.
+    pub fn get_nonblocking_writer_sized(name: &str, max_item_size: usize, max_items: usize) -> Result<TableInterface, &'static str> {
+        match MqTransport::open(name, O_CREAT | O_WRONLY | O_NONBLOCK, max_item_size, max_items) {
+            Ok(transport) => Ok(TableInterface::with_transport(name, Box::new(transport))),
             Err(_) => Err("Failed to get table writer")
         }
     }
 
-    pub fn get_table_reader(name: &str) -> Result<TableInterface, &'static str> {
-        let msgq = init_or_open_mq(name, O_CREAT | O_RDONLY, MAX_QITEM_SIZE, MAX_QITEMS);
-        match msgq {
-            Ok(result) => {
-                let (handle, _mqd) = result;
-                let table = TableInterface{
-                    handle: handle,
-                };
-                Ok(table)
-            },
+    /// Opens a non-blocking writer on a queue somebody else created. Fails if
+    /// the queue does not exist.
+    pub fn open_existing_writer(name: &str) -> Result<TableInterface, &'static str> {
+        match MqTransport::open(name, O_WRONLY | O_NONBLOCK, MAX_QITEM_SIZE, MAX_QITEMS) {
+            Ok(transport) => Ok(TableInterface::with_transport(name, Box::new(transport))),
+            Err(_) => Err("Failed to open existing queue")
+        }
+    }
+
+    /// Like `get_writer`, with explicit queue item size and queue depth.
+    pub fn get_writer_sized(name: &str, max_item_size: usize, max_items: usize) -> Result<TableInterface, &'static str> {
+        match MqTransport::open(name, O_CREAT | O_WRONLY, max_item_size, max_items) {
+            Ok(transport) => Ok(TableInterface::with_transport(name, Box::new(transport))),
+            Err(_) => Err("Failed to get table writer")
+        }
+    }
+
+    /// Like `get_table_reader`, with explicit queue item size and queue depth.
+    pub fn get_table_reader_sized(name: &str, max_item_size: usize, max_items: usize) -> Result<TableInterface, &'static str> {
+        match MqTransport::open(name, O_CREAT | O_RDONLY, max_item_size, max_items) {
+            Ok(transport) => Ok(TableInterface::with_transport(name, Box::new(transport))),
             Err(_) => Err("Failed to get table reader: ")
         }
     }
+
+    /// Opens an end of the in-process queue `name`, creating it if needed.
+    /// Both reading and writing block.
+    pub fn get_memory_queue(name: &str, max_item_size: usize, max_items: usize) -> TableInterface {
+        TableInterface::with_transport(name, Box::new(MemoryTransport::open(name, max_item_size, max_items, false)))
+    }
+
+    /// Connects to the server listening on the Unix socket `path`. The
+    /// connection carries both the requests and their replies.
+    pub fn connect_socket(path: &Path) -> Result<TableInterface, String> {
+        let transport = SocketTransport::connect(path)?;
+        Ok(TableInterface::with_transport(&path.display().to_string(), Box::new(transport)))
+    }
+
+    /// An end of a queue reached over `transport`, called `name`.
+    pub fn with_transport(name: &str, transport: Box<dyn Transport>) -> TableInterface {
+        // An existing queue keeps the item size it was created with, which
+        // the transport reports.
+        let max_item_size = transport.max_item_size();
+        TableInterface {
+            transport,
+            name: name.to_string(),
+            max_item_size,
+            max_message_size: fragment::MAX_MESSAGE_SIZE,
+            reassembler: Mutex::new(Reassembler::default()),
+            // Starting from the clock keeps the numbers of a restarted client
+            // above those the server remembers from its previous run.
+            sequence: AtomicU64::new(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64)),
+        }
+    }
+
+    /// Name of the queue this end was opened on.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Removes the queue's name, so the next open creates a new queue. This end
+    /// and others already open keep working until they are dropped.
+    pub fn unlink(&self) -> Result<(), String> {
+        self.transport.unlink()
+    }
+
+    fn receive(&self, deadline: Option<Instant>) -> Result<Option<Vec<u8>>, &'static str> {
+        let mut buffer = vec![0u8; self.max_item_size];
+        let mut reassembler = self.reassembler.lock().unwrap();
+        loop {
+            let received_bytes = match self.transport.receive(&mut buffer, deadline) {
+                Ok(Some(received_bytes)) => received_bytes,
+                Ok(None) => return Ok(None),
+                Err(e) if e == transport::CONNECTION_CLOSED => return Err(transport::CONNECTION_CLOSED),
+                Err(_) => return Err("Failed to receive message"),
+            };
+            if let Some(message) = reassembler.push(&buffer[0..received_bytes])? {
+                return Ok(Some(message));
+            }
+        }
+    }
+}
+
+/// A POSIX message queue.
+pub struct MqTransport {
+    handle: Arc<Mutex<mqd_t>>,
+    name: String,
+    max_item_size: usize,
+}
+
+impl MqTransport {
+    /// Opens the queue `name` with `mq_open` flags `mode`, creating it with
+    /// the given sizes if `O_CREAT` is among them.
+    pub fn open(name: &str, mode: i32, max_item_size: usize, max_items: usize) -> Result<MqTransport, String> {
+        let (handle, mqd) = init_or_open_mq(name, mode, max_item_size, max_items)?;
+        let max_item_size = queue_item_size(mqd).unwrap_or(max_item_size);
+        Ok(MqTransport { handle, name: name.to_string(), max_item_size })
+    }
+}
+
+impl Transport for MqTransport {
+    fn send(&self, item: &[u8]) -> Result<(), String> {
+        send_message(&self.handle.lock().unwrap(), item)
+    }
+
+    fn receive(&self, buffer: &mut [u8], deadline: Option<Instant>) -> Result<Option<usize>, String> {
+        let handle = self.handle.lock().unwrap();
+        match deadline {
+            None => receive_message(&handle, buffer).map(Some),
+            Some(deadline) => {
+                let deadline = SystemTime::now() + deadline.saturating_duration_since(Instant::now());
+                receive_message_until(&handle, buffer, deadline)
+            },
+        }
+    }
+
+    fn max_item_size(&self) -> usize {
+        self.max_item_size
+    }
+
+    fn unlink(&self) -> Result<(), String> {
+        unlink_mq(&self.name)
+    }
 }
 
-impl Drop for TableInterface {
+impl Drop for MqTransport {
     fn drop(&mut self) {
         close_mq(*self.handle.lock().unwrap()).expect("Failed to close TableInterface")
     }
@@ -283,7 +681,7 @@ fn init_or_open_mq(name: &str,
     attr.mq_maxmsg = max_queue_size as i64; // Maximum number of messages
 
     let mqd = unsafe {
-        libc::mq_open(c_name.as_ptr(), oflag, 0644, &mut attr)
+        libc::mq_open(c_name.as_ptr(), oflag, 0o644, &mut attr)
     };
 
     if mqd == -1 {
@@ -293,6 +691,16 @@ fn init_or_open_mq(name: &str,
     }
 }
 
+/// Returns the maximum message size of an open queue.
+fn queue_item_size(mqd: mqd_t) -> Option<usize> {
+    let mut attr: mq_attr = unsafe { std::mem::zeroed() };
+    if unsafe { libc::mq_getattr(mqd, &mut attr) } == -1 {
+        None
+    } else {
+        Some(attr.mq_msgsize as usize)
+    }
+}
+
 /// Sends a message to the message queue.
 fn send_message(handle: &mqd_t, message: &[u8]) -> Result<(), String> {
     let res = unsafe { libc::mq_send(*handle, message.as_ptr() as *const _, message.len(), 0) };
@@ -313,6 +721,28 @@ fn receive_message(handle: &mqd_t, buffer: &mut [u8]) -> Result<usize, String> {
     }
 }
 
+/// Receives a message, waiting at most until `deadline`. Returns `None` on timeout.
+fn receive_message_until(handle: &mqd_t, buffer: &mut [u8], deadline: SystemTime) -> Result<Option<usize>, String> {
+    let since_epoch = deadline.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let abs_timeout = libc::timespec {
+        tv_sec: since_epoch.as_secs() as libc::time_t,
+        tv_nsec: since_epoch.subsec_nanos() as libc::c_long,
+    };
+    let res = unsafe {
+        libc::mq_timedreceive(*handle, buffer.as_mut_ptr() as *mut _, buffer.len(), std::ptr::null_mut(), &abs_timeout)
+    };
+    if res == -1 {
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() == Some(libc::ETIMEDOUT) {
+            Ok(None)
+        } else {
+            Err(format!("Failed to receive message: {}", error))
+        }
+    } else {
+        Ok(Some(res as usize))
+    }
+}
+
 ///  Closes a message queue.
 pub fn close_mq(mqd: mqd_t) -> Result<(), String> {
     unsafe {
@@ -334,6 +764,62 @@ pub fn unlink_mq(name: &str) -> Result<(), String> {
     }
 }
 
+/// User owning the queue `name`, `None` if it cannot be opened.
+pub fn mq_owner(name: &str) -> Option<u32> {
+    let c_name = CString::new(name).ok()?;
+    let mqd = unsafe { libc::mq_open(c_name.as_ptr(), O_RDONLY | O_NONBLOCK) };
+    if mqd == -1 {
+        return None;
+    }
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::fstat(mqd, &mut stat) };
+    unsafe { libc::mq_close(mqd) };
+    (res == 0).then_some(stat.st_uid)
+}
+
+/// Whether a queue named `name` currently exists.
+pub fn mq_exists(name: &str) -> bool {
+    let c_name = match CString::new(name) {
+        Ok(c_name) => c_name,
+        Err(_) => return false,
+    };
+    let mqd = unsafe { libc::mq_open(c_name.as_ptr(), O_RDONLY | O_NONBLOCK) };
+    if mqd == -1 {
+        return std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOENT);
+    }
+    unsafe { libc::mq_close(mqd) };
+    true
+}
+
+/// Settings and fill level of a queue, as reported by `mq_getattr`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueAttributes {
+    pub max_item_size: usize,
+    pub max_items: usize,
+    /// Items currently waiting in the queue.
+    pub pending: usize,
+}
+
+pub fn mq_attributes(name: &str) -> Result<QueueAttributes, String> {
+    let c_name = CString::new(name).map_err(|_| "CString::new failed")?;
+    let mqd = unsafe { libc::mq_open(c_name.as_ptr(), O_RDONLY | O_NONBLOCK) };
+    if mqd == -1 {
+        return Err(format!("Failed to open message queue: {}", std::io::Error::last_os_error()));
+    }
+    let mut attr: mq_attr = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::mq_getattr(mqd, &mut attr) };
+    let error = std::io::Error::last_os_error();
+    unsafe { libc::mq_close(mqd) };
+    if res == -1 {
+        return Err(format!("Failed to get message queue attributes: {}", error));
+    }
+    Ok(QueueAttributes {
+        max_item_size: attr.mq_msgsize as usize,
+        max_items: attr.mq_maxmsg as usize,
+        pending: attr.mq_curmsgs as usize,
+    })
+}
+
 pub fn close_and_unlink_mq(mqd: mqd_t, name: &str) -> Result<(), String> {
     use std::thread;
     use std::time::Duration;
@@ -343,6 +829,95 @@ pub fn close_and_unlink_mq(mqd: mqd_t, name: &str) -> Result<(), String> {
     Ok(())
 }
 
+#[cfg(test)]
+mod item_tests {
+    use super::*;
+
+    #[test]
+    fn test_builders() {
+        let item = CItem::builder("acl").action(Actions::Add).priority(7).key(&[10, 0]).mask(&[0xff, 0]).result(b"permit")
+            .build().unwrap();
+        assert_eq!((item.table_id(), item.action(), item.priority()), ("acl", Actions::Add, 7));
+        assert_eq!((item.key(), item.mask(), item.result()), (&[10, 0][..], &[0xff, 0][..], &b"permit"[..]));
+        assert_eq!(CItem::unpack(&item.to_bytes()), Ok(item.clone()));
+
+        assert_eq!(CItem::builder("acl").key(&[10, 0]).mask(&[0xff]).build(), Err("Key and mask length differ"));
+        assert_eq!(CItem::builder("").build(), Err("Table id must not be empty"));
+
+        let item = SItem::builder("nexthop").action(Actions::Delete).index(3).value(b"eth0").build().unwrap();
+        assert_eq!((item.table_id(), item.action(), item.index(), item.value()), ("nexthop", Actions::Delete, 3, &b"eth0"[..]));
+        assert_ne!(item, SItem::builder("nexthop").build().unwrap());
+        assert_eq!(SItem::builder("").index(3).build(), Err("Table id must not be empty"));
+    }
+
+    #[test]
+    fn test_batch() {
+        let items: Vec<SItem> = (0..10u16).map(|i| {
+            SItem::builder("nexthop").action(Actions::Add).index(i).value(&vec![i as u8; i as usize]).build().unwrap()
+        }).collect();
+        let size: usize = items.iter().map(SItem::encoded_len).sum();
+        let mut buffer = Vec::with_capacity(size);
+        let start = buffer.as_ptr();
+        for item in &items {
+            item.pack(&mut buffer);
+        }
+        assert_eq!(buffer.len(), size);
+        // Packed in place, without growing the buffer.
+        assert_eq!(buffer.as_ptr(), start);
+
+        let mut rest = &buffer[..];
+        for item in &items {
+            let decoded = SItem::unpack(rest).unwrap();
+            assert_eq!(&decoded, item);
+            rest = &rest[decoded.encoded_len()..];
+        }
+        assert!(rest.is_empty());
+
+        let item = CItem::builder("acl").key(&[1, 2]).mask(&[3, 4]).result(b"r").build().unwrap();
+        let mut buffer = vec![0xaa];
+        item.pack(&mut buffer);
+        assert_eq!(buffer.len(), 1 + item.encoded_len());
+        assert_eq!(&buffer[1..], item.to_bytes());
+    }
+
+    #[test]
+    fn test_borrowed_views() {
+        let item = CItem::builder("acl").action(Actions::Add).priority(2).key(&[1, 2]).mask(&[3, 4]).result(b"r")
+            .build().unwrap();
+        let buffer = item.to_bytes();
+        let view = CItemRef::parse(&buffer).unwrap();
+        assert_eq!((view.table_id(), view.action(), view.priority()), ("acl", Actions::Add, 2));
+        assert_eq!(view.key().as_ptr(), buffer[LENGTH_SIZE * 2 + 6..].as_ptr());
+        assert_eq!((view.mask(), view.result()), (&[3, 4][..], &b"r"[..]));
+        assert_eq!((view.encoded_len(), view.to_item()), (buffer.len(), item));
+
+        let item = SItem::builder("nexthop").index(9).value(b"eth0").build().unwrap();
+        let buffer = item.to_bytes();
+        let view = SItemRef::parse(&buffer).unwrap();
+        assert_eq!((view.table_id(), view.index(), view.value()), ("nexthop", 9, &b"eth0"[..]));
+        assert_eq!(view.to_item(), item);
+    }
+
+    #[test]
+    fn test_invalid_items() {
+        let mut buffer = CItem::builder("acl").key(&[1]).mask(&[1]).build().unwrap().to_bytes();
+        for len in 0..buffer.len() {
+            assert!(CItemRef::parse(&buffer[..len]).is_err(), "Accepted {} bytes", len);
+        }
+        // Table ids must be UTF-8.
+        buffer[LENGTH_SIZE] = 0xff;
+        assert_eq!(CItem::unpack(&buffer), Err("String is not valid UTF-8"));
+        // Lengths running past the end, or overflowing, do not panic.
+        let mut buffer = SItem::builder("nexthop").build().unwrap().to_bytes();
+        let value_length = buffer.len() - LENGTH_SIZE;
+        buffer[value_length..].copy_from_slice(&usize::MAX.to_le_bytes());
+        assert!(SItem::unpack(&buffer).is_err());
+        buffer[..LENGTH_SIZE].copy_from_slice(&usize::MAX.to_le_bytes());
+        assert!(SItemRef::parse(&buffer).is_err());
+        assert_eq!(SItem::unpack(&[]), Err("Buffer too short to contain valid data"));
+    }
+}
+
 #[cfg(test)]
 mod mq_tests {
     use super::*;
@@ -367,7 +942,7 @@ mod mq_tests {
         let (handle, mqd) = init_or_open_mq(name, O_CREAT | O_WRONLY, 1024, 10).expect("Failed to open message queue");
 
         // Send a simple message
-        send_message(&*handle.lock().unwrap(), b"Hello MQ").expect("Failed to send message");
+        send_message(&handle.lock().unwrap(), b"Hello MQ").expect("Failed to send message");
 
         // Close and unlink immediately for cleanup
         close_and_unlink_mq(mqd, name).expect("Failed to close and unlink message queue");
@@ -381,12 +956,12 @@ mod mq_tests {
         let (handle, mqd) = init_or_open_mq(name, O_CREAT | O_RDWR, 1024, 10)
             .expect("Failed to open message queue for writing");
 
-        send_message(&*handle.lock().unwrap(), test_message)
+        send_message(&handle.lock().unwrap(), test_message)
             .expect("Failed to send message");
 
         let mut buffer = vec![0u8; 1024];
 
-        let received_bytes = receive_message(&*handle.lock().unwrap(), &mut buffer)
+        let received_bytes = receive_message(&handle.lock().unwrap(), &mut buffer)
             .expect("Failed to receive message");
 
         // Assertion: Verify that the received bytes match what was sent.
@@ -411,6 +986,31 @@ mod mq_tests {
         assert!(result.is_err(), "Expected error when reopening an unlinked message queue");
     }
 
+    #[test]
+    fn test_mq_exists() {
+        let name = "/exists_test_queue";
+        assert!(!mq_exists(name));
+
+        let (_handle, mqd) = init_or_open_mq(name, O_CREAT | O_WRONLY, 1024, 10).expect("Failed to open message queue");
+        assert!(mq_exists(name));
+
+        close_and_unlink_mq(mqd, name).expect("Failed to close and unlink message queue");
+        assert!(!mq_exists(name));
+    }
+
+    #[test]
+    fn test_mq_attributes() {
+        let name = "/attributes_test_queue";
+        assert!(mq_attributes(name).is_err());
+
+        let (handle, mqd) = init_or_open_mq(name, O_CREAT | O_WRONLY, 1024, 10).expect("Failed to open message queue");
+        send_message(&handle.lock().unwrap(), b"one").expect("Failed to send message");
+        let attr = mq_attributes(name).expect("Failed to get attributes");
+        assert_eq!(attr, QueueAttributes { max_item_size: 1024, max_items: 10, pending: 1 });
+
+        close_and_unlink_mq(mqd, name).expect("Failed to close and unlink message queue");
+    }
+
     #[test]
     fn test_close_and_unlink_mq() {
         let name = "/close_and_unlink_test_queue";
@@ -429,11 +1029,149 @@ mod mq_tests {
 
 #[cfg(test)]
 mod table_tests {
+    use super::*;
+
     #[test]
     fn test_writer() {
+        let name = "/table_writer_test";
+        let writer = TableInterface::get_memory_queue(name, 64, 4);
+        let raw = MemoryTransport::open(name, 64, 4, false);
+
+        writer.write(b"short").expect("Failed to write message");
+        let message: Vec<u8> = (0..100u8).collect();
+        writer.write(&message).expect("Failed to write message");
+        // Every item carries a fragment header; the long message takes three.
+        assert_eq!(raw.pending(), 4);
+        let mut buffer = [0u8; 64];
+        assert_eq!(raw.receive(&mut buffer, None), Ok(Some(fragment::FRAGMENT_HEADER_SIZE + 5)));
+        assert_eq!(&buffer[fragment::FRAGMENT_HEADER_SIZE..fragment::FRAGMENT_HEADER_SIZE + 5], b"short");
+
+        let mut limited = TableInterface::get_memory_queue(name, 64, 4);
+        limited.set_max_message_size(50);
+        assert!(limited.write(&message).is_err());
+        writer.unlink().expect("Failed to unlink queue");
     }
 
     #[test]
     fn test_reader() {
+        let name = "/table_reader_test";
+        let writer = TableInterface::get_memory_queue(name, 64, 16);
+        let reader = TableInterface::get_memory_queue(name, 1024, 1);
+        assert_eq!(reader.name(), name);
+
+        assert_eq!(reader.read_timeout(Duration::ZERO), Ok(None));
+        let message: Vec<u8> = (0..500u32).map(|i| i as u8).collect();
+        writer.write(&message).expect("Failed to write message");
+        writer.write(b"tail").expect("Failed to write message");
+        assert_eq!(reader.read().expect("Failed to read message"), message);
+        assert_eq!(reader.read_timeout(Duration::from_millis(10)), Ok(Some(b"tail".to_vec())));
+
+        // A message arriving while the reader waits.
+        let sender = std::thread::spawn(move || writer.write(b"later"));
+        assert_eq!(reader.read().expect("Failed to read message"), b"later");
+        sender.join().unwrap().expect("Failed to write message");
+        reader.unlink().expect("Failed to unlink queue");
+    }
+
+    #[test]
+    fn test_write_acked() {
+        use protocol::RequestBody;
+        use server::Server;
+        use tables::Tables;
+
+        let (requests, replies) = ("/write_acked_test", "/write_acked_test.reply");
+        let writer = TableInterface::get_memory_queue(requests, 1024, 16);
+        let reader = TableInterface::get_memory_queue(replies, 1024, 16);
+        // The server's first acknowledgement gets lost, so the client resends.
+        let responder = std::thread::spawn(move || {
+            let mut tables = Tables::default();
+            tables.declare("nexthop:direct:16".parse().unwrap()).unwrap();
+            let mut server = Server::new(tables);
+            let queue = TableInterface::get_memory_queue(requests, 1024, 16);
+            let reply_queue = TableInterface::get_memory_queue(replies, 1024, 16);
+            for attempt in 0..2 {
+                let request = Request::unpack(&queue.read().unwrap()).unwrap();
+                let reply = server.handle_request(&request, None);
+                if attempt > 0 {
+                    reply_queue.write(&reply.pack()).unwrap();
+                }
+            }
+            server
+        });
+
+        let mut item = SItem::default_instance();
+        item.table_id = "nexthop".to_string();
+        item.action = Actions::Add;
+        item.index = 3;
+        let mut request = Request { reply_to: replies.to_string(), sequence: 0, token: None,
+                                    body: RequestBody::Direct(item, Default::default()) };
+        let reply = writer.write_acked(&mut request, Encoding::Native, &reader, Duration::from_millis(20), Duration::from_secs(5));
+        assert_eq!(reply, Ok(Reply::Ok));
+        let server = responder.join().unwrap();
+        assert_eq!(server.duplicates(), 1);
+        assert_eq!(server.tables().get("nexthop").unwrap().len(), 1);
+
+        // Nobody answers any more.
+        let first = request.sequence;
+        assert!(writer.write_acked(&mut request, Encoding::Native, &reader, Duration::from_millis(5), Duration::from_millis(20)).is_err());
+        assert!(request.sequence > first);
+        writer.unlink().unwrap();
+        reader.unlink().unwrap();
+    }
+
+    #[test]
+    fn test_fragmented_round_trip() {
+        let name = "/fragment_test_queue";
+        let writer = TableInterface::get_writer_sized(name, 1024, 10).expect("Failed to get writer");
+        let reader = TableInterface::get_table_reader_sized(name, 1024, 10).expect("Failed to get reader");
+
+        let message: Vec<u8> = (0..6000u32).map(|i| (i % 251) as u8).collect();
+        writer.write(&message).expect("Failed to write message");
+        writer.write(b"tail").expect("Failed to write message");
+
+        assert_eq!(reader.read().expect("Failed to read message"), message);
+        assert_eq!(reader.read().expect("Failed to read message"), b"tail");
+
+        unlink_mq(name).expect("Failed to unlink message queue");
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let name = "/read_timeout_test_queue";
+        let writer = TableInterface::get_writer_sized(name, 1024, 10).expect("Failed to get writer");
+        let reader = TableInterface::get_table_reader_sized(name, 1024, 10).expect("Failed to get reader");
+
+        assert_eq!(reader.read_timeout(Duration::from_millis(10)), Ok(None));
+        writer.write(b"hello").expect("Failed to write message");
+        assert_eq!(reader.read_timeout(Duration::from_millis(10)), Ok(Some(b"hello".to_vec())));
+
+        unlink_mq(name).expect("Failed to unlink message queue");
+    }
+
+    #[test]
+    fn test_message_size_limit() {
+        let name = "/fragment_limit_test_queue";
+        let mut writer = TableInterface::get_writer_sized(name, 1024, 10).expect("Failed to get writer");
+        writer.set_max_message_size(2048);
+
+        assert!(writer.write(&[0u8; 4096]).is_err());
+
+        unlink_mq(name).expect("Failed to unlink message queue");
+    }
+
+    #[test]
+    fn test_unlink() {
+        let name = "/unlink_test_queue";
+        let writer = TableInterface::get_writer_sized(name, 1024, 10).expect("Failed to get writer");
+        let reader = TableInterface::get_table_reader_sized(name, 1024, 10).expect("Failed to get reader");
+        assert_eq!(reader.name(), name);
+
+        // Both ends keep working after the name is gone, so pending messages can be drained.
+        writer.write(b"last").expect("Failed to write message");
+        reader.unlink().expect("Failed to unlink queue");
+        assert!(!mq_exists(name));
+        assert_eq!(reader.read_timeout(Duration::ZERO), Ok(Some(b"last".to_vec())));
+        assert_eq!(reader.read_timeout(Duration::ZERO), Ok(None));
+        assert!(reader.unlink().is_err());
     }
 }
\ No newline at end of file
@@ -0,0 +1,606 @@
+//! Protobuf encoding of requests, replies and events.
+//!
+//! The messages are generated from `mem_ipc.proto` by prost-build; this module
+//! converts between them and the types of [`protocol`](crate::protocol).
+//! Clients pick an encoding per message with
+//! [`Encoding`](crate::protocol::Encoding). Only built with the `protobuf`
+//! feature.
+
+use prost::Message;
+
+use crate::protocol::{Event, EventKind, KeyFilter, Reply, Request, RequestBody, TableInfo, WriteOptions, PROTOBUF_MAGIC};
+use crate::reconcile::Drift;
+use crate::tables::{Counters, DirectEntry, Entry, Owner, TableKind, TernaryEntry, Timeouts};
+use crate::{Actions, CItem, SItem};
+
+/// Messages generated from `mem_ipc.proto`.
+pub mod messages {
+    include!(concat!(env!("OUT_DIR"), "/mem_ipc.rs"));
+}
+
+use messages::{reply, request};
+
+/// `message` behind `PROTOBUF_MAGIC`.
+pub(crate) fn encode(message: &impl Message) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(PROTOBUF_MAGIC.len() + message.encoded_len());
+    buffer.extend_from_slice(PROTOBUF_MAGIC);
+    message.encode(&mut buffer).expect("Vec grows as needed");
+    buffer
+}
+
+/// Reads a message written by `encode`.
+pub(crate) fn decode<M: Message + Default>(buffer: &[u8]) -> Result<M, &'static str> {
+    let message = buffer.strip_prefix(PROTOBUF_MAGIC).ok_or("Missing protobuf marker")?;
+    M::decode(message).map_err(|_| "Invalid protobuf message")
+}
+
+fn u16_field(value: u32, error: &'static str) -> Result<u16, &'static str> {
+    u16::try_from(value).map_err(|_| error)
+}
+
+fn action(value: i32) -> Result<Actions, &'static str> {
+    u8::try_from(value).map_err(|_| "Invalid action value").and_then(Actions::try_from)
+}
+
+impl From<&CItem> for messages::CItem {
+    fn from(item: &CItem) -> Self {
+        messages::CItem {
+            table_id: item.table_id.clone(),
+            action: item.action as i32,
+            priority: item.p as u32,
+            key: item.k.clone(),
+            mask: item.m.clone(),
+            result: item.r.clone(),
+        }
+    }
+}
+
+impl TryFrom<messages::CItem> for CItem {
+    type Error = &'static str;
+
+    fn try_from(item: messages::CItem) -> Result<Self, Self::Error> {
+        if item.key.len() != item.mask.len() {
+            return Err("Key and mask length differ");
+        }
+        Ok(CItem {
+            table_id: item.table_id,
+            action: action(item.action)?,
+            p: u16_field(item.priority, "Priority out of range")?,
+            k: item.key,
+            m: item.mask,
+            r: item.result,
+        })
+    }
+}
+
+impl From<&SItem> for messages::SItem {
+    fn from(item: &SItem) -> Self {
+        messages::SItem {
+            table_id: item.table_id.clone(),
+            action: item.action as i32,
+            index: item.index as u32,
+            value: item.value.clone(),
+        }
+    }
+}
+
+impl TryFrom<messages::SItem> for SItem {
+    type Error = &'static str;
+
+    fn try_from(item: messages::SItem) -> Result<Self, Self::Error> {
+        Ok(SItem {
+            table_id: item.table_id,
+            action: action(item.action)?,
+            index: u16_field(item.index, "Index out of range")?,
+            value: item.value,
+        })
+    }
+}
+
+impl From<&WriteOptions> for messages::WriteOptions {
+    fn from(options: &WriteOptions) -> Self {
+        messages::WriteOptions {
+            idle_timeout: options.timeouts.idle,
+            hard_timeout: options.timeouts.hard,
+            session: options.session,
+            persistent: options.persistent,
+            expected_version: options.expected_version,
+        }
+    }
+}
+
+impl From<messages::WriteOptions> for WriteOptions {
+    fn from(options: messages::WriteOptions) -> Self {
+        WriteOptions {
+            timeouts: Timeouts { idle: options.idle_timeout, hard: options.hard_timeout },
+            session: options.session,
+            persistent: options.persistent,
+            expected_version: options.expected_version,
+        }
+    }
+}
+
+fn table_id(table_id: &str) -> messages::TableId {
+    messages::TableId { table_id: table_id.to_string() }
+}
+
+impl From<&Request> for messages::Request {
+    fn from(request: &Request) -> Self {
+        let body = match &request.body {
+            RequestBody::Ternary(item, options) => request::Body::Ternary(messages::TernaryWrite {
+                item: Some(item.into()),
+                options: Some(options.into()),
+            }),
+            RequestBody::Direct(item, options) => request::Body::Direct(messages::DirectWrite {
+                item: Some(item.into()),
+                options: Some(options.into()),
+            }),
+            RequestBody::Lookup { table_id, key, bytes } => request::Body::Lookup(messages::Lookup {
+                table_id: table_id.clone(),
+                key: key.clone(),
+                bytes: *bytes,
+            }),
+            RequestBody::Dump { table_id: id } => request::Body::Dump(table_id(id)),
+            RequestBody::ResetCounters { table_id: id } => request::Body::ResetCounters(table_id(id)),
+            RequestBody::ListTables => request::Body::ListTables(messages::Empty {}),
+            RequestBody::Subscribe { queue, tables, filter } => request::Body::Subscribe(messages::Subscribe {
+                queue: queue.clone(),
+                tables: tables.clone(),
+                filter: filter.as_ref().map(|f| messages::KeyFilter { key: f.key.clone(), mask: f.mask.clone() }),
+            }),
+            RequestBody::Unsubscribe { queue } => request::Body::Unsubscribe(messages::Queue { queue: queue.clone() }),
+            RequestBody::SetDefault { table_id, result } => request::Body::SetDefault(messages::SetDefault {
+                table_id: table_id.clone(),
+                result: result.clone(),
+            }),
+            RequestBody::GetDefault { table_id: id } => request::Body::GetDefault(table_id(id)),
+            RequestBody::SaveSnapshot => request::Body::SaveSnapshot(messages::Empty {}),
+            RequestBody::Reconcile { repair } => request::Body::Reconcile(messages::Flag { set: *repair }),
+            RequestBody::Reload { force } => request::Body::Reload(messages::Flag { set: *force }),
+            RequestBody::OpenSession { lease_ms } => request::Body::OpenSession(messages::OpenSession { lease_ms: *lease_ms }),
+            RequestBody::Heartbeat { session } => request::Body::Heartbeat(messages::Session { session: *session }),
+            RequestBody::CloseSession { session } => request::Body::CloseSession(messages::Session { session: *session }),
+        };
+        messages::Request {
+            reply_to: request.reply_to.clone(),
+            sequence: request.sequence,
+            token: request.token.clone(),
+            body: Some(body),
+        }
+    }
+}
+
+impl TryFrom<messages::Request> for Request {
+    type Error = &'static str;
+
+    fn try_from(request: messages::Request) -> Result<Self, Self::Error> {
+        let body = match request.body.ok_or("Missing request body")? {
+            request::Body::Ternary(write) => RequestBody::Ternary(
+                write.item.ok_or("Missing item")?.try_into()?,
+                write.options.map(WriteOptions::from).unwrap_or_default(),
+            ),
+            request::Body::Direct(write) => RequestBody::Direct(
+                write.item.ok_or("Missing item")?.try_into()?,
+                write.options.map(WriteOptions::from).unwrap_or_default(),
+            ),
+            request::Body::Lookup(lookup) => RequestBody::Lookup {
+                table_id: lookup.table_id,
+                key: lookup.key,
+                bytes: lookup.bytes,
+            },
+            request::Body::Dump(id) => RequestBody::Dump { table_id: id.table_id },
+            request::Body::ResetCounters(id) => RequestBody::ResetCounters { table_id: id.table_id },
+            request::Body::ListTables(_) => RequestBody::ListTables,
+            request::Body::Subscribe(subscribe) => RequestBody::Subscribe {
+                queue: subscribe.queue,
+                tables: subscribe.tables,
+                filter: subscribe.filter.map(|f| KeyFilter { key: f.key, mask: f.mask }),
+            },
+            request::Body::Unsubscribe(queue) => RequestBody::Unsubscribe { queue: queue.queue },
+            request::Body::SetDefault(set) => RequestBody::SetDefault { table_id: set.table_id, result: set.result },
+            request::Body::GetDefault(id) => RequestBody::GetDefault { table_id: id.table_id },
+            request::Body::SaveSnapshot(_) => RequestBody::SaveSnapshot,
+            request::Body::Reconcile(flag) => RequestBody::Reconcile { repair: flag.set },
+            request::Body::Reload(flag) => RequestBody::Reload { force: flag.set },
+            request::Body::OpenSession(open) => RequestBody::OpenSession { lease_ms: open.lease_ms },
+            request::Body::Heartbeat(session) => RequestBody::Heartbeat { session: session.session },
+            request::Body::CloseSession(session) => RequestBody::CloseSession { session: session.session },
+        };
+        Ok(Request { reply_to: request.reply_to, sequence: request.sequence, token: request.token, body })
+    }
+}
+
+impl From<&Counters> for messages::Counters {
+    fn from(counters: &Counters) -> Self {
+        messages::Counters { packets: counters.packets, bytes: counters.bytes, last_hit: counters.last_hit }
+    }
+}
+
+impl From<messages::Counters> for Counters {
+    fn from(counters: messages::Counters) -> Self {
+        Counters { packets: counters.packets, bytes: counters.bytes, last_hit: counters.last_hit }
+    }
+}
+
+impl From<&TernaryEntry> for messages::TernaryEntry {
+    fn from(entry: &TernaryEntry) -> Self {
+        messages::TernaryEntry {
+            priority: entry.priority as u32,
+            key: entry.key.clone(),
+            mask: entry.mask.clone(),
+            result: entry.result.clone(),
+            counters: Some((&entry.counters).into()),
+            idle_timeout: entry.timeouts.idle,
+            hard_timeout: entry.timeouts.hard,
+            installed: entry.installed,
+            session: entry.owner.session,
+            persistent: entry.owner.persistent,
+            version: entry.version,
+        }
+    }
+}
+
+impl TryFrom<messages::TernaryEntry> for TernaryEntry {
+    type Error = &'static str;
+
+    fn try_from(entry: messages::TernaryEntry) -> Result<Self, Self::Error> {
+        Ok(TernaryEntry {
+            priority: u16_field(entry.priority, "Priority out of range")?,
+            key: entry.key,
+            mask: entry.mask,
+            result: entry.result,
+            counters: entry.counters.map(Counters::from).unwrap_or_default(),
+            timeouts: Timeouts { idle: entry.idle_timeout, hard: entry.hard_timeout },
+            installed: entry.installed,
+            owner: Owner { session: entry.session, persistent: entry.persistent },
+            version: entry.version,
+        })
+    }
+}
+
+impl From<&DirectEntry> for messages::DirectEntry {
+    fn from(entry: &DirectEntry) -> Self {
+        messages::DirectEntry {
+            index: entry.index as u32,
+            value: entry.value.clone(),
+            counters: Some((&entry.counters).into()),
+            idle_timeout: entry.timeouts.idle,
+            hard_timeout: entry.timeouts.hard,
+            installed: entry.installed,
+            session: entry.owner.session,
+            persistent: entry.owner.persistent,
+            version: entry.version,
+        }
+    }
+}
+
+impl TryFrom<messages::DirectEntry> for DirectEntry {
+    type Error = &'static str;
+
+    fn try_from(entry: messages::DirectEntry) -> Result<Self, Self::Error> {
+        Ok(DirectEntry {
+            index: u16_field(entry.index, "Index out of range")?,
+            value: entry.value,
+            counters: entry.counters.map(Counters::from).unwrap_or_default(),
+            timeouts: Timeouts { idle: entry.idle_timeout, hard: entry.hard_timeout },
+            installed: entry.installed,
+            owner: Owner { session: entry.session, persistent: entry.persistent },
+            version: entry.version,
+        })
+    }
+}
+
+impl From<&Entry> for messages::Entry {
+    fn from(entry: &Entry) -> Self {
+        let entry = match entry {
+            Entry::Ternary(entry) => messages::entry::Entry::Ternary(entry.into()),
+            Entry::Direct(entry) => messages::entry::Entry::Direct(entry.into()),
+        };
+        messages::Entry { entry: Some(entry) }
+    }
+}
+
+impl TryFrom<messages::Entry> for Entry {
+    type Error = &'static str;
+
+    fn try_from(entry: messages::Entry) -> Result<Self, Self::Error> {
+        match entry.entry.ok_or("Missing entry")? {
+            messages::entry::Entry::Ternary(entry) => Ok(Entry::Ternary(entry.try_into()?)),
+            messages::entry::Entry::Direct(entry) => Ok(Entry::Direct(entry.try_into()?)),
+        }
+    }
+}
+
+fn entries<T, M>(entries: Vec<M>) -> Result<Vec<T>, &'static str>
+where
+    T: TryFrom<M, Error = &'static str>,
+{
+    entries.into_iter().map(T::try_from).collect()
+}
+
+impl From<&TableInfo> for messages::TableInfo {
+    fn from(table: &TableInfo) -> Self {
+        let kind = match table.kind {
+            TableKind::Ternary => messages::TableKind::Ternary,
+            TableKind::Direct => messages::TableKind::Direct,
+        };
+        messages::TableInfo {
+            name: table.name.clone(),
+            kind: kind as i32,
+            key_width: table.key_width as u64,
+            capacity: table.capacity as u64,
+            len: table.len as u64,
+            default_result: table.default_result.clone(),
+            version: table.version,
+        }
+    }
+}
+
+impl TryFrom<messages::TableInfo> for TableInfo {
+    type Error = &'static str;
+
+    fn try_from(table: messages::TableInfo) -> Result<Self, Self::Error> {
+        let kind = match messages::TableKind::try_from(table.kind) {
+            Ok(messages::TableKind::Ternary) => TableKind::Ternary,
+            Ok(messages::TableKind::Direct) => TableKind::Direct,
+            Err(_) => return Err("Invalid table kind"),
+        };
+        Ok(TableInfo {
+            name: table.name,
+            kind,
+            key_width: table.key_width as usize,
+            capacity: table.capacity as usize,
+            len: table.len as usize,
+            default_result: table.default_result,
+            version: table.version,
+        })
+    }
+}
+
+impl From<&Drift> for messages::Drift {
+    fn from(drift: &Drift) -> Self {
+        messages::Drift {
+            table_id: drift.table_id.clone(),
+            missing: drift.missing.iter().map(Into::into).collect(),
+            extra: drift.extra.iter().map(Into::into).collect(),
+            mismatched: drift.mismatched.iter()
+                .map(|(shadow, installed)| messages::Mismatch { shadow: Some(shadow.into()), installed: Some(installed.into()) })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<messages::Drift> for Drift {
+    type Error = &'static str;
+
+    fn try_from(drift: messages::Drift) -> Result<Self, Self::Error> {
+        let mismatched = drift.mismatched.into_iter()
+            .map(|m| Ok((m.shadow.ok_or("Missing entry")?.try_into()?, m.installed.ok_or("Missing entry")?.try_into()?)))
+            .collect::<Result<_, &'static str>>()?;
+        Ok(Drift { table_id: drift.table_id, missing: entries(drift.missing)?, extra: entries(drift.extra)?, mismatched })
+    }
+}
+
+impl From<&Reply> for messages::Reply {
+    fn from(r: &Reply) -> Self {
+        let r = match r {
+            Reply::Ok => reply::Reply::Ok(messages::Empty {}),
+            Reply::Error(message) => reply::Reply::Error(message.clone()),
+            Reply::Result(result) => reply::Reply::Result(result.clone()),
+            Reply::Miss => reply::Reply::Miss(messages::Empty {}),
+            Reply::Counters(counters) => reply::Reply::Counters(counters.into()),
+            Reply::Ternary(e) => reply::Reply::Ternary(messages::TernaryEntries { entries: e.iter().map(Into::into).collect() }),
+            Reply::Direct(e) => reply::Reply::Direct(messages::DirectEntries { entries: e.iter().map(Into::into).collect() }),
+            Reply::Tables(tables) => reply::Reply::Tables(messages::Tables { tables: tables.iter().map(Into::into).collect() }),
+            Reply::Default(result) => reply::Reply::Default(messages::Default { result: result.clone() }),
+            Reply::Drift(drifts) => reply::Reply::Drift(messages::Drifts { drifts: drifts.iter().map(Into::into).collect() }),
+            Reply::Reloaded(lines) => reply::Reply::Reloaded(messages::Lines { lines: lines.clone() }),
+            Reply::Session(session) => reply::Reply::Session(*session),
+            Reply::Acked { sequence, reply } => reply::Reply::Acked(Box::new(messages::Acked {
+                sequence: *sequence,
+                reply: Some(Box::new(reply.as_ref().into())),
+            })),
+            Reply::Conflict { current } => reply::Reply::Conflict(messages::Conflict { current: *current }),
+        };
+        messages::Reply { reply: Some(r) }
+    }
+}
+
+impl TryFrom<messages::Reply> for Reply {
+    type Error = &'static str;
+
+    fn try_from(r: messages::Reply) -> Result<Self, &'static str> {
+        Ok(match r.reply.ok_or("Missing reply")? {
+            reply::Reply::Ok(_) => Reply::Ok,
+            reply::Reply::Error(message) => Reply::Error(message),
+            reply::Reply::Result(result) => Reply::Result(result),
+            reply::Reply::Miss(_) => Reply::Miss,
+            reply::Reply::Counters(counters) => Reply::Counters(counters.into()),
+            reply::Reply::Ternary(e) => Reply::Ternary(entries(e.entries)?),
+            reply::Reply::Direct(e) => Reply::Direct(entries(e.entries)?),
+            reply::Reply::Tables(tables) => Reply::Tables(entries(tables.tables)?),
+            reply::Reply::Default(default) => Reply::Default(default.result),
+            reply::Reply::Drift(drifts) => Reply::Drift(entries(drifts.drifts)?),
+            reply::Reply::Reloaded(lines) => Reply::Reloaded(lines.lines),
+            reply::Reply::Session(session) => Reply::Session(session),
+            reply::Reply::Acked(acked) => {
+                let reply = Reply::try_from(*acked.reply.ok_or("Missing reply")?)?;
+                if matches!(reply, Reply::Acked { .. }) {
+                    return Err("Nested acknowledgement");
+                }
+                Reply::Acked { sequence: acked.sequence, reply: Box::new(reply) }
+            },
+            reply::Reply::Conflict(conflict) => Reply::Conflict { current: conflict.current },
+        })
+    }
+}
+
+impl From<&Event> for messages::Event {
+    fn from(event: &Event) -> Self {
+        let kind = match event.kind {
+            EventKind::Expired => messages::EventKind::Expired,
+            EventKind::Added => messages::EventKind::Added,
+            EventKind::Modified => messages::EventKind::Modified,
+            EventKind::Deleted => messages::EventKind::Deleted,
+            EventKind::Released => messages::EventKind::Released,
+        };
+        messages::Event { kind: kind as i32, table_id: event.table_id.clone(), entry: Some((&event.entry).into()) }
+    }
+}
+
+impl TryFrom<messages::Event> for Event {
+    type Error = &'static str;
+
+    fn try_from(event: messages::Event) -> Result<Self, Self::Error> {
+        let kind = match messages::EventKind::try_from(event.kind) {
+            Ok(messages::EventKind::Expired) => EventKind::Expired,
+            Ok(messages::EventKind::Added) => EventKind::Added,
+            Ok(messages::EventKind::Modified) => EventKind::Modified,
+            Ok(messages::EventKind::Deleted) => EventKind::Deleted,
+            Ok(messages::EventKind::Released) => EventKind::Released,
+            Err(_) => return Err("Invalid event kind"),
+        };
+        Ok(Event { kind, table_id: event.table_id, entry: event.entry.ok_or("Missing entry")?.try_into()? })
+    }
+}
+
+#[cfg(test)]
+mod proto_tests {
+    use super::*;
+    use crate::protocol::Encoding;
+    use crate::server::Server;
+    use crate::tables::Tables;
+
+    fn request(body: RequestBody) -> Request {
+        Request { reply_to: "/client.reply".to_string(), sequence: 7, token: Some("s3cret".to_string()), body }
+    }
+
+    fn direct_entry() -> DirectEntry {
+        DirectEntry {
+            counters: Counters { packets: 1, bytes: 64, last_hit: 5 },
+            timeouts: Timeouts { idle: 10, hard: 0 },
+            installed: 4,
+            owner: Owner { session: 2, persistent: true },
+            version: 6,
+            ..DirectEntry::new(3, &[8])
+        }
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let item = CItem::builder("acl").action(Actions::Add).priority(7).key(&[1, 2]).mask(&[0xff, 0]).result(&[9]).build().unwrap();
+        let options = WriteOptions { timeouts: Timeouts { idle: 30, hard: 300 }, session: 5, persistent: true, expected_version: Some(0) };
+        let bodies = vec![
+            RequestBody::Ternary(item, options),
+            RequestBody::Direct(SItem::builder("nexthop").action(Actions::Delete).index(3).build().unwrap(), WriteOptions::default()),
+            RequestBody::Lookup { table_id: "acl".to_string(), key: vec![1, 2], bytes: 64 },
+            RequestBody::Dump { table_id: "acl".to_string() },
+            RequestBody::ResetCounters { table_id: "acl".to_string() },
+            RequestBody::ListTables,
+            RequestBody::Subscribe {
+                queue: "/events".to_string(),
+                tables: vec!["acl".to_string()],
+                filter: Some(KeyFilter { key: vec![10], mask: vec![0xff] }),
+            },
+            RequestBody::Unsubscribe { queue: "/events".to_string() },
+            RequestBody::SetDefault { table_id: "acl".to_string(), result: Some(vec![]) },
+            RequestBody::SetDefault { table_id: "acl".to_string(), result: None },
+            RequestBody::GetDefault { table_id: "acl".to_string() },
+            RequestBody::SaveSnapshot,
+            RequestBody::Reconcile { repair: true },
+            RequestBody::Reload { force: false },
+            RequestBody::OpenSession { lease_ms: 1000 },
+            RequestBody::Heartbeat { session: 3 },
+            RequestBody::CloseSession { session: 3 },
+        ];
+        for body in bodies {
+            let request = request(body);
+            let message = request.encode(Encoding::Protobuf);
+            assert!(message.starts_with(PROTOBUF_MAGIC));
+            let (decoded, encoding) = Request::decode(&message).unwrap();
+            assert_eq!(encoding, Encoding::Protobuf);
+            // The native layout covers every field.
+            assert_eq!(decoded.pack(), request.pack());
+        }
+        assert_eq!(Request::decode(&request(RequestBody::ListTables).pack()).map(|(_, e)| e), Ok(Encoding::Native));
+    }
+
+    #[test]
+    fn test_reply_round_trip() {
+        let ternary = TernaryEntry { installed: 10, version: 12, ..TernaryEntry::new(4, &[1], &[0xff], &[2]) };
+        let replies = vec![
+            Reply::Ok,
+            Reply::Error("No such table".to_string()),
+            Reply::Result(vec![]),
+            Reply::Miss,
+            Reply::Counters(Counters { packets: 1, bytes: 2, last_hit: 3 }),
+            Reply::Ternary(vec![ternary.clone()]),
+            Reply::Direct(vec![direct_entry(), direct_entry()]),
+            Reply::Tables(vec![TableInfo {
+                name: "nexthop".to_string(),
+                kind: TableKind::Direct,
+                key_width: 2,
+                capacity: 16,
+                len: 1,
+                default_result: Some(vec![]),
+                version: 3,
+            }]),
+            Reply::Default(None),
+            Reply::Drift(vec![Drift {
+                table_id: "acl".to_string(),
+                missing: vec![Entry::Ternary(ternary.clone())],
+                extra: vec![Entry::Direct(direct_entry())],
+                mismatched: vec![(Entry::Ternary(ternary.clone()), Entry::Ternary(ternary))],
+            }]),
+            Reply::Reloaded(vec!["added acl:ternary:2:16".to_string()]),
+            Reply::Session(7),
+            Reply::Acked { sequence: 9, reply: Box::new(Reply::Conflict { current: 2 }) },
+        ];
+        for reply in replies {
+            assert_eq!(Reply::decode(&reply.encode(Encoding::Protobuf)), Ok(reply));
+        }
+
+        let event = Event { kind: EventKind::Released, table_id: "nexthop".to_string(), entry: Entry::Direct(direct_entry()) };
+        assert_eq!(Event::decode(&event.encode(Encoding::Protobuf)), Ok(event));
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let decode_request = |message: &messages::Request| Request::decode(&encode(message)).map(|_| ());
+        assert_eq!(decode_request(&messages::Request::default()), Err("Missing request body"));
+        let item = |priority, mask: &[u8]| messages::Request {
+            body: Some(request::Body::Ternary(messages::TernaryWrite {
+                item: Some(messages::CItem { table_id: "acl".to_string(), priority, key: vec![1], mask: mask.to_vec(), ..Default::default() }),
+                options: None,
+            })),
+            ..Default::default()
+        };
+        assert_eq!(decode_request(&item(1, &[0xff])), Ok(()));
+        assert_eq!(decode_request(&item(70000, &[0xff])), Err("Priority out of range"));
+        assert_eq!(decode_request(&item(1, &[])), Err("Key and mask length differ"));
+
+        let nested = messages::Reply::from(&Reply::Acked { sequence: 1, reply: Box::new(Reply::Acked { sequence: 2, reply: Box::new(Reply::Ok) }) });
+        assert_eq!(Reply::decode(&encode(&nested)), Err("Nested acknowledgement"));
+        assert_eq!(Reply::decode(b"MIPB\xff"), Err("Invalid protobuf message"));
+    }
+
+    #[test]
+    fn test_subscriber_encoding() {
+        let mut tables = Tables::default();
+        tables.declare("nexthop:direct:16".parse().unwrap()).unwrap();
+        let mut server = Server::new(tables);
+        let subscribe = request(RequestBody::Subscribe { queue: "/events".to_string(), tables: vec![], filter: None });
+        let (_, reply, encoding) = server.handle_message(&subscribe.encode(Encoding::Protobuf), None).unwrap();
+        assert_eq!((reply, encoding), (Reply::Acked { sequence: 7, reply: Box::new(Reply::Ok) }, Encoding::Protobuf));
+
+        let add = SItem::builder("nexthop").action(Actions::Add).index(3).value(b"eth0").build().unwrap();
+        let add = Request { reply_to: String::new(), sequence: 0, token: None, body: RequestBody::Direct(add, WriteOptions::default()) };
+        let (_, reply, encoding) = server.handle_message(&add.pack(), None).unwrap();
+        assert_eq!((reply, encoding), (Reply::Ok, Encoding::Native));
+        let notifications = server.take_notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].encoding, Encoding::Protobuf);
+        let event = Event::decode(&notifications[0].event.encode(notifications[0].encoding)).unwrap();
+        assert_eq!((event.kind, event.table_id.as_str()), (EventKind::Added, "nexthop"));
+    }
+}
@@ -0,0 +1,1030 @@
+//! Request and reply envelopes exchanged between clients and the server.
+//!
+//! Every request names the queue the reply should be sent to (empty for no
+//! reply), optionally followed by `SEQUENCE_TAG` and a sequence number and by
+//! `TOKEN_TAG` and an access token, followed by a tagged body. Lengths are
+//! encoded as `usize` little endian, like the `CItem` and `SItem` layouts.
+//!
+//! With the `protobuf` feature every message can also be sent as protobuf,
+//! see [`Encoding`] and `mem_ipc.proto`.
+
+#[cfg(feature = "protobuf")]
+use crate::proto;
+use crate::reconcile::Drift;
+use crate::tables::{Counters, DirectEntry, Entry, Owner, Table, TableKind, TernaryEntry, Timeouts};
+use crate::{Actions, CItem, SItem};
+
+/// Marks an access token in front of a request body.
+const TOKEN_TAG: u8 = 0xff;
+/// Marks a sequence number in front of a request body.
+const SEQUENCE_TAG: u8 = 0xfe;
+
+/// Starts every protobuf message. No native message starts like this: a
+/// request would announce a reply queue name of over a gigabyte, and replies
+/// and events start with a small tag.
+pub const PROTOBUF_MAGIC: &[u8; 4] = b"MIPB";
+
+/// How a message is laid out. The server answers a request in the encoding
+/// it arrived in, and notifies a subscriber in the encoding it subscribed in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    /// The `pack` layout.
+    #[default]
+    Native,
+    /// `PROTOBUF_MAGIC` followed by a message of `mem_ipc.proto`.
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl Encoding {
+    /// The encoding of `message`.
+    pub fn detect(message: &[u8]) -> Result<Encoding, &'static str> {
+        if !message.starts_with(PROTOBUF_MAGIC) {
+            return Ok(Encoding::Native);
+        }
+        #[cfg(feature = "protobuf")]
+        return Ok(Encoding::Protobuf);
+        #[cfg(not(feature = "protobuf"))]
+        Err("Protobuf messages are not supported by this build")
+    }
+}
+
+pub(crate) fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend(bytes.len().to_le_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+pub(crate) fn put_option_bytes(buffer: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            buffer.push(1);
+            put_bytes(buffer, bytes);
+        },
+        None => buffer.push(0),
+    }
+}
+
+/// Sequential reader over an encoded buffer.
+pub(crate) struct Cursor<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(buffer: &'a [u8]) -> Self {
+        Cursor { buffer, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if self.buffer.len() - self.offset < len {
+            return Err("Buffer too short to contain valid data");
+        }
+        let bytes = &self.buffer[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn get_u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn get_u16(&mut self) -> Result<u16, &'static str> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn get_u32(&mut self) -> Result<u32, &'static str> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn get_u64(&mut self) -> Result<u64, &'static str> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn get_bytes(&mut self) -> Result<&'a [u8], &'static str> {
+        let len = usize::from_le_bytes(self.take(std::mem::size_of::<usize>())?.try_into().unwrap());
+        self.take(len)
+    }
+
+    pub(crate) fn get_option_bytes(&mut self) -> Result<Option<&'a [u8]>, &'static str> {
+        match self.get_u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.get_bytes()?)),
+        }
+    }
+
+    pub(crate) fn get_str(&mut self) -> Result<&'a str, &'static str> {
+        std::str::from_utf8(self.get_bytes()?).map_err(|_| "String is not valid UTF-8")
+    }
+
+    pub(crate) fn get_string(&mut self) -> Result<String, &'static str> {
+        self.get_str().map(str::to_string)
+    }
+
+    /// Everything not consumed yet.
+    pub(crate) fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buffer[self.offset..];
+        self.offset = self.buffer.len();
+        rest
+    }
+}
+
+/// Per-write settings that travel next to a `CItem` or `SItem`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WriteOptions {
+    /// Aging of the entry written by an `Add`.
+    pub timeouts: Timeouts,
+    /// Session that owns the entry written by an `Add`, 0 for none. Writing
+    /// in a session also renews its lease.
+    pub session: u64,
+    /// Keep the entry when its session ends.
+    pub persistent: bool,
+    /// Apply an `Add` or `Delete` only if the entry is at this version, 0
+    /// meaning that it must not exist. The reply is `Reply::Conflict` if not.
+    pub expected_version: Option<u64>,
+}
+
+impl WriteOptions {
+    fn write_to(&self, buffer: &mut Vec<u8>) {
+        buffer.extend(self.timeouts.idle.to_le_bytes());
+        buffer.extend(self.timeouts.hard.to_le_bytes());
+        buffer.extend(self.session.to_le_bytes());
+        buffer.push(self.persistent as u8);
+        match self.expected_version {
+            Some(version) => {
+                buffer.push(1);
+                buffer.extend(version.to_le_bytes());
+            },
+            None => buffer.push(0),
+        }
+    }
+
+    fn read_from(cursor: &mut Cursor) -> Result<WriteOptions, &'static str> {
+        Ok(WriteOptions {
+            timeouts: Timeouts { idle: cursor.get_u32()?, hard: cursor.get_u32()? },
+            session: cursor.get_u64()?,
+            persistent: cursor.get_u8()? != 0,
+            expected_version: match cursor.get_u8()? {
+                0 => None,
+                _ => Some(cursor.get_u64()?),
+            },
+        })
+    }
+}
+
+pub enum RequestBody {
+    /// Add, delete, query or counter access on a ternary table entry.
+    Ternary(CItem, WriteOptions),
+    /// Add, delete, query or counter access on a direct table entry.
+    Direct(SItem, WriteOptions),
+    /// Runs the software classifier on `key` and accounts `bytes` on the
+    /// matching entry. For direct tables the key is the little endian index.
+    Lookup { table_id: String, key: Vec<u8>, bytes: u32 },
+    /// Returns every entry of a table, including counters.
+    Dump { table_id: String },
+    /// Clears the counters of every entry of a table.
+    ResetCounters { table_id: String },
+    ListTables,
+    /// Sends an `Event` to `queue` for every change to the named tables (all
+    /// tables if empty) whose key passes `filter`.
+    Subscribe { queue: String, tables: Vec<String>, filter: Option<KeyFilter> },
+    /// Removes every subscription of `queue`.
+    Unsubscribe { queue: String },
+    /// Sets the result lookups return when no entry matches, or clears it.
+    SetDefault { table_id: String, result: Option<Vec<u8>> },
+    GetDefault { table_id: String },
+    /// Writes all tables to the server's snapshot file.
+    SaveSnapshot,
+    /// Compares every table with the backend and, if `repair` is set,
+    /// reprograms the backend to match.
+    Reconcile { repair: bool },
+    /// Re-reads the server's configuration file. Changes that drop entries are
+    /// refused unless `force` is set.
+    Reload { force: bool },
+    /// Starts a session that lives for `lease_ms` after each heartbeat. The
+    /// reply is `Reply::Session` with its id.
+    OpenSession { lease_ms: u32 },
+    /// Renews the lease of a session.
+    Heartbeat { session: u64 },
+    /// Ends a session as if its lease had expired.
+    CloseSession { session: u64 },
+}
+
+impl RequestBody {
+    /// Whether the request only reads, so applying it again is harmless.
+    pub fn reads_only(&self) -> bool {
+        match self {
+            RequestBody::Ternary(CItem { action, .. }, _) | RequestBody::Direct(SItem { action, .. }, _) => {
+                matches!(action, Actions::Noop | Actions::Query | Actions::ReadCounters)
+            },
+            RequestBody::Lookup { .. } | RequestBody::Dump { .. } | RequestBody::GetDefault { .. } | RequestBody::ListTables => true,
+            _ => false,
+        }
+    }
+}
+
+/// Value/mask filter on entry keys. For direct tables the key is the little
+/// endian index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyFilter {
+    pub key: Vec<u8>,
+    pub mask: Vec<u8>,
+}
+
+impl KeyFilter {
+    pub fn matches(&self, key: &[u8]) -> bool {
+        key.len() == self.key.len()
+            && key.iter().zip(&self.mask).zip(&self.key).all(|((k, m), f)| k & m == f & m)
+    }
+}
+
+pub struct Request {
+    pub reply_to: String,
+    /// Number the client gives the request, 0 for none. The server applies a
+    /// numbered request only once per client and reply queue and answers it
+    /// with `Reply::Acked`, repeating the first reply for a duplicate.
+    /// Requests that only read are numbered too, so the client can tell their
+    /// reply from a late one; the server does not keep their replies.
+    pub sequence: u64,
+    /// Secret identifying the client to the server's access policy.
+    pub token: Option<String>,
+    pub body: RequestBody,
+}
+
+impl Request {
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        put_bytes(&mut buffer, self.reply_to.as_bytes());
+        if self.sequence != 0 {
+            buffer.push(SEQUENCE_TAG);
+            buffer.extend(self.sequence.to_le_bytes());
+        }
+        if let Some(token) = &self.token {
+            buffer.push(TOKEN_TAG);
+            put_bytes(&mut buffer, token.as_bytes());
+        }
+        match &self.body {
+            RequestBody::Ternary(item, options) => {
+                buffer.push(1);
+                options.write_to(&mut buffer);
+                item.pack(&mut buffer);
+            },
+            RequestBody::Direct(item, options) => {
+                buffer.push(2);
+                options.write_to(&mut buffer);
+                item.pack(&mut buffer);
+            },
+            RequestBody::Lookup { table_id, key, bytes } => {
+                buffer.push(3);
+                put_bytes(&mut buffer, table_id.as_bytes());
+                put_bytes(&mut buffer, key);
+                buffer.extend(bytes.to_le_bytes());
+            },
+            RequestBody::Dump { table_id } => {
+                buffer.push(4);
+                put_bytes(&mut buffer, table_id.as_bytes());
+            },
+            RequestBody::ResetCounters { table_id } => {
+                buffer.push(5);
+                put_bytes(&mut buffer, table_id.as_bytes());
+            },
+            RequestBody::ListTables => buffer.push(6),
+            RequestBody::Subscribe { queue, tables, filter } => {
+                buffer.push(7);
+                put_bytes(&mut buffer, queue.as_bytes());
+                buffer.extend(tables.len().to_le_bytes());
+                for table in tables {
+                    put_bytes(&mut buffer, table.as_bytes());
+                }
+                match filter {
+                    Some(filter) => {
+                        buffer.push(1);
+                        put_bytes(&mut buffer, &filter.key);
+                        put_bytes(&mut buffer, &filter.mask);
+                    },
+                    None => buffer.push(0),
+                }
+            },
+            RequestBody::Unsubscribe { queue } => {
+                buffer.push(8);
+                put_bytes(&mut buffer, queue.as_bytes());
+            },
+            RequestBody::SetDefault { table_id, result } => {
+                buffer.push(9);
+                put_bytes(&mut buffer, table_id.as_bytes());
+                put_option_bytes(&mut buffer, result.as_deref());
+            },
+            RequestBody::GetDefault { table_id } => {
+                buffer.push(10);
+                put_bytes(&mut buffer, table_id.as_bytes());
+            },
+            RequestBody::SaveSnapshot => buffer.push(11),
+            RequestBody::Reconcile { repair } => {
+                buffer.push(12);
+                buffer.push(*repair as u8);
+            },
+            RequestBody::Reload { force } => {
+                buffer.push(13);
+                buffer.push(*force as u8);
+            },
+            RequestBody::OpenSession { lease_ms } => {
+                buffer.push(14);
+                buffer.extend(lease_ms.to_le_bytes());
+            },
+            RequestBody::Heartbeat { session } => {
+                buffer.push(15);
+                buffer.extend(session.to_le_bytes());
+            },
+            RequestBody::CloseSession { session } => {
+                buffer.push(16);
+                buffer.extend(session.to_le_bytes());
+            },
+        }
+        buffer
+    }
+
+    pub fn unpack(buffer: &[u8]) -> Result<Request, &'static str> {
+        let mut cursor = Cursor::new(buffer);
+        let reply_to = cursor.get_string()?;
+        let mut tag = cursor.get_u8()?;
+        let mut sequence = 0;
+        if tag == SEQUENCE_TAG {
+            sequence = cursor.get_u64()?;
+            tag = cursor.get_u8()?;
+        }
+        let mut token = None;
+        if tag == TOKEN_TAG {
+            token = Some(cursor.get_string()?);
+            tag = cursor.get_u8()?;
+        }
+        let body = match tag {
+            1 => {
+                let options = WriteOptions::read_from(&mut cursor)?;
+                RequestBody::Ternary(CItem::unpack(cursor.rest())?, options)
+            },
+            2 => {
+                let options = WriteOptions::read_from(&mut cursor)?;
+                RequestBody::Direct(SItem::unpack(cursor.rest())?, options)
+            },
+            3 => RequestBody::Lookup {
+                table_id: cursor.get_string()?,
+                key: cursor.get_bytes()?.to_vec(),
+                bytes: cursor.get_u32()?,
+            },
+            4 => RequestBody::Dump { table_id: cursor.get_string()? },
+            5 => RequestBody::ResetCounters { table_id: cursor.get_string()? },
+            6 => RequestBody::ListTables,
+            7 => {
+                let queue = cursor.get_string()?;
+                let count = cursor.get_u64()? as usize;
+                let mut tables = Vec::new();
+                for _ in 0..count {
+                    tables.push(cursor.get_string()?);
+                }
+                let filter = match cursor.get_u8()? {
+                    0 => None,
+                    _ => Some(KeyFilter { key: cursor.get_bytes()?.to_vec(), mask: cursor.get_bytes()?.to_vec() }),
+                };
+                RequestBody::Subscribe { queue, tables, filter }
+            },
+            8 => RequestBody::Unsubscribe { queue: cursor.get_string()? },
+            9 => RequestBody::SetDefault {
+                table_id: cursor.get_string()?,
+                result: cursor.get_option_bytes()?.map(|r| r.to_vec()),
+            },
+            10 => RequestBody::GetDefault { table_id: cursor.get_string()? },
+            11 => RequestBody::SaveSnapshot,
+            12 => RequestBody::Reconcile { repair: cursor.get_u8()? != 0 },
+            13 => RequestBody::Reload { force: cursor.get_u8()? != 0 },
+            14 => RequestBody::OpenSession { lease_ms: cursor.get_u32()? },
+            15 => RequestBody::Heartbeat { session: cursor.get_u64()? },
+            16 => RequestBody::CloseSession { session: cursor.get_u64()? },
+            _ => return Err("Invalid request type"),
+        };
+        Ok(Request { reply_to, sequence, token, body })
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        match encoding {
+            Encoding::Native => self.pack(),
+            #[cfg(feature = "protobuf")]
+            Encoding::Protobuf => proto::encode(&proto::messages::Request::from(self)),
+        }
+    }
+
+    /// Decodes a request in either encoding, also returning the encoding the
+    /// reply should use.
+    pub fn decode(message: &[u8]) -> Result<(Request, Encoding), &'static str> {
+        let encoding = Encoding::detect(message)?;
+        let request = match encoding {
+            Encoding::Native => Request::unpack(message)?,
+            #[cfg(feature = "protobuf")]
+            Encoding::Protobuf => proto::decode::<proto::messages::Request>(message)?.try_into()?,
+        };
+        Ok((request, encoding))
+    }
+}
+
+/// Summary of a declared table, as returned by `ListTables`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableInfo {
+    pub name: String,
+    pub kind: TableKind,
+    pub key_width: usize,
+    pub capacity: usize,
+    pub len: usize,
+    pub default_result: Option<Vec<u8>>,
+    pub version: u64,
+}
+
+impl From<&Table> for TableInfo {
+    fn from(table: &Table) -> Self {
+        let spec = table.spec();
+        TableInfo {
+            name: spec.name.clone(),
+            kind: spec.kind,
+            key_width: spec.key_width,
+            capacity: spec.capacity,
+            len: table.len(),
+            default_result: table.default_result().map(|r| r.to_vec()),
+            version: table.version(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    Ok,
+    Error(String),
+    /// Result of a classifier hit.
+    Result(Vec<u8>),
+    /// The classifier found no matching entry.
+    Miss,
+    Counters(Counters),
+    Ternary(Vec<TernaryEntry>),
+    Direct(Vec<DirectEntry>),
+    Tables(Vec<TableInfo>),
+    /// The table's default result, if one is set.
+    Default(Option<Vec<u8>>),
+    /// Tables that differ from the backend, as found before any repair.
+    Drift(Vec<Drift>),
+    /// What a reload changed, one line per change.
+    Reloaded(Vec<String>),
+    /// Id of the session `OpenSession` started.
+    Session(u64),
+    /// Reply to the request numbered `sequence`.
+    Acked { sequence: u64, reply: Box<Reply> },
+    /// A write expected another version of the entry; it is at `current`, 0
+    /// if it does not exist.
+    Conflict { current: u64 },
+}
+
+fn put_counters(buffer: &mut Vec<u8>, counters: &Counters) {
+    buffer.extend(counters.packets.to_le_bytes());
+    buffer.extend(counters.bytes.to_le_bytes());
+    buffer.extend(counters.last_hit.to_le_bytes());
+}
+
+fn get_counters(cursor: &mut Cursor) -> Result<Counters, &'static str> {
+    Ok(Counters {
+        packets: cursor.get_u64()?,
+        bytes: cursor.get_u64()?,
+        last_hit: cursor.get_u64()?,
+    })
+}
+
+fn put_timeouts(buffer: &mut Vec<u8>, timeouts: &Timeouts, installed: u64) {
+    buffer.extend(timeouts.idle.to_le_bytes());
+    buffer.extend(timeouts.hard.to_le_bytes());
+    buffer.extend(installed.to_le_bytes());
+}
+
+/// Layout of the entries written by `put_ternary_entry` and
+/// `put_direct_entry`. Snapshots record it as their version: format 1 has no
+/// owners, format 2 no versions.
+pub(crate) const ENTRY_FORMAT: u8 = 3;
+
+fn put_owner(buffer: &mut Vec<u8>, owner: &Owner) {
+    buffer.extend(owner.session.to_le_bytes());
+    buffer.push(owner.persistent as u8);
+}
+
+fn get_owner(cursor: &mut Cursor) -> Result<Owner, &'static str> {
+    Ok(Owner { session: cursor.get_u64()?, persistent: cursor.get_u8()? != 0 })
+}
+
+pub(crate) fn put_ternary_entry(buffer: &mut Vec<u8>, entry: &TernaryEntry) {
+    buffer.extend(entry.priority.to_le_bytes());
+    put_bytes(buffer, &entry.key);
+    put_bytes(buffer, &entry.mask);
+    put_bytes(buffer, &entry.result);
+    put_counters(buffer, &entry.counters);
+    put_timeouts(buffer, &entry.timeouts, entry.installed);
+    put_owner(buffer, &entry.owner);
+    buffer.extend(entry.version.to_le_bytes());
+}
+
+/// Reads an entry written by `put_ternary_entry` in `format`, see `ENTRY_FORMAT`.
+pub(crate) fn get_ternary_entry(cursor: &mut Cursor, format: u8) -> Result<TernaryEntry, &'static str> {
+    Ok(TernaryEntry {
+        priority: cursor.get_u16()?,
+        key: cursor.get_bytes()?.to_vec(),
+        mask: cursor.get_bytes()?.to_vec(),
+        result: cursor.get_bytes()?.to_vec(),
+        counters: get_counters(cursor)?,
+        timeouts: Timeouts { idle: cursor.get_u32()?, hard: cursor.get_u32()? },
+        installed: cursor.get_u64()?,
+        owner: if format >= 2 { get_owner(cursor)? } else { Owner::default() },
+        version: if format >= 3 { cursor.get_u64()? } else { 0 },
+    })
+}
+
+pub(crate) fn put_direct_entry(buffer: &mut Vec<u8>, entry: &DirectEntry) {
+    buffer.extend(entry.index.to_le_bytes());
+    put_bytes(buffer, &entry.value);
+    put_counters(buffer, &entry.counters);
+    put_timeouts(buffer, &entry.timeouts, entry.installed);
+    put_owner(buffer, &entry.owner);
+    buffer.extend(entry.version.to_le_bytes());
+}
+
+/// Reads an entry written by `put_direct_entry`, see `get_ternary_entry`.
+pub(crate) fn get_direct_entry(cursor: &mut Cursor, format: u8) -> Result<DirectEntry, &'static str> {
+    Ok(DirectEntry {
+        index: cursor.get_u16()?,
+        value: cursor.get_bytes()?.to_vec(),
+        counters: get_counters(cursor)?,
+        timeouts: Timeouts { idle: cursor.get_u32()?, hard: cursor.get_u32()? },
+        installed: cursor.get_u64()?,
+        owner: if format >= 2 { get_owner(cursor)? } else { Owner::default() },
+        version: if format >= 3 { cursor.get_u64()? } else { 0 },
+    })
+}
+
+fn put_entry(buffer: &mut Vec<u8>, entry: &Entry) {
+    match entry {
+        Entry::Ternary(entry) => {
+            buffer.push(0);
+            put_ternary_entry(buffer, entry);
+        },
+        Entry::Direct(entry) => {
+            buffer.push(1);
+            put_direct_entry(buffer, entry);
+        },
+    }
+}
+
+fn get_entry(cursor: &mut Cursor) -> Result<Entry, &'static str> {
+    match cursor.get_u8()? {
+        0 => Ok(Entry::Ternary(get_ternary_entry(cursor, ENTRY_FORMAT)?)),
+        1 => Ok(Entry::Direct(get_direct_entry(cursor, ENTRY_FORMAT)?)),
+        _ => Err("Invalid entry kind"),
+    }
+}
+
+fn put_entries(buffer: &mut Vec<u8>, entries: &[Entry]) {
+    buffer.extend(entries.len().to_le_bytes());
+    entries.iter().for_each(|e| put_entry(buffer, e));
+}
+
+fn get_entries(cursor: &mut Cursor) -> Result<Vec<Entry>, &'static str> {
+    let count = cursor.get_u64()? as usize;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        entries.push(get_entry(cursor)?);
+    }
+    Ok(entries)
+}
+
+impl Reply {
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        match self {
+            Reply::Ok => buffer.push(0),
+            Reply::Error(message) => {
+                buffer.push(1);
+                put_bytes(&mut buffer, message.as_bytes());
+            },
+            Reply::Result(result) => {
+                buffer.push(2);
+                put_bytes(&mut buffer, result);
+            },
+            Reply::Miss => buffer.push(3),
+            Reply::Counters(counters) => {
+                buffer.push(4);
+                put_counters(&mut buffer, counters);
+            },
+            Reply::Ternary(entries) => {
+                buffer.push(5);
+                buffer.extend(entries.len().to_le_bytes());
+                for entry in entries {
+                    put_ternary_entry(&mut buffer, entry);
+                }
+            },
+            Reply::Direct(entries) => {
+                buffer.push(6);
+                buffer.extend(entries.len().to_le_bytes());
+                for entry in entries {
+                    put_direct_entry(&mut buffer, entry);
+                }
+            },
+            Reply::Tables(tables) => {
+                buffer.push(7);
+                buffer.extend(tables.len().to_le_bytes());
+                for table in tables {
+                    put_bytes(&mut buffer, table.name.as_bytes());
+                    buffer.push(match table.kind {
+                        TableKind::Ternary => 0,
+                        TableKind::Direct => 1,
+                    });
+                    buffer.extend((table.key_width as u64).to_le_bytes());
+                    buffer.extend((table.capacity as u64).to_le_bytes());
+                    buffer.extend((table.len as u64).to_le_bytes());
+                    put_option_bytes(&mut buffer, table.default_result.as_deref());
+                    buffer.extend(table.version.to_le_bytes());
+                }
+            },
+            Reply::Default(result) => {
+                buffer.push(8);
+                put_option_bytes(&mut buffer, result.as_deref());
+            },
+            Reply::Drift(drifts) => {
+                buffer.push(9);
+                buffer.extend(drifts.len().to_le_bytes());
+                for drift in drifts {
+                    put_bytes(&mut buffer, drift.table_id.as_bytes());
+                    put_entries(&mut buffer, &drift.missing);
+                    put_entries(&mut buffer, &drift.extra);
+                    buffer.extend(drift.mismatched.len().to_le_bytes());
+                    for (shadow, installed) in &drift.mismatched {
+                        put_entry(&mut buffer, shadow);
+                        put_entry(&mut buffer, installed);
+                    }
+                }
+            },
+            Reply::Reloaded(changes) => {
+                buffer.push(10);
+                buffer.extend(changes.len().to_le_bytes());
+                for change in changes {
+                    put_bytes(&mut buffer, change.as_bytes());
+                }
+            },
+            Reply::Session(session) => {
+                buffer.push(11);
+                buffer.extend(session.to_le_bytes());
+            },
+            Reply::Acked { sequence, reply } => {
+                buffer.push(12);
+                buffer.extend(sequence.to_le_bytes());
+                buffer.extend(reply.pack());
+            },
+            Reply::Conflict { current } => {
+                buffer.push(13);
+                buffer.extend(current.to_le_bytes());
+            },
+        }
+        buffer
+    }
+
+    pub fn unpack(buffer: &[u8]) -> Result<Reply, &'static str> {
+        let mut cursor = Cursor::new(buffer);
+        let reply = match cursor.get_u8()? {
+            0 => Reply::Ok,
+            1 => Reply::Error(cursor.get_string()?),
+            2 => Reply::Result(cursor.get_bytes()?.to_vec()),
+            3 => Reply::Miss,
+            4 => Reply::Counters(get_counters(&mut cursor)?),
+            5 => {
+                let count = cursor.get_u64()? as usize;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push(get_ternary_entry(&mut cursor, ENTRY_FORMAT)?);
+                }
+                Reply::Ternary(entries)
+            },
+            6 => {
+                let count = cursor.get_u64()? as usize;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push(get_direct_entry(&mut cursor, ENTRY_FORMAT)?);
+                }
+                Reply::Direct(entries)
+            },
+            7 => {
+                let count = cursor.get_u64()? as usize;
+                let mut tables = Vec::new();
+                for _ in 0..count {
+                    tables.push(TableInfo {
+                        name: cursor.get_string()?,
+                        kind: match cursor.get_u8()? {
+                            0 => TableKind::Ternary,
+                            1 => TableKind::Direct,
+                            _ => return Err("Invalid table kind"),
+                        },
+                        key_width: cursor.get_u64()? as usize,
+                        capacity: cursor.get_u64()? as usize,
+                        len: cursor.get_u64()? as usize,
+                        default_result: cursor.get_option_bytes()?.map(|r| r.to_vec()),
+                        version: cursor.get_u64()?,
+                    });
+                }
+                Reply::Tables(tables)
+            },
+            8 => Reply::Default(cursor.get_option_bytes()?.map(|r| r.to_vec())),
+            9 => {
+                let count = cursor.get_u64()? as usize;
+                let mut drifts = Vec::new();
+                for _ in 0..count {
+                    let table_id = cursor.get_string()?;
+                    let missing = get_entries(&mut cursor)?;
+                    let extra = get_entries(&mut cursor)?;
+                    let mut mismatched = Vec::new();
+                    for _ in 0..cursor.get_u64()? {
+                        mismatched.push((get_entry(&mut cursor)?, get_entry(&mut cursor)?));
+                    }
+                    drifts.push(Drift { table_id, missing, extra, mismatched });
+                }
+                Reply::Drift(drifts)
+            },
+            10 => {
+                let count = cursor.get_u64()? as usize;
+                let mut changes = Vec::new();
+                for _ in 0..count {
+                    changes.push(cursor.get_string()?);
+                }
+                Reply::Reloaded(changes)
+            },
+            11 => Reply::Session(cursor.get_u64()?),
+            12 => {
+                let sequence = cursor.get_u64()?;
+                match Reply::unpack(cursor.rest())? {
+                    Reply::Acked { .. } => return Err("Nested acknowledgement"),
+                    reply => Reply::Acked { sequence, reply: Box::new(reply) },
+                }
+            },
+            13 => Reply::Conflict { current: cursor.get_u64()? },
+            _ => return Err("Invalid reply type"),
+        };
+        Ok(reply)
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        match encoding {
+            Encoding::Native => self.pack(),
+            #[cfg(feature = "protobuf")]
+            Encoding::Protobuf => proto::encode(&proto::messages::Reply::from(self)),
+        }
+    }
+
+    /// Decodes a reply in either encoding.
+    pub fn decode(message: &[u8]) -> Result<Reply, &'static str> {
+        match Encoding::detect(message)? {
+            Encoding::Native => Reply::unpack(message),
+            #[cfg(feature = "protobuf")]
+            Encoding::Protobuf => proto::decode::<proto::messages::Reply>(message)?.try_into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    /// The entry's idle or hard timeout passed and the server removed it.
+    Expired,
+    Added,
+    Modified,
+    Deleted,
+    /// The session that owned the entry ended and the server removed it.
+    Released,
+}
+
+/// Notification about a change the server made to a table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub table_id: String,
+    pub entry: Entry,
+}
+
+impl Event {
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.push(match self.kind {
+            EventKind::Expired => 0,
+            EventKind::Added => 1,
+            EventKind::Modified => 2,
+            EventKind::Deleted => 3,
+            EventKind::Released => 4,
+        });
+        put_bytes(&mut buffer, self.table_id.as_bytes());
+        put_entry(&mut buffer, &self.entry);
+        buffer
+    }
+
+    pub fn unpack(buffer: &[u8]) -> Result<Event, &'static str> {
+        let mut cursor = Cursor::new(buffer);
+        let kind = match cursor.get_u8()? {
+            0 => EventKind::Expired,
+            1 => EventKind::Added,
+            2 => EventKind::Modified,
+            3 => EventKind::Deleted,
+            4 => EventKind::Released,
+            _ => return Err("Invalid event kind"),
+        };
+        let table_id = cursor.get_string()?;
+        let entry = get_entry(&mut cursor)?;
+        Ok(Event { kind, table_id, entry })
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        match encoding {
+            Encoding::Native => self.pack(),
+            #[cfg(feature = "protobuf")]
+            Encoding::Protobuf => proto::encode(&proto::messages::Event::from(self)),
+        }
+    }
+
+    /// Decodes an event in either encoding.
+    pub fn decode(message: &[u8]) -> Result<Event, &'static str> {
+        match Encoding::detect(message)? {
+            Encoding::Native => Event::unpack(message),
+            #[cfg(feature = "protobuf")]
+            Encoding::Protobuf => proto::decode::<proto::messages::Event>(message)?.try_into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod protocol_tests {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let request = Request {
+            reply_to: "/client.reply".to_string(),
+            sequence: 0,
+            token: None,
+            body: RequestBody::Ternary(CItem {
+                table_id: "acl".to_string(),
+                action: Actions::ReadCounters,
+                p: 7,
+                k: vec![1, 2],
+                m: vec![0xff, 0],
+                r: vec![],
+            }, WriteOptions {
+                timeouts: Timeouts { idle: 30, hard: 300 },
+                session: 5,
+                persistent: true,
+                expected_version: Some(0),
+            }),
+        };
+        let decoded = Request::unpack(&request.pack()).unwrap();
+        assert_eq!(decoded.reply_to, "/client.reply");
+        assert_eq!(decoded.token, None);
+        match decoded.body {
+            RequestBody::Ternary(item, options) => {
+                assert_eq!(options.timeouts, Timeouts { idle: 30, hard: 300 });
+                assert_eq!((options.session, options.persistent), (5, true));
+                assert_eq!(options.expected_version, Some(0));
+                assert_eq!(item.table_id, "acl");
+                assert_eq!(item.action, Actions::ReadCounters);
+                assert_eq!(item.p, 7);
+                assert_eq!(item.k, vec![1, 2]);
+            },
+            _ => panic!("Wrong request type"),
+        }
+
+        let request = Request {
+            reply_to: String::new(),
+            sequence: 12,
+            token: Some("s3cret".to_string()),
+            body: RequestBody::Lookup { table_id: "acl".to_string(), key: vec![1, 2], bytes: 64 },
+        };
+        let decoded = Request::unpack(&request.pack()).unwrap();
+        assert_eq!(decoded.token.as_deref(), Some("s3cret"));
+        assert_eq!(decoded.sequence, 12);
+        match decoded.body {
+            RequestBody::Lookup { table_id, key, bytes } => {
+                assert_eq!((table_id.as_str(), key, bytes), ("acl", vec![1, 2], 64));
+            },
+            _ => panic!("Wrong request type"),
+        }
+    }
+
+    #[test]
+    fn test_reads_only() {
+        let item = |action| CItem { table_id: "acl".to_string(), action, p: 1, k: vec![], m: vec![], r: vec![] };
+        assert!(RequestBody::Ternary(item(Actions::Query), WriteOptions::default()).reads_only());
+        assert!(!RequestBody::Ternary(item(Actions::Add), WriteOptions::default()).reads_only());
+        assert!(RequestBody::Dump { table_id: "acl".to_string() }.reads_only());
+        assert!(!RequestBody::SaveSnapshot.reads_only());
+    }
+
+    #[test]
+    fn test_reply_round_trip() {
+        let replies = vec![
+            Reply::Ok,
+            Reply::Error("No such table".to_string()),
+            Reply::Result(vec![9, 9]),
+            Reply::Miss,
+            Reply::Counters(Counters { packets: 1, bytes: 2, last_hit: 3 }),
+            Reply::Ternary(vec![TernaryEntry {
+                counters: Counters { packets: 5, bytes: 6, last_hit: 7 },
+                timeouts: Timeouts { idle: 8, hard: 9 },
+                installed: 10,
+                owner: Owner { session: 11, persistent: true },
+                version: 12,
+                ..TernaryEntry::new(4, &[1], &[0xff], &[2])
+            }]),
+            Reply::Direct(vec![direct_entry()]),
+            Reply::Tables(vec![TableInfo {
+                name: "acl".to_string(),
+                kind: TableKind::Ternary,
+                key_width: 2,
+                capacity: 16,
+                len: 1,
+                default_result: Some(vec![0xde, 0xad]),
+                version: 3,
+            }]),
+            Reply::Default(None),
+            Reply::Default(Some(vec![1])),
+            Reply::Drift(vec![Drift {
+                table_id: "nexthop".to_string(),
+                missing: vec![Entry::Direct(direct_entry())],
+                extra: vec![],
+                mismatched: vec![(Entry::Direct(direct_entry()), Entry::Direct(direct_entry()))],
+            }]),
+            Reply::Reloaded(vec!["added acl:ternary:2:16".to_string()]),
+            Reply::Session(7),
+            Reply::Acked { sequence: 9, reply: Box::new(Reply::Error("Table is full".to_string())) },
+            Reply::Conflict { current: 0 },
+        ];
+        for reply in replies {
+            assert_eq!(Reply::unpack(&reply.pack()).unwrap(), reply);
+        }
+    }
+
+    fn direct_entry() -> DirectEntry {
+        DirectEntry { owner: Owner { session: 2, persistent: false }, version: 4, ..DirectEntry::new(3, &[8]) }
+    }
+
+    #[test]
+    fn test_event_round_trip() {
+        for kind in [EventKind::Expired, EventKind::Released] {
+            let event = Event { kind, table_id: "nexthop".to_string(), entry: Entry::Direct(direct_entry()) };
+            assert_eq!(Event::unpack(&event.pack()).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn test_subscribe_round_trip() {
+        let request = Request {
+            reply_to: String::new(),
+            sequence: 0,
+            token: None,
+            body: RequestBody::Subscribe {
+                queue: "/agent.events".to_string(),
+                tables: vec!["acl".to_string(), "nexthop".to_string()],
+                filter: Some(KeyFilter { key: vec![10, 0], mask: vec![0xff, 0] }),
+            },
+        };
+        match Request::unpack(&request.pack()).unwrap().body {
+            RequestBody::Subscribe { queue, tables, filter } => {
+                assert_eq!(queue, "/agent.events");
+                assert_eq!(tables, vec!["acl", "nexthop"]);
+                let filter = filter.unwrap();
+                assert!(filter.matches(&[10, 7]));
+                assert!(!filter.matches(&[11, 7]));
+                assert!(!filter.matches(&[10]));
+            },
+            _ => panic!("Wrong request type"),
+        }
+    }
+
+    #[test]
+    fn test_set_default_round_trip() {
+        let request = Request {
+            reply_to: String::new(),
+            sequence: 0,
+            token: None,
+            body: RequestBody::SetDefault { table_id: "acl".to_string(), result: Some(vec![0, 1]) },
+        };
+        match Request::unpack(&request.pack()).unwrap().body {
+            RequestBody::SetDefault { table_id, result } => {
+                assert_eq!(table_id, "acl");
+                assert_eq!(result, Some(vec![0, 1]));
+            },
+            _ => panic!("Wrong request type"),
+        }
+    }
+
+    #[test]
+    fn test_truncated_request() {
+        let request = Request {
+            reply_to: String::new(),
+            sequence: 0,
+            token: None,
+            body: RequestBody::Dump { table_id: "acl".to_string() },
+        };
+        let buffer = request.pack();
+        assert!(Request::unpack(&buffer[..buffer.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! Comparison of the shadow tables with what a backend actually holds.
+//!
+//! Devices can lose or change entries behind the server's back, e.g. on a
+//! reset. [`diff`] finds where a table and the entries read back from the
+//! backend disagree; the server can then push the shadow copy again.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::tables::{Entry, Table};
+
+/// Differences between a shadow table and the backend.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Drift {
+    pub table_id: String,
+    /// In the shadow table but not installed.
+    pub missing: Vec<Entry>,
+    /// Installed but not in the shadow table.
+    pub extra: Vec<Entry>,
+    /// Installed with a different result, as `(shadow, installed)`.
+    pub mismatched: Vec<(Entry, Entry)>,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} missing={} extra={} mismatched={}",
+               self.table_id, self.missing.len(), self.extra.len(), self.mismatched.len())
+    }
+}
+
+/// What [`Entry::same_key`] compares, so entries can be looked up by it.
+#[derive(PartialEq, Eq, Hash)]
+enum Key<'a> {
+    Ternary(u16, &'a [u8], &'a [u8]),
+    Direct(u16),
+}
+
+fn key(entry: &Entry) -> Key<'_> {
+    match entry {
+        Entry::Ternary(e) => Key::Ternary(e.priority, &e.key, &e.mask),
+        Entry::Direct(e) => Key::Direct(e.index),
+    }
+}
+
+pub fn diff(table: &Table, installed: &[Entry]) -> Drift {
+    let shadow: Vec<Entry> = match table {
+        Table::Ternary(t) => t.entries().iter().cloned().map(Entry::Ternary).collect(),
+        Table::Direct(t) => t.entries().cloned().map(Entry::Direct).collect(),
+    };
+    let by_key: HashMap<Key, &Entry> = installed.iter().map(|i| (key(i), i)).collect();
+    let mut drift = Drift { table_id: table.spec().name.clone(), ..Drift::default() };
+    for entry in &shadow {
+        match by_key.get(&key(entry)) {
+            None => drift.missing.push(entry.clone()),
+            Some(i) if !i.same_programming(entry) => drift.mismatched.push((entry.clone(), (*i).clone())),
+            Some(_) => {},
+        }
+    }
+    let shadow_keys: HashSet<Key> = shadow.iter().map(key).collect();
+    drift.extra = installed.iter().filter(|i| !shadow_keys.contains(&key(i))).cloned().collect();
+    drift
+}
+
+#[cfg(test)]
+mod reconcile_tests {
+    use super::*;
+    use crate::tables::{DirectEntry, Tables, Timeouts};
+
+    fn direct(index: u16, value: &[u8]) -> Entry {
+        Entry::Direct(DirectEntry::new(index, value))
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut tables = Tables::default();
+        tables.declare("nexthop:direct:16".parse().unwrap()).unwrap();
+        let table = tables.get_mut("nexthop").unwrap().as_direct_mut().unwrap();
+        table.insert(1, b"a", Timeouts::default(), 0).unwrap();
+        table.insert(2, b"b", Timeouts::default(), 0).unwrap();
+        table.insert(3, b"c", Timeouts::default(), 0).unwrap();
+        // Counters do not count as drift.
+        table.lookup(3, 64, 5);
+
+        let table = tables.get("nexthop").unwrap();
+        let drift = diff(table, &[direct(2, b"x"), direct(3, b"c"), direct(4, b"d")]);
+        assert!(matches!(&drift.missing[..], [entry] if entry.same_programming(&direct(1, b"a"))));
+        assert_eq!(drift.extra, vec![direct(4, b"d")]);
+        assert_eq!(drift.mismatched.len(), 1);
+        assert_eq!(drift.mismatched[0].1, direct(2, b"x"));
+        assert_eq!(drift.to_string(), "nexthop missing=1 extra=1 mismatched=1");
+
+        assert!(diff(table, &[direct(1, b"a"), direct(2, b"b"), direct(3, b"c")]).is_empty());
+    }
+}
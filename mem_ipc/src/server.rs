@@ -0,0 +1,1740 @@
+//! Request handling for the shadow table server.
+//!
+//! The `server` binary owns the queues and hands every message to
+//! [`Server::handle_message`], which decodes the request, applies it to the
+//! shadow tables and produces the reply.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::access::{self, Access, Caller, Policy, Scope};
+use crate::protocol::{Encoding, Event, EventKind, KeyFilter, Reply, Request, RequestBody, TableInfo, WriteOptions};
+use crate::tables::{now_nanos, to_hex, Change, Entry, Owner, Table, TableSpec, Tables};
+use crate::backend::TableBackend;
+use crate::config::Config;
+use crate::reconcile::{self, Drift};
+use crate::snapshot;
+use crate::socket::PeerCredentials;
+use crate::{Actions, CItem, SItem};
+
+/// Interest of a notification queue in changes to some tables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subscription {
+    pub queue: String,
+    /// Empty means every table.
+    pub tables: Vec<String>,
+    pub filter: Option<KeyFilter>,
+    /// How the events sent to `queue` are encoded.
+    pub encoding: Encoding,
+    /// Client that subscribed, `None` for subscriptions the server makes
+    /// itself. Only the client or an admin may unsubscribe.
+    pub owner: Option<Caller>,
+}
+
+impl Subscription {
+    fn matches(&self, event: &Event) -> bool {
+        if !self.tables.is_empty() && !self.tables.contains(&event.table_id) {
+            return false;
+        }
+        match (&self.filter, &event.entry) {
+            (None, _) => true,
+            (Some(filter), Entry::Ternary(entry)) => filter.matches(&entry.key),
+            (Some(filter), Entry::Direct(entry)) => filter.matches(&entry.index.to_le_bytes()),
+        }
+    }
+}
+
+/// An event addressed to one subscriber's queue.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub queue: String,
+    pub event: Event,
+    pub encoding: Encoding,
+}
+
+/// Lease of the sessions that own entries of the tables the server starts
+/// with, so their clients can resume them with a heartbeat after a restart.
+const RESTORED_LEASE: u64 = 60_000_000_000;
+
+/// Numbered requests remembered per client and reply queue to detect
+/// duplicates.
+const DUPLICATE_WINDOW: usize = 256;
+
+/// How long the numbered requests of a client and reply queue are
+/// remembered after its last one. Clients must give up retrying well before.
+const DUPLICATE_RETENTION: u64 = 300_000_000_000;
+
+/// Client numbered requests are remembered for: the user and token it
+/// authenticated with, as in `Caller::same_client`, and its reply queue.
+type ReplyKey = (Option<u32>, Option<String>, String);
+
+/// Replies to the latest numbered requests from one client and reply queue.
+#[derive(Default)]
+struct Acknowledged {
+    replies: BTreeMap<u64, Reply>,
+    last_used: u64,
+}
+
+/// A client session, see `RequestBody::OpenSession`.
+struct Session {
+    /// In nanoseconds.
+    lease: u64,
+    expires: u64,
+    /// Client that opened the session, `None` for restored sessions until a
+    /// client resumes them.
+    client: Option<Caller>,
+}
+
+/// Configuration file re-read by `reload_config`, with the settings that were
+/// applied from it and the adjustments (e.g. command line options) to make to
+/// every version read.
+struct ConfigSource {
+    path: PathBuf,
+    current: Config,
+    adjust: Box<dyn Fn(&mut Config) + Send>,
+}
+
+/// A change `reload` makes to the declared tables.
+enum Redeclaration {
+    Add(TableSpec),
+    Resize(TableSpec),
+    Replace(TableSpec),
+    Remove(String),
+}
+
+pub struct Server {
+    tables: Tables,
+    config: Option<ConfigSource>,
+    log: Option<File>,
+    snapshot_path: Option<PathBuf>,
+    client_queue_prefix: Option<String>,
+    backend: Option<Box<dyn TableBackend>>,
+    backend_errors: u64,
+    subscriptions: Vec<Subscription>,
+    notifications: Vec<Notification>,
+    policy: Option<Policy>,
+    denials: u64,
+    caller: Caller,
+    /// Encoding of the request being handled.
+    encoding: Encoding,
+    sessions: BTreeMap<u64, Session>,
+    next_session: u64,
+    acknowledged: BTreeMap<ReplyKey, Acknowledged>,
+    duplicates: u64,
+}
+
+impl Server {
+    pub fn new(tables: Tables) -> Self {
+        let expires = now_nanos() + RESTORED_LEASE;
+        let sessions: BTreeMap<u64, Session> = tables.iter().flat_map(Table::sessions)
+            .map(|id| (id, Session { lease: RESTORED_LEASE, expires, client: None }))
+            .collect();
+        let next_session = sessions.keys().last().map_or(1, |id| id + 1);
+        Server {
+            tables,
+            config: None,
+            log: None,
+            snapshot_path: None,
+            client_queue_prefix: None,
+            backend: None,
+            backend_errors: 0,
+            subscriptions: Vec::new(),
+            notifications: Vec::new(),
+            policy: None,
+            denials: 0,
+            caller: Caller::default(),
+            encoding: Encoding::Native,
+            sessions,
+            next_session,
+            acknowledged: BTreeMap::new(),
+            duplicates: 0,
+        }
+    }
+
+    /// Appends a line for every change to the tables to `log`.
+    pub fn set_log(&mut self, log: File) {
+        self.log = Some(log);
+    }
+
+    /// Pushes every change committed to the shadow tables to `backend`.
+    ///
+    /// Entries already in the tables are not programmed. A failing backend
+    /// call is logged and counted in `backend_errors`. A client's write the
+    /// backend refuses is undone and answered with the error; changes the
+    /// server makes on its own, e.g. expiries, stay in the shadow tables.
+    pub fn set_backend(&mut self, backend: Box<dyn TableBackend>) {
+        self.backend = Some(backend);
+    }
+
+    /// Number of backend calls that failed.
+    pub fn backend_errors(&self) -> u64 {
+        self.backend_errors
+    }
+
+    /// File written by `save_snapshot` and `SaveSnapshot` requests.
+    pub fn set_snapshot_path(&mut self, path: PathBuf) {
+        self.snapshot_path = Some(path);
+    }
+
+    /// Where clients reading from message queues keep them, e.g.
+    /// `<request queue>.` for the reply queues of `TableClient`. Under an
+    /// access policy such clients may only have the server write to queues
+    /// named after `prefix`, see `may_write_to`.
+    pub fn set_client_queue_prefix(&mut self, prefix: String) {
+        self.client_queue_prefix = Some(prefix);
+    }
+
+    pub fn save_snapshot(&self) -> Result<(), String> {
+        let path = self.snapshot_path.as_ref().ok_or("No snapshot file configured")?;
+        snapshot::save(&self.tables, path)
+    }
+
+    pub fn tables(&self) -> &Tables {
+        &self.tables
+    }
+
+    /// Lets `reload_config` and `Reload` requests re-read `path`. `current`
+    /// is the configuration the server was started with, after `adjust`.
+    pub fn set_config(&mut self, path: PathBuf, current: Config, adjust: Box<dyn Fn(&mut Config) + Send>) {
+        self.config = Some(ConfigSource { path, current, adjust });
+    }
+
+    /// Re-reads the configuration file and applies its table declarations with
+    /// `reload`, its log file, snapshot path and access policy. Queue settings
+    /// only take effect on restart. Returns a description of every change.
+    pub fn reload_config(&mut self, force: bool) -> Result<Vec<String>, String> {
+        let source = self.config.as_ref().ok_or("No config file configured")?;
+        let mut config = Config::load(&source.path)?;
+        (source.adjust)(&mut config);
+        let current = source.current.clone();
+
+        let log = match &config.log {
+            Some(path) if config.log != current.log => Some(OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| format!("Failed to open logfile {}: {}", path.display(), e))?),
+            _ => None,
+        };
+        let mut changes = self.reload(&config.tables, force)?;
+        if config.log != current.log {
+            self.log = log;
+            changes.push(format!("log {}", config.log.as_ref().map_or("disabled".to_string(), |p| p.display().to_string())));
+        }
+        if config.snapshot != current.snapshot {
+            self.snapshot_path = config.snapshot.clone();
+            changes.push(format!("snapshot {}", config.snapshot.as_ref().map_or("disabled".to_string(), |p| p.display().to_string())));
+        }
+        if config.policy != current.policy {
+            self.policy = config.policy.clone();
+            changes.push(format!("access policy {}", if config.policy.is_some() { "updated" } else { "removed" }));
+        }
+        if config.queue != current.queue {
+            changes.push("queue settings changed, restart to apply".to_string());
+        }
+        self.config.as_mut().expect("Config source checked above").current = config;
+        Ok(changes)
+    }
+
+    /// Makes the declared tables match `specs`: adds new tables, changes
+    /// capacities and removes tables that are no longer declared.
+    ///
+    /// Removing a populated table, changing the kind or key width of one, or
+    /// shrinking one below the entries it holds drops entries. Unless `force`
+    /// is set such a change refuses the whole reload and nothing is changed.
+    /// Dropped entries are removed from the backend and published as `Deleted`.
+    pub fn reload(&mut self, specs: &[TableSpec], force: bool) -> Result<Vec<String>, String> {
+        for (i, spec) in specs.iter().enumerate() {
+            if specs[..i].iter().any(|s| s.name == spec.name) {
+                return Err(format!("Table '{}' declared twice", spec.name));
+            }
+        }
+        let mut steps = Vec::new();
+        let mut refused = Vec::new();
+        for spec in specs {
+            spec.validate()?;
+            match self.tables.get(&spec.name) {
+                None => steps.push(Redeclaration::Add(spec.clone())),
+                Some(table) if table.spec() == spec => {},
+                Some(table) if table.spec().kind == spec.kind && table.spec().key_width == spec.key_width => {
+                    let dropped = table.overflow(spec.capacity).len();
+                    if dropped > 0 && !force {
+                        refused.push(format!("shrinking {} to {} would drop {} entries", spec.name, spec.capacity, dropped));
+                    }
+                    steps.push(Redeclaration::Resize(spec.clone()));
+                },
+                Some(table) => {
+                    if !table.is_empty() && !force {
+                        refused.push(format!("redeclaring {} as {} would drop {} entries", table.spec(), spec, table.len()));
+                    }
+                    steps.push(Redeclaration::Replace(spec.clone()));
+                },
+            }
+        }
+        for table in self.tables.iter().filter(|t| !specs.iter().any(|s| s.name == t.spec().name)) {
+            if !table.is_empty() && !force {
+                refused.push(format!("removing {} would drop {} entries", table.spec(), table.len()));
+            }
+            steps.push(Redeclaration::Remove(table.spec().name.clone()));
+        }
+        if !refused.is_empty() {
+            return Err(format!("Reload refused, force to apply: {}", refused.join("; ")));
+        }
+
+        let mut changes = Vec::new();
+        for step in steps {
+            let (change, dropped) = match step {
+                Redeclaration::Add(spec) => {
+                    self.tables.declare(spec.clone())?;
+                    (format!("added {}", spec), 0)
+                },
+                Redeclaration::Resize(spec) => {
+                    let table = self.tables.get_mut(&spec.name)?;
+                    let change = format!("resized {} from {} to {}", spec.name, table.spec().capacity, spec.capacity);
+                    let dropped = table.resize(spec.capacity)?;
+                    let count = dropped.len();
+                    for entry in dropped {
+                        self.commit(Event { kind: EventKind::Deleted, table_id: spec.name.clone(), entry });
+                    }
+                    (change, count)
+                },
+                Redeclaration::Replace(spec) => {
+                    let old = self.tables.get(&spec.name).expect("Planned table exists").spec().clone();
+                    let dropped = self.drop_entries(&spec.name);
+                    self.tables.remove(&spec.name);
+                    self.tables.declare(spec.clone())?;
+                    (format!("redeclared {} as {}", old, spec), dropped)
+                },
+                Redeclaration::Remove(table_id) => {
+                    let spec = self.tables.get(&table_id).expect("Planned table exists").spec().clone();
+                    let dropped = self.drop_entries(&table_id);
+                    self.tables.remove(&table_id);
+                    (format!("removed {}", spec), dropped)
+                },
+            };
+            let change = match dropped {
+                0 => change,
+                n => format!("{}, dropped {} entries", change, n),
+            };
+            self.log_line(&format!("reload {}", change));
+            changes.push(change);
+        }
+        Ok(changes)
+    }
+
+    /// Removes every entry of a table from the backend and publishes their
+    /// deletion, before the table itself goes away. Returns how many there were.
+    fn drop_entries(&mut self, table_id: &str) -> usize {
+        let entries = self.tables.get(table_id).expect("Dropping entries of unknown table").overflow(0);
+        let count = entries.len();
+        for entry in entries {
+            self.commit(Event { kind: EventKind::Deleted, table_id: table_id.to_string(), entry });
+        }
+        count
+    }
+
+    pub fn subscribe(&mut self, subscription: Subscription) -> Result<(), String> {
+        if let Some(table_id) = subscription.tables.iter().find(|t| self.tables.get(t).is_none()) {
+            return Err(format!("No such table '{}'", table_id));
+        }
+        if let Some(filter) = &subscription.filter {
+            if filter.key.len() != filter.mask.len() {
+                return Err("Key and mask length differ".to_string());
+            }
+        }
+        self.subscriptions.push(subscription);
+        Ok(())
+    }
+
+    /// Drops the subscriptions of `queue` for the client asking, which must
+    /// have made all of them unless it is an admin.
+    fn unsubscribe_caller(&mut self, queue: &str) -> Result<(), String> {
+        if let Some(policy) = &self.policy {
+            let caller = &self.caller;
+            let others = self.subscriptions.iter()
+                .any(|s| s.queue == queue && !s.owner.as_ref().is_some_and(|owner| owner.same_client(caller)));
+            if others && policy.check(caller, Scope::All, Access::Admin).is_err() {
+                self.deny(&format!("unsubscribe {}", queue));
+                return Err(format!("Permission denied: {} has subscriptions of another client", queue));
+            }
+        }
+        self.unsubscribe(queue);
+        Ok(())
+    }
+
+    /// Drops every subscription of `queue`, e.g. because the queue disappeared.
+    pub fn unsubscribe(&mut self, queue: &str) {
+        self.subscriptions.retain(|s| s.queue != queue);
+        self.notifications.retain(|n| n.queue != queue);
+    }
+
+    pub fn subscriptions(&self) -> &[Subscription] {
+        &self.subscriptions
+    }
+
+    /// Returns the notifications produced since the last call, oldest first.
+    pub fn take_notifications(&mut self) -> Vec<Notification> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    /// Periodic housekeeping: removes entries whose idle or hard timeout has
+    /// passed and publishes an `Expired` event for each of them, and ends the
+    /// sessions whose lease has run out.
+    pub fn tick(&mut self, now: u64) {
+        let lapsed: Vec<u64> = self.sessions.iter().filter(|(_, s)| s.expires <= now).map(|(id, _)| *id).collect();
+        for id in lapsed {
+            self.release(id, "expired");
+        }
+        self.acknowledged.retain(|_, a| now.saturating_sub(a.last_used) < DUPLICATE_RETENTION);
+
+        let mut expired = Vec::new();
+        for table in self.tables.iter_mut() {
+            let table_id = table.spec().name.clone();
+            for entry in table.expire(now) {
+                expired.push(Event { kind: EventKind::Expired, table_id: table_id.clone(), entry });
+            }
+        }
+        for event in expired {
+            self.log_line(&format!("expire {} {}", event.table_id, event.entry));
+            self.commit(event);
+        }
+    }
+
+    /// Ids of the open sessions.
+    pub fn sessions(&self) -> Vec<u64> {
+        self.sessions.keys().copied().collect()
+    }
+
+    fn open_session(&mut self, lease_ms: u32, now: u64) -> Result<Reply, String> {
+        if lease_ms == 0 {
+            return Err("Lease must not be zero".to_string());
+        }
+        let id = self.next_session;
+        self.next_session += 1;
+        let lease = lease_ms as u64 * 1_000_000;
+        self.sessions.insert(id, Session { lease, expires: now + lease, client: Some(self.caller.clone()) });
+        self.log_line(&format!("session {} opened lease={}ms", id, lease_ms));
+        Ok(Reply::Session(id))
+    }
+
+    /// Renews the lease of session `id` for a request from its client. A
+    /// restored session is taken over by the first client to use it.
+    fn renew(&mut self, id: u64, now: u64) -> Result<(), String> {
+        let session = self.sessions.get_mut(&id).ok_or_else(|| format!("No such session {}", id))?;
+        let client = session.client.get_or_insert_with(|| self.caller.clone());
+        if self.policy.is_some() && !client.same_client(&self.caller) {
+            self.deny(&format!("session {}", id));
+            return Err(format!("Permission denied: session {} belongs to another client", id));
+        }
+        session.expires = now + session.lease;
+        Ok(())
+    }
+
+    /// Ends session `id`: removes its entries, except persistent ones, which
+    /// stay without an owner, and publishes a `Released` event for each.
+    fn release(&mut self, id: u64, reason: &str) {
+        self.sessions.remove(&id);
+        let mut released = Vec::new();
+        let mut kept = 0;
+        for table in self.tables.iter_mut() {
+            let (removed, persistent) = table.release(id);
+            kept += persistent;
+            let table_id = table.spec().name.clone();
+            released.extend(removed.into_iter().map(|entry| Event { kind: EventKind::Released, table_id: table_id.clone(), entry }));
+        }
+        self.log_line(&format!("session {} {}, removed {} entries, kept {}", id, reason, released.len(), kept));
+        for event in released {
+            self.log_line(&format!("release {} {}", event.table_id, event.entry));
+            self.commit(event);
+        }
+    }
+
+    /// Reads every table back from the backend and returns the tables that
+    /// differ from the shadow copy. With `repair`, extra entries are removed,
+    /// mismatched ones modified and missing ones programmed, in that order so
+    /// a full device has room, until the backend matches the shadow tables
+    /// again. A table that cannot be read back is logged and skipped.
+    pub fn reconcile(&mut self, repair: bool) -> Result<Vec<Drift>, String> {
+        let backend = self.backend.as_mut().ok_or("No backend configured")?;
+        let mut drifts = Vec::new();
+        let mut errors = Vec::new();
+        for table in self.tables.iter() {
+            let spec = table.spec();
+            let installed = match backend.read_back(spec) {
+                Ok(installed) => installed,
+                Err(e) => {
+                    errors.push((spec.name.clone(), e));
+                    continue;
+                },
+            };
+            let drift = reconcile::diff(table, &installed);
+            if drift.is_empty() {
+                continue;
+            }
+            if repair {
+                let mut results = Vec::new();
+                results.extend(drift.extra.iter().map(|e| backend.remove(spec, e)));
+                results.extend(drift.mismatched.iter().map(|(e, _)| backend.modify(spec, e)));
+                results.extend(drift.missing.iter().map(|e| backend.program(spec, e)));
+                errors.extend(results.into_iter().filter_map(|r| r.err()).map(|e| (spec.name.clone(), e)));
+            }
+            drifts.push(drift);
+        }
+
+        for drift in &drifts {
+            self.log_line(&format!("{} {}", if repair { "repair" } else { "drift" }, drift));
+        }
+        for (table_id, e) in errors {
+            self.backend_errors += 1;
+            self.log_line(&format!("backend-error {} {}", table_id, e));
+        }
+        Ok(drifts)
+    }
+
+    /// Pushes a change the server made on its own, e.g. an expiry, to the
+    /// backend and notifies subscribers. The shadow tables keep the change
+    /// even if the backend fails, which `reconcile` can repair later.
+    fn commit(&mut self, event: Event) {
+        let _ = self.program(&event);
+        self.publish(event);
+    }
+
+    /// Pushes a change a client asked for to the backend, then logs `line` and
+    /// notifies subscribers. If the backend refuses it, the entry is put back
+    /// to `previous` and the client gets the error, so `Ok` means the device
+    /// has the change too.
+    fn commit_write(&mut self, event: Event, previous: Option<Entry>, line: String) -> Result<Reply, String> {
+        if let Err(e) = self.program(&event) {
+            let changed = (event.kind != EventKind::Deleted).then_some(&event.entry);
+            revert(self.tables.get_mut(&event.table_id)?, changed, previous)?;
+            return Err(format!("Backend failed: {}", e));
+        }
+        self.log_line(&line);
+        self.publish(event);
+        Ok(Reply::Ok)
+    }
+
+    /// Pushes a change that was applied to the shadow tables to the backend.
+    /// Failures are counted in `backend_errors` and logged.
+    fn program(&mut self, event: &Event) -> Result<(), String> {
+        let Some(backend) = self.backend.as_mut() else {
+            return Ok(());
+        };
+        let spec = self.tables.get(&event.table_id).expect("Committed change to unknown table").spec();
+        let result = match event.kind {
+            EventKind::Added => backend.program(spec, &event.entry),
+            EventKind::Modified => backend.modify(spec, &event.entry),
+            EventKind::Deleted | EventKind::Expired | EventKind::Released => backend.remove(spec, &event.entry),
+        };
+        if let Err(e) = &result {
+            self.backend_errors += 1;
+            self.log_line(&format!("backend-error {} {}", event.table_id, e));
+        }
+        result
+    }
+
+    /// Queues `event` once for every subscribed queue that is interested in it.
+    fn publish(&mut self, event: Event) {
+        let mut queues: Vec<(String, Encoding)> = Vec::new();
+        for subscription in self.subscriptions.iter().filter(|s| s.matches(&event)) {
+            if !queues.iter().any(|(queue, _)| *queue == subscription.queue) {
+                queues.push((subscription.queue.clone(), subscription.encoding));
+            }
+        }
+        for (queue, encoding) in queues {
+            self.notifications.push(Notification { queue, event: event.clone(), encoding });
+        }
+    }
+
+    /// Records the shutdown in the log and makes sure everything logged so far
+    /// reaches the disk.
+    pub fn shutdown(&mut self) -> Result<(), String> {
+        self.log_line("shutdown");
+        match self.log.as_mut() {
+            Some(log) => log.flush().and_then(|_| log.sync_data()).map_err(|e| format!("Failed to flush transaction log: {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn handle(&mut self, body: &RequestBody) -> Reply {
+        self.handle_at(body, now_nanos())
+    }
+
+    /// Handles a request from the process `peer`, which `peer()` returns
+    /// while the request is handled. Log lines name the peer.
+    pub fn handle_from(&mut self, body: &RequestBody, peer: PeerCredentials) -> Reply {
+        self.handle_as(body, Caller { peer: Some(peer), token: None })
+    }
+
+    /// Decodes a request in either encoding and handles it. Returns the
+    /// request, whose reply queue gets the reply in the returned encoding.
+    /// The reply queue is cleared if the caller may not have the server write
+    /// to it.
+    pub fn handle_message(&mut self, message: &[u8], peer: Option<PeerCredentials>)
+                          -> Result<(Request, Reply, Encoding), &'static str> {
+        let (mut request, encoding) = Request::decode(message)?;
+        self.encoding = encoding;
+        let (reply, deliver) = self.answer(&request, peer);
+        self.encoding = Encoding::Native;
+        if !deliver {
+            request.reply_to.clear();
+        }
+        Ok((request, reply, encoding))
+    }
+
+    /// Handles a decoded request, from `peer` if it came over a socket. A
+    /// token the access policy does not know is refused.
+    ///
+    /// A numbered request is applied only once: a duplicate from the same
+    /// client and reply queue gets the reply of the first one, and one too
+    /// old to tell is refused. Refusals are not remembered, so a retry with
+    /// the right token or access goes through, and neither are requests
+    /// without a reply queue, which cannot be told apart.
+    pub fn handle_request(&mut self, request: &Request, peer: Option<PeerCredentials>) -> Reply {
+        self.answer(request, peer).0
+    }
+
+    /// Like `handle_request`, and whether the reply may go to the request's
+    /// reply queue. Clients connected over the socket get theirs on the
+    /// connection.
+    fn answer(&mut self, request: &Request, peer: Option<PeerCredentials>) -> (Reply, bool) {
+        let sequence = request.sequence;
+        let acked = |reply: Reply| if sequence == 0 { reply } else { Reply::Acked { sequence, reply: Box::new(reply) } };
+        let deliver = |server: &Server, caller: &Caller| {
+            peer.is_some() || request.reply_to.is_empty() || server.may_write_to(caller, &request.reply_to)
+        };
+        let caller = match self.authenticate(request, peer) {
+            Ok(caller) => caller,
+            Err(reply) => return (acked(reply), deliver(self, &Caller { peer, token: None })),
+        };
+        if !deliver(self, &caller) {
+            self.caller = caller;
+            self.deny(&format!("reply queue {}", request.reply_to));
+            self.caller = Caller::default();
+            return (acked(Reply::Error(format!("Permission denied: reply queue {}", request.reply_to))), false);
+        }
+        (self.handle_numbered(request, caller), true)
+    }
+
+    /// Applies a numbered request once, see `handle_request`.
+    fn handle_numbered(&mut self, request: &Request, caller: Caller) -> Reply {
+        let sequence = request.sequence;
+        let acked = |reply: Reply| if sequence == 0 { reply } else { Reply::Acked { sequence, reply: Box::new(reply) } };
+        // Reads are only numbered to match their reply, there is nothing to
+        // apply twice.
+        if sequence == 0 || request.reply_to.is_empty() || request.body.reads_only() {
+            return acked(self.handle_as(&request.body, caller));
+        }
+        let key = (caller.peer.map(|p| p.uid), caller.token.clone(), request.reply_to.clone());
+        let acknowledged = self.acknowledged.entry(key.clone()).or_default();
+        acknowledged.last_used = now_nanos();
+        if let Some(reply) = acknowledged.replies.get(&sequence) {
+            let reply = Box::new(reply.clone());
+            self.duplicates += 1;
+            self.log_line(&format!("duplicate {} sequence={}", request.reply_to, sequence));
+            return Reply::Acked { sequence, reply };
+        }
+        if acknowledged.replies.len() >= DUPLICATE_WINDOW && acknowledged.replies.keys().next().is_some_and(|&s| s > sequence) {
+            let e = format!("Sequence number {} is too old to tell whether it was applied", sequence);
+            return Reply::Acked { sequence, reply: Box::new(Reply::Error(e)) };
+        }
+
+        let denials = self.denials;
+        let reply = self.handle_as(&request.body, caller);
+        if self.denials == denials {
+            let acknowledged = self.acknowledged.get_mut(&key).expect("Added above");
+            acknowledged.replies.insert(sequence, reply.clone());
+            if acknowledged.replies.len() > DUPLICATE_WINDOW {
+                acknowledged.replies.pop_first();
+            }
+        }
+        Reply::Acked { sequence, reply: Box::new(reply) }
+    }
+
+    /// Who sent `request`, or the reply refusing a token the access policy
+    /// does not know.
+    fn authenticate(&mut self, request: &Request, peer: Option<PeerCredentials>) -> Result<Caller, Reply> {
+        let token = match (&self.policy, &request.token) {
+            (Some(policy), Some(secret)) => match policy.authenticate(secret) {
+                Some(name) => Some(name.to_string()),
+                None => {
+                    self.caller.peer = peer;
+                    self.deny("invalid token");
+                    self.caller = Caller::default();
+                    return Err(Reply::Error("Invalid token".to_string()));
+                },
+            },
+            _ => None,
+        };
+        Ok(Caller { peer, token })
+    }
+
+    fn handle_as(&mut self, body: &RequestBody, caller: Caller) -> Reply {
+        self.caller = caller;
+        let reply = self.handle(body);
+        self.caller = Caller::default();
+        reply
+    }
+
+    /// Who sent the request being handled, if it came over a socket.
+    pub fn peer(&self) -> Option<PeerCredentials> {
+        self.caller.peer
+    }
+
+    /// Restricts what clients may do, or lifts all restrictions for `None`.
+    pub fn set_policy(&mut self, policy: Option<Policy>) {
+        self.policy = policy;
+    }
+
+    /// Number of requests refused by the access policy.
+    pub fn denials(&self) -> u64 {
+        self.denials
+    }
+
+    /// Number of numbered requests received again and not reapplied.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Handles a request as if the current time were `now`.
+    pub fn handle_at(&mut self, body: &RequestBody, now: u64) -> Reply {
+        if let Err(e) = self.authorize(body) {
+            return Reply::Error(e);
+        }
+        let result = match body {
+            RequestBody::Ternary(item, options) => self.handle_ternary(item, options, now),
+            RequestBody::Direct(item, options) => self.handle_direct(item, options, now),
+            RequestBody::Lookup { table_id, key, bytes } => self.lookup(table_id, key, *bytes as u64, now),
+            RequestBody::Dump { table_id } => self.dump(table_id),
+            RequestBody::ResetCounters { table_id } => {
+                self.tables.get_mut(table_id).map(|table| {
+                    table.reset_counters();
+                    Reply::Ok
+                }).map_err(String::from)
+            },
+            RequestBody::ListTables => Ok(self.list_tables()),
+            RequestBody::Subscribe { queue, tables, filter } => {
+                self.check_queue(queue).and_then(|_| self.subscribe(Subscription {
+                    queue: queue.clone(),
+                    tables: tables.clone(),
+                    filter: filter.clone(),
+                    encoding: self.encoding,
+                    owner: Some(self.caller.clone()),
+                })).map(|_| Reply::Ok)
+            },
+            RequestBody::Unsubscribe { queue } => self.unsubscribe_caller(queue).map(|_| Reply::Ok),
+            RequestBody::SetDefault { table_id, result } => self.set_default(table_id, result),
+            RequestBody::GetDefault { table_id } => {
+                self.tables.get(table_id).map(|table| Reply::Default(table.default_result().map(|r| r.to_vec())))
+                    .ok_or_else(|| "No such table".to_string())
+            },
+            RequestBody::SaveSnapshot => self.save_snapshot().map(|_| Reply::Ok),
+            RequestBody::Reconcile { repair } => self.reconcile(*repair).map(Reply::Drift),
+            RequestBody::Reload { force } => self.reload_config(*force).map(Reply::Reloaded),
+            RequestBody::OpenSession { lease_ms } => self.open_session(*lease_ms, now),
+            RequestBody::Heartbeat { session } => self.renew(*session, now).map(|_| Reply::Ok),
+            RequestBody::CloseSession { session } => self.renew(*session, now).map(|_| {
+                self.release(*session, "closed");
+                Reply::Ok
+            }),
+        };
+        result.unwrap_or_else(Reply::Error)
+    }
+
+    fn handle_ternary(&mut self, item: &CItem, options: &WriteOptions, now: u64) -> Result<Reply, String> {
+        let owner = self.owner(options, now)?;
+        let table = self.tables.get_mut(&item.table_id)?.as_ternary_mut()?;
+        let current = table.get(item.p, &item.k, &item.m).map_or(0, |e| e.version);
+        if let Some(expected) = stale(item.action, options, current) {
+            let what = format!("{} prio={} key={} mask={}", item.table_id, item.p, to_hex(&item.k), to_hex(&item.m));
+            return Ok(self.conflict(&what, expected, current));
+        }
+        match item.action {
+            Actions::Noop => Ok(Reply::Ok),
+            Actions::Add => {
+                let previous = table.get(item.p, &item.k, &item.m).cloned().map(Entry::Ternary);
+                let change = table.insert(item.p, &item.k, &item.m, &item.r, options.timeouts, now)?;
+                let entry = table.get_mut(item.p, &item.k, &item.m).map(|e| {
+                    e.owner = owner;
+                    Entry::Ternary(e.clone())
+                }).expect("Entry just inserted");
+                let line = format!("{} {} prio={} key={} mask={} result={} {}{}",
+                                   change_name(change), item.table_id, item.p,
+                                   to_hex(&item.k), to_hex(&item.m), to_hex(&item.r), options.timeouts, owner_suffix(owner));
+                self.commit_write(Event { kind: change_kind(change), table_id: item.table_id.clone(), entry }, previous, line)
+            },
+            Actions::Delete => {
+                let entry = Entry::Ternary(table.remove(item.p, &item.k, &item.m)?);
+                let line = format!("delete {} prio={} key={} mask={}", item.table_id, item.p, to_hex(&item.k), to_hex(&item.m));
+                let event = Event { kind: EventKind::Deleted, table_id: item.table_id.clone(), entry: entry.clone() };
+                self.commit_write(event, Some(entry), line)
+            },
+            Actions::Query => {
+                let entry = table.get(item.p, &item.k, &item.m).ok_or("No such entry")?;
+                Ok(Reply::Ternary(vec![entry.clone()]))
+            },
+            Actions::ReadCounters => {
+                let entry = table.get(item.p, &item.k, &item.m).ok_or("No such entry")?;
+                Ok(Reply::Counters(entry.counters))
+            },
+            Actions::ResetCounters => {
+                let entry = table.get_mut(item.p, &item.k, &item.m).ok_or("No such entry")?;
+                entry.counters = Default::default();
+                Ok(Reply::Ok)
+            },
+        }
+    }
+
+    fn handle_direct(&mut self, item: &SItem, options: &WriteOptions, now: u64) -> Result<Reply, String> {
+        let owner = self.owner(options, now)?;
+        let table = self.tables.get_mut(&item.table_id)?.as_direct_mut()?;
+        let current = table.get(item.index).map_or(0, |e| e.version);
+        if let Some(expected) = stale(item.action, options, current) {
+            return Ok(self.conflict(&format!("{} index={}", item.table_id, item.index), expected, current));
+        }
+        match item.action {
+            Actions::Noop => Ok(Reply::Ok),
+            Actions::Add => {
+                let previous = table.get(item.index).cloned().map(Entry::Direct);
+                let change = table.insert(item.index, &item.value, options.timeouts, now)?;
+                let entry = table.get_mut(item.index).map(|e| {
+                    e.owner = owner;
+                    Entry::Direct(e.clone())
+                }).expect("Entry just inserted");
+                let line = format!("{} {} index={} value={} {}{}",
+                                   change_name(change), item.table_id, item.index,
+                                   to_hex(&item.value), options.timeouts, owner_suffix(owner));
+                self.commit_write(Event { kind: change_kind(change), table_id: item.table_id.clone(), entry }, previous, line)
+            },
+            Actions::Delete => {
+                let entry = Entry::Direct(table.remove(item.index)?);
+                let line = format!("delete {} index={}", item.table_id, item.index);
+                let event = Event { kind: EventKind::Deleted, table_id: item.table_id.clone(), entry: entry.clone() };
+                self.commit_write(event, Some(entry), line)
+            },
+            Actions::Query => {
+                let entry = table.get(item.index).ok_or("No such entry")?;
+                Ok(Reply::Direct(vec![entry.clone()]))
+            },
+            Actions::ReadCounters => {
+                let entry = table.get(item.index).ok_or("No such entry")?;
+                Ok(Reply::Counters(entry.counters))
+            },
+            Actions::ResetCounters => {
+                let entry = table.get_mut(item.index).ok_or("No such entry")?;
+                entry.counters = Default::default();
+                Ok(Reply::Ok)
+            },
+        }
+    }
+
+    fn conflict(&mut self, what: &str, expected: u64, current: u64) -> Reply {
+        self.log_line(&format!("conflict {} expected={} current={}", what, expected, current));
+        Reply::Conflict { current }
+    }
+
+    /// Owner of an entry added with `options`, renewing the session's lease.
+    /// The last write decides who owns an entry.
+    fn owner(&mut self, options: &WriteOptions, now: u64) -> Result<Owner, String> {
+        if options.session == 0 {
+            return Ok(Owner::default());
+        }
+        self.renew(options.session, now)?;
+        Ok(Owner { session: options.session, persistent: options.persistent })
+    }
+
+    fn set_default(&mut self, table_id: &str, result: &Option<Vec<u8>>) -> Result<Reply, String> {
+        self.tables.get_mut(table_id)?.set_default_result(result.clone());
+        match result {
+            Some(result) => self.log_line(&format!("default {} result={}", table_id, to_hex(result))),
+            None => self.log_line(&format!("default {} cleared", table_id)),
+        }
+        Ok(Reply::Ok)
+    }
+
+    /// Runs the classifier. Falls back to the table's default result when no
+    /// entry matches, and reports a miss only if there is none.
+    fn lookup(&mut self, table_id: &str, key: &[u8], bytes: u64, now: u64) -> Result<Reply, String> {
+        let table = self.tables.get_mut(table_id)?;
+        let result = match table {
+            Table::Ternary(table) => table.lookup(key, bytes, now).map(|e| e.result.clone()),
+            Table::Direct(table) => {
+                let index: [u8; 2] = key.try_into().map_err(|_| "Direct table key must be a 2 byte index")?;
+                table.lookup(u16::from_le_bytes(index), bytes, now).map(|e| e.value.clone())
+            },
+        };
+        let result = result.or_else(|| table.default_result().map(|r| r.to_vec()));
+        Ok(result.map(Reply::Result).unwrap_or(Reply::Miss))
+    }
+
+    fn dump(&self, table_id: &str) -> Result<Reply, String> {
+        match self.tables.get(table_id).ok_or("No such table")? {
+            Table::Ternary(table) => Ok(Reply::Ternary(table.entries().to_vec())),
+            Table::Direct(table) => Ok(Reply::Direct(table.entries().cloned().collect())),
+        }
+    }
+
+    fn list_tables(&self) -> Reply {
+        let readable = |table: &&Table| self.policy.as_ref()
+            .is_none_or(|policy| policy.access(&self.caller, Some(&table.spec().name)).is_some());
+        Reply::Tables(self.tables.iter().filter(readable).map(TableInfo::from).collect())
+    }
+
+    /// Checks `body` against the access policy, auditing refusals.
+    fn authorize(&mut self, body: &RequestBody) -> Result<(), String> {
+        let Some(policy) = &self.policy else {
+            return Ok(());
+        };
+        for (scope, needed) in access::requirements(body) {
+            if let Err(e) = policy.check(&self.caller, scope, needed) {
+                self.deny(&format!("{} {}", needed.as_str(), scope));
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `caller` may have the server write to `queue`. Under an access
+    /// policy, a client connected over the socket may name queues its user
+    /// owns, and other clients, which the server cannot tell apart, only
+    /// queues named after the client queue prefix. Admins may name any queue.
+    fn may_write_to(&self, caller: &Caller, queue: &str) -> bool {
+        let Some(policy) = &self.policy else {
+            return true;
+        };
+        if policy.check(caller, Scope::All, Access::Admin).is_ok() {
+            return true;
+        }
+        match caller.peer {
+            Some(peer) => crate::mq_owner(queue) == Some(peer.uid),
+            None => self.client_queue_prefix.as_ref()
+                .is_some_and(|prefix| queue.len() > prefix.len() && queue.starts_with(prefix.as_str())),
+        }
+    }
+
+    fn check_queue(&mut self, queue: &str) -> Result<(), String> {
+        if self.may_write_to(&self.caller, queue) {
+            return Ok(());
+        }
+        self.deny(&format!("queue {}", queue));
+        Err(format!("Permission denied: queue {}", queue))
+    }
+
+    fn deny(&mut self, what: &str) {
+        self.denials += 1;
+        // Anonymous callers get no suffix from log_line, so name them here.
+        let who = if self.caller == Caller::default() { " by anonymous" } else { "" };
+        self.log_line(&format!("denied {}{}", what, who));
+    }
+
+    fn log_line(&mut self, line: &str) {
+        if let Some(log) = self.log.as_mut() {
+            let result = if self.caller == Caller::default() {
+                writeln!(log, "{} {}", now_nanos(), line)
+            } else {
+                writeln!(log, "{} {} by {}", now_nanos(), line, self.caller)
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to write transaction log: {}", e);
+            }
+        }
+    }
+}
+
+/// The version a compare-and-set write expected, if the entry is at another
+/// version `current`. Only adds and deletes compare.
+fn stale(action: Actions, options: &WriteOptions, current: u64) -> Option<u64> {
+    match action {
+        Actions::Add | Actions::Delete => options.expected_version.filter(|&expected| expected != current),
+        _ => None,
+    }
+}
+
+fn owner_suffix(owner: Owner) -> String {
+    if owner == Owner::default() { String::new() } else { format!(" {}", owner) }
+}
+
+fn change_name(change: Change) -> &'static str {
+    match change {
+        Change::Added => "add",
+        Change::Modified => "modify",
+    }
+}
+
+fn change_kind(change: Change) -> EventKind {
+    match change {
+        Change::Added => EventKind::Added,
+        Change::Modified => EventKind::Modified,
+    }
+}
+
+/// Puts `table` back the way it was before a change the backend refused:
+/// removes `changed`, the entry the change left, and restores `previous`.
+fn revert(table: &mut Table, changed: Option<&Entry>, previous: Option<Entry>) -> Result<(), &'static str> {
+    match changed {
+        Some(Entry::Ternary(e)) => {
+            table.as_ternary_mut()?.remove(e.priority, &e.key, &e.mask)?;
+        },
+        Some(Entry::Direct(e)) => {
+            table.as_direct_mut()?.remove(e.index)?;
+        },
+        None => {},
+    }
+    match previous {
+        Some(Entry::Ternary(e)) => table.as_ternary_mut()?.restore(e),
+        Some(Entry::Direct(e)) => table.as_direct_mut()?.restore(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+    use crate::backend::{SimBackend, TcamBackend};
+    use crate::tables::Timeouts;
+
+    fn server() -> Server {
+        let mut tables = Tables::default();
+        tables.declare("acl:ternary:2:16".parse().unwrap()).unwrap();
+        tables.declare("nexthop:direct:16".parse().unwrap()).unwrap();
+        Server::new(tables)
+    }
+
+    fn ternary(action: Actions, p: u16, k: &[u8], m: &[u8], r: &[u8]) -> RequestBody {
+        RequestBody::Ternary(CItem {
+            table_id: "acl".to_string(),
+            action,
+            p,
+            k: k.to_vec(),
+            m: m.to_vec(),
+            r: r.to_vec(),
+        }, WriteOptions::default())
+    }
+
+    fn lookup(key: &[u8], bytes: u32) -> RequestBody {
+        RequestBody::Lookup { table_id: "acl".to_string(), key: key.to_vec(), bytes }
+    }
+
+    #[test]
+    fn test_add_lookup_delete() {
+        let mut server = server();
+        assert_eq!(server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"permit")), Reply::Ok);
+        assert_eq!(server.handle(&lookup(&[10, 5], 64)), Reply::Result(b"permit".to_vec()));
+        assert_eq!(server.handle(&lookup(&[11, 5], 64)), Reply::Miss);
+
+        assert_eq!(server.handle(&ternary(Actions::Delete, 1, &[10, 0], &[0xff, 0], b"")), Reply::Ok);
+        assert_eq!(server.handle(&lookup(&[10, 5], 64)), Reply::Miss);
+        assert!(matches!(server.handle(&ternary(Actions::Delete, 1, &[10, 0], &[0xff, 0], b"")), Reply::Error(_)));
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut server = server();
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"permit"));
+        server.handle_at(&lookup(&[10, 1], 100), 5);
+        server.handle_at(&lookup(&[10, 2], 20), 8);
+
+        let reply = server.handle(&ternary(Actions::ReadCounters, 1, &[10, 0], &[0xff, 0], b""));
+        assert_eq!(reply, Reply::Counters(crate::tables::Counters { packets: 2, bytes: 120, last_hit: 8 }));
+
+        server.handle(&ternary(Actions::ResetCounters, 1, &[10, 0], &[0xff, 0], b""));
+        let reply = server.handle(&ternary(Actions::ReadCounters, 1, &[10, 0], &[0xff, 0], b""));
+        assert_eq!(reply, Reply::Counters(Default::default()));
+    }
+
+    #[test]
+    fn test_direct_counters() {
+        let mut server = server();
+        let add = RequestBody::Direct(SItem {
+            table_id: "nexthop".to_string(),
+            action: Actions::Add,
+            index: 3,
+            value: vec![1, 2, 3],
+        }, WriteOptions::default());
+        assert_eq!(server.handle(&add), Reply::Ok);
+
+        let lookup = RequestBody::Lookup { table_id: "nexthop".to_string(), key: 3u16.to_le_bytes().to_vec(), bytes: 64 };
+        assert_eq!(server.handle(&lookup), Reply::Result(vec![1, 2, 3]));
+
+        match server.handle(&RequestBody::Dump { table_id: "nexthop".to_string() }) {
+            Reply::Direct(entries) => assert_eq!(entries[0].counters.packets, 1),
+            reply => panic!("Unexpected reply {:?}", reply),
+        }
+
+        server.handle(&RequestBody::ResetCounters { table_id: "nexthop".to_string() });
+        match server.handle(&RequestBody::Dump { table_id: "nexthop".to_string() }) {
+            Reply::Direct(entries) => assert_eq!(entries[0].counters.packets, 0),
+            reply => panic!("Unexpected reply {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_expiry_events() {
+        const SECOND: u64 = 1_000_000_000;
+        let mut server = server();
+        let mut add = ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"learned");
+        if let RequestBody::Ternary(_, options) = &mut add {
+            options.timeouts = Timeouts { idle: 5, hard: 60 };
+        }
+        assert_eq!(server.handle_at(&add, 0), Reply::Ok);
+        server.subscribe(subscription("/events", &[], None)).unwrap();
+
+        server.handle_at(&lookup(&[10, 1], 64), 3 * SECOND);
+        server.tick(7 * SECOND);
+        assert!(server.take_notifications().is_empty());
+
+        server.tick(8 * SECOND);
+        let notifications = server.take_notifications();
+        assert_eq!(notifications.len(), 1);
+        let event = &notifications[0].event;
+        assert_eq!(event.kind, EventKind::Expired);
+        assert_eq!(event.table_id, "acl");
+        match &event.entry {
+            Entry::Ternary(entry) => assert_eq!(entry.result, b"learned"),
+            entry => panic!("Unexpected entry {:?}", entry),
+        }
+        assert_eq!(server.handle_at(&lookup(&[10, 1], 64), 8 * SECOND), Reply::Miss);
+        assert!(server.take_notifications().is_empty());
+    }
+
+    fn subscription(queue: &str, tables: &[&str], filter: Option<KeyFilter>) -> Subscription {
+        Subscription {
+            queue: queue.to_string(),
+            tables: tables.iter().map(|t| t.to_string()).collect(),
+            filter,
+            encoding: Encoding::Native,
+            owner: None,
+        }
+    }
+
+    fn kinds(notifications: &[Notification], queue: &str) -> Vec<EventKind> {
+        notifications.iter().filter(|n| n.queue == queue).map(|n| n.event.kind).collect()
+    }
+
+    #[test]
+    fn test_sessions() {
+        const SECOND: u64 = 1_000_000_000;
+        let mut server = server();
+        let Reply::Session(session) = server.handle_at(&RequestBody::OpenSession { lease_ms: 5000 }, 0) else {
+            panic!("No session opened");
+        };
+        assert_eq!(server.handle_at(&RequestBody::OpenSession { lease_ms: 0 }, 0), Reply::Error("Lease must not be zero".to_string()));
+        let add = |p: u16, persistent: bool| {
+            let mut add = ternary(Actions::Add, p, &[p as u8, 0], &[0xff, 0], b"a");
+            if let RequestBody::Ternary(_, options) = &mut add {
+                options.session = session;
+                options.persistent = persistent;
+            }
+            add
+        };
+        assert_eq!(server.handle_at(&add(1, false), 0), Reply::Ok);
+        assert_eq!(server.handle_at(&add(2, true), 0), Reply::Ok);
+        // A write outside the session takes the entry over.
+        assert_eq!(server.handle_at(&ternary(Actions::Add, 3, &[3, 0], &[0xff, 0], b"a"), 0), Reply::Ok);
+        assert_eq!(server.handle_at(&add(3, false), 0), Reply::Ok);
+        assert_eq!(server.handle_at(&ternary(Actions::Add, 3, &[3, 0], &[0xff, 0], b"b"), 0), Reply::Ok);
+        server.subscribe(subscription("/events", &[], None)).unwrap();
+
+        // Heartbeats and writes renew the lease.
+        assert_eq!(server.handle_at(&RequestBody::Heartbeat { session }, 4 * SECOND), Reply::Ok);
+        server.tick(8 * SECOND);
+        assert_eq!(server.sessions(), vec![session]);
+        server.tick(9 * SECOND);
+        assert!(server.sessions().is_empty());
+
+        let notifications = server.take_notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].event.kind, EventKind::Released);
+        assert!(matches!(&notifications[0].event.entry, Entry::Ternary(e) if e.priority == 1));
+        let entries = server.tables().get("acl").unwrap().as_ternary().unwrap().entries().to_vec();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| e.owner == Owner::default()));
+
+        assert_eq!(server.handle_at(&RequestBody::Heartbeat { session }, 9 * SECOND),
+                   Reply::Error(format!("No such session {}", session)));
+        assert!(matches!(server.handle_at(&add(4, false), 9 * SECOND), Reply::Error(_)));
+    }
+
+    #[test]
+    fn test_session_ownership() {
+        let mut server = server();
+        server.set_policy(Some(Policy {
+            tokens: BTreeMap::new(),
+            rules: vec![crate::access::Rule { principal: crate::access::Principal::Anyone, tables: vec![], access: crate::access::Access::Write }],
+        }));
+        let alice = PeerCredentials { pid: 7, uid: 1000, gid: 100 };
+        let bob = PeerCredentials { pid: 8, uid: 1001, gid: 100 };
+        let Reply::Session(session) = server.handle_from(&RequestBody::OpenSession { lease_ms: 1000 }, alice) else {
+            panic!("No session opened");
+        };
+        assert!(matches!(server.handle_from(&RequestBody::CloseSession { session }, bob), Reply::Error(_)));
+        assert_eq!(server.denials(), 1);
+        // Another process of the same user may carry on.
+        assert_eq!(server.handle_from(&RequestBody::Heartbeat { session }, PeerCredentials { pid: 9, ..alice }), Reply::Ok);
+        let mut add = RequestBody::Direct(SItem { table_id: "nexthop".to_string(), action: Actions::Add, index: 3, value: vec![1] },
+                                          WriteOptions { session, ..Default::default() });
+        assert_eq!(server.handle_from(&add, alice), Reply::Ok);
+
+        // Sessions owning entries survive a restart, until their lease runs out.
+        let mut restarted = Server::new(snapshot::decode(&snapshot::encode(server.tables())).unwrap());
+        assert_eq!(restarted.sessions(), vec![session]);
+        assert_eq!(restarted.handle(&RequestBody::CloseSession { session }), Reply::Ok);
+        assert!(restarted.tables().get("nexthop").unwrap().is_empty());
+        let Reply::Session(next) = restarted.handle(&RequestBody::OpenSession { lease_ms: 1000 }) else {
+            panic!("No session opened");
+        };
+        assert!(next > session);
+        if let RequestBody::Direct(_, options) = &mut add {
+            options.session = next;
+        }
+        assert_eq!(restarted.handle(&add), Reply::Ok);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut server = server();
+        let request = |reply_to: &str, sequence: u64| Request {
+            reply_to: reply_to.to_string(), sequence, token: None,
+            body: ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"permit"),
+        };
+        let acked = |sequence: u64, reply: Reply| Reply::Acked { sequence, reply: Box::new(reply) };
+        server.subscribe(subscription("/events", &[], None)).unwrap();
+
+        assert_eq!(server.handle_request(&request("/a", 1), None), acked(1, Reply::Ok));
+        assert_eq!(server.handle_request(&request("/a", 1), None), acked(1, Reply::Ok));
+        assert_eq!(server.duplicates(), 1);
+        assert_eq!(server.take_notifications().len(), 1);
+        // Numbers are per reply queue.
+        assert_eq!(server.handle_request(&request("/b", 1), None), acked(1, Reply::Ok));
+        assert_eq!(server.take_notifications()[0].event.kind, EventKind::Modified);
+
+        for sequence in 2..DUPLICATE_WINDOW as u64 + 2 {
+            server.handle_request(&request("/a", sequence), None);
+        }
+        assert!(matches!(server.handle_request(&request("/a", 1), None), Reply::Acked { sequence: 1, reply } if matches!(*reply, Reply::Error(_))));
+        assert_eq!(server.handle_request(&request("/a", 2), None), acked(2, Reply::Ok));
+        assert_eq!(server.duplicates(), 2);
+
+        // Reads are answered with their number but not remembered.
+        let dump = |sequence: u64| Request {
+            reply_to: "/c".to_string(), sequence, token: None, body: RequestBody::Dump { table_id: "acl".to_string() },
+        };
+        assert!(matches!(server.handle_request(&dump(1), None), Reply::Acked { sequence: 1, reply } if matches!(*reply, Reply::Ternary(ref e) if e.len() == 1)));
+        assert_eq!(server.handle_request(&request("/c", 2), None), acked(2, Reply::Ok));
+        assert!(matches!(server.handle_request(&dump(1), None), Reply::Acked { sequence: 1, reply } if matches!(*reply, Reply::Ternary(_))));
+        assert_eq!(server.duplicates(), 2);
+
+        // Without a reply queue requests cannot be told apart.
+        assert_eq!(server.handle_request(&request("", 1), None), acked(1, Reply::Ok));
+        assert_eq!(server.handle_request(&request("", 1), None), acked(1, Reply::Ok));
+        assert_eq!(server.duplicates(), 2);
+    }
+
+    #[test]
+    fn test_duplicates_per_client() {
+        let config = Config::parse(r#"
+            [tokens]
+            operator = "s3cret"
+
+            [[access]]
+            token = "operator"
+            level = "write"
+        "#).unwrap();
+        let mut server = server();
+        server.set_policy(config.policy);
+        server.set_client_queue_prefix("/tables.".to_string());
+        let request = |token: Option<&str>, body: RequestBody| Request {
+            reply_to: "/tables.a".to_string(), sequence: 1, token: token.map(String::from), body,
+        };
+        let acked = |reply: Reply| Reply::Acked { sequence: 1, reply: Box::new(reply) };
+        let add = || ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"permit");
+
+        // Refusals are not remembered, a retry with the right token is applied.
+        assert_eq!(server.handle_request(&request(Some("guess"), add()), None), acked(Reply::Error("Invalid token".to_string())));
+        assert!(matches!(server.handle_request(&request(None, add()), None), Reply::Acked { reply, .. } if matches!(*reply, Reply::Error(_))));
+        assert_eq!(server.handle_request(&request(Some("s3cret"), add()), None), acked(Reply::Ok));
+        assert_eq!(server.duplicates(), 0);
+
+        // Nor does another client get the reply meant for the first one.
+        assert!(matches!(server.handle_request(&request(None, add()), None), Reply::Acked { reply, .. } if matches!(*reply, Reply::Error(_))));
+        let other = PeerCredentials { pid: 7, uid: 1000, gid: 100 };
+        assert!(matches!(server.handle_request(&request(None, add()), Some(other)), Reply::Acked { reply, .. } if matches!(*reply, Reply::Error(_))));
+        assert_eq!(server.duplicates(), 0);
+        assert_eq!(server.handle_request(&request(Some("s3cret"), add()), None), acked(Reply::Ok));
+        assert_eq!(server.duplicates(), 1);
+    }
+
+    #[test]
+    fn test_compare_and_set() {
+        let mut server = server();
+        let write = |action: Actions, result: &[u8], expected_version: Option<u64>| {
+            let mut body = ternary(action, 1, &[10, 0], &[0xff, 0], result);
+            if let RequestBody::Ternary(_, options) = &mut body {
+                options.expected_version = expected_version;
+            }
+            body
+        };
+        let version = |server: &Server| server.tables().get("acl").unwrap().as_ternary().unwrap().entries()
+            .first().map(|e| e.version);
+
+        assert_eq!(server.handle(&write(Actions::Add, b"a", Some(0))), Reply::Ok);
+        assert_eq!(version(&server), Some(1));
+        // Another controller got there first.
+        assert_eq!(server.handle(&write(Actions::Add, b"b", Some(0))), Reply::Conflict { current: 1 });
+        assert_eq!(server.handle(&write(Actions::Add, b"b", Some(1))), Reply::Ok);
+        assert_eq!(server.handle(&write(Actions::Add, b"c", Some(1))), Reply::Conflict { current: 2 });
+        assert_eq!(server.handle(&write(Actions::Delete, b"", Some(1))), Reply::Conflict { current: 2 });
+        assert_eq!(server.handle(&write(Actions::Query, b"", Some(1))), Reply::Ternary(vec![
+            server.tables().get("acl").unwrap().as_ternary().unwrap().entries()[0].clone()
+        ]));
+        assert_eq!(server.handle(&write(Actions::Delete, b"", Some(2))), Reply::Ok);
+        assert_eq!(server.handle(&write(Actions::Delete, b"", Some(2))), Reply::Conflict { current: 0 });
+
+        // A re-added entry does not repeat an old version.
+        assert_eq!(server.handle(&write(Actions::Add, b"a", None)), Reply::Ok);
+        assert_eq!(version(&server), Some(4));
+        match server.handle(&RequestBody::ListTables) {
+            Reply::Tables(tables) => assert_eq!(tables[0].version, 4),
+            reply => panic!("Unexpected reply {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let mut server = server();
+        let subscribe = RequestBody::Subscribe { queue: "/all".to_string(), tables: vec![], filter: None };
+        assert_eq!(server.handle(&subscribe), Reply::Ok);
+        server.subscribe(subscription("/acl", &["acl"], Some(KeyFilter { key: vec![10, 0], mask: vec![0xff, 0] }))).unwrap();
+        server.subscribe(subscription("/acl", &["acl", "nexthop"], None)).unwrap();
+        assert!(server.subscribe(subscription("/bad", &["missing"], None)).is_err());
+
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"a"));
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"b"));
+        server.handle(&ternary(Actions::Add, 1, &[11, 0], &[0xff, 0], b"c"));
+        server.handle(&ternary(Actions::Delete, 1, &[10, 0], &[0xff, 0], b""));
+        // Failed writes do not notify.
+        server.handle(&ternary(Actions::Delete, 1, &[10, 0], &[0xff, 0], b""));
+
+        let notifications = server.take_notifications();
+        let all = vec![EventKind::Added, EventKind::Modified, EventKind::Added, EventKind::Deleted];
+        assert_eq!(kinds(&notifications, "/all"), all);
+        // Both "/acl" subscriptions match the first entry, but it is notified once.
+        assert_eq!(kinds(&notifications, "/acl"), all);
+
+        server.handle(&RequestBody::Unsubscribe { queue: "/acl".to_string() });
+        server.handle(&ternary(Actions::Add, 1, &[12, 0], &[0xff, 0], b"d"));
+        let notifications = server.take_notifications();
+        assert_eq!(kinds(&notifications, "/all"), vec![EventKind::Added]);
+        assert!(kinds(&notifications, "/acl").is_empty());
+    }
+
+    #[test]
+    fn test_key_filter() {
+        let mut server = server();
+        server.subscribe(subscription("/ten", &["acl"], Some(KeyFilter { key: vec![10, 0], mask: vec![0xff, 0] }))).unwrap();
+        server.subscribe(subscription("/index3", &["nexthop"], Some(KeyFilter { key: vec![3, 0], mask: vec![0xff, 0xff] }))).unwrap();
+
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"a"));
+        server.handle(&ternary(Actions::Add, 1, &[11, 0], &[0xff, 0], b"b"));
+        for index in 2..5 {
+            server.handle(&RequestBody::Direct(SItem {
+                table_id: "nexthop".to_string(),
+                action: Actions::Add,
+                index,
+                value: vec![],
+            }, WriteOptions::default()));
+        }
+
+        let notifications = server.take_notifications();
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(kinds(&notifications, "/ten"), vec![EventKind::Added]);
+        match &notifications[1].event.entry {
+            Entry::Direct(entry) => assert_eq!(entry.index, 3),
+            entry => panic!("Unexpected entry {:?}", entry),
+        }
+    }
+
+    #[test]
+    fn test_default_result() {
+        let mut server = server();
+        let get_default = RequestBody::GetDefault { table_id: "acl".to_string() };
+        assert_eq!(server.handle(&get_default), Reply::Default(None));
+        assert_eq!(server.handle(&lookup(&[10, 1], 64)), Reply::Miss);
+
+        let set_default = RequestBody::SetDefault { table_id: "acl".to_string(), result: Some(b"deny".to_vec()) };
+        assert_eq!(server.handle(&set_default), Reply::Ok);
+        assert_eq!(server.handle(&get_default), Reply::Default(Some(b"deny".to_vec())));
+        assert_eq!(server.handle(&lookup(&[10, 1], 64)), Reply::Result(b"deny".to_vec()));
+
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"permit"));
+        assert_eq!(server.handle(&lookup(&[10, 1], 64)), Reply::Result(b"permit".to_vec()));
+
+        let clear_default = RequestBody::SetDefault { table_id: "acl".to_string(), result: None };
+        server.handle(&clear_default);
+        assert_eq!(server.handle(&lookup(&[11, 1], 64)), Reply::Miss);
+    }
+
+    #[test]
+    fn test_default_result_in_snapshot() {
+        let path = std::env::temp_dir().join(format!("mem_ipc_server_snapshot_test.{}", std::process::id()));
+        let mut server = server();
+        assert!(matches!(server.handle(&RequestBody::SaveSnapshot), Reply::Error(_)));
+
+        server.set_snapshot_path(path.clone());
+        server.handle(&RequestBody::SetDefault { table_id: "nexthop".to_string(), result: Some(vec![7]) });
+        assert_eq!(server.handle(&RequestBody::SaveSnapshot), Reply::Ok);
+
+        let mut restored = Server::new(snapshot::load(&path).unwrap());
+        let lookup = RequestBody::Lookup { table_id: "nexthop".to_string(), key: vec![9, 0], bytes: 1 };
+        assert_eq!(restored.handle(&lookup), Reply::Result(vec![7]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_backend() {
+        const SECOND: u64 = 1_000_000_000;
+        let sim = SimBackend::new();
+        let mut server = server();
+        server.set_backend(Box::new(sim.clone()));
+
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"a"));
+        server.handle(&ternary(Actions::Add, 2, &[11, 0], &[0xff, 0], b"b"));
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"c"));
+        server.handle(&ternary(Actions::Delete, 2, &[11, 0], &[0xff, 0], b""));
+        let installed = sim.entries("acl");
+        assert_eq!(installed.len(), 1);
+        match &installed[0] {
+            Entry::Ternary(entry) => assert_eq!(entry.result, b"c"),
+            entry => panic!("Unexpected entry {:?}", entry),
+        }
+
+        let mut add = ternary(Actions::Add, 3, &[12, 0], &[0xff, 0], b"d");
+        if let RequestBody::Ternary(_, options) = &mut add {
+            options.timeouts = Timeouts { idle: 0, hard: 1 };
+        }
+        server.handle_at(&add, 0);
+        assert_eq!(sim.entries("acl").len(), 2);
+        server.tick(SECOND);
+        assert_eq!(sim.entries("acl").len(), 1);
+        assert_eq!(server.backend_errors(), 0);
+
+        // A change the device refuses is undone and reported to the client.
+        server.subscribe(subscription("/events", &[], None)).unwrap();
+        let failed = Reply::Error("Backend failed: Simulated device failure".to_string());
+        sim.fail_next(3);
+        assert_eq!(server.handle(&ternary(Actions::Add, 4, &[13, 0], &[0xff, 0], b"e")), failed);
+        assert_eq!(server.handle(&lookup(&[13, 0], 1)), Reply::Miss);
+        assert_eq!(server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"f")), failed);
+        assert_eq!(server.handle(&lookup(&[10, 0], 1)), Reply::Result(b"c".to_vec()));
+        assert_eq!(server.handle(&ternary(Actions::Delete, 1, &[10, 0], &[0xff, 0], b"")), failed);
+        assert_eq!(server.handle(&lookup(&[10, 0], 1)), Reply::Result(b"c".to_vec()));
+        assert_eq!(sim.entries("acl").len(), 1);
+        assert_eq!(server.backend_errors(), 3);
+        assert!(server.take_notifications().is_empty());
+        assert!(server.reconcile(false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let sim = SimBackend::new();
+        let mut server = server();
+        assert!(server.reconcile(false).is_err());
+        server.set_backend(Box::new(sim.clone()));
+
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"a"));
+        server.handle(&ternary(Actions::Add, 2, &[11, 0], &[0xff, 0], b"b"));
+        assert_eq!(server.handle(&RequestBody::Reconcile { repair: false }), Reply::Drift(vec![]));
+
+        // After a device reset the shadow and the device diverge until repaired.
+        sim.reset();
+        let drifts = server.reconcile(false).unwrap();
+        assert_eq!(drifts.len(), 1);
+        assert_eq!(drifts[0].missing.len(), 2);
+        assert!(sim.entries("acl").is_empty());
+
+        match server.handle(&RequestBody::Reconcile { repair: true }) {
+            Reply::Drift(drifts) => assert_eq!(drifts[0].to_string(), "acl missing=2 extra=0 mismatched=0"),
+            reply => panic!("Unexpected reply {:?}", reply),
+        }
+        assert_eq!(sim.entries("acl").len(), 2);
+        assert!(server.reconcile(false).unwrap().is_empty());
+        assert!(server.take_notifications().is_empty());
+
+        // A table that cannot be read back does not stop the others.
+        let nexthop = SItem { table_id: "nexthop".to_string(), action: Actions::Add, index: 1, value: b"x".to_vec() };
+        server.handle(&RequestBody::Direct(nexthop, WriteOptions::default()));
+        sim.reset();
+        sim.fail_next(1);
+        assert_eq!(server.reconcile(false).unwrap().len(), 1);
+        assert_eq!(server.backend_errors(), 1);
+    }
+
+    #[test]
+    fn test_reconcile_full_backend() {
+        let tcam = TcamBackend::new(2);
+        let mut server = server();
+        server.set_backend(Box::new(tcam.clone()));
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"a"));
+        server.handle(&ternary(Actions::Add, 2, &[11, 0], &[0xff, 0], b"b"));
+
+        // The device lost both entries and was filled with others, so the
+        // extra entries have to go before the missing ones fit.
+        let spec = server.tables().get("acl").unwrap().spec().clone();
+        let mut device = tcam.clone();
+        for mut entry in device.read_back(&spec).unwrap() {
+            device.remove(&spec, &entry).unwrap();
+            if let Entry::Ternary(e) = &mut entry {
+                e.key[1] = 1;
+            }
+            device.program(&spec, &entry).unwrap();
+        }
+        let drifts = server.reconcile(true).unwrap();
+        assert_eq!(drifts[0].to_string(), "acl missing=2 extra=2 mismatched=0");
+        assert_eq!(server.backend_errors(), 0);
+        assert!(server.reconcile(false).unwrap().is_empty());
+
+        // Direct tables live next to the TCAM.
+        let nexthop = SItem { table_id: "nexthop".to_string(), action: Actions::Add, index: 1, value: b"x".to_vec() };
+        assert_eq!(server.handle(&RequestBody::Direct(nexthop, WriteOptions::default())), Reply::Ok);
+        assert!(server.reconcile(false).unwrap().is_empty());
+    }
+
+    fn specs(specs: &[&str]) -> Vec<TableSpec> {
+        specs.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_reload() {
+        let sim = SimBackend::new();
+        let mut server = server();
+        server.set_backend(Box::new(sim.clone()));
+        server.subscribe(subscription("/all", &[], None)).unwrap();
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"a"));
+        server.handle(&ternary(Actions::Add, 2, &[11, 0], &[0xff, 0], b"b"));
+        server.take_notifications();
+
+        // Growing, adding and removing an empty table are safe.
+        let changes = server.reload(&specs(&["acl:ternary:2:32", "mac:ternary:6:8"]), false).unwrap();
+        assert_eq!(changes, vec!["resized acl from 16 to 32", "added mac:ternary:6:8", "removed nexthop:direct:16"]);
+        assert!(server.tables().get("nexthop").is_none());
+        assert_eq!(server.reload(&specs(&["acl:ternary:2:32", "mac:ternary:6:8"]), false).unwrap(), Vec::<String>::new());
+
+        // Destructive changes are refused as a whole.
+        for declared in [&["acl:ternary:2:1", "mac:ternary:6:8"][..], &["acl:ternary:4:32"], &["mac:ternary:6:8"]] {
+            let reply = server.reload(&specs(declared), false);
+            assert!(reply.unwrap_err().starts_with("Reload refused"));
+        }
+        assert!(server.tables().get("mac").is_some());
+        assert_eq!(server.tables().get("acl").unwrap().len(), 2);
+
+        let changes = server.reload(&specs(&["acl:ternary:2:1"]), true).unwrap();
+        assert_eq!(changes, vec!["resized acl from 32 to 1, dropped 1 entries", "removed mac:ternary:6:8"]);
+        assert_eq!(sim.entries("acl").len(), 1);
+        assert_eq!(kinds(&server.take_notifications(), "/all"), vec![EventKind::Deleted]);
+
+        let changes = server.reload(&specs(&["acl:ternary:4:8"]), true).unwrap();
+        assert_eq!(changes, vec!["redeclared acl:ternary:2:1 as acl:ternary:4:8, dropped 1 entries"]);
+        assert!(sim.entries("acl").is_empty());
+        assert!(server.reload(&specs(&["acl:ternary:4:8", "acl:ternary:4:8"]), false).is_err());
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let path = std::env::temp_dir().join(format!("mem_ipc_server_config_test.{}.toml", std::process::id()));
+        let table = "[[table]]\nname = \"acl\"\nkind = \"ternary\"\nkey_width = 2\ncapacity = 16\n";
+        std::fs::write(&path, table).unwrap();
+
+        let mut server = server();
+        assert!(matches!(server.handle(&RequestBody::Reload { force: false }), Reply::Error(_)));
+        let config = Config::load(&path).unwrap();
+        // The adjustment keeps "nexthop" declared, like a --table option.
+        let adjust = |config: &mut Config| config.tables.push("nexthop:direct:16".parse().unwrap());
+        server.set_config(path.clone(), config, Box::new(adjust));
+
+        std::fs::write(&path, format!("{}[queue]\ndepth = 4\n[[table]]\nname = \"mac\"\nkind = \"direct\"\ncapacity = 8\n", table)).unwrap();
+        match server.handle(&RequestBody::Reload { force: false }) {
+            Reply::Reloaded(changes) => assert_eq!(changes, vec![
+                "added mac:direct:8",
+                "queue settings changed, restart to apply",
+            ]),
+            reply => panic!("Unexpected reply {:?}", reply),
+        }
+        assert!(server.tables().get("nexthop").is_some());
+
+        std::fs::write(&path, "[[table]]\nname = \"acl\"").unwrap();
+        assert!(matches!(server.handle(&RequestBody::Reload { force: false }), Reply::Error(_)));
+        assert!(server.tables().get("mac").is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let path = std::env::temp_dir().join(format!("mem_ipc_server_shutdown_test.{}", std::process::id()));
+        let mut server = server();
+        server.shutdown().unwrap();
+        server.set_log(File::create(&path).unwrap());
+        server.handle(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"a"));
+        server.shutdown().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().map(|l| l.split_once(' ').unwrap().1).collect();
+        assert_eq!(lines, vec!["add acl prio=1 key=0a00 mask=ff00 result=61 idle_timeout=0 hard_timeout=0", "shutdown"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_handle_from() {
+        let path = std::env::temp_dir().join(format!("mem_ipc_server_peer_test.{}", std::process::id()));
+        let mut server = server();
+        server.set_log(File::create(&path).unwrap());
+        let peer = PeerCredentials { pid: 42, uid: 1000, gid: 100 };
+        assert_eq!(server.handle_from(&ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"a"), peer), Reply::Ok);
+        assert_eq!(server.peer(), None);
+        server.handle(&ternary(Actions::Delete, 1, &[10, 0], &[0xff, 0], b""));
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().map(|l| l.split_once(' ').unwrap().1).collect();
+        assert_eq!(lines[0], "add acl prio=1 key=0a00 mask=ff00 result=61 idle_timeout=0 hard_timeout=0 by pid=42 uid=1000 gid=100");
+        assert!(lines[1].starts_with("delete acl") && !lines[1].contains(" by "));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_queue_ownership() {
+        let config = Config::parse(r#"
+            [tokens]
+            monitoring = "s3cret"
+            agent = "agent"
+            operator = "op"
+
+            [[access]]
+            token = "monitoring"
+            level = "read-only"
+
+            [[access]]
+            token = "agent"
+            level = "read-only"
+
+            [[access]]
+            token = "operator"
+            level = "admin"
+
+            [[access]]
+            gid = 100
+            level = "read-only"
+        "#).unwrap();
+        let mut server = server();
+        server.set_policy(config.policy);
+        server.set_client_queue_prefix("/tables.".to_string());
+        let request = |token: &str, reply_to: &str, body: RequestBody| Request {
+            reply_to: reply_to.to_string(), sequence: 0, token: Some(token.to_string()), body,
+        };
+        let subscribe = |queue: &str| RequestBody::Subscribe { queue: queue.to_string(), tables: vec![], filter: None };
+        let unsubscribe = |queue: &str| RequestBody::Unsubscribe { queue: queue.to_string() };
+
+        // Clients on message queues stay among the client queues.
+        assert!(matches!(server.handle_request(&request("s3cret", "", subscribe("/tables")), None), Reply::Error(_)));
+        assert_eq!(server.handle_request(&request("s3cret", "", subscribe("/tables.monitoring")), None), Reply::Ok);
+        let message = request("s3cret", "/tables", RequestBody::ListTables).pack();
+        let (request_sent, reply, _) = server.handle_message(&message, None).unwrap();
+        assert!(request_sent.reply_to.is_empty() && matches!(reply, Reply::Error(_)));
+        let message = request("s3cret", "/tables.reply", RequestBody::ListTables).pack();
+        assert_eq!(server.handle_message(&message, None).unwrap().0.reply_to, "/tables.reply");
+        assert_eq!(server.handle_request(&request("op", "", subscribe("/elsewhere")), None), Reply::Ok);
+
+        // Only the client that subscribed, or an admin, may unsubscribe.
+        server.subscribe(subscription("/tables.notify", &[], None)).unwrap();
+        assert!(matches!(server.handle_request(&request("agent", "", unsubscribe("/tables.monitoring")), None), Reply::Error(_)));
+        assert!(matches!(server.handle_request(&request("s3cret", "", unsubscribe("/tables.notify")), None), Reply::Error(_)));
+        assert_eq!(server.subscriptions().len(), 3);
+        assert_eq!(server.handle_request(&request("s3cret", "", unsubscribe("/tables.monitoring")), None), Reply::Ok);
+        assert_eq!(server.handle_request(&request("op", "", unsubscribe("/tables.notify")), None), Reply::Ok);
+        assert_eq!(server.subscriptions().len(), 1);
+        assert_eq!(server.denials(), 4);
+
+        // Clients on the socket may name queues their user owns.
+        let name = format!("/server_queue_owner_test.{}", std::process::id());
+        let queue = crate::TableInterface::get_table_reader_sized(&name, 1024, 10).unwrap();
+        let uid = unsafe { libc::getuid() };
+        let owner = PeerCredentials { pid: 7, uid, gid: 100 };
+        let other = PeerCredentials { pid: 8, uid: uid + 1, gid: 100 };
+        assert!(matches!(server.handle_from(&subscribe(&name), other), Reply::Error(_)));
+        assert_eq!(server.handle_from(&subscribe(&name), owner), Reply::Ok);
+        queue.unlink().unwrap();
+    }
+
+    #[test]
+    fn test_access_policy() {
+        let path = std::env::temp_dir().join(format!("mem_ipc_server_access_test.{}", std::process::id()));
+        let config = Config::parse(r#"
+            [tokens]
+            monitoring = "s3cret"
+
+            [[access]]
+            gid = 100
+            tables = ["acl"]
+            level = "write"
+
+            [[access]]
+            token = "monitoring"
+            level = "read-only"
+        "#).unwrap();
+        let mut server = server();
+        server.set_policy(config.policy);
+        server.set_log(File::create(&path).unwrap());
+        let request = |token: Option<&str>, body: RequestBody| Request { reply_to: String::new(), sequence: 0, token: token.map(String::from), body };
+        let add = || ternary(Actions::Add, 1, &[10, 0], &[0xff, 0], b"a");
+
+        // Monitoring may look but not touch.
+        let dump = request(Some("s3cret"), RequestBody::Dump { table_id: "acl".to_string() });
+        assert_eq!(server.handle_request(&dump, None), Reply::Ternary(vec![]));
+        assert_eq!(server.handle_request(&request(Some("s3cret"), add()), None),
+                   Reply::Error("Permission denied: write access to table acl".to_string()));
+        assert_eq!(server.handle_request(&request(Some("guess"), add()), None), Reply::Error("Invalid token".to_string()));
+
+        let writer = PeerCredentials { pid: 7, uid: 1000, gid: 100 };
+        assert_eq!(server.handle_request(&request(None, add()), Some(writer)), Reply::Ok);
+        let nexthop = RequestBody::ResetCounters { table_id: "nexthop".to_string() };
+        assert!(matches!(server.handle_request(&request(None, nexthop), Some(writer)), Reply::Error(_)));
+        assert!(matches!(server.handle_request(&request(None, RequestBody::SaveSnapshot), Some(writer)), Reply::Error(_)));
+        match server.handle_from(&RequestBody::ListTables, writer) {
+            Reply::Tables(tables) => assert_eq!(tables.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(), vec!["acl"]),
+            reply => panic!("Unexpected reply {:?}", reply),
+        }
+
+        // Without credentials or token nothing is granted.
+        assert!(matches!(server.handle(&add()), Reply::Error(_)));
+        assert_eq!(server.handle(&RequestBody::ListTables), Reply::Tables(vec![]));
+        assert!(matches!(server.handle(&RequestBody::Unsubscribe { queue: "/events".to_string() }), Reply::Error(_)));
+        assert!(matches!(server.handle(&RequestBody::OpenSession { lease_ms: 1000 }), Reply::Error(_)));
+        assert!(matches!(server.handle_from(&RequestBody::OpenSession { lease_ms: 1000 }, writer), Reply::Session(_)));
+        assert_eq!(server.denials(), 7);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().map(|l| l.split_once(' ').unwrap().1).collect();
+        assert_eq!(lines, vec![
+            "denied write acl by token=monitoring",
+            "denied invalid token by anonymous",
+            "add acl prio=1 key=0a00 mask=ff00 result=61 idle_timeout=0 hard_timeout=0 by pid=7 uid=1000 gid=100",
+            "denied write nexthop by pid=7 uid=1000 gid=100",
+            "denied admin * by pid=7 uid=1000 gid=100",
+            "denied write acl by anonymous",
+            "denied read-only any by anonymous",
+            "denied write any by anonymous",
+            "session 1 opened lease=1000ms by pid=7 uid=1000 gid=100",
+        ]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_table() {
+        let mut server = server();
+        let reply = server.handle(&RequestBody::Dump { table_id: "missing".to_string() });
+        assert_eq!(reply, Reply::Error("No such table".to_string()));
+
+        let mut item = CItem::default_instance();
+        item.table_id = "nexthop".to_string();
+        item.action = Actions::Add;
+        let reply = server.handle(&RequestBody::Ternary(item, WriteOptions::default()));
+        assert_eq!(reply, Reply::Error("Table is not a ternary table".to_string()));
+    }
+}
@@ -0,0 +1,75 @@
+//! Signals turned into flags a main loop can poll.
+//!
+//! The handlers only set a flag, so the loop acts on a signal the next time it
+//! checks, at the latest after its read timeout. Handlers are installed with
+//! `SA_RESTART`, so blocking queue calls are not interrupted.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc::c_int;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    Hangup,
+    Interrupt,
+    Terminate,
+}
+
+static RAISED: [AtomicBool; 3] = [AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false)];
+
+impl Signal {
+    fn number(self) -> c_int {
+        match self {
+            Signal::Hangup => libc::SIGHUP,
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Terminate => libc::SIGTERM,
+        }
+    }
+
+    fn flag(self) -> &'static AtomicBool {
+        &RAISED[self as usize]
+    }
+
+    fn from_number(number: c_int) -> Option<Signal> {
+        [Signal::Hangup, Signal::Interrupt, Signal::Terminate].into_iter().find(|s| s.number() == number)
+    }
+}
+
+extern "C" fn raise_flag(number: c_int) {
+    if let Some(signal) = Signal::from_number(number) {
+        signal.flag().store(true, Ordering::SeqCst);
+    }
+}
+
+/// Replaces the default action of `signal` with setting its flag.
+pub fn catch(signal: Signal) -> Result<(), String> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = raise_flag as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal.number(), &action, std::ptr::null_mut()) != 0 {
+            return Err(format!("Failed to catch {:?}: {}", signal, std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `signal` arrived since the last call, clearing its flag.
+pub fn take(signal: Signal) -> bool {
+    signal.flag().swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod signals_tests {
+    use super::*;
+
+    #[test]
+    fn test_catch_hangup() {
+        catch(Signal::Hangup).unwrap();
+        assert!(!take(Signal::Hangup));
+        unsafe { libc::raise(libc::SIGHUP) };
+        assert!(take(Signal::Hangup));
+        assert!(!take(Signal::Hangup));
+    }
+}
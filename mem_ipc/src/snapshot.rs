@@ -0,0 +1,145 @@
+//! Point-in-time copies of all shadow tables.
+//!
+//! A snapshot holds every table's declaration, default result and entries,
+//! including counters and aging state, so a server can be restarted from it.
+
+use std::fs;
+use std::path::Path;
+
+use crate::protocol::{get_direct_entry, get_ternary_entry, put_bytes, put_direct_entry, put_option_bytes, put_ternary_entry, Cursor,
+                      ENTRY_FORMAT};
+use crate::tables::{Table, TableKind, TableSpec, Tables};
+
+const MAGIC: &[u8; 8] = b"MIPCSNAP";
+/// Version 2 added entry owners, version 3 entry and table versions. Entries
+/// are in the `ENTRY_FORMAT` of the same number. Older snapshots are still read.
+const VERSION: u8 = ENTRY_FORMAT;
+
+pub fn encode(tables: &Tables) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(MAGIC);
+    buffer.push(VERSION);
+    buffer.extend((tables.iter().count() as u64).to_le_bytes());
+    for table in tables.iter() {
+        let spec = table.spec();
+        put_bytes(&mut buffer, spec.name.as_bytes());
+        buffer.push(match spec.kind {
+            TableKind::Ternary => 0,
+            TableKind::Direct => 1,
+        });
+        buffer.extend((spec.key_width as u64).to_le_bytes());
+        buffer.extend((spec.capacity as u64).to_le_bytes());
+        put_option_bytes(&mut buffer, table.default_result());
+        buffer.extend(table.version().to_le_bytes());
+        buffer.extend((table.len() as u64).to_le_bytes());
+        match table {
+            Table::Ternary(t) => t.entries().iter().for_each(|e| put_ternary_entry(&mut buffer, e)),
+            Table::Direct(t) => t.entries().for_each(|e| put_direct_entry(&mut buffer, e)),
+        }
+    }
+    buffer
+}
+
+pub fn decode(buffer: &[u8]) -> Result<Tables, String> {
+    if buffer.len() < MAGIC.len() + 1 || &buffer[..MAGIC.len()] != MAGIC {
+        return Err("Not a table snapshot".to_string());
+    }
+    let version = buffer[MAGIC.len()];
+    if version == 0 || version > VERSION {
+        return Err(format!("Unsupported snapshot version {}", version));
+    }
+
+    let mut cursor = Cursor::new(&buffer[MAGIC.len() + 1..]);
+    let mut tables = Tables::default();
+    let count = cursor.get_u64()?;
+    for _ in 0..count {
+        let spec = TableSpec {
+            name: cursor.get_string()?,
+            kind: match cursor.get_u8()? {
+                0 => TableKind::Ternary,
+                1 => TableKind::Direct,
+                _ => return Err("Invalid table kind".to_string()),
+            },
+            key_width: cursor.get_u64()? as usize,
+            capacity: cursor.get_u64()? as usize,
+        };
+        let name = spec.name.clone();
+        tables.declare(spec)?;
+        let table = tables.get_mut(&name)?;
+        table.set_default_result(cursor.get_option_bytes()?.map(|r| r.to_vec()));
+        if version >= 3 {
+            table.set_version(cursor.get_u64()?);
+        }
+        let entries = cursor.get_u64()?;
+        for _ in 0..entries {
+            match table {
+                Table::Ternary(t) => t.restore(get_ternary_entry(&mut cursor, version)?)?,
+                Table::Direct(t) => t.restore(get_direct_entry(&mut cursor, version)?)?,
+            }
+        }
+    }
+    Ok(tables)
+}
+
+/// Writes a snapshot of `tables` to `path`, replacing the previous one
+/// atomically.
+pub fn save(tables: &Tables, path: &Path) -> Result<(), String> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, encode(tables)).map_err(|e| format!("Failed to write snapshot {}: {}", path.display(), e))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Failed to replace snapshot {}: {}", path.display(), e))
+}
+
+pub fn load(path: &Path) -> Result<Tables, String> {
+    let buffer = fs::read(path).map_err(|e| format!("Failed to read snapshot {}: {}", path.display(), e))?;
+    decode(&buffer).map_err(|e| format!("Invalid snapshot {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+    use crate::tables::Timeouts;
+
+    #[test]
+    fn test_round_trip() {
+        let mut tables = Tables::default();
+        tables.declare("acl:ternary:2:16".parse().unwrap()).unwrap();
+        tables.declare("nexthop:direct:16".parse().unwrap()).unwrap();
+
+        let acl = tables.get_mut("acl").unwrap();
+        acl.set_default_result(Some(b"deny".to_vec()));
+        let acl = acl.as_ternary_mut().unwrap();
+        acl.insert(1, &[10, 0], &[0xff, 0], b"permit", Timeouts { idle: 5, hard: 0 }, 42).unwrap();
+        acl.insert(7, &[10, 1], &[0xff, 0xff], b"log", Timeouts::default(), 43).unwrap();
+        acl.lookup(&[10, 1], 100, 50);
+        tables.get_mut("nexthop").unwrap().as_direct_mut().unwrap().insert(3, b"eth0", Timeouts::default(), 44).unwrap();
+
+        let restored = decode(&encode(&tables)).unwrap();
+        let acl = restored.get("acl").unwrap();
+        assert_eq!(acl.default_result(), Some(&b"deny"[..]));
+        assert_eq!(acl.version(), tables.get("acl").unwrap().version());
+        assert_eq!(acl.as_ternary().unwrap().entries(), tables.get("acl").unwrap().as_ternary().unwrap().entries());
+        let nexthop = restored.get("nexthop").unwrap();
+        assert_eq!(nexthop.default_result(), None);
+        assert_eq!(nexthop.as_direct().unwrap().get(3).unwrap().value, b"eth0");
+    }
+
+    #[test]
+    fn test_invalid_snapshot() {
+        assert!(decode(b"garbage").is_err());
+        let mut buffer = encode(&Tables::default());
+        buffer[MAGIC.len()] = 99;
+        assert!(decode(&buffer).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("mem_ipc_snapshot_test.{}", std::process::id()));
+        let mut tables = Tables::default();
+        tables.declare("acl:ternary:2:16".parse().unwrap()).unwrap();
+        save(&tables, &path).unwrap();
+        let restored = load(&path).unwrap();
+        assert!(restored.get("acl").is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+//! Unix domain socket transport.
+//!
+//! An alternative to the message queues: the server listens on a
+//! `SOCK_SEQPACKET` socket and every client connects to it. A connection is a
+//! [`Transport`] like a queue, so a [`TableInterface`](crate::TableInterface) on it reads and writes
+//! the same fragmented messages.
+//!
+//! Replies travel back over the connection the request came in on; a request
+//! whose `reply_to` is empty still gets no reply. The kernel tells the server
+//! which process is on the other end, see [`PeerCredentials`].
+
+use std::ffi::CString;
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::transport::{Transport, CONNECTION_CLOSED};
+use crate::MAX_QITEM_SIZE;
+
+/// Largest item sent over a socket. Bigger messages are fragmented.
+pub const SOCKET_ITEM_SIZE: usize = MAX_QITEM_SIZE;
+
+/// Connections waiting to be accepted before new ones are refused.
+const BACKLOG: i32 = 128;
+
+/// The process at the other end of a connection, as recorded by the kernel
+/// when it connected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerCredentials {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// One end of a connection.
+pub struct SocketTransport {
+    fd: OwnedFd,
+    nonblocking: bool,
+}
+
+impl SocketTransport {
+    /// Connects to the server listening on `path`.
+    pub fn connect(path: &Path) -> Result<SocketTransport, String> {
+        let fd = seqpacket_socket(0)?;
+        let (address, len) = socket_address(path)?;
+        if unsafe { libc::connect(fd.as_raw_fd(), &address as *const _ as *const libc::sockaddr, len) } == -1 {
+            return Err(format!("Failed to connect to {}: {}", path.display(), std::io::Error::last_os_error()));
+        }
+        Ok(SocketTransport { fd, nonblocking: false })
+    }
+
+    /// Who is at the other end of the connection.
+    pub fn peer_credentials(&self) -> Result<PeerCredentials, String> {
+        let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(self.fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut _, &mut len)
+        };
+        if res == -1 {
+            return Err(format!("Failed to get peer credentials: {}", std::io::Error::last_os_error()));
+        }
+        Ok(PeerCredentials { pid: cred.pid as u32, uid: cred.uid, gid: cred.gid })
+    }
+}
+
+impl Transport for SocketTransport {
+    fn send(&self, item: &[u8]) -> Result<(), String> {
+        let flags = libc::MSG_NOSIGNAL | if self.nonblocking { libc::MSG_DONTWAIT } else { 0 };
+        let res = unsafe { libc::send(self.fd.as_raw_fd(), item.as_ptr() as *const _, item.len(), flags) };
+        if res == -1 {
+            return Err(format!("Failed to send message: {}", std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn receive(&self, buffer: &mut [u8], deadline: Option<Instant>) -> Result<Option<usize>, String> {
+        loop {
+            let flags = match deadline {
+                None => 0,
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if !remaining.is_zero() && poll_readable(&[self.as_raw_fd()], remaining)?.is_empty() {
+                        continue;
+                    }
+                    libc::MSG_DONTWAIT
+                },
+            };
+            let res = unsafe { libc::recv(self.fd.as_raw_fd(), buffer.as_mut_ptr() as *mut _, buffer.len(), flags) };
+            match res {
+                -1 => {
+                    let error = std::io::Error::last_os_error();
+                    match (error.raw_os_error(), deadline) {
+                        (Some(libc::EAGAIN), Some(deadline)) if Instant::now() >= deadline => return Ok(None),
+                        (Some(libc::EAGAIN), _) | (Some(libc::EINTR), _) => continue,
+                        _ => return Err(format!("Failed to receive message: {}", error)),
+                    }
+                },
+                // Every item carries a fragment header, so only a closed
+                // connection reads as empty.
+                0 => return Err(CONNECTION_CLOSED.to_string()),
+                received_bytes => return Ok(Some(received_bytes as usize)),
+            }
+        }
+    }
+
+    fn max_item_size(&self) -> usize {
+        SOCKET_ITEM_SIZE
+    }
+
+    /// A connection has no name, so there is nothing to remove.
+    fn unlink(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for SocketTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// The server's listening socket.
+pub struct SocketListener {
+    fd: OwnedFd,
+    path: PathBuf,
+}
+
+impl SocketListener {
+    /// Listens on `path`. A socket left behind by a server that is gone is
+    /// replaced; one somebody still listens on is not.
+    pub fn bind(path: &Path) -> Result<SocketListener, String> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(format!("{} exists and is not a socket", path.display()));
+            }
+            if SocketTransport::connect(path).is_ok() {
+                return Err(format!("{} is in use by another server", path.display()));
+            }
+            fs::remove_file(path).map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+        }
+        let fd = seqpacket_socket(libc::SOCK_NONBLOCK)?;
+        let (address, len) = socket_address(path)?;
+        if unsafe { libc::bind(fd.as_raw_fd(), &address as *const _ as *const libc::sockaddr, len) } == -1 {
+            return Err(format!("Failed to bind {}: {}", path.display(), std::io::Error::last_os_error()));
+        }
+        if unsafe { libc::listen(fd.as_raw_fd(), BACKLOG) } == -1 {
+            return Err(format!("Failed to listen on {}: {}", path.display(), std::io::Error::last_os_error()));
+        }
+        Ok(SocketListener { fd, path: path.to_path_buf() })
+    }
+
+    /// Waits at most `timeout` for a client to connect. Writes on the new
+    /// connection fail instead of blocking when the client stops reading.
+    pub fn accept_timeout(&self, timeout: Duration) -> Result<Option<SocketTransport>, String> {
+        if !timeout.is_zero() && poll_readable(&[self.as_raw_fd()], timeout)?.is_empty() {
+            return Ok(None);
+        }
+        let fd = unsafe { libc::accept4(self.fd.as_raw_fd(), std::ptr::null_mut(), std::ptr::null_mut(), libc::SOCK_CLOEXEC) };
+        if fd == -1 {
+            let error = std::io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::ECONNABORTED) => Ok(None),
+                _ => Err(format!("Failed to accept connection: {}", error)),
+            };
+        }
+        Ok(Some(SocketTransport { fd: unsafe { OwnedFd::from_raw_fd(fd) }, nonblocking: true }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes the socket's path, so no new client can connect. Connections
+    /// already made keep working.
+    pub fn unlink(&self) -> Result<(), String> {
+        fs::remove_file(&self.path).map_err(|e| format!("Failed to remove {}: {}", self.path.display(), e))
+    }
+}
+
+impl AsRawFd for SocketListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Waits at most `timeout` until one of `fds` can be read from, or was closed
+/// by the other end. Returns the positions of those in `fds`.
+pub fn poll_readable(fds: &[RawFd], timeout: Duration) -> Result<Vec<usize>, String> {
+    let mut pollfds: Vec<libc::pollfd> = fds.iter().map(|fd| libc::pollfd { fd: *fd, events: libc::POLLIN, revents: 0 }).collect();
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let res = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout_ms) };
+    if res == -1 {
+        let error = std::io::Error::last_os_error();
+        // A signal arrived; the caller looks at it and polls again.
+        if error.raw_os_error() == Some(libc::EINTR) {
+            return Ok(Vec::new());
+        }
+        return Err(format!("Failed to poll: {}", error));
+    }
+    Ok(pollfds.iter().enumerate().filter(|(_, p)| p.revents != 0).map(|(i, _)| i).collect())
+}
+
+fn seqpacket_socket(flags: i32) -> Result<OwnedFd, String> {
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC | flags, 0) };
+    if fd == -1 {
+        return Err(format!("Failed to create socket: {}", std::io::Error::last_os_error()));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn socket_address(path: &Path) -> Result<(libc::sockaddr_un, libc::socklen_t), String> {
+    let c_path = CString::new(path.as_os_str().as_encoded_bytes()).map_err(|_| "CString::new failed")?;
+    let bytes = c_path.as_bytes_with_nul();
+    let mut address: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    if bytes.len() > address.sun_path.len() {
+        return Err(format!("Socket path {} is too long", path.display()));
+    }
+    address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in address.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+    let len = std::mem::size_of::<libc::sa_family_t>() + bytes.len();
+    Ok((address, len as libc::socklen_t))
+}
+
+#[cfg(test)]
+mod socket_tests {
+    use super::*;
+    use crate::TableInterface;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mem_ipc_{}.{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = socket_path("round_trip");
+        let listener = SocketListener::bind(&path).expect("Failed to bind");
+        assert_eq!(listener.accept_timeout(Duration::ZERO).unwrap().map(|_| ()), None);
+
+        let client = TableInterface::connect_socket(&path).expect("Failed to connect");
+        let server = listener.accept_timeout(Duration::from_secs(1)).unwrap().expect("No connection");
+        let server = TableInterface::with_transport("client", Box::new(server));
+        assert_eq!(server.read_timeout(Duration::from_millis(10)).unwrap(), None);
+
+        // Large enough to be split into several items.
+        let message: Vec<u8> = (0..3 * SOCKET_ITEM_SIZE).map(|i| i as u8).collect();
+        let writer = std::thread::spawn(move || {
+            client.write(b"hello").unwrap();
+            client.write(&message).unwrap();
+            (client, message)
+        });
+        assert_eq!(server.read().unwrap(), b"hello");
+        let received = server.read_timeout(Duration::from_secs(1)).unwrap().expect("Timed out");
+        let (client, message) = writer.join().unwrap();
+        assert_eq!(received, message);
+
+        server.write(b"reply").unwrap();
+        assert_eq!(client.read().unwrap(), b"reply");
+
+        drop(client);
+        assert_eq!(server.read_timeout(Duration::from_secs(1)), Err(CONNECTION_CLOSED));
+        listener.unlink().unwrap();
+    }
+
+    #[test]
+    fn test_peer_credentials() {
+        let path = socket_path("peer_credentials");
+        let listener = SocketListener::bind(&path).expect("Failed to bind");
+        let client = SocketTransport::connect(&path).expect("Failed to connect");
+        let server = listener.accept_timeout(Duration::from_secs(1)).unwrap().expect("No connection");
+
+        let peer = server.peer_credentials().unwrap();
+        assert_eq!(peer, PeerCredentials { pid: std::process::id(), uid: unsafe { libc::getuid() }, gid: unsafe { libc::getgid() } });
+        assert_eq!(client.peer_credentials().unwrap(), peer);
+        listener.unlink().unwrap();
+    }
+
+    #[test]
+    fn test_bind_replaces_stale_socket() {
+        let path = socket_path("stale");
+        let listener = SocketListener::bind(&path).expect("Failed to bind");
+        assert!(SocketListener::bind(&path).err().expect("Bound twice").contains("in use"));
+        // The path stays behind, as after a crash.
+        drop(listener);
+        assert!(path.exists());
+        let listener = SocketListener::bind(&path).expect("Failed to replace stale socket");
+        listener.unlink().unwrap();
+
+        fs::write(&path, "").unwrap();
+        assert!(SocketListener::bind(&path).err().expect("Bound over a file").contains("not a socket"));
+        fs::remove_file(&path).unwrap();
+    }
+}
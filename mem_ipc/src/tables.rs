@@ -0,0 +1,984 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Nanoseconds since the Unix epoch, the time base used for all table timestamps.
+pub fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+/// Formats bytes as lowercase hex.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses the output of [`to_hex`].
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("Invalid hex string '{}'", hex));
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Invalid hex string '{}'", hex)))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TableKind {
+    Ternary,
+    Direct,
+}
+
+impl TableKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TableKind::Ternary => "ternary",
+            TableKind::Direct => "direct",
+        }
+    }
+}
+
+impl FromStr for TableKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ternary" => Ok(TableKind::Ternary),
+            "direct" => Ok(TableKind::Direct),
+            _ => Err(format!("Unknown table kind '{}'", s)),
+        }
+    }
+}
+
+/// Declaration of a shadow table.
+///
+/// For ternary tables `key_width` is the key and mask length in bytes. Direct
+/// tables are indexed by a `u16` and `capacity` bounds the index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableSpec {
+    pub name: String,
+    pub kind: TableKind,
+    pub key_width: usize,
+    pub capacity: usize,
+}
+
+impl TableSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("Table name must not be empty".to_string());
+        }
+        if self.kind == TableKind::Ternary && self.key_width == 0 {
+            return Err(format!("Ternary table '{}' needs a non-zero key width", self.name));
+        }
+        if self.kind == TableKind::Direct && self.capacity > u16::MAX as usize + 1 {
+            return Err(format!("Direct table '{}' capacity exceeds the u16 index range", self.name));
+        }
+        if self.capacity == 0 {
+            return Err(format!("Table '{}' needs a non-zero capacity", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Formats the spec the way `from_str` parses it.
+impl fmt::Display for TableSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            TableKind::Ternary => write!(f, "{}:ternary:{}:{}", self.name, self.key_width, self.capacity),
+            TableKind::Direct => write!(f, "{}:direct:{}", self.name, self.capacity),
+        }
+    }
+}
+
+/// Parses `name:ternary:<key_width>:<capacity>` or `name:direct:<capacity>`.
+impl FromStr for TableSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let number = |field: &str| field.parse::<usize>().map_err(|_| format!("Invalid number '{}' in table spec '{}'", field, s));
+        let spec = match fields.as_slice() {
+            [name, "ternary", key_width, capacity] => TableSpec {
+                name: name.to_string(),
+                kind: TableKind::Ternary,
+                key_width: number(key_width)?,
+                capacity: number(capacity)?,
+            },
+            [name, "direct", capacity] => TableSpec {
+                name: name.to_string(),
+                kind: TableKind::Direct,
+                key_width: std::mem::size_of::<u16>(),
+                capacity: number(capacity)?,
+            },
+            _ => return Err(format!("Invalid table spec '{}'", s)),
+        };
+        spec.validate()?;
+        Ok(spec)
+    }
+}
+
+/// Hit statistics kept for every entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub packets: u64,
+    pub bytes: u64,
+    /// Time of the last hit in nanoseconds since the Unix epoch, 0 if never hit.
+    pub last_hit: u64,
+}
+
+impl Counters {
+    fn hit(&mut self, bytes: u64, now: u64) {
+        self.packets += 1;
+        self.bytes += bytes;
+        self.last_hit = now;
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "packets={} bytes={} last_hit={}", self.packets, self.bytes, self.last_hit)
+    }
+}
+
+/// Aging parameters of an entry, in seconds. Zero disables a timeout.
+///
+/// An entry expires `hard` seconds after it was installed, or `idle` seconds
+/// after it was last hit (or installed, if it was never hit).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timeouts {
+    pub idle: u32,
+    pub hard: u32,
+}
+
+impl Timeouts {
+    pub fn expired(&self, installed: u64, last_hit: u64, now: u64) -> bool {
+        const NANOS: u64 = 1_000_000_000;
+        let hard = self.hard != 0 && now >= installed.saturating_add(self.hard as u64 * NANOS);
+        let idle = self.idle != 0 && now >= installed.max(last_hit).saturating_add(self.idle as u64 * NANOS);
+        hard || idle
+    }
+}
+
+impl fmt::Display for Timeouts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "idle_timeout={} hard_timeout={}", self.idle, self.hard)
+    }
+}
+
+/// The client session that installed an entry. The server removes a session's
+/// entries when the session ends, except for persistent ones, which it keeps
+/// without an owner.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Owner {
+    /// Session id, 0 for entries installed outside a session.
+    pub session: u64,
+    pub persistent: bool,
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "session={}", self.session)?;
+        if self.persistent {
+            write!(f, " persistent")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a write created a new entry or replaced the result of an existing one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Added,
+    Modified,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TernaryEntry {
+    pub priority: u16,
+    /// Stored already masked, so `key & mask == key`.
+    pub key: Vec<u8>,
+    pub mask: Vec<u8>,
+    pub result: Vec<u8>,
+    pub counters: Counters,
+    pub timeouts: Timeouts,
+    /// Time the entry was added, or last re-added, in nanoseconds since the Unix epoch.
+    pub installed: u64,
+    pub owner: Owner,
+    /// Table version of the entry's last change, see `Table::version`.
+    pub version: u64,
+}
+
+impl TernaryEntry {
+    pub fn matches(&self, key: &[u8]) -> bool {
+        key.len() == self.key.len()
+            && key.iter().zip(&self.mask).zip(&self.key).all(|((k, m), e)| k & m == *e)
+    }
+
+    fn is_rule(&self, priority: u16, key: &[u8], mask: &[u8]) -> bool {
+        self.priority == priority && self.mask == mask && self.key == key
+    }
+
+    pub fn expired(&self, now: u64) -> bool {
+        self.timeouts.expired(self.installed, self.counters.last_hit, now)
+    }
+}
+
+#[cfg(test)]
+impl TernaryEntry {
+    /// A rule with nothing else set, for tests to fill in the rest.
+    pub(crate) fn new(priority: u16, key: &[u8], mask: &[u8], result: &[u8]) -> TernaryEntry {
+        TernaryEntry {
+            priority,
+            key: key.to_vec(),
+            mask: mask.to_vec(),
+            result: result.to_vec(),
+            counters: Counters::default(),
+            timeouts: Timeouts::default(),
+            installed: 0,
+            owner: Owner::default(),
+            version: 0,
+        }
+    }
+}
+
+impl fmt::Display for TernaryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "prio={} key={} mask={} result={}",
+               self.priority, to_hex(&self.key), to_hex(&self.mask), to_hex(&self.result))?;
+        if self.timeouts != Timeouts::default() {
+            write!(f, " {}", self.timeouts)?;
+        }
+        if self.owner != Owner::default() {
+            write!(f, " {}", self.owner)?;
+        }
+        if self.version != 0 {
+            write!(f, " version={}", self.version)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirectEntry {
+    pub index: u16,
+    pub value: Vec<u8>,
+    pub counters: Counters,
+    pub timeouts: Timeouts,
+    pub installed: u64,
+    pub owner: Owner,
+    pub version: u64,
+}
+
+impl DirectEntry {
+    pub fn expired(&self, now: u64) -> bool {
+        self.timeouts.expired(self.installed, self.counters.last_hit, now)
+    }
+}
+
+#[cfg(test)]
+impl DirectEntry {
+    /// An entry with nothing but its value set, for tests to fill in the rest.
+    pub(crate) fn new(index: u16, value: &[u8]) -> DirectEntry {
+        DirectEntry {
+            index,
+            value: value.to_vec(),
+            counters: Counters::default(),
+            timeouts: Timeouts::default(),
+            installed: 0,
+            owner: Owner::default(),
+            version: 0,
+        }
+    }
+}
+
+impl fmt::Display for DirectEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "index={} value={}", self.index, to_hex(&self.value))?;
+        if self.timeouts != Timeouts::default() {
+            write!(f, " {}", self.timeouts)?;
+        }
+        if self.owner != Owner::default() {
+            write!(f, " {}", self.owner)?;
+        }
+        if self.version != 0 {
+            write!(f, " version={}", self.version)?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry of either table kind.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entry {
+    Ternary(TernaryEntry),
+    Direct(DirectEntry),
+}
+
+impl Entry {
+    /// Whether both entries occupy the same slot of a table: same priority,
+    /// key and mask, or same index.
+    pub fn same_key(&self, other: &Entry) -> bool {
+        match (self, other) {
+            (Entry::Ternary(a), Entry::Ternary(b)) => a.is_rule(b.priority, &b.key, &b.mask),
+            (Entry::Direct(a), Entry::Direct(b)) => a.index == b.index,
+            _ => false,
+        }
+    }
+
+    /// Whether both entries program the same thing, ignoring counters and aging.
+    pub fn same_programming(&self, other: &Entry) -> bool {
+        self.same_key(other) && match (self, other) {
+            (Entry::Ternary(a), Entry::Ternary(b)) => a.result == b.result,
+            (Entry::Direct(a), Entry::Direct(b)) => a.value == b.value,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::Ternary(entry) => entry.fmt(f),
+            Entry::Direct(entry) => entry.fmt(f),
+        }
+    }
+}
+
+fn masked(key: &[u8], mask: &[u8]) -> Vec<u8> {
+    key.iter().zip(mask).map(|(k, m)| k & m).collect()
+}
+
+/// Priority ordered value/mask entries. Higher priorities match first; among
+/// equal priorities the entry installed first wins.
+pub struct TernaryTable {
+    spec: TableSpec,
+    entries: Vec<TernaryEntry>,
+    default_result: Option<Vec<u8>>,
+    version: u64,
+}
+
+impl TernaryTable {
+    pub fn new(spec: TableSpec) -> Self {
+        TernaryTable { spec, entries: Vec::new(), default_result: None, version: 0 }
+    }
+
+    pub fn entries(&self) -> &[TernaryEntry] {
+        &self.entries
+    }
+
+    fn check_key(&self, key: &[u8], mask: &[u8]) -> Result<(), &'static str> {
+        if key.len() != mask.len() {
+            return Err("Key and mask length differ");
+        }
+        if key.len() != self.spec.key_width {
+            return Err("Key length does not match table key width");
+        }
+        Ok(())
+    }
+
+    fn position(&self, priority: u16, key: &[u8], mask: &[u8]) -> Option<usize> {
+        let key = masked(key, mask);
+        self.entries.iter().position(|e| e.is_rule(priority, &key, mask))
+    }
+
+    /// Adds an entry, or replaces the result of an existing one. Re-adding an
+    /// entry restarts its timeouts.
+    pub fn insert(&mut self, priority: u16, key: &[u8], mask: &[u8], result: &[u8],
+                  timeouts: Timeouts, now: u64) -> Result<Change, &'static str> {
+        self.check_key(key, mask)?;
+        if let Some(pos) = self.position(priority, key, mask) {
+            self.version += 1;
+            let entry = &mut self.entries[pos];
+            entry.result = result.to_vec();
+            entry.timeouts = timeouts;
+            entry.installed = now;
+            entry.version = self.version;
+            return Ok(Change::Modified);
+        }
+        if self.entries.len() >= self.spec.capacity {
+            return Err("Table is full");
+        }
+        self.version += 1;
+        let version = self.version;
+        let pos = self.entries.partition_point(|e| e.priority >= priority);
+        self.entries.insert(pos, TernaryEntry {
+            priority,
+            key: masked(key, mask),
+            mask: mask.to_vec(),
+            result: result.to_vec(),
+            counters: Counters::default(),
+            timeouts,
+            installed: now,
+            owner: Owner::default(),
+            version,
+        });
+        Ok(Change::Added)
+    }
+
+    /// Inserts a complete entry, e.g. one read back from a snapshot.
+    pub fn restore(&mut self, entry: TernaryEntry) -> Result<(), &'static str> {
+        self.check_key(&entry.key, &entry.mask)?;
+        if self.position(entry.priority, &entry.key, &entry.mask).is_some() {
+            return Err("Duplicate entry");
+        }
+        if self.entries.len() >= self.spec.capacity {
+            return Err("Table is full");
+        }
+        let pos = self.entries.partition_point(|e| e.priority >= entry.priority);
+        self.version = self.version.max(entry.version);
+        self.entries.insert(pos, entry);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, priority: u16, key: &[u8], mask: &[u8]) -> Result<TernaryEntry, &'static str> {
+        self.check_key(key, mask)?;
+        match self.position(priority, key, mask) {
+            Some(pos) => {
+                self.version += 1;
+                Ok(self.entries.remove(pos))
+            },
+            None => Err("No such entry"),
+        }
+    }
+
+    pub fn get(&self, priority: u16, key: &[u8], mask: &[u8]) -> Option<&TernaryEntry> {
+        self.position(priority, key, mask).map(|pos| &self.entries[pos])
+    }
+
+    pub fn get_mut(&mut self, priority: u16, key: &[u8], mask: &[u8]) -> Option<&mut TernaryEntry> {
+        self.position(priority, key, mask).map(move |pos| &mut self.entries[pos])
+    }
+
+    /// Classifies `key` and accounts a hit of `bytes` bytes on the matching entry.
+    pub fn lookup(&mut self, key: &[u8], bytes: u64, now: u64) -> Option<&TernaryEntry> {
+        let entry = self.entries.iter_mut().find(|e| e.matches(key))?;
+        entry.counters.hit(bytes, now);
+        Some(entry)
+    }
+
+    pub fn reset_counters(&mut self) {
+        self.entries.iter_mut().for_each(|e| e.counters = Counters::default());
+    }
+
+    /// Removes and returns every entry whose timeout has passed.
+    pub fn expire(&mut self, now: u64) -> Vec<TernaryEntry> {
+        let (expired, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.entries).into_iter().partition(|e| e.expired(now));
+        self.entries = kept;
+        if !expired.is_empty() {
+            self.version += 1;
+        }
+        expired
+    }
+}
+
+/// Entries addressed directly by index.
+pub struct DirectTable {
+    spec: TableSpec,
+    entries: BTreeMap<u16, DirectEntry>,
+    default_result: Option<Vec<u8>>,
+    version: u64,
+}
+
+impl DirectTable {
+    pub fn new(spec: TableSpec) -> Self {
+        DirectTable { spec, entries: BTreeMap::new(), default_result: None, version: 0 }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &DirectEntry> {
+        self.entries.values()
+    }
+
+    /// Sets the entry at `index`. Overwriting an entry restarts its timeouts.
+    pub fn insert(&mut self, index: u16, value: &[u8], timeouts: Timeouts, now: u64) -> Result<Change, &'static str> {
+        if index as usize >= self.spec.capacity {
+            return Err("Index out of range");
+        }
+        self.version += 1;
+        let version = self.version;
+        if let Some(entry) = self.entries.get_mut(&index) {
+            entry.value = value.to_vec();
+            entry.timeouts = timeouts;
+            entry.installed = now;
+            entry.version = version;
+            return Ok(Change::Modified);
+        }
+        self.entries.insert(index, DirectEntry {
+            index,
+            value: value.to_vec(),
+            counters: Counters::default(),
+            timeouts,
+            installed: now,
+            owner: Owner::default(),
+            version,
+        });
+        Ok(Change::Added)
+    }
+
+    /// Inserts a complete entry, e.g. one read back from a snapshot.
+    pub fn restore(&mut self, entry: DirectEntry) -> Result<(), &'static str> {
+        if entry.index as usize >= self.spec.capacity {
+            return Err("Index out of range");
+        }
+        if self.entries.contains_key(&entry.index) {
+            return Err("Duplicate entry");
+        }
+        self.version = self.version.max(entry.version);
+        self.entries.insert(entry.index, entry);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: u16) -> Result<DirectEntry, &'static str> {
+        let entry = self.entries.remove(&index).ok_or("No such entry")?;
+        self.version += 1;
+        Ok(entry)
+    }
+
+    pub fn get(&self, index: u16) -> Option<&DirectEntry> {
+        self.entries.get(&index)
+    }
+
+    pub fn get_mut(&mut self, index: u16) -> Option<&mut DirectEntry> {
+        self.entries.get_mut(&index)
+    }
+
+    /// Reads the entry at `index` and accounts a hit of `bytes` bytes on it.
+    pub fn lookup(&mut self, index: u16, bytes: u64, now: u64) -> Option<&DirectEntry> {
+        let entry = self.entries.get_mut(&index)?;
+        entry.counters.hit(bytes, now);
+        Some(entry)
+    }
+
+    pub fn reset_counters(&mut self) {
+        self.entries.values_mut().for_each(|e| e.counters = Counters::default());
+    }
+
+    /// Removes and returns every entry whose timeout has passed.
+    pub fn expire(&mut self, now: u64) -> Vec<DirectEntry> {
+        let expired: Vec<u16> = self.entries.values().filter(|e| e.expired(now)).map(|e| e.index).collect();
+        if !expired.is_empty() {
+            self.version += 1;
+        }
+        expired.iter().filter_map(|index| self.entries.remove(index)).collect()
+    }
+}
+
+pub enum Table {
+    Ternary(TernaryTable),
+    Direct(DirectTable),
+}
+
+impl Table {
+    pub fn new(spec: TableSpec) -> Table {
+        match spec.kind {
+            TableKind::Ternary => Table::Ternary(TernaryTable::new(spec)),
+            TableKind::Direct => Table::Direct(DirectTable::new(spec)),
+        }
+    }
+
+    pub fn spec(&self) -> &TableSpec {
+        match self {
+            Table::Ternary(t) => &t.spec,
+            Table::Direct(t) => &t.spec,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Table::Ternary(t) => t.entries.len(),
+            Table::Direct(t) => t.entries.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Result returned by lookups that match no entry.
+    pub fn default_result(&self) -> Option<&[u8]> {
+        match self {
+            Table::Ternary(t) => t.default_result.as_deref(),
+            Table::Direct(t) => t.default_result.as_deref(),
+        }
+    }
+
+    pub fn set_default_result(&mut self, result: Option<Vec<u8>>) {
+        match self {
+            Table::Ternary(t) => t.default_result = result,
+            Table::Direct(t) => t.default_result = result,
+        }
+        *self.version_mut() += 1;
+    }
+
+    /// Raised by every change to the entries or the default result. An entry
+    /// carries the version of its last change, so versions never repeat, not
+    /// even for an entry removed and added again.
+    pub fn version(&self) -> u64 {
+        match self {
+            Table::Ternary(t) => t.version,
+            Table::Direct(t) => t.version,
+        }
+    }
+
+    /// Sets the version, e.g. one read back from a snapshot. It never goes
+    /// below the version of an entry.
+    pub fn set_version(&mut self, version: u64) {
+        let version_mut = self.version_mut();
+        *version_mut = (*version_mut).max(version);
+    }
+
+    fn version_mut(&mut self) -> &mut u64 {
+        match self {
+            Table::Ternary(t) => &mut t.version,
+            Table::Direct(t) => &mut t.version,
+        }
+    }
+
+    pub fn as_ternary(&self) -> Option<&TernaryTable> {
+        match self {
+            Table::Ternary(t) => Some(t),
+            Table::Direct(_) => None,
+        }
+    }
+
+    pub fn as_direct(&self) -> Option<&DirectTable> {
+        match self {
+            Table::Direct(t) => Some(t),
+            Table::Ternary(_) => None,
+        }
+    }
+
+    pub fn as_ternary_mut(&mut self) -> Result<&mut TernaryTable, &'static str> {
+        match self {
+            Table::Ternary(t) => Ok(t),
+            Table::Direct(_) => Err("Table is not a ternary table"),
+        }
+    }
+
+    pub fn as_direct_mut(&mut self) -> Result<&mut DirectTable, &'static str> {
+        match self {
+            Table::Direct(t) => Ok(t),
+            Table::Ternary(_) => Err("Table is not a direct table"),
+        }
+    }
+
+    pub fn reset_counters(&mut self) {
+        match self {
+            Table::Ternary(t) => t.reset_counters(),
+            Table::Direct(t) => t.reset_counters(),
+        }
+    }
+
+    pub fn expire(&mut self, now: u64) -> Vec<Entry> {
+        match self {
+            Table::Ternary(t) => t.expire(now).into_iter().map(Entry::Ternary).collect(),
+            Table::Direct(t) => t.expire(now).into_iter().map(Entry::Direct).collect(),
+        }
+    }
+
+    /// Entries that do not fit into `capacity`: the lowest priority ones of a
+    /// ternary table, those with an index of at least `capacity` in a direct
+    /// table.
+    pub fn overflow(&self, capacity: usize) -> Vec<Entry> {
+        match self {
+            Table::Ternary(t) => t.entries.iter().skip(capacity).cloned().map(Entry::Ternary).collect(),
+            Table::Direct(t) => t.entries.values().filter(|e| e.index as usize >= capacity).cloned().map(Entry::Direct).collect(),
+        }
+    }
+
+    /// Ends `session`'s ownership of its entries: removes and returns those
+    /// that are not persistent, and disowns the others, returning their number.
+    pub fn release(&mut self, session: u64) -> (Vec<Entry>, usize) {
+        let owned = |owner: &Owner| owner.session == session;
+        let dropped = |owner: &Owner| owned(owner) && !owner.persistent;
+        let mut kept = 0;
+        let removed: Vec<Entry> = match self {
+            Table::Ternary(t) => {
+                let (removed, entries): (Vec<_>, Vec<_>) = std::mem::take(&mut t.entries).into_iter()
+                    .partition(|e| dropped(&e.owner));
+                t.entries = entries;
+                for entry in t.entries.iter_mut().filter(|e| owned(&e.owner)) {
+                    entry.owner = Owner::default();
+                    kept += 1;
+                }
+                removed.into_iter().map(Entry::Ternary).collect()
+            },
+            Table::Direct(t) => {
+                let removed = t.entries.values().filter(|e| dropped(&e.owner)).cloned().map(Entry::Direct).collect();
+                t.entries.retain(|_, e| !dropped(&e.owner));
+                for entry in t.entries.values_mut().filter(|e| owned(&e.owner)) {
+                    entry.owner = Owner::default();
+                    kept += 1;
+                }
+                removed
+            },
+        };
+        if !removed.is_empty() {
+            *self.version_mut() += 1;
+        }
+        (removed, kept)
+    }
+
+    /// Sessions that own entries of the table.
+    pub fn sessions(&self) -> BTreeSet<u64> {
+        let owners: Vec<Owner> = match self {
+            Table::Ternary(t) => t.entries.iter().map(|e| e.owner).collect(),
+            Table::Direct(t) => t.entries.values().map(|e| e.owner).collect(),
+        };
+        owners.into_iter().map(|o| o.session).filter(|&s| s != 0).collect()
+    }
+
+    /// Changes the table's capacity, dropping and returning the entries that no
+    /// longer fit.
+    pub fn resize(&mut self, capacity: usize) -> Result<Vec<Entry>, String> {
+        let spec = TableSpec { capacity, ..self.spec().clone() };
+        spec.validate()?;
+        let dropped = self.overflow(capacity);
+        match self {
+            Table::Ternary(t) => {
+                t.entries.truncate(capacity);
+                t.spec = spec;
+            },
+            Table::Direct(t) => {
+                t.entries.retain(|index, _| (*index as usize) < capacity);
+                t.spec = spec;
+            },
+        }
+        if !dropped.is_empty() {
+            *self.version_mut() += 1;
+        }
+        Ok(dropped)
+    }
+}
+
+/// The set of shadow tables, by table id.
+#[derive(Default)]
+pub struct Tables {
+    tables: BTreeMap<String, Table>,
+}
+
+impl Tables {
+    /// Declares `spec` unless a table with exactly this spec already exists.
+    pub fn declare_or_match(&mut self, spec: TableSpec) -> Result<(), String> {
+        match self.tables.get(&spec.name) {
+            Some(table) if *table.spec() == spec => Ok(()),
+            Some(_) => Err(format!("Table '{}' does not match its existing declaration", spec.name)),
+            None => self.declare(spec),
+        }
+    }
+
+    pub fn declare(&mut self, spec: TableSpec) -> Result<(), String> {
+        spec.validate()?;
+        if self.tables.contains_key(&spec.name) {
+            return Err(format!("Table '{}' declared twice", spec.name));
+        }
+        self.tables.insert(spec.name.clone(), Table::new(spec));
+        Ok(())
+    }
+
+    pub fn get(&self, table_id: &str) -> Option<&Table> {
+        self.tables.get(table_id)
+    }
+
+    pub fn remove(&mut self, table_id: &str) -> Option<Table> {
+        self.tables.remove(table_id)
+    }
+
+    pub fn get_mut(&mut self, table_id: &str) -> Result<&mut Table, &'static str> {
+        self.tables.get_mut(table_id).ok_or("No such table")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Table> {
+        self.tables.values()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Table> {
+        self.tables.values_mut()
+    }
+}
+
+#[cfg(test)]
+mod tables_tests {
+    use super::*;
+
+    const NEVER: Timeouts = Timeouts { idle: 0, hard: 0 };
+    const SECOND: u64 = 1_000_000_000;
+
+    fn acl() -> TernaryTable {
+        TernaryTable::new("acl:ternary:2:4".parse().unwrap())
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x0a, 0xff]), "0aff");
+        assert_eq!(from_hex("0aff"), Ok(vec![0x0a, 0xff]));
+        assert_eq!(from_hex(""), Ok(vec![]));
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_table_spec() {
+        let spec: TableSpec = "acl:ternary:16:1024".parse().unwrap();
+        assert_eq!(spec.kind, TableKind::Ternary);
+        assert_eq!(spec.key_width, 16);
+        assert_eq!(spec.capacity, 1024);
+
+        let spec: TableSpec = "nexthop:direct:256".parse().unwrap();
+        assert_eq!(spec.kind, TableKind::Direct);
+        assert_eq!(spec.capacity, 256);
+
+        assert!("acl:ternary:0:10".parse::<TableSpec>().is_err());
+        assert!("acl:exact:2:10".parse::<TableSpec>().is_err());
+        assert!("nexthop:direct:70000".parse::<TableSpec>().is_err());
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let mut table = acl();
+        table.insert(1, &[0x0a, 0x00], &[0xff, 0x00], b"low", NEVER, 0).unwrap();
+        table.insert(5, &[0x0a, 0x01], &[0xff, 0xff], b"high", NEVER, 0).unwrap();
+
+        assert_eq!(table.lookup(&[0x0a, 0x01], 64, 1).unwrap().result, b"high");
+        assert_eq!(table.lookup(&[0x0a, 0x02], 64, 1).unwrap().result, b"low");
+        assert!(table.lookup(&[0x0b, 0x02], 64, 1).is_none());
+    }
+
+    #[test]
+    fn test_insert_modify_remove() {
+        let mut table = acl();
+        assert_eq!(table.insert(1, &[0x0a, 0x33], &[0xff, 0x00], b"a", NEVER, 0), Ok(Change::Added));
+        // Bits outside the mask do not make a different rule.
+        assert_eq!(table.insert(1, &[0x0a, 0x44], &[0xff, 0x00], b"b", NEVER, 0), Ok(Change::Modified));
+        assert_eq!(table.entries().len(), 1);
+        assert_eq!(table.entries()[0].key, vec![0x0a, 0x00]);
+        assert_eq!(table.entries()[0].result, b"b");
+
+        assert!(table.insert(1, &[0x0a], &[0xff, 0x00], b"a", NEVER, 0).is_err());
+        assert!(table.remove(2, &[0x0a, 0x00], &[0xff, 0x00]).is_err());
+        assert!(table.remove(1, &[0x0a, 0x00], &[0xff, 0x00]).is_ok());
+        assert!(table.entries().is_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut table = acl();
+        for i in 0..4 {
+            table.insert(i, &[i as u8, 0], &[0xff, 0xff], b"", NEVER, 0).unwrap();
+        }
+        assert_eq!(table.insert(9, &[9, 0], &[0xff, 0xff], b"", NEVER, 0), Err("Table is full"));
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut table = Table::Ternary(acl());
+        for i in 0..3 {
+            table.as_ternary_mut().unwrap().insert(i, &[i as u8, 0], &[0xff, 0xff], b"", NEVER, 0).unwrap();
+        }
+        assert!(table.resize(8).unwrap().is_empty());
+        assert_eq!(table.spec().capacity, 8);
+
+        // Shrinking drops the lowest priority entries.
+        assert_eq!(table.overflow(2).len(), 1);
+        let dropped = table.resize(2).unwrap();
+        assert_eq!(dropped.len(), 1);
+        assert!(matches!(&dropped[0], Entry::Ternary(e) if e.priority == 0));
+        assert_eq!(table.len(), 2);
+        assert!(table.resize(0).is_err());
+
+        let mut table = Table::new("nexthop:direct:16".parse().unwrap());
+        table.as_direct_mut().unwrap().insert(12, b"", NEVER, 0).unwrap();
+        assert_eq!(table.overflow(12).len(), 1);
+        assert!(table.overflow(13).is_empty());
+        assert_eq!(table.spec().to_string(), "nexthop:direct:16");
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut table = acl();
+        table.insert(1, &[0x0a, 0x00], &[0xff, 0x00], b"a", NEVER, 0).unwrap();
+        table.lookup(&[0x0a, 0x01], 100, 7);
+        table.lookup(&[0x0a, 0x02], 50, 9);
+
+        let counters = table.get(1, &[0x0a, 0x00], &[0xff, 0x00]).unwrap().counters;
+        assert_eq!(counters, Counters { packets: 2, bytes: 150, last_hit: 9 });
+
+        table.reset_counters();
+        assert_eq!(table.entries()[0].counters, Counters::default());
+    }
+
+    #[test]
+    fn test_hard_timeout() {
+        let mut table = acl();
+        table.insert(1, &[1, 0], &[0xff, 0], b"a", Timeouts { idle: 0, hard: 10 }, 0).unwrap();
+        table.insert(1, &[2, 0], &[0xff, 0], b"b", NEVER, 0).unwrap();
+
+        table.lookup(&[1, 0], 64, 9 * SECOND);
+        assert!(table.expire(9 * SECOND).is_empty());
+
+        let expired = table.expire(10 * SECOND);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].result, b"a");
+        assert_eq!(table.entries().len(), 1);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let mut table = acl();
+        table.insert(1, &[1, 0], &[0xff, 0], b"a", Timeouts { idle: 5, hard: 0 }, 0).unwrap();
+
+        table.lookup(&[1, 0], 64, 4 * SECOND);
+        assert!(table.expire(8 * SECOND).is_empty());
+        assert_eq!(table.expire(9 * SECOND).len(), 1);
+
+        // Re-adding restarts the timers.
+        table.insert(1, &[1, 0], &[0xff, 0], b"a", Timeouts { idle: 5, hard: 0 }, 10 * SECOND).unwrap();
+        table.insert(1, &[1, 0], &[0xff, 0], b"a", Timeouts { idle: 5, hard: 0 }, 14 * SECOND).unwrap();
+        assert!(table.expire(18 * SECOND).is_empty());
+        assert_eq!(table.expire(19 * SECOND).len(), 1);
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut table = acl();
+        table.insert(1, &[1, 0], &[0xff, 0], b"a", NEVER, 0).unwrap();
+        let mut entry = table.entries()[0].clone();
+        entry.priority = 9;
+        entry.counters.packets = 3;
+        table.restore(entry.clone()).unwrap();
+        assert_eq!(table.entries()[0], entry);
+        assert_eq!(table.restore(entry), Err("Duplicate entry"));
+    }
+
+    #[test]
+    fn test_release() {
+        let mut table = Table::Ternary(acl());
+        let acl = table.as_ternary_mut().unwrap();
+        for (i, owner) in [(1, Owner { session: 1, persistent: false }), (2, Owner { session: 1, persistent: true }),
+                           (3, Owner { session: 2, persistent: false })] {
+            acl.insert(i, &[i as u8, 0], &[0xff, 0xff], b"", NEVER, 0).unwrap();
+            acl.get_mut(i, &[i as u8, 0], &[0xff, 0xff]).unwrap().owner = owner;
+        }
+        assert_eq!(table.sessions(), BTreeSet::from([1, 2]));
+
+        let (removed, kept) = table.release(1);
+        assert_eq!(kept, 1);
+        assert!(matches!(&removed[..], [Entry::Ternary(e)] if e.priority == 1));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.sessions(), BTreeSet::from([2]));
+        assert_eq!(table.release(1), (vec![], 0));
+    }
+
+    #[test]
+    fn test_direct_table() {
+        let mut table = DirectTable::new("nexthop:direct:8".parse().unwrap());
+        assert_eq!(table.insert(3, b"x", NEVER, 0), Ok(Change::Added));
+        assert_eq!(table.insert(3, b"y", NEVER, 0), Ok(Change::Modified));
+        assert!(table.insert(8, b"z", NEVER, 0).is_err());
+
+        assert_eq!(table.lookup(3, 10, 5).unwrap().value, b"y");
+        assert_eq!(table.get(3).unwrap().counters.packets, 1);
+        assert!(table.lookup(4, 10, 5).is_none());
+        assert!(table.remove(3).is_ok());
+        assert!(table.remove(3).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! An in-process server for tests.
+//!
+//! [`TestServer`] runs a [`Server`] on a thread, reading requests from an
+//! in-process queue and answering on in-process queues, the way the `server`
+//! binary does with message queues. No kernel queues are created, so tests
+//! can run in parallel and leave nothing behind.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::client::TableClient;
+use crate::protocol::{Reply, Request, RequestBody};
+use crate::server::Server;
+use crate::tables::now_nanos;
+use crate::transport::{unlink_memory_queue, MemoryTransport};
+use crate::TableInterface;
+
+/// Item size and depth of the in-process queues.
+const ITEM_SIZE: usize = 1024;
+const DEPTH: usize = 64;
+
+/// How long the server thread waits for a request before checking whether
+/// it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+fn unique_name(kind: &str) -> String {
+    format!("/{}.{}.{}", kind, std::process::id(), NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+pub struct TestServer {
+    name: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Server>>,
+}
+
+impl TestServer {
+    /// Starts serving `server` on a fresh in-process queue.
+    pub fn start(mut server: Server) -> TestServer {
+        let name = unique_name("test_server");
+        let reader = TableInterface::get_memory_queue(&name, ITEM_SIZE, DEPTH);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let thread = std::thread::spawn(move || {
+            let mut writers: HashMap<String, TableInterface> = HashMap::new();
+            while !stopping.load(Ordering::Relaxed) {
+                server.tick(now_nanos());
+                if let Ok(Some(message)) = reader.read_timeout(POLL_INTERVAL) {
+                    if let Ok((request, reply, encoding)) = server.handle_message(&message, None) {
+                        if !request.reply_to.is_empty() {
+                            let writer = writers.entry(request.reply_to.clone())
+                                .or_insert_with(|| TableInterface::get_memory_queue(&request.reply_to, ITEM_SIZE, DEPTH));
+                            let _ = writer.write(&reply.encode(encoding));
+                        }
+                    }
+                }
+                // Like the binary, notifications only go to queues that exist
+                // and are dropped when they are full.
+                for notification in server.take_notifications() {
+                    if let Some(transport) = MemoryTransport::open_existing(&notification.queue, true) {
+                        let writer = TableInterface::with_transport(&notification.queue, Box::new(transport));
+                        let _ = writer.write(&notification.event.encode(notification.encoding));
+                    }
+                }
+            }
+            server
+        });
+        TestServer { name, stop, thread: Some(thread) }
+    }
+
+    /// Name of the in-process queue the server reads requests from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Connects a new client with its own reply queue.
+    pub fn client(&self) -> TestClient {
+        let reply_to = unique_name("test_client");
+        TestClient {
+            writer: TableInterface::get_memory_queue(&self.name, ITEM_SIZE, DEPTH),
+            reader: TableInterface::get_memory_queue(&reply_to, ITEM_SIZE, DEPTH),
+            reply_to,
+            token: None,
+        }
+    }
+
+    /// Connects a [`TableClient`] with its own reply queue.
+    pub fn table_client(&self) -> TableClient {
+        TableClient::with_queues(TableInterface::get_memory_queue(&self.name, ITEM_SIZE, DEPTH),
+                                 TableInterface::get_memory_queue(&unique_name("table_client"), ITEM_SIZE, DEPTH))
+    }
+
+    /// Stops the server thread and returns the server, to look at its state.
+    pub fn stop(mut self) -> Server {
+        self.join().expect("Stopped only once")
+    }
+
+    fn join(&mut self) -> Option<Server> {
+        self.stop.store(true, Ordering::Relaxed);
+        let server = self.thread.take()?.join().expect("Server thread panicked");
+        let _ = unlink_memory_queue(&self.name);
+        Some(server)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// A client of a [`TestServer`].
+pub struct TestClient {
+    writer: TableInterface,
+    reader: TableInterface,
+    reply_to: String,
+    token: Option<String>,
+}
+
+impl TestClient {
+    /// Sends `body` and waits for the reply.
+    pub fn request(&self, body: RequestBody) -> Result<Reply, String> {
+        self.writer.write(&Request { reply_to: self.reply_to.clone(), sequence: 0, token: self.token.clone(), body }.pack())?;
+        Ok(Reply::unpack(&self.reader.read()?)?)
+    }
+
+    /// Sends `body` without asking for a reply.
+    pub fn send(&self, body: RequestBody) -> Result<(), String> {
+        Ok(self.writer.write(&Request { reply_to: String::new(), sequence: 0, token: self.token.clone(), body }.pack())?)
+    }
+
+    /// Sends `token` with every request from now on.
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+
+    /// Queue the replies come back on.
+    pub fn reply_to(&self) -> &str {
+        &self.reply_to
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        let _ = unlink_memory_queue(&self.reply_to);
+    }
+}
+
+#[cfg(test)]
+mod testing_tests {
+    use super::*;
+    use crate::protocol::Event;
+    use crate::tables::Tables;
+    use crate::{Actions, SItem};
+
+    fn server() -> Server {
+        let mut tables = Tables::default();
+        tables.declare("nexthop:direct:16".parse().unwrap()).unwrap();
+        Server::new(tables)
+    }
+
+    fn add(index: u16, value: &[u8]) -> RequestBody {
+        let mut item = SItem::default_instance();
+        item.table_id = "nexthop".to_string();
+        item.action = Actions::Add;
+        item.index = index;
+        item.value = value.to_vec();
+        RequestBody::Direct(item, Default::default())
+    }
+
+    #[test]
+    fn test_requests() {
+        let test_server = TestServer::start(server());
+        let client = test_server.client();
+        let other = test_server.client();
+        assert_ne!(client.reply_to(), other.reply_to());
+
+        assert_eq!(client.request(add(3, b"eth0")), Ok(Reply::Ok));
+        other.send(add(4, b"eth1")).unwrap();
+        assert!(matches!(other.request(RequestBody::Dump { table_id: "nexthop".to_string() }), Ok(Reply::Direct(e)) if e.len() == 2));
+        assert_eq!(client.request(RequestBody::Dump { table_id: "missing".to_string() }), Ok(Reply::Error("No such table".to_string())));
+
+        let server = test_server.stop();
+        assert_eq!(server.tables().get("nexthop").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_notifications() {
+        let test_server = TestServer::start(server());
+        let client = test_server.client();
+        let queue = unique_name("test_subscriber");
+        let events = TableInterface::get_memory_queue(&queue, ITEM_SIZE, DEPTH);
+        let subscribe = RequestBody::Subscribe { queue: queue.clone(), tables: Vec::new(), filter: None };
+        assert_eq!(client.request(subscribe), Ok(Reply::Ok));
+
+        assert_eq!(client.request(add(3, b"eth0")), Ok(Reply::Ok));
+        let event = Event::unpack(&events.read_timeout(Duration::from_secs(1)).unwrap().expect("No notification")).unwrap();
+        assert_eq!(event.table_id, "nexthop");
+        unlink_memory_queue(&queue).unwrap();
+    }
+}
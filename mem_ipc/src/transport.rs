@@ -0,0 +1,192 @@
+//! What carries the items of a [`TableInterface`](crate::TableInterface).
+//!
+//! A transport moves single items, each at most `max_item_size` bytes;
+//! `TableInterface` fragments messages on top of it. Besides POSIX message
+//! queues there are in-process queues, so clients and servers can be tested
+//! in one process without creating kernel queues.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+/// Error of `receive` once the other end of a connection has gone away.
+/// [`TableInterface`](crate::TableInterface) passes it on unchanged.
+pub const CONNECTION_CLOSED: &str = "Connection closed";
+
+pub trait Transport: Send + Sync {
+    /// Sends one item.
+    fn send(&self, item: &[u8]) -> Result<(), String>;
+
+    /// Receives one item into `buffer`, waiting at most until `deadline` or
+    /// forever if there is none. Returns `None` once the deadline has passed.
+    fn receive(&self, buffer: &mut [u8], deadline: Option<Instant>) -> Result<Option<usize>, String>;
+
+    /// Largest item `send` takes and `receive` returns.
+    fn max_item_size(&self) -> usize;
+
+    /// Removes the queue's name, so the next open creates a new queue.
+    fn unlink(&self) -> Result<(), String>;
+}
+
+struct MemoryQueue {
+    items: Mutex<VecDeque<Vec<u8>>>,
+    /// Signalled whenever an item is added or taken.
+    changed: Condvar,
+    max_item_size: usize,
+    max_items: usize,
+}
+
+/// In-process queues by name.
+static MEMORY_QUEUES: Mutex<BTreeMap<String, Arc<MemoryQueue>>> = Mutex::new(BTreeMap::new());
+
+/// One end of an in-process queue. Like a message queue it is opened by name
+/// and lives on until unlinked, independent of the ends open on it.
+pub struct MemoryTransport {
+    name: String,
+    queue: Arc<MemoryQueue>,
+    nonblocking: bool,
+}
+
+impl MemoryTransport {
+    /// Opens the in-process queue `name`, creating it with room for
+    /// `max_items` items of `max_item_size` bytes if it does not exist. An
+    /// existing queue keeps the sizes it was created with. If `nonblocking`
+    /// is set, `send` fails instead of waiting when the queue is full.
+    pub fn open(name: &str, max_item_size: usize, max_items: usize, nonblocking: bool) -> MemoryTransport {
+        let queue = MEMORY_QUEUES.lock().unwrap().entry(name.to_string()).or_insert_with(|| Arc::new(MemoryQueue {
+            items: Mutex::new(VecDeque::new()),
+            changed: Condvar::new(),
+            max_item_size,
+            max_items,
+        })).clone();
+        MemoryTransport { name: name.to_string(), queue, nonblocking }
+    }
+
+    /// Opens the in-process queue `name` only if it exists.
+    pub fn open_existing(name: &str, nonblocking: bool) -> Option<MemoryTransport> {
+        let queue = MEMORY_QUEUES.lock().unwrap().get(name)?.clone();
+        Some(MemoryTransport { name: name.to_string(), queue, nonblocking })
+    }
+
+    /// Items waiting in the queue.
+    pub fn pending(&self) -> usize {
+        self.queue.items.lock().unwrap().len()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&self, item: &[u8]) -> Result<(), String> {
+        if item.len() > self.queue.max_item_size {
+            return Err(format!("Item of {} bytes exceeds the queue's {} bytes", item.len(), self.queue.max_item_size));
+        }
+        let mut items = self.queue.items.lock().unwrap();
+        while items.len() >= self.queue.max_items {
+            if self.nonblocking {
+                return Err(format!("Queue {} is full", self.name));
+            }
+            items = self.queue.changed.wait(items).unwrap();
+        }
+        items.push_back(item.to_vec());
+        self.queue.changed.notify_all();
+        Ok(())
+    }
+
+    fn receive(&self, buffer: &mut [u8], deadline: Option<Instant>) -> Result<Option<usize>, String> {
+        let mut items = self.queue.items.lock().unwrap();
+        loop {
+            // Like mq_receive, an item too large for the buffer stays queued.
+            if let Some(item) = items.front() {
+                if item.len() > buffer.len() {
+                    return Err(format!("Item of {} bytes does not fit into {} bytes", item.len(), buffer.len()));
+                }
+                let item = items.pop_front().expect("Checked above");
+                self.queue.changed.notify_all();
+                buffer[..item.len()].copy_from_slice(&item);
+                return Ok(Some(item.len()));
+            }
+            items = match deadline {
+                None => self.queue.changed.wait(items).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    self.queue.changed.wait_timeout(items, deadline - now).unwrap().0
+                },
+            };
+        }
+    }
+
+    fn max_item_size(&self) -> usize {
+        self.queue.max_item_size
+    }
+
+    fn unlink(&self) -> Result<(), String> {
+        unlink_memory_queue(&self.name)
+    }
+}
+
+/// Removes the in-process queue `name`. Ends already open on it keep working.
+pub fn unlink_memory_queue(name: &str) -> Result<(), String> {
+    match MEMORY_QUEUES.lock().unwrap().remove(name) {
+        Some(_) => Ok(()),
+        None => Err(format!("No in-process queue {}", name)),
+    }
+}
+
+pub fn memory_queue_exists(name: &str) -> bool {
+    MEMORY_QUEUES.lock().unwrap().contains_key(name)
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_memory_queue() {
+        let name = "/memory_transport_test";
+        let writer = MemoryTransport::open(name, 8, 2, true);
+        let reader = MemoryTransport::open(name, 1024, 10, false);
+        assert_eq!(reader.max_item_size(), 8);
+        assert!(memory_queue_exists(name));
+
+        writer.send(b"one").unwrap();
+        writer.send(b"two").unwrap();
+        assert!(writer.send(b"three").is_err());
+        assert!(writer.send(b"too long!").is_err());
+        assert_eq!(reader.pending(), 2);
+
+        let mut buffer = [0u8; 8];
+        assert_eq!(reader.receive(&mut buffer, None), Ok(Some(3)));
+        assert_eq!(&buffer[..3], b"one");
+        assert_eq!(reader.receive(&mut buffer, Some(Instant::now())), Ok(Some(3)));
+        assert_eq!(&buffer[..3], b"two");
+        assert_eq!(reader.receive(&mut buffer, Some(Instant::now() + Duration::from_millis(10))), Ok(None));
+
+        reader.unlink().unwrap();
+        assert!(!memory_queue_exists(name));
+        assert!(MemoryTransport::open_existing(name, false).is_none());
+        // The ends stay connected to each other.
+        writer.send(b"late").unwrap();
+        // An item too large for the buffer is left for a larger one.
+        assert!(reader.receive(&mut [0u8; 2], None).is_err());
+        assert_eq!(reader.pending(), 1);
+        assert_eq!(reader.receive(&mut buffer, None), Ok(Some(4)));
+    }
+
+    #[test]
+    fn test_blocking_send_waits_for_room() {
+        let name = "/memory_transport_blocking_test";
+        let writer = MemoryTransport::open(name, 8, 1, false);
+        let reader = MemoryTransport::open(name, 8, 1, false);
+        writer.send(b"first").unwrap();
+        let sender = std::thread::spawn(move || writer.send(b"second"));
+        let mut buffer = [0u8; 8];
+        assert_eq!(reader.receive(&mut buffer, None), Ok(Some(5)));
+        assert_eq!(reader.receive(&mut buffer, None), Ok(Some(6)));
+        assert_eq!(&buffer[..6], b"second");
+        sender.join().unwrap().unwrap();
+        unlink_memory_queue(name).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+//! Builds the C example in examples/c against the static library and runs it
+//! against a server.
+#![cfg(feature = "ffi")]
+
+use mem_ipc::server::Server;
+use mem_ipc::tables::Tables;
+use mem_ipc::{unlink_mq, TableInterface};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+/// Unlinks a queue when the test ends, whether it passed or not.
+struct Unlink(String);
+
+impl Drop for Unlink {
+    fn drop(&mut self) {
+        let _ = unlink_mq(&self.0);
+    }
+}
+
+/// Builds the static library with the C ABI into `target_dir`. The cargo
+/// running the test holds the lock on its own target directory, and does not
+/// build the staticlib for tests anyway.
+fn build_staticlib(target_dir: &Path) -> PathBuf {
+    let status = Command::new(env!("CARGO"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["rustc", "--lib", "--crate-type", "staticlib", "--features", "ffi", "--target-dir"]).arg(target_dir)
+        .status()
+        .expect("Failed to run cargo");
+    assert!(status.success(), "Failed to build the static library");
+    target_dir.join("debug/libmem_ipc.a")
+}
+
+#[test]
+fn test_c_example() {
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let library = build_staticlib(&tmp_dir.join("c_example"));
+    let program = tmp_dir.join(format!("program_tables.{}", std::process::id()));
+    let status = Command::new("cc")
+        .arg("-I").arg(crate_dir.join("include"))
+        .arg(crate_dir.join("examples/c/program_tables.c"))
+        .arg(&library)
+        .args(["-lpthread", "-ldl", "-lm", "-lrt", "-o"]).arg(&program)
+        .status()
+        .expect("Failed to run cc, the C example needs a C compiler");
+    assert!(status.success(), "Failed to build the C example");
+
+    let name = format!("/ffi_test.{}", std::process::id());
+    let reply_to = format!("{}.reply", name);
+    let _unlink = [Unlink(name.clone()), Unlink(reply_to.clone())];
+    let queue = TableInterface::get_table_reader_sized(&name, 1024, 10).expect("Failed to get reader");
+    let responder = std::thread::spawn(move || {
+        let mut tables = Tables::default();
+        tables.declare("acl:ternary:2:16".parse().unwrap()).unwrap();
+        tables.declare("nexthop:direct:16".parse().unwrap()).unwrap();
+        let mut server = Server::new(tables);
+        for _ in 0..2 {
+            // Gives up if the example fails before sending both requests.
+            let Some(message) = queue.read_timeout(Duration::from_secs(5)).unwrap() else {
+                break;
+            };
+            let (request, reply, encoding) = server.handle_message(&message, None).unwrap();
+            let reply_queue = TableInterface::open_existing_writer(&request.reply_to).unwrap();
+            reply_queue.write(&reply.encode(encoding)).unwrap();
+        }
+        server
+    });
+
+    let output = Command::new(&program).args([&name, &reply_to]).output().expect("Failed to run the C example");
+    let _ = std::fs::remove_file(&program);
+    let server = responder.join().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let acl = server.tables().get("acl").unwrap().as_ternary().unwrap();
+    assert_eq!(acl.get(1, &[10, 0], &[0xff, 0]).unwrap().result, b"permit");
+    let nexthop = server.tables().get("nexthop").unwrap().as_direct().unwrap();
+    assert_eq!(nexthop.get(3).unwrap().value, b"eth0");
+}